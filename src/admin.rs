@@ -0,0 +1,213 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use mio::Sender;
+use cache::{CacheEntry, Names};
+use dns::message::DnsName;
+use dns::record::{Rcode, RecordType};
+use server::Control;
+use view::{View, Views};
+
+const TIMEOUT_MS: u64 = 5000;
+
+const USAGE: &'static str = "Commands, each optionally followed by the view to run it in:
+  stats              entries, bytes, evictions and expiries of the caches
+  list NAME          the cached answers for NAME
+  listtree NAME      the cached answers for NAME and the names below it
+  flushname NAME     drop the cached answers for NAME
+  flushtree NAME     drop the cached answers for NAME and the names below it
+  flush              drop everything cached
+";
+
+///
+///Takes a command per connection, like rndc. It's handed to the event loop, which runs it against the
+///views it has on a thread of its own, and what it outputs is written back before the connection is closed
+///
+pub fn listen(addr: SocketAddr, sender: Sender<Control>) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Couldn't listen for admin commands on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("Listening for admin commands on {}", addr);
+    let _ = thread::Builder::new()
+        .name("dns_srv_admin".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.map_err(|e| e.to_string()).and_then(|stream| serve(stream, &sender));
+                if let Err(e) = result {
+                    warn!("Admin connection failed: {}", e);
+                }
+            }
+        });
+}
+
+fn serve(stream: TcpStream, sender: &Sender<Control>) -> Result<(), String> {
+    let timeout = Some(Duration::from_millis(TIMEOUT_MS));
+    stream.set_read_timeout(timeout).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(timeout).map_err(|e| e.to_string())?;
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command).map_err(|e| e.to_string())?;
+
+    let (output, reply) = mpsc::channel();
+    sender.send(Control::Admin(command.trim().to_owned(), output)).map_err(|e| format!("{:?}", e))?;
+    let reply = reply.recv_timeout(Duration::from_millis(TIMEOUT_MS)).map_err(|e| e.to_string())?;
+    (&stream).write_all(reply.as_bytes()).map_err(|e| e.to_string())
+}
+
+///Run a command against the views, returning what it output
+pub fn run(command: &str, views: &Views) -> String {
+    let words = command.split_whitespace().collect::<Vec<_>>();
+    let (name, view) = match words.len() {
+        0 => return USAGE.to_owned(),
+        1 => (None, None),
+        _ if words[0] == "stats" || words[0] == "flush" => (None, words.get(1)),
+        _ => (words.get(1), words.get(2)),
+    };
+    let selected = match view {
+        Some(view) => match views.all().find(|v| v.name == *view) {
+            Some(found) => vec![found],
+            None => return format!("No view {}\n", view),
+        },
+        None => views.all().collect::<Vec<_>>(),
+    };
+    let name = name.map(|name| DnsName::from_string(name.trim_end_matches('.').to_owned()));
+
+    match (words[0], name) {
+        ("stats", None) => stats(&selected),
+        ("list", Some(name)) => list(&selected, &Names::Name(name)),
+        ("listtree", Some(name)) => list(&selected, &Names::Tree(name)),
+        ("flushname", Some(name)) => flush(&selected, &Names::Name(name)),
+        ("flushtree", Some(name)) => flush(&selected, &Names::Tree(name)),
+        ("flush", None) => flush(&selected, &Names::All),
+        _ => format!("Bad command {:?}\n{}", command, USAGE),
+    }
+}
+
+fn stats(views: &[&View]) -> String {
+    views.iter().map(|view| format!("{} {:?}\n", view.name, view.cache.stats())).collect()
+}
+
+fn list(views: &[&View], names: &Names) -> String {
+    let mut out = String::new();
+    for view in views {
+        let mut entries = view.cache.entries(names);
+        entries.sort_by_key(|entry| (entry.key.name().to_string(), entry.key.qtype()));
+        for entry in entries {
+            out.push_str(&describe(&view.name, &entry));
+        }
+    }
+    out
+}
+
+///view name type ttl security, and whether it's stale or negative
+fn describe(view: &str, entry: &CacheEntry) -> String {
+    let ttl = entry.calc_ttl();
+    let mut line = format!("{} {} {} {} {:?}", view, entry.key.name().to_string(), RecordType::name(entry.key.qtype()), ttl, entry.security);
+    if ttl == 0 {
+        line.push_str(" stale");
+    }
+    if entry.is_negative() {
+        line.push_str(if entry.rcode == Rcode::NXDOMAIN { " NXDOMAIN" } else { " NODATA" });
+    }
+    line.push('\n');
+    line
+}
+
+fn flush(views: &[&View], names: &Names) -> String {
+    views.iter().map(|view| format!("{} flushed {}\n", view.name, view.cache.flush(names))).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::run;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::{Arc, RwLock};
+    use cache::{CacheEntry, CacheKey, ShardedCache};
+    use dns::message::{DnsAnswer, DnsName};
+    use request::base::RequestParams;
+    use settings::CacheConfig;
+    use view::{View, Views};
+    use zone::ZoneStore;
+
+    fn view(name: &str) -> View {
+        let params = RequestParams {
+            timeout: 200,
+            upstream_addr: SocketAddr::from_str("8.8.8.8:53").unwrap(),
+        };
+        let mut view = View::default_for(Arc::new(RwLock::new(ZoneStore::default())), Arc::new(ShardedCache::new(&CacheConfig::default())), params);
+        view.name = name.to_owned();
+        view
+    }
+
+    fn cache(view: &View, name: &str, qtype: u16) {
+        let key = CacheKey::new(name.to_owned(), qtype, 1);
+        let answer = DnsAnswer::new(DnsName::from_string(name.to_owned()), qtype, 1, 60, 4, vec![10, 0, 0, 5]);
+        view.cache.upsert(key.clone(), CacheEntry::new(key, vec![answer], 60));
+    }
+
+    fn views() -> Views {
+        let guests = view("guests");
+        cache(&guests, "www.example.org", 1);
+        let default = view("default");
+        cache(&default, "www.example.org", 1);
+        cache(&default, "www.example.org", 28);
+        cache(&default, "mail.example.org", 1);
+        cache(&default, "www.example.net", 1);
+        Views::new(vec![guests], default)
+    }
+
+    ///The lines listed, with the TTL, which may have ticked down a second, taken out
+    fn list_views(command: &str, views: &Views) -> Vec<String> {
+        run(command, views)
+            .lines()
+            .map(|line| {
+                let mut words = line.split(' ').collect::<Vec<_>>();
+                let ttl = words.remove(3).parse::<u32>().unwrap();
+                assert!(ttl >= 59 && ttl <= 60, "{}", line);
+                words.join(" ")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn list() {
+        let views = views();
+        assert_eq!(vec!["guests www.example.org A Indeterminate", "default www.example.org A Indeterminate", "default www.example.org AAAA Indeterminate"],
+                   list_views("list www.example.org.", &views));
+        assert_eq!(vec!["default mail.example.org A Indeterminate", "default www.example.org A Indeterminate", "default www.example.org AAAA Indeterminate"],
+                   list_views("listtree example.org default", &views));
+        assert_eq!("", run("list example.com", &views));
+    }
+
+    #[test]
+    fn flush() {
+        let views = views();
+        assert_eq!("default flushed 3\n", run("flushtree example.org default", &views));
+        assert_eq!("guests flushed 1\ndefault flushed 0\n", run("flushname WWW.example.org", &views));
+        assert_eq!("guests flushed 0\ndefault flushed 1\n", run("flush", &views));
+        assert_eq!(0, views.default_view().cache.stats().entries);
+    }
+
+    #[test]
+    fn stats() {
+        let views = views();
+        let stats = run("stats guests", &views);
+        assert!(stats.starts_with("guests CacheStats { entries: 1,"), "{}", stats);
+        assert_eq!(2, run("stats", &views).lines().count());
+    }
+
+    #[test]
+    fn bad_commands() {
+        let views = views();
+        assert_eq!("No view staff\n", run("list www.example.org staff", &views));
+        assert!(run("list", &views).starts_with("Bad command \"list\""));
+        assert!(run("purge", &views).starts_with("Bad command"));
+        assert!(run("", &views).starts_with("Commands"));
+    }
+}
@@ -90,7 +90,8 @@ pub trait BufWrite : BufRead {
         if self.pos() >= self.len() {
             return false;
         }
-        self.buf()[self.pos()] = byte;
+        let pos = self.pos();
+        self.buf()[pos] = byte;
         self.advance(1);
         true
     }
@@ -1,24 +1,143 @@
 extern crate time;
 
-use std::collections::{HashMap};
-use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::cmp::{Ordering, Reverse};
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 use time::*;
 use dns::message::*;
+use dns::record::{Rcode, RecordType, Soa};
+use dns::validate::Security;
+use settings::{CacheConfig, TtlLimits};
+use zone::transfer::MAX_MESSAGE_LEN;
+
+mod redis;
+mod sharded;
+pub mod snapshot;
+
+use self::redis::RedisCache;
+pub use self::sharded::ShardedCache;
+
+pub const DEFAULT_MAX_ENTRIES: usize = 10000;
+pub const DEFAULT_MAX_BYTES: usize = 8 * 1024 * 1024;
+pub const DEFAULT_SHARDS: usize = 16;
+///The TTL of a stale answer. RFC8767 4
+pub const STALE_TTL: u32 = 30;
+///How long after upstream fails for a stale answer it's answered without asking upstream again. RFC8767 4
+const FAILURE_RECHECK: i64 = 30;
+
+///Cache of DnsAnswer, bounded by an entry count and an approximate byte budget
+///It tries to be somewhat performant by using a HashMap for lookups and a min-heap
+///of expiries, so inserting and expiring are O(log n).
+///Each insert gets a new generation. Replacing a key or evicting it leaves its old expiry in
+///the heap, where it's skipped when it comes up because the generation no longer matches.
+///The heap is rebuilt from the map if those build up.
+///When it's over either limit, expired entries are removed, then the least recently used
+///until it's under 90% of both, so eviction isn't done on every insert.
+///With a stale window, entries are kept that long after they expire, to answer with should upstream fail. RFC8767
+///Hits are counted, so popular entries can be fetched again before they expire.
+///It's a shard of a ShardedCache, which does the locking.
+pub struct Cache {
+    map: HashMap<CacheKey,Slot>, //for retrieval
+    expiries: BinaryHeap<Reverse<CacheExpiry>>, //for expiring, soonest first
+    generation: u64,
+    config: CacheConfig,
+    bytes: usize,
+    clock: AtomicUsize, //ticks on every get, for when entries were last used
+    evicted: u64,
+    expired: u64
+}
 
+///An entry with what the cache keeps about it
+#[derive(Debug)]
+struct Slot {
+    entry: CacheEntry,
+    size: usize,
+    generation: u64, //of the one expiry in the heap that's for this entry
+    last_used: AtomicUsize, //atomic so get can mark it under a read lock
+    hits: AtomicUsize
+}
 
-///Unbounded cache of DnsAnswer
-///It tries to be somewhat performant by using a HashMap for lookups and keeping
-///an ordered Vec of keys by expiry for fast removal of expired items. 
-pub struct Cache {
-    map: HashMap<CacheKey,CacheEntry>, //for retrieval
-    keys: Vec<CacheExpiry> //for expiring (ordered). BTreeSet/Map doesn't work because it does't have any way to iterate and remove
+///For monitoring. Entries evicted to stay within the limits, and removed because they expired
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: usize,
+    pub evicted: u64,
+    pub expired: u64
 }
 
 impl Default for Cache  {
     fn default() -> Cache {
-        Cache {
-            map: HashMap::new(),
-            keys: Vec::new()
+        Cache::new(&CacheConfig::default())
+    }
+}
+
+///
+///Where answers are cached. In memory, or in Redis so several servers can share one cache.
+///Entries are returned by value, as they may have come over the network.
+///A backend does its own locking, so it can be shared between threads without one lock around it all
+///
+pub trait CacheBackend: Send + Sync {
+    fn get(&self, key: &CacheKey) -> Option<CacheEntry>;
+    ///Insert the entry, replacing any there is for the key
+    fn upsert(&self, key: CacheKey, val: CacheEntry);
+    ///Remove the entries past their expiry and stale window, returning how many
+    fn remove_expired(&self) -> usize;
+    ///Upstream failed for the expired entry. It's answered stale for a while without asking again
+    fn upstream_failed(&self, key: &CacheKey);
+    ///The entry is popular and nearly expired, so worth fetching again
+    fn prefetch_due(&self, key: &CacheKey) -> bool;
+    fn stats(&self) -> CacheStats;
+    ///The entries for the names, expired or not, for an admin to look at
+    fn entries(&self, names: &Names) -> Vec<CacheEntry>;
+    ///Remove the entries for the names, returning how many
+    fn flush(&self, names: &Names) -> usize;
+    ///Save the entries to the file they're restored from on startup, returning how many.
+    ///Nothing to do for a backend that keeps them itself
+    fn save_snapshot(&self) -> Result<usize, String> {
+        Ok(0)
+    }
+}
+
+///The entries an admin command is for. Any type and class
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Names {
+    Name(DnsName),
+    ///The name and those below it
+    Tree(DnsName),
+    All,
+}
+
+impl Names {
+    pub fn matches(&self, key: &CacheKey) -> bool {
+        match *self {
+            Names::Name(ref name) => key.name().eq_ignore_case(name),
+            Names::Tree(ref name) => key.name().is_subdomain_of(name),
+            Names::All => true,
+        }
+    }
+}
+
+///The backend the config asks for. A view's entries are kept apart by its name
+pub fn backend(config: &CacheConfig, view: &str) -> Arc<dyn CacheBackend> {
+    match config.redis {
+        Some(addr) => Arc::new(RedisCache::new(addr, view, config)),
+        None => {
+            let mut cache = ShardedCache::new(config);
+            if let Some(path) = snapshot::path(config, view) {
+                match cache.restore(&path) {
+                    Ok(count) => info!("Restored {} cache entries for view {} from {}", count, view, path.display()),
+                    Err(e) => warn!("{}. Starting view {} with an empty cache", e, view),
+                }
+            }
+            Arc::new(cache)
         }
     }
 }
@@ -28,18 +147,137 @@ pub trait Expires {
 }
 
 impl Cache  {
-    pub fn upsert(&mut self, key: CacheKey, val: CacheEntry) {
+    pub fn new(config: &CacheConfig) -> Cache {
+        Cache {
+            map: HashMap::new(),
+            expiries: BinaryHeap::new(),
+            generation: 0,
+            config: config.clone(),
+            bytes: 0,
+            clock: AtomicUsize::new(0),
+            evicted: 0,
+            expired: 0
+        }
+    }
+
+    ///Insert the entry, replacing any there is for the key. Its TTL is kept within the limits for its name
+    pub fn upsert(&mut self, key: CacheKey, mut val: CacheEntry) {
+        val.clamp_ttl(&self.config.ttl_limits(&key.name()));
         self.remove_expired();
-        let expiry_data = CacheExpiry::new(key.clone(), val.expiry());
+        self.generation += 1;
         debug!("Cached answer with key {:?}", key);
-        self.keys.insert(0, expiry_data);
-        self.keys.sort(); //only 1 item should ever be out-of-order. 
-        self.map.entry(key).or_insert(val);        
-        debug!("There are {} keys and {} map entries", self.keys.len(), self.map.len());
+        let removal = self.removal(&val);
+        self.expiries.push(Reverse(CacheExpiry::new(key.clone(), removal, self.generation)));
+        let slot = Slot {
+            size: val.size(),
+            entry: val,
+            generation: self.generation,
+            last_used: AtomicUsize::new(self.tick()),
+            hits: AtomicUsize::new(0)
+        };
+        self.bytes += slot.size;
+        if let Some(old) = self.map.insert(key, slot) {
+            self.bytes -= old.size;
+        }
+        if self.map.len() > self.config.max_entries || self.bytes > self.config.max_bytes {
+            self.evict();
+        }
+        if self.expiries.len() > self.map.len() * 2 + 64 {
+            self.rebuild_expiries();
+        }
+        debug!("There are {} expiries and {} map entries", self.expiries.len(), self.map.len());
     }
 
     pub fn get(&self, key: &CacheKey) -> Option<&CacheEntry> {
-        self.map.get(key)
+        self.map.get(key).map(|slot| {
+            slot.last_used.store(self.tick(), AtomicOrdering::Relaxed);
+            slot.hits.fetch_add(1, AtomicOrdering::Relaxed);
+            &slot.entry
+        })
+    }
+
+    ///The entry has had enough hits, and is in the last part of its TTL, to be worth fetching again before it expires
+    pub fn prefetch_due(&self, key: &CacheKey) -> bool {
+        if self.config.prefetch == 0 {
+            return false;
+        }
+        self.map.get(key).map_or(false, |slot| {
+            let left = slot.entry.calc_ttl() as u64;
+            left > 0 && left * 100 <= slot.entry.ttl as u64 * self.config.prefetch as u64 &&
+            slot.hits.load(AtomicOrdering::Relaxed) >= self.config.prefetch_hits
+        })
+    }
+
+    ///Upstream failed for the expired entry. It's answered stale for a while without asking again
+    pub fn upstream_failed(&mut self, key: &CacheKey) {
+        if let Some(slot) = self.map.get_mut(key) {
+            slot.entry.mark_failed();
+        }
+    }
+
+    ///When the entry is to be removed, which is once it's expired and the stale window has passed
+    fn removal(&self, entry: &CacheEntry) -> SteadyTime {
+        entry.expiry() + Duration::seconds(self.config.serve_stale as i64)
+    }
+
+    ///What's in it, to save or look at
+    pub fn entries(&self) -> impl Iterator<Item = (&CacheKey, &CacheEntry)> {
+        self.map.iter().map(|(key, slot)| (key, &slot.entry))
+    }
+
+    ///Remove the entries for the names, returning how many. Their expiries are skipped when they come up
+    pub fn flush(&mut self, names: &Names) -> usize {
+        let keys = self.map.keys().filter(|key| names.matches(key)).cloned().collect::<Vec<_>>();
+        for key in &keys {
+            if let Some(slot) = self.map.remove(key) {
+                self.bytes -= slot.size;
+            }
+        }
+        debug!("Flushed {} entries for {:?}", keys.len(), names);
+        keys.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.map.len(),
+            bytes: self.bytes,
+            evicted: self.evicted,
+            expired: self.expired
+        }
+    }
+
+    fn tick(&self) -> usize {
+        self.clock.fetch_add(1, AtomicOrdering::Relaxed) + 1
+    }
+
+    ///Remove the least recently used entries until under 90% of both limits. Expired ones are already gone
+    fn evict(&mut self) {
+        let (max_entries, max_bytes) = (self.config.max_entries / 10 * 9, self.config.max_bytes / 10 * 9);
+        let mut by_use = self.map
+                             .iter()
+                             .map(|(key, slot)| (slot.last_used.load(AtomicOrdering::Relaxed), key.clone()))
+                             .collect::<Vec<_>>();
+        by_use.sort();
+        let mut evicted = 0;
+        for (_, key) in by_use {
+            if self.map.len() <= max_entries && self.bytes <= max_bytes {
+                break;
+            }
+            if let Some(slot) = self.map.remove(&key) {
+                self.bytes -= slot.size;
+                evicted += 1;
+            }
+        }
+        debug!("Evicted {} entries. {} left of {} bytes", evicted, self.map.len(), self.bytes);
+        self.evicted += evicted;
+    }
+
+    ///Drop the expiries of replaced and evicted entries. O(n), but only once they're as many as the entries
+    fn rebuild_expiries(&mut self) {
+        self.expiries = self.map
+                            .iter()
+                            .map(|(key, slot)| Reverse(CacheExpiry::new(key.clone(), self.removal(&slot.entry), slot.generation)))
+                            .collect();
     }
 
     #[allow(dead_code)]
@@ -57,26 +295,24 @@ impl Cache  {
         self.map.is_empty()
     }
 
+    ///Remove the entries that have expired, returning how many
     pub fn remove_expired(&mut self) -> usize {
-        let now = SteadyTime::now();        
-        let key_count = self.keys.len();
-
-        self.remove_expired_map(now);
-        self.keys.retain(|cache_expiry| cache_expiry.expiry > now);
-
-        debug_assert!(self.map.len() == self.keys.len(), format!("map.len {:?} != keys.len {:?} map {:?} keys {:?}", self.map.len(), self.keys.len(), self.map, self.keys));        
-        key_count - self.keys.len()
-    }
-
-    fn remove_expired_map(&mut self, now: SteadyTime) {
-        //keys are ordered by expiry
-        for cache_expiry in &self.keys {
-            if cache_expiry.expiry > now {
-                break;
+        let now = SteadyTime::now();
+        let mut removed = 0;
+        while self.expiries.peek().map_or(false, |next| next.0.expiry <= now) {
+            let Reverse(cache_expiry) = self.expiries.pop().unwrap();
+            let current = self.map.get(&cache_expiry.key).map_or(false, |slot| slot.generation == cache_expiry.generation);
+            if !current {
+                continue; //replaced or evicted since
             }
             debug!("Removing {:?} with expiry {:?}", &cache_expiry.key, cache_expiry.expiry);
-            self.map.remove(&cache_expiry.key);
+            if let Some(slot) = self.map.remove(&cache_expiry.key) {
+                self.bytes -= slot.size;
+                removed += 1;
+            }
         }
+        self.expired += removed as u64;
+        removed
     }
 }
 
@@ -104,6 +340,14 @@ impl CacheKey {
     pub fn from(query: &DnsQuestion) -> CacheKey {
         CacheKey::new(query.qname.to_string(), query.qtype, query.qclass)        
     }
+
+    pub fn name(&self) -> DnsName {
+        DnsName::from_string(self.qname.clone())
+    }
+
+    pub fn qtype(&self) -> u16 {
+        self.qtype
+    }
 }
 
 impl Ord for CacheKey {
@@ -115,12 +359,20 @@ impl Ord for CacheKey {
 #[derive(PartialOrd)]
 #[derive(PartialEq)]
 #[derive(Eq)]
+#[derive(Clone)]
 #[derive(Debug)]
 pub struct CacheEntry {
     pub key: CacheKey, //for expiring
     pub answers: Vec<DnsAnswer>,
+    ///NXDOMAIN for a name that doesn't exist. With no answers, the SOA and any proof are kept to answer with. RFC2308 5
+    pub rcode: u8,
+    pub authorities: Vec<DnsAnswer>,
+    ///How the answer validated, if it was. RFC4035 4.5
+    pub security: Security,
     ttl: u32,
-    expiry: SteadyTime
+    expiry: SteadyTime,
+    ///Set when upstream fails for the entry once expired. Till then it's answered stale without asking upstream
+    retry: Option<SteadyTime>
 }
 
 impl CacheEntry {
@@ -128,22 +380,147 @@ impl CacheEntry {
         CacheEntry {
             key: key,
             answers: answers,
+            rcode: Rcode::NOERROR,
+            authorities: Vec::new(),
+            security: Security::default(),
             ttl: ttl,
-            expiry: SteadyTime::now() + Duration::seconds(ttl as i64)
+            expiry: SteadyTime::now() + Duration::seconds(ttl as i64),
+            retry: None
         }
     }
 
+    ///The entry for an upstream answer, keyed by its question, as a CNAME chain's first record isn't
+    ///what was asked for. It expires with the shortest lived of its records. One with no
+    ///records is negative, and cached for its SOA's TTL or minimum, whichever is less. Without an SOA
+    ///it isn't cached. RFC2308 5
     pub fn from(msg: &DnsMessage) -> Option<CacheEntry> {        
         if let Some(answer) = msg.first_answer() {
-            let a = answer.clone();
-            let key = CacheKey::new(a.name.to_string(), a.atype, a.aclass);
-            return Some(CacheEntry::new(key, msg.clone().answers, answer.ttl))
-        } else {
-            warn!("No answer in {:?}", msg);
+            let key = match msg.first_question() {
+                Some(question) => CacheKey::from(question),
+                None => CacheKey::new(answer.name.to_string(), answer.atype, answer.aclass),
+            };
+            let ttl = msg.answers.iter().map(|rr| rr.ttl).min().unwrap_or(answer.ttl);
+            let mut entry = CacheEntry::new(key, msg.clone().answers, ttl);
+            entry.rcode = msg.header.rcode;
+            return Some(entry)
+        }
+        let negative_ttl = msg.authorities
+                              .iter()
+                              .find(|rr| rr.atype == RecordType::SOA)
+                              .and_then(|rr| Soa::from_rdata(&rr.rdata).map(|soa| rr.ttl.min(soa.minimum)));
+        match (msg.first_question(), negative_ttl) {
+            (Some(question), Some(ttl)) if msg.header.rcode == Rcode::NOERROR || msg.header.rcode == Rcode::NXDOMAIN => {
+                let mut entry = CacheEntry::new(CacheKey::from(question), Vec::new(), ttl);
+                entry.rcode = msg.header.rcode;
+                entry.authorities = msg.authorities.clone();
+                for rr in &mut entry.authorities {
+                    rr.ttl = rr.ttl.min(ttl);
+                }
+                return Some(entry)
+            }
+            _ => warn!("No answer in {:?}", msg),
         }
         None
     }
 
+    ///NXDOMAIN or NODATA. RFC2308 1
+    pub fn is_negative(&self) -> bool {
+        self.answers.is_empty()
+    }
+
+    ///Keep the TTL, and each record's, within the limits, moving the expiry as much. Clamping again changes nothing
+    pub fn clamp_ttl(&mut self, limits: &TtlLimits) {
+        let negative = self.is_negative();
+        for rr in self.answers.iter_mut().chain(self.authorities.iter_mut()) {
+            rr.ttl = limits.clamp(rr.ttl, negative);
+        }
+        let ttl = limits.clamp(self.ttl, negative);
+        if ttl != self.ttl {
+            debug!("Clamping ttl of {:?} {} -> {}", self.key, self.ttl, ttl);
+            self.expiry = self.expiry + Duration::seconds(ttl as i64 - self.ttl as i64);
+            self.ttl = ttl;
+        }
+    }
+
+    ///Roughly the memory the entry takes, for the cache's byte budget
+    fn size(&self) -> usize {
+        let name_size = |name: &DnsName| name.labels().iter().map(|label| mem::size_of::<String>() + label.len()).sum::<usize>();
+        mem::size_of::<Slot>() + self.key.qname.len() +
+        self.answers.iter().chain(&self.authorities).map(|rr| mem::size_of::<DnsAnswer>() + name_size(&rr.name) + rr.rdata.len()).sum::<usize>()
+    }
+
+    ///
+    ///The entry in a form that means the same to another process or after a restart. The security,
+    ///TTL, expiry and the time upstream can be asked again, or 0, then the answers, authorities and rcode as a message.
+    ///Times are wall clock milliseconds, as steady clocks have nothing in common. None if the records
+    ///don't fit in a message, rather than cut them short
+    ///
+    pub fn encode(&self) -> Option<Vec<u8>> {
+        //names aren't compressed, so it's the header then each record in full
+        let expected = 12 + self.answers.iter().chain(&self.authorities).map(DnsAnswer::wire_len).sum::<usize>();
+        if expected > MAX_MESSAGE_LEN {
+            warn!("{:?} is too large to encode", self.key);
+            return None;
+        }
+        let bytes = stored_message(self.rcode, &self.answers, &self.authorities).to_bytes_max(MAX_MESSAGE_LEN);
+        let mut buf = vec![security_byte(self.security)];
+        write_u64(&mut buf, self.ttl as u64);
+        write_u64(&mut buf, to_wall(self.expiry) as u64);
+        write_u64(&mut buf, self.retry.map_or(0, to_wall) as u64);
+        buf.extend_from_slice(&bytes);
+        Some(buf)
+    }
+
+    ///None if it's damaged or was cut short. Records missing from the end are parsed as empty ones
+    ///and one cut part way has less data than its length, so then the records don't add up to the message
+    pub fn decode(key: &CacheKey, value: &[u8]) -> Option<CacheEntry> {
+        if value.len() < 25 + 12 {
+            warn!("Bad cache entry for {:?}", key);
+            return None;
+        }
+        let retry = read_u64(&value[17..25]) as i64;
+        let msg = DnsMessage::parse(&value[25..]);
+        let records = msg.answers.iter().chain(&msg.authorities).collect::<Vec<_>>();
+        let short = records.iter().any(|rr| rr.rdata.len() != rr.rdlength as usize);
+        let len = 12 + records.iter().map(|rr| rr.wire_len()).sum::<usize>();
+        if short || records.len() != msg.header.ancount as usize + msg.header.nscount as usize || len != value.len() - 25 {
+            warn!("Cache entry for {:?} is incomplete", key);
+            return None;
+        }
+        Some(CacheEntry {
+            key: key.clone(),
+            answers: msg.answers,
+            rcode: msg.header.rcode,
+            authorities: msg.authorities,
+            security: security_from(value[0]),
+            ttl: read_u64(&value[1..9]) as u32,
+            expiry: to_steady(read_u64(&value[9..17]) as i64),
+            retry: if retry == 0 { None } else { Some(to_steady(retry)) },
+        })
+    }
+
+    ///If it has expired, note that upstream failed for it
+    fn mark_failed(&mut self) {
+        if self.calc_ttl() == 0 {
+            self.retry = Some(SteadyTime::now() + Duration::seconds(FAILURE_RECHECK));
+        }
+    }
+
+    ///Upstream has failed for it since it expired
+    pub fn failed_upstream(&self) -> bool {
+        self.retry.is_some()
+    }
+
+    ///Upstream failed for it long enough ago to try again
+    pub fn retry_due(&self) -> bool {
+        self.retry.map_or(false, |retry| retry <= SteadyTime::now())
+    }
+
+    ///Seconds since it was cached, taken off each record's TTL when it's answered with
+    pub fn age(&self) -> u32 {
+        self.ttl.saturating_sub(self.calc_ttl())
+    }
+
     pub fn calc_ttl(&self) -> u32 {
         let now = SteadyTime::now();
         if self.expiry > now {
@@ -154,6 +531,15 @@ impl CacheEntry {
     }
 }
 
+///The message an entry's records are encoded as
+fn stored_message(rcode: u8, answers: &[DnsAnswer], authorities: &[DnsAnswer]) -> DnsMessage {
+    let mut header = DnsHeader::new_query(0);
+    header.rcode = rcode;
+    let mut msg = DnsMessage::new_reply(header, Vec::new(), answers.to_vec());
+    msg.authorities = authorities.to_vec();
+    msg
+}
+
 impl Ord for CacheEntry {
     fn cmp(&self, other: &Self) -> Ordering {
         self.expiry.cmp(&other.expiry)
@@ -166,37 +552,89 @@ impl Expires for CacheEntry {
     }
 }
 
-#[derive(PartialOrd)]
+///Milliseconds since the epoch
+fn wall_ms() -> i64 {
+    let now = time::get_time();
+    now.sec * 1000 + now.nsec as i64 / 1_000_000
+}
+
+fn to_wall(at: SteadyTime) -> i64 {
+    wall_ms() + (at - SteadyTime::now()).num_milliseconds()
+}
+
+fn to_steady(ms: i64) -> SteadyTime {
+    SteadyTime::now() + Duration::milliseconds(ms - wall_ms())
+}
+
+fn security_byte(security: Security) -> u8 {
+    match security {
+        Security::Secure => 1,
+        Security::Insecure => 2,
+        Security::Bogus => 3,
+        Security::Indeterminate => 0,
+    }
+}
+
+fn security_from(byte: u8) -> Security {
+    match byte {
+        1 => Security::Secure,
+        2 => Security::Insecure,
+        3 => Security::Bogus,
+        _ => Security::Indeterminate,
+    }
+}
+
+fn write_u64(buf: &mut Vec<u8>, n: u64) {
+    for shift in (0..8).rev() {
+        buf.push((n >> (shift * 8)) as u8);
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |n, b| (n << 8) | *b as u64)
+}
+
 #[derive(PartialEq)]
 #[derive(Eq)]
 #[derive(Debug)]
 pub struct CacheExpiry {
     pub key: CacheKey,
-    pub expiry: SteadyTime
+    pub expiry: SteadyTime,
+    pub generation: u64
 }
 
 impl CacheExpiry {
-    pub fn new(key: CacheKey, expiry: SteadyTime) -> CacheExpiry {
+    pub fn new(key: CacheKey, expiry: SteadyTime, generation: u64) -> CacheExpiry {
         CacheExpiry {
             key: key,
-            expiry: expiry
+            expiry: expiry,
+            generation: generation
         }
     }
 }
 
 impl Ord for CacheExpiry {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.expiry.cmp(&other.expiry)
+        (self.expiry, self.generation).cmp(&(other.expiry, other.generation))
+    }
+}
+
+//the heap goes by this, so it has to order by expiry too rather than by key first
+impl PartialOrd for CacheExpiry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Cache, CacheEntry, CacheKey};
+    use super::{Cache, CacheEntry, CacheKey, CacheStats, Names};
     use std::thread;
     use std::time::Duration;
     use std::str::FromStr;
-    use dns::message::{DnsAnswer, DnsName};
+    use dns::message::{DnsAnswer, DnsHeader, DnsMessage, DnsName, DnsQuestion};
+    use dns::record::{Rcode, RecordType, Soa};
+    use settings::{CacheConfig, TtlLimits};
 
     fn test_cache() -> Cache {
         let mut cache = Cache::default();
@@ -206,6 +644,10 @@ mod test {
         cache
     }
 
+    fn limited(max_entries: usize, max_bytes: usize, serve_stale: u32) -> Cache {
+        Cache::new(&CacheConfig { max_entries: max_entries, max_bytes: max_bytes, serve_stale: serve_stale, ..CacheConfig::default() })
+    }
+
     fn test_key_with(name: String) -> CacheKey {
         CacheKey::new(name, 1, 1)
     }
@@ -256,6 +698,235 @@ mod test {
         assert_eq!(cache.len(), 1);
     }
 
+    fn fill(cache: &mut Cache, names: &[&str]) {
+        for name in names {
+            let key = test_key_with(name.to_string());
+            cache.upsert(key.clone(), CacheEntry::new(key, test_answers_with(name.to_string()), 60));
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = limited(10, 1 << 20, 0);
+        let names = (0..10).map(|i| format!("{}.example.org", i)).collect::<Vec<_>>();
+        fill(&mut cache, &names.iter().map(|name| name.as_str()).collect::<Vec<_>>());
+        assert_eq!(10, cache.len());
+        //used since it was added, so isn't the least recently used any more
+        assert!(cache.get(&test_key_with(names[0].clone())).is_some());
+        fill(&mut cache, &["new.example.org"]);
+        //down to 9 of the 11
+        assert_eq!(9, cache.len());
+        assert!(cache.contains(&test_key_with(names[0].clone())));
+        assert!(cache.contains(&test_key_with("new.example.org".to_owned())));
+        assert!(!cache.contains(&test_key_with(names[1].clone())));
+        assert!(!cache.contains(&test_key_with(names[2].clone())));
+        assert_eq!(2, cache.stats().evicted);
+        assert_eq!(0, cache.stats().expired);
+    }
+
+    #[test]
+    fn byte_budget() {
+        let mut one = Cache::default();
+        fill(&mut one, &["a.example.org"]);
+        let entry_size = one.stats().bytes;
+        assert!(entry_size > 0);
+
+        let mut cache = limited(1000, entry_size * 5, 0);
+        fill(&mut cache, &["a.example.org", "b.example.org", "c.example.org", "d.example.org", "e.example.org"]);
+        assert_eq!(CacheStats { entries: 5, bytes: entry_size * 5, evicted: 0, expired: 0 }, cache.stats());
+        fill(&mut cache, &["f.example.org"]);
+        assert_eq!(CacheStats { entries: 4, bytes: entry_size * 4, evicted: 2, expired: 0 }, cache.stats());
+        assert!(!cache.contains(&test_key_with("a.example.org".to_owned())));
+        assert!(cache.contains(&test_key_with("f.example.org".to_owned())));
+    }
+
+    #[test]
+    fn expired_before_evicted() {
+        let mut cache = limited(2, 1 << 20, 0);
+        let key = test_key_with("short.example.org".to_owned());
+        cache.upsert(key.clone(), CacheEntry::new(key.clone(), test_answers_with("short.example.org".to_owned()), 1));
+        fill(&mut cache, &["a.example.org"]);
+        thread::sleep(Duration::from_millis(1010));
+        fill(&mut cache, &["b.example.org"]);
+        let stats = cache.stats();
+        assert_eq!((2, 0, 1), (stats.entries, stats.evicted, stats.expired));
+        assert!(cache.contains(&test_key_with("a.example.org".to_owned())));
+    }
+
+    #[test]
+    fn replacing_supersedes() {
+        let mut cache = Cache::default();
+        let key = test_key();
+        cache.upsert(key.clone(), CacheEntry::new(key.clone(), test_answers(), 1));
+        let bytes = cache.stats().bytes;
+        let mut answers = test_answers();
+        answers[0].rdata = vec![10, 0, 0, 1];
+        cache.upsert(key.clone(), CacheEntry::new(key.clone(), answers, 60));
+        assert_eq!(vec![10, 0, 0, 1], cache.get(&key).unwrap().answers[0].rdata);
+        assert_eq!((1, bytes), (cache.len(), cache.stats().bytes));
+        //the first one's expiry doesn't remove the second
+        thread::sleep(Duration::from_millis(1010));
+        assert_eq!(0, cache.remove_expired());
+        assert!(cache.contains(&key));
+        assert_eq!(0, cache.stats().expired);
+    }
+
+    #[test]
+    fn stale_expiries_dont_build_up() {
+        let mut cache = limited(10, 1 << 20, 0);
+        let names = (0..20).map(|i| format!("{}.example.org", i)).collect::<Vec<_>>();
+        for _ in 0..50 {
+            fill(&mut cache, &names.iter().map(|name| name.as_str()).collect::<Vec<_>>());
+        }
+        assert!(cache.len() <= 10);
+        assert!(cache.expiries.len() <= cache.len() * 2 + 64, "{} expiries", cache.expiries.len());
+    }
+
+    #[test]
+    fn kept_for_stale_window() {
+        let mut cache = limited(10, 1 << 20, 60);
+        let key = test_key();
+        cache.upsert(key.clone(), CacheEntry::new(key.clone(), test_answers(), 1));
+        thread::sleep(Duration::from_millis(1010));
+        assert_eq!(0, cache.remove_expired());
+        assert_eq!(0, cache.get(&key).unwrap().calc_ttl());
+        assert!(!cache.get(&key).unwrap().failed_upstream());
+
+        cache.upstream_failed(&key);
+        let entry = cache.get(&key).unwrap();
+        assert!(entry.failed_upstream());
+        assert!(!entry.retry_due());
+
+        //a fresh answer isn't marked, and replaces the stale one
+        cache.upsert(key.clone(), CacheEntry::new(key.clone(), test_answers(), 60));
+        cache.upstream_failed(&key);
+        assert!(!cache.get(&key).unwrap().failed_upstream());
+    }
+
+    #[test]
+    fn prefetch_when_popular_and_nearly_expired() {
+        let mut cache = Cache::new(&CacheConfig { prefetch: 50, prefetch_hits: 2, ..CacheConfig::default() });
+        let key = test_key();
+        cache.upsert(key.clone(), CacheEntry::new(key.clone(), test_answers(), 3));
+        cache.get(&key);
+        cache.get(&key);
+        //popular, but over half its TTL left
+        assert!(!cache.prefetch_due(&key));
+        thread::sleep(Duration::from_millis(1100));
+        assert!(cache.prefetch_due(&key));
+
+        //the replacement starts over
+        cache.upsert(key.clone(), CacheEntry::new(key.clone(), test_answers(), 1));
+        assert!(!cache.prefetch_due(&key));
+        assert!(!test_cache().prefetch_due(&key));
+    }
+
+    #[test]
+    fn clamps_ttl() {
+        let mut config = CacheConfig::default();
+        config.ttl = TtlLimits { min: 30, max: 3600, negative_min: 0, negative_max: 60 };
+        config.ttl_overrides.push((DnsName::from_string("cdn.example.org".to_owned()), TtlLimits { min: 300, ..config.ttl }));
+        let mut cache = Cache::new(&config);
+        let key = test_key_with("www.example.org".to_owned());
+        cache.upsert(key.clone(), CacheEntry::new(key.clone(), test_answers_with("www.example.org".to_owned()), 1));
+        let ttl = cache.get(&key).unwrap().calc_ttl();
+        assert!(ttl >= 29 && ttl <= 30, "ttl {}", ttl);
+        fill(&mut cache, &["www.cdn.example.org"]);
+        let ttl = cache.get(&test_key_with("www.cdn.example.org".to_owned())).unwrap().calc_ttl();
+        assert!(ttl >= 299 && ttl <= 300, "ttl {}", ttl);
+
+        let mut week = CacheEntry::new(key.clone(), test_answers(), 7 * 24 * 3600);
+        week.clamp_ttl(&config.ttl);
+        //and each record's, so they're answered with what the entry's kept for
+        assert_eq!(30, week.answers[0].ttl);
+        let ttl = week.calc_ttl();
+        week.clamp_ttl(&config.ttl);
+        assert_eq!(ttl, week.calc_ttl());
+        assert!(ttl >= 3599 && ttl <= 3600, "ttl {}", ttl);
+    }
+
+    fn negative_reply(rcode: u8, soa_ttl: u32, minimum: u32) -> DnsMessage {
+        let name = DnsName::from_string("missing.example.org".to_owned());
+        let mut header = DnsHeader::new_query(1);
+        header.qr = true;
+        header.rcode = rcode;
+        let mut msg = DnsMessage::new_reply(header, vec![DnsQuestion::new(name, 1, 1)], Vec::new());
+        let soa = Soa {
+            mname: DnsName::from_string("ns1.example.org".to_owned()),
+            rname: DnsName::from_string("hostmaster.example.org".to_owned()),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: minimum,
+        };
+        msg.authorities.push(DnsAnswer::with_rdata(DnsName::from_string("example.org".to_owned()), RecordType::SOA, 1, soa_ttl, soa.to_rdata()));
+        msg
+    }
+
+    #[test]
+    fn negative_answers() {
+        let entry = CacheEntry::from(&negative_reply(Rcode::NXDOMAIN, 3600, 300)).unwrap();
+        assert!(entry.is_negative());
+        assert_eq!(CacheKey::new("missing.example.org".to_owned(), 1, 1), entry.key);
+        assert_eq!((Rcode::NXDOMAIN, 1), (entry.rcode, entry.authorities.len()));
+        let ttl = entry.calc_ttl();
+        assert!(ttl >= 299 && ttl <= 300, "ttl {}", ttl);
+        //the SOA's TTL if that's less than its minimum
+        assert!(CacheEntry::from(&negative_reply(Rcode::NOERROR, 60, 300)).unwrap().calc_ttl() <= 60);
+        //and kept within the negative limits
+        let mut cache = Cache::default();
+        cache.upsert(entry.key.clone(), CacheEntry::from(&negative_reply(Rcode::NXDOMAIN, 86400, 86400)).unwrap());
+        let ttl = cache.get(&entry.key).unwrap().calc_ttl();
+        assert!(ttl >= 3 * 3600 - 1 && ttl <= 3 * 3600, "ttl {}", ttl);
+
+        //the SOA is given with the negative TTL
+        assert_eq!(300, entry.authorities[0].ttl);
+        let decoded = CacheEntry::decode(&entry.key, &entry.encode().unwrap()).unwrap();
+        assert_eq!((Rcode::NXDOMAIN, &entry.authorities), (decoded.rcode, &decoded.authorities));
+        //not without an SOA to say for how long, or for a SERVFAIL
+        let mut no_soa = negative_reply(Rcode::NXDOMAIN, 3600, 300);
+        no_soa.authorities.clear();
+        assert_eq!(None, CacheEntry::from(&no_soa));
+        assert_eq!(None, CacheEntry::from(&negative_reply(Rcode::SERVFAIL, 3600, 300)));
+    }
+
+    #[test]
+    fn encodes_large_entries() {
+        let entry = |count: usize| {
+            let key = test_key();
+            let answers = (0..count).map(|_| DnsAnswer::new(key.name(), RecordType::TXT, 1, 60, 201, vec![200; 201])).collect();
+            CacheEntry::new(key, answers, 60)
+        };
+        //past the 4KiB of a UDP message
+        let large = entry(40);
+        let value = large.encode().unwrap();
+        assert_eq!(large.answers, CacheEntry::decode(&large.key, &value).unwrap().answers);
+        //cut short, though the header still says 40 answers
+        assert_eq!(None, CacheEntry::decode(&large.key, &value[..25 + 4096]));
+        assert_eq!(None, CacheEntry::decode(&large.key, &value[..value.len() - 1]));
+        //too large for any message
+        assert_eq!(None, entry(400).encode());
+    }
+
+    #[test]
+    fn flush() {
+        let mut cache = limited(10, 1 << 20, 0);
+        fill(&mut cache, &["example.org", "www.example.org", "a.b.example.org", "www.example.net"]);
+        let bytes = cache.stats().bytes;
+        let name = |name: &str| DnsName::from_string(name.to_owned());
+        assert_eq!(1, cache.flush(&Names::Name(name("WWW.example.org"))));
+        assert!(!cache.contains(&test_key_with("www.example.org".to_owned())));
+        assert!(cache.stats().bytes < bytes);
+        assert_eq!(2, cache.flush(&Names::Tree(name("example.org"))));
+        assert_eq!(1, cache.len());
+        //and inserted again, it isn't removed by the old expiry
+        fill(&mut cache, &["example.org"]);
+        assert_eq!(0, cache.remove_expired());
+        assert_eq!(2, cache.flush(&Names::All));
+        assert_eq!(CacheStats { entries: 0, bytes: 0, evicted: 0, expired: 0 }, cache.stats());
+    }
+
     #[test]
     fn contains() {
         let cache = test_cache();
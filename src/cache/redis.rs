@@ -0,0 +1,457 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+use time::{Duration, SteadyTime};
+use settings::CacheConfig;
+use super::{CacheBackend, CacheEntry, CacheKey, CacheStats, Names};
+
+///How long to wait on Redis. It's asked on the server's thread, so should be close by
+const TIMEOUT_MS: u64 = 200;
+///Seconds Redis isn't asked after it fails, so each query doesn't wait out the timeout meanwhile
+const RETRY_SECS: i64 = 5;
+
+///
+///Cache entries kept in Redis, so several servers can share them. Keys are prefixed with the view's name,
+///and Redis expires them once their TTL and the stale window have passed. It's sized with Redis' own
+///maxmemory rather than the cache's limits. Hits aren't counted, so nothing is prefetched.
+///Times are kept as wall clock milliseconds, as the servers' steady clocks have nothing in common.
+///If Redis can't be reached, it's as if the cache were empty till it's connected to again, RETRY_SECS later
+///
+pub struct RedisCache {
+    addr: SocketAddr,
+    prefix: String,
+    config: CacheConfig,
+    conn: Mutex<Conn>,
+}
+
+enum Conn {
+    Closed,
+    Open(BufReader<TcpStream>),
+    ///Redis failed. It's not asked again till then
+    Down(SteadyTime),
+}
+
+///A RESP reply. https://redis.io/docs/reference/protocol-spec/
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl RedisCache {
+    pub fn new(addr: SocketAddr, view: &str, config: &CacheConfig) -> RedisCache {
+        RedisCache {
+            addr: addr,
+            prefix: format!("koala:{}:", view),
+            config: config.clone(),
+            conn: Mutex::new(Conn::Closed),
+        }
+    }
+
+    fn command(&self, args: &[&[u8]]) -> Result<Reply, String> {
+        let mut conn = self.conn.lock().map_err(|e| format!("Redis connection lock poisoned {}", e))?;
+        let result = match *conn {
+            Conn::Down(retry) if retry > SteadyTime::now() => return Err(format!("Redis at {} is down", self.addr)),
+            Conn::Open(_) => Ok(()),
+            _ => self.connect().map(|reader| *conn = Conn::Open(reader)),
+        };
+        let result = result.and_then(|_| match *conn {
+            Conn::Open(ref mut reader) => reader.get_mut().write_all(&encode(args)).map_err(|e| e.to_string()).and_then(|_| read_reply(reader)),
+            _ => unreachable!(),
+        });
+        if result.is_err() {
+            //in an unknown state, so start over once it's had time to recover
+            *conn = Conn::Down(SteadyTime::now() + Duration::seconds(RETRY_SECS));
+        }
+        match result? {
+            Reply::Error(e) => Err(e),
+            reply => Ok(reply),
+        }
+    }
+
+    fn connect(&self) -> Result<BufReader<TcpStream>, String> {
+        let timeout = StdDuration::from_millis(TIMEOUT_MS);
+        let stream = TcpStream::connect_timeout(&self.addr, timeout).map_err(|e| format!("Couldn't connect to Redis at {}. {}", self.addr, e))?;
+        stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+        stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+        debug!("Connected to Redis at {}", self.addr);
+        Ok(BufReader::new(stream))
+    }
+
+    fn key(&self, key: &CacheKey) -> Vec<u8> {
+        format!("{}{}:{}:{}", self.prefix, key.qname, key.qtype, key.qclass).into_bytes()
+    }
+
+    ///The CacheKey a Redis key of the view's is for
+    fn cache_key(&self, key: &[u8]) -> Option<CacheKey> {
+        let key = String::from_utf8_lossy(key);
+        let mut parts = key.get(self.prefix.len()..)?.rsplitn(3, ':');
+        let qclass = parts.next()?.parse().ok()?;
+        let qtype = parts.next()?.parse().ok()?;
+        Some(CacheKey::new(parts.next()?.to_owned(), qtype, qclass))
+    }
+
+    ///
+    ///The view's keys for the names, with the CacheKeys they're for. Redis' patterns are case sensitive
+    ///and can't tell a name from one ending in it, so all the view's keys are scanned and checked here.
+    ///It's for an admin, so that's not a worry
+    ///
+    fn keys(&self, names: &Names) -> Result<Vec<(Vec<u8>, CacheKey)>, String> {
+        let pattern = format!("{}*", self.prefix);
+        let mut cursor = b"0".to_vec();
+        let mut keys = Vec::new();
+        loop {
+            let (next, batch) = match self.command(&[b"SCAN", &cursor, b"MATCH", pattern.as_bytes(), b"COUNT", b"1000"])? {
+                Reply::Array(mut reply) if reply.len() == 2 => {
+                    match (reply.remove(0), reply.remove(0)) {
+                        (Reply::Bulk(Some(next)), Reply::Array(batch)) => (next, batch),
+                        other => return Err(format!("Bad SCAN reply {:?}", other)),
+                    }
+                }
+                other => return Err(format!("Bad SCAN reply {:?}", other)),
+            };
+            for key in batch {
+                if let Reply::Bulk(Some(key)) = key {
+                    if let Some(cache_key) = self.cache_key(&key).filter(|cache_key| names.matches(cache_key)) {
+                        keys.push((key, cache_key));
+                    }
+                }
+            }
+            if next == b"0" {
+                return Ok(keys);
+            }
+            cursor = next;
+        }
+    }
+
+    ///Store the entry till its TTL and the stale window have passed
+    fn store(&self, key: &CacheKey, entry: &CacheEntry) {
+        let keep = (entry.expiry - SteadyTime::now()).num_milliseconds() + self.config.serve_stale as i64 * 1000;
+        if keep <= 0 {
+            return;
+        }
+        let keep = keep.to_string();
+        let value = match entry.encode() {
+            Some(value) => value,
+            None => return,
+        };
+        if let Err(e) = self.command(&[b"SET", &self.key(key), &value, b"PX", keep.as_bytes()]) {
+            warn!("Couldn't cache {:?} in Redis. {}", key, e);
+        }
+    }
+}
+
+impl CacheBackend for RedisCache {
+    fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        match self.command(&[b"GET", &self.key(key)]) {
+            Ok(Reply::Bulk(Some(value))) => CacheEntry::decode(key, &value),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("Couldn't get {:?} from Redis. {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn upsert(&self, key: CacheKey, mut val: CacheEntry) {
+        val.clamp_ttl(&self.config.ttl_limits(&key.name()));
+        debug!("Cached answer with key {:?} in Redis", key);
+        self.store(&key, &val);
+    }
+
+    ///Redis expires the entries itself
+    fn remove_expired(&self) -> usize {
+        0
+    }
+
+    fn upstream_failed(&self, key: &CacheKey) {
+        if let Some(mut entry) = self.get(key) {
+            if entry.calc_ttl() == 0 {
+                entry.mark_failed();
+                self.store(key, &entry);
+            }
+        }
+    }
+
+    fn prefetch_due(&self, _: &CacheKey) -> bool {
+        false
+    }
+
+    fn entries(&self, names: &Names) -> Vec<CacheEntry> {
+        match self.keys(names) {
+            Ok(keys) => keys.iter().filter_map(|&(_, ref key)| self.get(key)).collect(),
+            Err(e) => {
+                warn!("Couldn't list the entries in Redis. {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn flush(&self, names: &Names) -> usize {
+        let keys = match self.keys(names) {
+            Ok(keys) => keys,
+            Err(e) => {
+                warn!("Couldn't list the entries in Redis to flush them. {}", e);
+                return 0;
+            }
+        };
+        if keys.is_empty() {
+            return 0;
+        }
+        let mut args = vec![&b"DEL"[..]];
+        args.extend(keys.iter().map(|&(ref key, _)| &key[..]));
+        match self.command(&args) {
+            Ok(Reply::Integer(count)) => count as usize,
+            Ok(_) => 0,
+            Err(e) => {
+                warn!("Couldn't flush the entries in Redis. {}", e);
+                0
+            }
+        }
+    }
+
+    ///The entries are all the keys in the Redis database, which may be more than this view's
+    ///Only the view's entries are counted, as others may share the database. That takes a SCAN of them
+    fn stats(&self) -> CacheStats {
+        let entries = match self.keys(&Names::All) {
+            Ok(keys) => keys.len(),
+            Err(e) => {
+                warn!("Couldn't count the entries in Redis. {}", e);
+                0
+            }
+        };
+        CacheStats {
+            entries: entries,
+            bytes: 0,
+            evicted: 0,
+            expired: 0,
+        }
+    }
+}
+
+///A command as an array of bulk strings
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+    buf
+}
+
+pub fn read_reply<R: BufRead>(reader: &mut R) -> Result<Reply, String> {
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    if line.len() < 3 || !line.ends_with("\r\n") {
+        return Err("Connection to Redis closed".to_owned());
+    }
+    let rest = line[1..line.len() - 2].to_owned();
+    let length = || rest.parse::<i64>().map_err(|_| format!("Bad length in Redis reply {:?}", line));
+    match line.as_bytes()[0] {
+        b'+' => Ok(Reply::Status(rest.clone())),
+        b'-' => Ok(Reply::Error(rest.clone())),
+        b':' => length().map(Reply::Integer),
+        b'$' => {
+            let len = length()?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            let mut buf = vec![0; len as usize + 2];
+            reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
+            buf.truncate(len as usize);
+            Ok(Reply::Bulk(Some(buf)))
+        }
+        b'*' => {
+            let len = length()?;
+            let mut replies = Vec::new();
+            for _ in 0..len {
+                replies.push(read_reply(reader)?);
+            }
+            Ok(Reply::Array(replies))
+        }
+        _ => Err(format!("Bad Redis reply {:?}", line)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RedisCache, Reply, TIMEOUT_MS, encode, read_reply};
+    use std::collections::HashMap;
+    use std::io::{BufReader, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use cache::{CacheBackend, CacheEntry, CacheKey, Names};
+    use dns::message::{DnsAnswer, DnsName};
+    use dns::validate::Security;
+    use settings::CacheConfig;
+
+    ///Enough of a Redis server for the cache. GET, SET with PX, DEL, and SCAN all at once with a prefix* MATCH
+    fn stand_in() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let store = Arc::new(Mutex::new(HashMap::<Vec<u8>, (Vec<u8>, Instant)>::new()));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let store = store.clone();
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    while let Ok(Reply::Array(args)) = read_reply(&mut reader) {
+                        let args = args.into_iter()
+                                       .map(|arg| match arg {
+                                           Reply::Bulk(Some(arg)) => arg,
+                                           _ => Vec::new(),
+                                       })
+                                       .collect::<Vec<_>>();
+                        let mut store = store.lock().unwrap();
+                        store.retain(|_, &mut (_, expiry)| expiry > Instant::now());
+                        let reply = match &args[0][..] {
+                            b"GET" => {
+                                match store.get(&args[1]) {
+                                    Some(&(ref value, _)) => [format!("${}\r\n", value.len()).into_bytes(), value.clone(), b"\r\n".to_vec()].concat(),
+                                    None => b"$-1\r\n".to_vec(),
+                                }
+                            }
+                            b"SET" => {
+                                let px = String::from_utf8(args[4].clone()).unwrap().parse().unwrap();
+                                store.insert(args[1].clone(), (args[2].clone(), Instant::now() + Duration::from_millis(px)));
+                                b"+OK\r\n".to_vec()
+                            }
+                            b"DEL" => format!(":{}\r\n", args[1..].iter().filter(|key| store.remove(*key).is_some()).count()).into_bytes(),
+                            b"SCAN" => {
+                                let prefix = &args[3][..args[3].len() - 1];
+                                let keys = store.keys().filter(|key| key.starts_with(prefix)).collect::<Vec<_>>();
+                                let mut reply = format!("*2\r\n$1\r\n0\r\n*{}\r\n", keys.len()).into_bytes();
+                                for key in keys {
+                                    reply.extend([format!("${}\r\n", key.len()).into_bytes(), key.clone(), b"\r\n".to_vec()].concat());
+                                }
+                                reply
+                            }
+                            _ => b"-ERR unknown command\r\n".to_vec(),
+                        };
+                        stream.write_all(&reply).unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey::new(name.to_owned(), 1, 1)
+    }
+
+    fn stale_for(serve_stale: u32) -> CacheConfig {
+        CacheConfig { serve_stale: serve_stale, ..CacheConfig::default() }
+    }
+
+    fn entry(name: &str, ttl: u32) -> CacheEntry {
+        let answer = DnsAnswer::new(DnsName::from_string(name.to_owned()), 1, 1, ttl, 4, vec![10, 0, 0, 5]);
+        CacheEntry::new(key(name), vec![answer], ttl)
+    }
+
+    #[test]
+    fn resp() {
+        assert_eq!(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".to_vec(), encode(&[b"GET", b"k"]));
+        let mut reply = &b"*3\r\n+OK\r\n:42\r\n$5\r\na\r\nbc\r\n$-1\r\n-ERR no\r\n"[..];
+        assert_eq!(Ok(Reply::Array(vec![Reply::Status("OK".to_owned()), Reply::Integer(42), Reply::Bulk(Some(b"a\r\nbc".to_vec()))])),
+                   read_reply(&mut reply));
+        assert_eq!(Ok(Reply::Bulk(None)), read_reply(&mut reply));
+        assert_eq!(Ok(Reply::Error("ERR no".to_owned())), read_reply(&mut reply));
+        assert!(read_reply(&mut reply).is_err());
+    }
+
+    #[test]
+    fn shared_between_servers() {
+        let addr = stand_in();
+        let one = RedisCache::new(addr, "default", &stale_for(0));
+        let two = RedisCache::new(addr, "default", &stale_for(0));
+        let guest = RedisCache::new(addr, "guest", &stale_for(0));
+        let mut val = entry("www.example.org", 60);
+        val.security = Security::Secure;
+        one.upsert(key("www.example.org"), val.clone());
+
+        let got = two.get(&key("www.example.org")).unwrap();
+        assert_eq!(val.answers, got.answers);
+        assert_eq!(Security::Secure, got.security);
+        assert!(got.calc_ttl() >= 58 && got.calc_ttl() <= 60, "ttl {}", got.calc_ttl());
+        assert_eq!(None, guest.get(&key("www.example.org")));
+        assert_eq!(1, two.stats().entries);
+        //each view counts only its own
+        guest.upsert(key("www.example.org"), val.clone());
+        guest.upsert(key("mail.example.org"), entry("mail.example.org", 60));
+        assert_eq!((1, 2), (one.stats().entries, guest.stats().entries));
+    }
+
+    #[test]
+    fn kept_for_stale_window() {
+        let addr = stand_in();
+        let cache = RedisCache::new(addr, "default", &stale_for(60));
+        let gone = RedisCache::new(addr, "fresh-only", &stale_for(0));
+        cache.upsert(key("www.example.org"), entry("www.example.org", 1));
+        gone.upsert(key("www.example.org"), entry("www.example.org", 1));
+        thread::sleep(Duration::from_millis(1100));
+        assert_eq!(None, gone.get(&key("www.example.org")));
+
+        let stale = cache.get(&key("www.example.org")).unwrap();
+        assert_eq!(0, stale.calc_ttl());
+        assert!(!stale.failed_upstream());
+        cache.upstream_failed(&key("www.example.org"));
+        let failed = cache.get(&key("www.example.org")).unwrap();
+        assert!(failed.failed_upstream() && !failed.retry_due());
+    }
+
+    #[test]
+    fn entries_and_flush() {
+        let addr = stand_in();
+        let cache = RedisCache::new(addr, "default", &stale_for(0));
+        let guest = RedisCache::new(addr, "guest", &stale_for(0));
+        for name in &["www.example.org", "mail.example.org", "www.example.net"] {
+            cache.upsert(key(name), entry(name, 60));
+        }
+        guest.upsert(key("www.example.org"), entry("www.example.org", 60));
+        let org = Names::Tree(DnsName::from_string("Example.org".to_owned()));
+        let mut names = cache.entries(&org).into_iter().map(|entry| entry.key.name().to_string()).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(vec!["mail.example.org", "www.example.org"], names);
+        assert_eq!(2, cache.flush(&org));
+        assert_eq!(None, cache.get(&key("www.example.org")));
+        assert_eq!(1, cache.flush(&Names::All));
+        //another view's are left alone
+        assert!(guest.get(&key("www.example.org")).is_some());
+    }
+
+    #[test]
+    fn unreachable() {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let cache = RedisCache::new(addr, "default", &stale_for(0));
+        cache.upsert(key("www.example.org"), entry("www.example.org", 60));
+        assert_eq!(None, cache.get(&key("www.example.org")));
+        assert_eq!(0, cache.stats().entries);
+    }
+
+    #[test]
+    fn backs_off_when_down() {
+        //connects, but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cache = RedisCache::new(listener.local_addr().unwrap(), "default", &stale_for(0));
+        let start = Instant::now();
+        assert_eq!(None, cache.get(&key("www.example.org")));
+        assert!(start.elapsed() >= Duration::from_millis(TIMEOUT_MS));
+
+        //not asked again for a while, so lookups don't wait on it
+        let start = Instant::now();
+        assert_eq!(None, cache.get(&key("www.example.org")));
+        cache.upsert(key("www.example.org"), entry("www.example.org", 60));
+        assert!(start.elapsed() < Duration::from_millis(TIMEOUT_MS / 2));
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use time::SteadyTime;
+use settings::CacheConfig;
+use super::{snapshot, Cache, CacheBackend, CacheEntry, CacheKey, CacheStats, Names};
+
+///
+///The in-memory cache split into shards by a hash of the key, each with its own lock, so inserting
+///one answer doesn't hold up lookups of the others. Lookups only take a shard's read lock.
+///Each shard has an even share of the limits, and expires and evicts on its own, so the least
+///recently used entry evicted is the least recently used of its shard
+///
+pub struct ShardedCache {
+    shards: Vec<RwLock<Cache>>,
+    snapshot: Option<PathBuf>, //the file it's saved to
+}
+
+impl ShardedCache {
+    pub fn new(config: &CacheConfig) -> ShardedCache {
+        let count = config.shards.max(1);
+        let shard = CacheConfig {
+            max_entries: (config.max_entries / count).max(1),
+            max_bytes: (config.max_bytes / count).max(1),
+            ..config.clone()
+        };
+        ShardedCache {
+            shards: (0..count).map(|_| RwLock::new(Cache::new(&shard))).collect(),
+            snapshot: None,
+        }
+    }
+
+    ///A panic while a shard was locked leaves at worst its byte count off, so it's used anyway
+    fn read<'a>(&'a self, key: &CacheKey) -> RwLockReadGuard<'a, Cache> {
+        self.shards[self.index(key)].read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write<'a>(&'a self, key: &CacheKey) -> RwLockWriteGuard<'a, Cache> {
+        self.shards[self.index(key)].write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn index(&self, key: &CacheKey) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    ///Load the entries saved to the file, and save to it from now on. Those past their expiry
+    ///and the stale window are skipped. Returns how many were restored
+    pub fn restore(&mut self, path: &Path) -> Result<usize, String> {
+        self.snapshot = Some(path.to_owned());
+        let now = SteadyTime::now();
+        let mut restored = 0;
+        for (key, entry) in snapshot::load(path)? {
+            let mut shard = self.write(&key);
+            if shard.removal(&entry) > now {
+                shard.upsert(key, entry);
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+}
+
+impl CacheBackend for ShardedCache {
+    fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        self.read(key).get(key).cloned()
+    }
+
+    fn upsert(&self, key: CacheKey, val: CacheEntry) {
+        self.write(&key).upsert(key, val)
+    }
+
+    fn remove_expired(&self) -> usize {
+        self.shards.iter().map(|shard| shard.write().unwrap_or_else(PoisonError::into_inner).remove_expired()).sum()
+    }
+
+    fn upstream_failed(&self, key: &CacheKey) {
+        self.write(key).upstream_failed(key)
+    }
+
+    fn prefetch_due(&self, key: &CacheKey) -> bool {
+        self.read(key).prefetch_due(key)
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.shards.iter().fold(CacheStats { entries: 0, bytes: 0, evicted: 0, expired: 0 }, |total, shard| {
+            let stats = shard.read().unwrap_or_else(PoisonError::into_inner).stats();
+            CacheStats {
+                entries: total.entries + stats.entries,
+                bytes: total.bytes + stats.bytes,
+                evicted: total.evicted + stats.evicted,
+                expired: total.expired + stats.expired,
+            }
+        })
+    }
+
+    fn entries(&self, names: &Names) -> Vec<CacheEntry> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.read().unwrap_or_else(PoisonError::into_inner);
+                shard.entries().filter(|&(key, _)| names.matches(key)).map(|(_, entry)| entry.clone()).collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    fn flush(&self, names: &Names) -> usize {
+        self.shards.iter().map(|shard| shard.write().unwrap_or_else(PoisonError::into_inner).flush(names)).sum()
+    }
+
+    ///The shards are read locked while they're saved, so lookups carry on meanwhile but inserts wait
+    fn save_snapshot(&self) -> Result<usize, String> {
+        let path = match self.snapshot {
+            Some(ref path) => path,
+            None => return Ok(0),
+        };
+        let shards = self.shards.iter().map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner)).collect::<Vec<_>>();
+        snapshot::save(path, shards.iter().flat_map(|shard| shard.entries()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ShardedCache;
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use test::Bencher;
+    use cache::{CacheBackend, CacheEntry, CacheKey, Names};
+    use dns::message::{DnsAnswer, DnsName};
+    use settings::CacheConfig;
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey::new(name.to_owned(), 1, 1)
+    }
+
+    fn entry(name: &str, ttl: u32) -> CacheEntry {
+        let answer = DnsAnswer::new(DnsName::from_string(name.to_owned()), 1, 1, ttl, 4, vec![10, 0, 0, 5]);
+        CacheEntry::new(key(name), vec![answer], ttl)
+    }
+
+    fn sharded(shards: usize, max_entries: usize) -> ShardedCache {
+        ShardedCache::new(&CacheConfig { shards: shards, max_entries: max_entries, ..CacheConfig::default() })
+    }
+
+    #[test]
+    fn spread_over_shards() {
+        let cache = sharded(4, 400);
+        for i in 0..100 {
+            let name = format!("{}.example.org", i);
+            cache.upsert(key(&name), entry(&name, 60));
+        }
+        assert_eq!(100, cache.stats().entries);
+        assert!(cache.shards.iter().all(|shard| shard.read().unwrap().len() > 0));
+        assert_eq!(Some(60), cache.get(&key("7.example.org")).map(|entry| entry.answers[0].ttl));
+        assert_eq!(None, cache.get(&key("www.example.org")));
+    }
+
+    #[test]
+    fn limits_per_shard() {
+        let cache = sharded(4, 40);
+        for i in 0..200 {
+            let name = format!("{}.example.org", i);
+            cache.upsert(key(&name), entry(&name, 60));
+        }
+        let stats = cache.stats();
+        assert!(stats.entries <= 40, "{:?}", stats);
+        assert!(cache.shards.iter().all(|shard| shard.read().unwrap().len() <= 10));
+        assert_eq!(200, stats.entries as u64 + stats.evicted);
+    }
+
+    #[test]
+    fn expires_every_shard() {
+        let cache = sharded(4, 400);
+        for i in 0..20 {
+            let name = format!("{}.example.org", i);
+            cache.upsert(key(&name), entry(&name, if i % 2 == 0 { 1 } else { 60 }));
+        }
+        thread::sleep(Duration::from_millis(1010));
+        assert_eq!(10, cache.remove_expired());
+        assert_eq!((10, 10), (cache.stats().entries, cache.stats().expired as usize));
+    }
+
+    #[test]
+    fn entries_and_flush() {
+        let cache = sharded(4, 400);
+        for name in &["www.example.org", "mail.example.org", "www.example.net"] {
+            cache.upsert(key(name), entry(name, 60));
+        }
+        let org = Names::Tree(DnsName::from_string("example.org".to_owned()));
+        let mut names = cache.entries(&org).into_iter().map(|entry| entry.key.name().to_string()).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(vec!["mail.example.org", "www.example.org"], names);
+        assert_eq!(2, cache.flush(&org));
+        assert_eq!(1, cache.entries(&Names::All).len());
+        assert_eq!(1, cache.flush(&Names::All));
+        assert_eq!(0, cache.stats().entries);
+    }
+
+    #[test]
+    fn restores_snapshot() {
+        let file = env::temp_dir().join("koala_cache_restore");
+        let mut cache = sharded(4, 100);
+        cache.snapshot = Some(file.clone());
+        cache.upsert(key("a.example.org"), entry("a.example.org", 60));
+        cache.upsert(key("b.example.org"), entry("b.example.org", 60));
+        cache.upsert(key("short.example.org"), entry("short.example.org", 1));
+        assert_eq!(Ok(3), cache.save_snapshot());
+
+        thread::sleep(Duration::from_millis(1010));
+        let mut restored = sharded(2, 100);
+        assert_eq!(Ok(2), restored.restore(&file));
+        assert!(restored.get(&key("a.example.org")).is_some());
+        assert!(restored.get(&key("short.example.org")).is_none());
+        let ttl = restored.get(&key("b.example.org")).unwrap().calc_ttl();
+        assert!(ttl >= 57 && ttl <= 59, "ttl {}", ttl);
+        //and saves back to it
+        assert_eq!(Ok(2), restored.save_snapshot());
+        let _ = fs::remove_file(&file);
+    }
+
+    ///Lookups while other threads insert. With one shard every insert holds up the lookups
+    fn contended(b: &mut Bencher, shards: usize) {
+        let cache = Arc::new(sharded(shards, 100000));
+        let names = (0..1000).map(|i| format!("{}.example.org", i)).collect::<Vec<_>>();
+        for name in &names {
+            cache.upsert(key(name), entry(name, 3600));
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let writers = (0..3).map(|writer| {
+            let (cache, stop) = (cache.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut i = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    let name = format!("{}.{}.example.net", i % 1000, writer);
+                    cache.upsert(key(&name), entry(&name, 3600));
+                    i += 1;
+                }
+            })
+        }).collect::<Vec<_>>();
+        let keys = names.iter().map(|name| key(name)).collect::<Vec<_>>();
+        b.iter(|| keys.iter().filter(|key| cache.get(key).is_some()).count());
+        stop.store(true, Ordering::Relaxed);
+        for writer in writers {
+            writer.join().unwrap();
+        }
+    }
+
+    #[bench]
+    fn contended_one_shard_bench(b: &mut Bencher) {
+        contended(b, 1);
+    }
+
+    #[bench]
+    fn contended_sharded_bench(b: &mut Bencher) {
+        contended(b, 16);
+    }
+}
@@ -0,0 +1,178 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use settings::CacheConfig;
+use super::{CacheEntry, CacheKey};
+
+const MAGIC: &'static [u8] = b"KOALACACHE";
+///Changed whenever the layout of a file, or of an entry in it, does
+const VERSION: u8 = 1;
+
+///The file a view's cache is saved to. The configured one for the default view, else with the view's name appended
+pub fn path(config: &CacheConfig, view: &str) -> Option<PathBuf> {
+    config.snapshot.as_ref().map(|path| {
+        if view == "default" {
+            path.clone()
+        } else {
+            PathBuf::from(format!("{}.{}", path.display(), view))
+        }
+    })
+}
+
+///
+///Save a cache's entries to a file, so they survive a restart. The magic and version, then for each
+///entry its name, type and class, and the entry as encoded for sharing, with times on the wall clock.
+///Lengths and numbers are big-endian. The file is written aside and renamed over the last, so a crash
+///while saving leaves the last snapshot whole. Entries too large to encode are left out. Returns how many were saved
+///
+pub fn save<'a, I: Iterator<Item = (&'a CacheKey, &'a CacheEntry)>>(path: &Path, entries: I) -> Result<usize, String> {
+    let mut buf = MAGIC.to_vec();
+    buf.push(VERSION);
+    let mut count = 0;
+    for (key, entry) in entries {
+        let value = match entry.encode() {
+            Some(value) => value,
+            None => continue,
+        };
+        write_bytes(&mut buf, key.qname.as_bytes(), 2);
+        buf.extend_from_slice(&[(key.qtype >> 8) as u8, key.qtype as u8, (key.qclass >> 8) as u8, key.qclass as u8]);
+        write_bytes(&mut buf, &value, 4);
+        count += 1;
+    }
+    let temp = PathBuf::from(format!("{}.tmp", path.display()));
+    File::create(&temp)
+        .and_then(|mut file| file.write_all(&buf).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp, path))
+        .map_err(|e| format!("Couldn't save the cache to {}. {}", path.display(), e))?;
+    Ok(count)
+}
+
+///The entries saved, expired or not. Empty if there's no file yet
+pub fn load(path: &Path) -> Result<Vec<(CacheKey, CacheEntry)>, String> {
+    let mut buf = Vec::new();
+    match File::open(path).and_then(|mut file| file.read_to_end(&mut buf)) {
+        Ok(_) => (),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Couldn't read the cache from {}. {}", path.display(), e)),
+    }
+    if !buf.starts_with(MAGIC) || buf.len() <= MAGIC.len() {
+        return Err(format!("{} isn't a cache snapshot", path.display()));
+    }
+    if buf[MAGIC.len()] != VERSION {
+        return Err(format!("{} is a version {} snapshot. Only {} is read", path.display(), buf[MAGIC.len()], VERSION));
+    }
+    let mut rest = &buf[MAGIC.len() + 1..];
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let truncated = || format!("{} is truncated after {} entries", path.display(), entries.len());
+        let qname = read_bytes(&mut rest, 2).ok_or_else(&truncated)?;
+        let class = take(&mut rest, 4).ok_or_else(&truncated)?;
+        let value = read_bytes(&mut rest, 4).ok_or_else(&truncated)?;
+        let key = CacheKey::new(String::from_utf8_lossy(qname).into_owned(),
+                                ((class[0] as u16) << 8) | class[1] as u16,
+                                ((class[2] as u16) << 8) | class[3] as u16);
+        if let Some(entry) = CacheEntry::decode(&key, value) {
+            entries.push((key, entry));
+        }
+    }
+    Ok(entries)
+}
+
+///The bytes with their length first, in width bytes
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8], width: usize) {
+    for i in (0..width).rev() {
+        buf.push((bytes.len() >> (i * 8)) as u8);
+    }
+    buf.extend_from_slice(bytes);
+}
+
+fn read_bytes<'a>(rest: &mut &'a [u8], width: usize) -> Option<&'a [u8]> {
+    let len = take(rest, width)?.iter().fold(0, |len, b| (len << 8) | *b as usize);
+    take(rest, len)
+}
+
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if rest.len() < len {
+        return None;
+    }
+    let (taken, left) = rest.split_at(len);
+    *rest = left;
+    Some(taken)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load, path, save};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use cache::{CacheEntry, CacheKey};
+    use dns::message::{DnsAnswer, DnsName};
+    use dns::validate::Security;
+    use settings::CacheConfig;
+
+    fn entry(name: &str, ttl: u32) -> (CacheKey, CacheEntry) {
+        let key = CacheKey::new(name.to_owned(), 28, 1);
+        let answer = DnsAnswer::new(DnsName::from_string(name.to_owned()), 28, 1, ttl, 16, vec![0x20, 1, 0xd, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        (key.clone(), CacheEntry::new(key, vec![answer], ttl))
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = env::temp_dir().join("koala_snapshot_round_trip");
+        let (key, mut val) = entry("www.example.org", 300);
+        val.security = Security::Secure;
+        let other = entry("ns1.example.org", 60);
+        let entries = vec![(key.clone(), val.clone()), other];
+        assert_eq!(2, save(&file, entries.iter().map(|&(ref key, ref entry)| (key, entry))).unwrap());
+
+        let loaded = load(&file).unwrap();
+        assert_eq!(2, loaded.len());
+        assert_eq!(key, loaded[0].0);
+        assert_eq!(val.answers, loaded[0].1.answers);
+        assert_eq!(Security::Secure, loaded[0].1.security);
+        assert!(loaded[0].1.calc_ttl() >= 298, "ttl {}", loaded[0].1.calc_ttl());
+    }
+
+    #[test]
+    fn large_answers() {
+        let file = env::temp_dir().join("koala_snapshot_large");
+        let key = CacheKey::new("example.org".to_owned(), 16, 1);
+        let answers = (0..40).map(|_| DnsAnswer::new(key.name(), 16, 1, 300, 201, vec![200; 201])).collect::<Vec<_>>();
+        let large = CacheEntry::new(key.clone(), answers, 300);
+        let small = entry("www.example.org", 60);
+        let entries = vec![(key.clone(), large.clone()), small.clone()];
+        assert_eq!(2, save(&file, entries.iter().map(|&(ref key, ref entry)| (key, entry))).unwrap());
+
+        //over 4KiB, and read back whole, with the entry after it
+        assert!(fs::metadata(&file).unwrap().len() > 8192);
+        let loaded = load(&file).unwrap();
+        assert_eq!(2, loaded.len());
+        assert_eq!(large.answers, loaded[0].1.answers);
+        assert_eq!(small.1.answers, loaded[1].1.answers);
+        let _ = fs::remove_file(&file);
+    }
+
+    #[test]
+    fn missing_or_bad() {
+        assert_eq!(0, load(&env::temp_dir().join("koala_snapshot_none")).unwrap().len());
+        let file = env::temp_dir().join("koala_snapshot_bad");
+        File::create(&file).and_then(|mut f| f.write_all(b"KOALACACHE\x09")).unwrap();
+        assert!(load(&file).is_err());
+        File::create(&file).and_then(|mut f| f.write_all(b"KOALACACHE\x01\x00\x0fwww.example")).unwrap();
+        assert!(load(&file).is_err());
+        File::create(&file).and_then(|mut f| f.write_all(b"not a snapshot")).unwrap();
+        assert!(load(&file).is_err());
+        let _ = fs::remove_file(&file);
+    }
+
+    #[test]
+    fn per_view() {
+        let mut config = CacheConfig::default();
+        assert_eq!(None, path(&config, "default"));
+        config.snapshot = Some(PathBuf::from("/var/lib/koala/cache"));
+        assert_eq!(Some(PathBuf::from("/var/lib/koala/cache")), path(&config, "default"));
+        assert_eq!(Some(PathBuf::from("/var/lib/koala/cache.guest")), path(&config, "guest"));
+    }
+}
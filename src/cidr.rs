@@ -0,0 +1,123 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+///A network in CIDR notation. E.g. 10.0.0.0/8 or fd00::/8. A bare address is a single host.
+///Used for the per-zone client allow-lists
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Cidr {
+        Cidr {
+            addr: addr,
+            prefix_len: prefix_len,
+        }
+    }
+
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, *addr) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => Self::matches(&net.octets(), &ip.octets(), self.prefix_len),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => Self::matches(&net.octets(), &ip.octets(), self.prefix_len),
+            _ => false,
+        }
+    }
+
+    ///True if any of the networks contains the address
+    pub fn any_contains(networks: &[Cidr], addr: &IpAddr) -> bool {
+        networks.iter().any(|net| net.contains(addr))
+    }
+
+    fn matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+        let mut bits = prefix_len as usize;
+        for (n, i) in net.iter().zip(ip) {
+            if bits == 0 {
+                break;
+            }
+            let take = if bits >= 8 { 8 } else { bits };
+            let mask = !(0xffu8.checked_shr(take as u32).unwrap_or(0));
+            if n & mask != i & mask {
+                return false;
+            }
+            bits -= take;
+        }
+        true
+    }
+
+    fn max_len(addr: &IpAddr) -> u8 {
+        match *addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr_str = parts.next().unwrap_or_default();
+        let addr = IpAddr::from_str(addr_str).map_err(|e| format!("Invalid address {:?} {:?}", addr_str, e))?;
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse::<u8>().map_err(|e| format!("Invalid prefix length {:?} {:?}", len, e))?,
+            None => Self::max_len(&addr),
+        };
+        if prefix_len > Self::max_len(&addr) {
+            return Err(format!("Prefix length {} too long for {:?}", prefix_len, addr));
+        }
+        Ok(Cidr::new(addr, prefix_len))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Cidr;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn contains_v4() {
+        let net = Cidr::from_str("10.1.0.0/16").unwrap();
+        assert!(net.contains(&ip("10.1.200.3")));
+        assert!(!net.contains(&ip("10.2.0.1")));
+        assert!(!net.contains(&ip("::1")));
+    }
+
+    #[test]
+    fn partial_octet() {
+        let net = Cidr::from_str("192.168.1.128/25").unwrap();
+        assert!(net.contains(&ip("192.168.1.200")));
+        assert!(!net.contains(&ip("192.168.1.127")));
+    }
+
+    #[test]
+    fn host_and_any() {
+        let host = Cidr::from_str("127.0.0.1").unwrap();
+        assert!(host.contains(&ip("127.0.0.1")));
+        assert!(!host.contains(&ip("127.0.0.2")));
+        let any = Cidr::from_str("0.0.0.0/0").unwrap();
+        assert!(any.contains(&ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn contains_v6() {
+        let net = Cidr::from_str("fd00::/8").unwrap();
+        assert!(net.contains(&ip("fd12:3456::1")));
+        assert!(!net.contains(&ip("fe80::1")));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("example.org").is_err());
+    }
+}
@@ -0,0 +1,7 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///Seconds since the epoch. For times compared with other hosts' or kept in files, like TSIG, signature
+///validity and lease ends, where a steady clock means nothing
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
@@ -6,13 +6,15 @@ use std::env;
 use std::process;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use settings::Settings;
 
 
 pub struct Config {
     pub port: u32,
     pub server: SocketAddr,
     pub timeout: u64,
-    pub master_file: String
+    pub master_file: String,
+    pub settings: Settings
 }
 
 pub fn parse_args() -> Config {
@@ -27,6 +29,7 @@ pub fn parse_args() -> Config {
                 "Upstream response timeout in milliseconds",
                 "1000");
     opts.optopt("m", "master_file", "Path to the master file", "master.txt");
+    opts.optopt("c", "config", "Path to the config file", "koala-dns.conf");
     opts.optflag("h", "help", "print this help menu");
 
     debug!("Parsing command line options");
@@ -75,12 +78,20 @@ pub fn parse_args() -> Config {
         master_file = matches.opt_str("m").unwrap();
     }
 
+    //Config file
+    debug!("Parsing config...");
+    let mut settings = Settings::default();
+    if matches.opt_present("c") {
+        let path = matches.opt_str("c").unwrap();
+        settings = Settings::load(&path).unwrap_or_else(|e| panic!("Failed to load config. {}", e));
+    }
 
     Config {
         port: port_num,
         server: upstream_server,
         timeout: timeout_num,
-        master_file: master_file
+        master_file: master_file,
+        settings: settings
     }
 }
 
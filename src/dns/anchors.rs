@@ -0,0 +1,627 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::slice;
+use mio::{EventSet, Token};
+use request::base::*;
+use request::udp::UdpRequestFactory;
+use server_mio::RequestCtx;
+use settings::{TrustAnchorConfig, parse_base64, to_base64};
+use dns::message::*;
+use dns::record::*;
+use dns::dnssec::{Dnskey, Rrsig};
+use clock;
+use dns::validate::{self, TrustAnchor};
+use zone::master;
+
+///A new key is only trusted once it's been published this long, so a stolen one can't be slipped in. RFC5011 2.4.1
+const ADD_HOLD_DOWN: u64 = 30 * 86400;
+///A revoked key is remembered this long, then forgotten. RFC5011 2.4.2
+const REMOVE_HOLD_DOWN: u64 = 30 * 86400;
+///Bounds on how often the zone's keys are checked. RFC5011 2.3
+const MIN_REFRESH: u64 = 3600;
+const MAX_REFRESH: u64 = 15 * 86400;
+const MAX_RETRY: u64 = 86400;
+const REFRESH_TIMEOUT_MS: u64 = 2000;
+
+///Where a key is in its life as a trust anchor. A key we haven't seen, or have forgotten, isn't tracked. RFC5011 4
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub enum KeyState {
+    ///Seen in the zone, waiting out the add hold-down
+    AddPend,
+    ///Trusted
+    Valid,
+    ///Trusted, but gone from the zone's DNSKEYs
+    Missing,
+    ///Revoked by the zone. Never trusted again
+    Revoked,
+}
+
+impl KeyState {
+    fn name(&self) -> &'static str {
+        match *self {
+            KeyState::AddPend => "addpend",
+            KeyState::Valid => "valid",
+            KeyState::Missing => "missing",
+            KeyState::Revoked => "revoked",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<KeyState> {
+        match name {
+            "addpend" => Some(KeyState::AddPend),
+            "valid" => Some(KeyState::Valid),
+            "missing" => Some(KeyState::Missing),
+            "revoked" => Some(KeyState::Revoked),
+            _ => None,
+        }
+    }
+}
+
+///A key signing key of an anchor's zone
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct TrackedKey {
+    ///Without the REVOKE flag, even once it's revoked
+    pub key: Dnskey,
+    pub state: KeyState,
+    ///When the key got to its state. Seconds since the epoch
+    pub since: u64,
+}
+
+///
+///A trust anchor that follows its zone's key rollovers. The zone's DNSKEYs are checked now and then,
+///and a key signing key that's been published for the hold-down time, signed by a key we trust,
+///becomes trusted itself. A key the zone revokes, by signing the DNSKEYs with it with the REVOKE
+///flag set, stops being trusted. What's been seen is kept in the state file so it survives a
+///restart. Until the zone's keys have first been seen, the configured anchor is trusted. RFC5011
+///
+pub struct ManagedAnchor {
+    initial: TrustAnchor,
+    state_file: String,
+    ///None until the first check, or a state file from an earlier run, says which keys to trust
+    keys: Option<Vec<TrackedKey>>,
+    token: Token,
+    request: Option<Box<dyn ForwardedRequest>>,
+    next_refresh: u64,
+}
+
+///
+///The trust anchors validation starts from, with those that are managed kept up to date, and the
+///negative trust anchors, below which answers aren't validated. Each managed anchor has its own
+///token for checking its zone's keys upstream.
+///
+#[derive(Default)]
+pub struct TrustAnchors {
+    fixed: Vec<TrustAnchor>,
+    managed: Vec<ManagedAnchor>,
+    negative: Vec<DnsName>,
+    params: Option<RequestParams>,
+}
+
+impl TrustAnchors {
+    ///Clear of the tokens of requests, secondaries and notifies
+    pub const TOKEN_START: usize = 300000;
+
+    pub fn new(configs: &[TrustAnchorConfig], negative: &[DnsName], params: RequestParams) -> TrustAnchors {
+        let mut anchors = TrustAnchors {
+            fixed: Vec::new(),
+            managed: Vec::new(),
+            negative: negative.to_vec(),
+            params: Some(params),
+        };
+        for config in configs {
+            match config.state_file {
+                Some(ref state_file) => {
+                    let token = Token(Self::TOKEN_START + anchors.managed.len());
+                    anchors.managed.push(ManagedAnchor::new(config.anchor.clone(), state_file, token));
+                }
+                None => anchors.fixed.push(config.anchor.clone()),
+            }
+        }
+        for name in negative {
+            info!("Negative trust anchor {:?}. Answers below it aren't validated", name.to_string());
+        }
+        anchors
+    }
+
+    ///The anchors as they are now
+    pub fn anchors(&self) -> Vec<TrustAnchor> {
+        self.fixed.iter().cloned().chain(self.managed.iter().map(|managed| managed.anchor())).collect()
+    }
+
+    ///True if answers for name are to be validated. There's an anchor above it and no negative anchor. RFC7646 2
+    pub fn validates(&self, name: &DnsName) -> bool {
+        !self.negative.iter().any(|negative| name.is_subdomain_of(negative)) &&
+        (self.fixed.iter().any(|anchor| name.is_subdomain_of(&anchor.owner)) ||
+         self.managed.iter().any(|managed| name.is_subdomain_of(&managed.initial.owner)))
+    }
+
+    pub fn owns(&self, token: Token) -> bool {
+        self.index(token).is_some()
+    }
+
+    fn index(&self, token: Token) -> Option<usize> {
+        let i = token.as_usize().wrapping_sub(Self::TOKEN_START);
+        if i < self.managed.len() { Some(i) } else { None }
+    }
+
+    ///Check the keys of zones that are due
+    pub fn tick(&mut self, ctx: &mut RequestCtx) {
+        let now = clock::now();
+        if let Some(params) = self.params {
+            for managed in &mut self.managed {
+                if managed.request.is_none() && now >= managed.next_refresh {
+                    managed.refresh(ctx, params);
+                }
+            }
+        }
+    }
+
+    pub fn ready(&mut self, ctx: &mut RequestCtx) {
+        if let Some(i) = self.index(ctx.token) {
+            self.managed[i].ready(ctx);
+        }
+    }
+
+    pub fn timeout(&mut self, ctx: &mut RequestCtx) {
+        if let Some(i) = self.index(ctx.token) {
+            self.managed[i].timeout(ctx);
+        }
+    }
+}
+
+impl ManagedAnchor {
+    pub fn new(initial: TrustAnchor, state_file: &str, token: Token) -> ManagedAnchor {
+        let keys = if Path::new(state_file).exists() {
+            match load(state_file) {
+                Ok(keys) => Some(keys),
+                Err(e) => {
+                    error!("{}. Starting from the configured trust anchor for {:?}", e, initial.owner.to_string());
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        ManagedAnchor {
+            initial: initial,
+            state_file: state_file.to_owned(),
+            keys: keys,
+            token: token,
+            request: None,
+            next_refresh: 0,
+        }
+    }
+
+    ///The keys trusted now
+    pub fn anchor(&self) -> TrustAnchor {
+        match self.keys {
+            Some(ref keys) => {
+                let mut anchor = TrustAnchor::new(self.initial.owner.clone());
+                anchor.keys = keys.iter()
+                                  .filter(|tracked| tracked.state == KeyState::Valid || tracked.state == KeyState::Missing)
+                                  .map(|tracked| tracked.key.clone())
+                                  .collect();
+                anchor
+            }
+            None => self.initial.clone(),
+        }
+    }
+
+    pub fn keys(&self) -> &[TrackedKey] {
+        self.keys.as_ref().map_or(&[], |keys| keys.as_slice())
+    }
+
+    ///
+    ///Move the keys on from what the zone's DNSKEY RRset, with its RRSIGs, says now. True if anything
+    ///changed. Revocations are taken from any RRset, as the revoked key signs them itself. Everything
+    ///else only from an RRset signed by a key we trust. RFC5011 2.2 and 4
+    ///
+    pub fn update(&mut self, records: &[DnsAnswer], now: u64) -> bool {
+        let owner = self.initial.owner.clone();
+        let published = records.iter()
+                               .filter(|rr| rr.atype == RecordType::DNSKEY && rr.name.eq_ignore_case(&owner))
+                               .filter_map(|rr| Dnskey::from_rdata(&rr.rdata))
+                               .collect::<Vec<_>>();
+        let trusted = self.anchor();
+        let mut keys = self.keys.clone().unwrap_or_default();
+        let mut changed = false;
+
+        for revoked in published.iter().filter(|key| key.is_revoked()) {
+            if !validate::self_signed(records, &owner, slice::from_ref(revoked), now as u32) {
+                continue;
+            }
+            let key = revoked.unrevoked();
+            match keys.iter().position(|tracked| tracked.key == key) {
+                Some(i) if keys[i].state == KeyState::Revoked => (),
+                Some(i) => {
+                    info!("Key {} of {:?} revoked", key.key_tag(), owner.to_string());
+                    keys[i] = TrackedKey::new(key, KeyState::Revoked, now);
+                    changed = true;
+                }
+                None if trusted.trusts(&key) => {
+                    info!("Key {} of {:?} revoked", key.key_tag(), owner.to_string());
+                    keys.push(TrackedKey::new(key, KeyState::Revoked, now));
+                    changed = true;
+                }
+                None => (),
+            }
+        }
+
+        let signers = published.iter()
+                               .filter(|key| !key.is_revoked() && trusted.trusts(key))
+                               .filter(|key| !keys.iter().any(|tracked| tracked.key == **key && tracked.state == KeyState::Revoked))
+                               .cloned()
+                               .collect::<Vec<_>>();
+        if !validate::self_signed(records, &owner, &signers, now as u32) {
+            warn!("DNSKEYs of {:?} aren't signed by a trusted key. Keys unchanged", owner.to_string());
+            if changed {
+                self.keys = Some(keys);
+            }
+            return changed;
+        }
+
+        //the first time, the keys the configured anchor trusts are trusted straight away
+        let first = self.keys.is_none();
+        for key in published.iter().filter(|key| key.is_sep() && !key.is_revoked()) {
+            match keys.iter().position(|tracked| tracked.key == *key) {
+                None => {
+                    let state = if first && self.initial.trusts(key) { KeyState::Valid } else { KeyState::AddPend };
+                    info!("New key {} of {:?} is {}", key.key_tag(), owner.to_string(), state.name());
+                    keys.push(TrackedKey::new(key.clone(), state, now));
+                }
+                Some(i) if keys[i].state == KeyState::AddPend && now >= keys[i].since + ADD_HOLD_DOWN => {
+                    info!("Key {} of {:?} is trusted after the hold-down", key.key_tag(), owner.to_string());
+                    keys[i] = TrackedKey::new(key.clone(), KeyState::Valid, now);
+                }
+                Some(i) if keys[i].state == KeyState::Missing => keys[i] = TrackedKey::new(key.clone(), KeyState::Valid, now),
+                Some(_) => continue,
+            }
+            changed = true;
+        }
+        let before = keys.len();
+        keys.retain(|tracked| {
+            let gone = !published.contains(&tracked.key);
+            match tracked.state {
+                KeyState::AddPend => !gone,
+                KeyState::Revoked => now < tracked.since + REMOVE_HOLD_DOWN,
+                _ => true,
+            }
+        });
+        changed |= keys.len() != before;
+        for tracked in keys.iter_mut().filter(|tracked| tracked.state == KeyState::Valid && !published.contains(&tracked.key)) {
+            warn!("Trusted key {} of {:?} is missing", tracked.key.key_tag(), owner.to_string());
+            tracked.state = KeyState::Missing;
+            tracked.since = now;
+            changed = true;
+        }
+        if !keys.iter().any(|tracked| tracked.state == KeyState::Valid || tracked.state == KeyState::Missing) {
+            error!("No trusted keys left for {:?}. Its answers will fail validation", owner.to_string());
+        }
+        changed |= first;
+        self.keys = Some(keys);
+        changed
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let mut text = format!("; RFC5011 state of the trust anchor for {}, kept by koala-dns. Don't edit while it's running\n",
+                               self.initial.owner.to_string());
+        for tracked in self.keys() {
+            text.push_str(&format!("{} {} {} {} {} {}\n",
+                                   tracked.state.name(),
+                                   tracked.since,
+                                   tracked.key.flags,
+                                   tracked.key.protocol,
+                                   tracked.key.algorithm,
+                                   to_base64(&tracked.key.public_key)));
+        }
+        master::save(&self.state_file, &text)
+    }
+
+    fn refresh(&mut self, ctx: &mut RequestCtx, params: RequestParams) {
+        debug!("Checking the keys of trust anchor {:?}", self.initial.owner.to_string());
+        let query = validate::fetch_query(::time::precise_time_ns() as u16, &self.initial.owner, RecordType::DNSKEY);
+        let params = RequestParams {
+            timeout: REFRESH_TIMEOUT_MS,
+            upstream_addr: params.upstream_addr,
+        };
+        let base = ForwardedRequestBase::new(self.token, query.to_bytes(), params);
+        let mut request = UdpRequestFactory.new_with(base);
+        let mut req_ctx = RequestCtx::new(ctx.event_loop,
+                                          EventSet::readable(),
+                                          self.token,
+                                          ctx.cache.clone(),
+                                          ctx.zones.clone(),
+                                          ctx.secondaries.clone(),
+                                          ctx.anchors.clone(),
+                                          ctx.views.clone(),
+                                          ctx.hosts.clone());
+        match request.ready(&mut req_ctx) {
+            Some(response) => self.finish(response),
+            None => self.request = Some(request),
+        }
+    }
+
+    fn ready(&mut self, ctx: &mut RequestCtx) {
+        if let Some(mut request) = self.request.take() {
+            match request.ready(ctx) {
+                Some(response) => self.finish(response),
+                None => self.request = Some(request),
+            }
+        }
+    }
+
+    fn timeout(&mut self, ctx: &mut RequestCtx) {
+        if let Some(mut request) = self.request.take() {
+            let response = request.get_mut().on_timeout(ctx.token);
+            self.finish(response);
+        }
+    }
+
+    fn finish(&mut self, response: Response) {
+        let now = clock::now();
+        let answered = response.source == Source::Upstream && response.msg.header.rcode == Rcode::NOERROR;
+        if answered && self.update(&response.msg.answers, now) {
+            if let Err(e) = self.save() {
+                error!("{}", e);
+            }
+        }
+        let interval = refresh_interval(&response.msg.answers, answered, now);
+        debug!("Next check of the keys of {:?} in {}s", self.initial.owner.to_string(), interval);
+        self.next_refresh = now + interval;
+    }
+}
+
+impl TrackedKey {
+    pub fn new(key: Dnskey, state: KeyState, since: u64) -> TrackedKey {
+        TrackedKey {
+            key: key,
+            state: state,
+            since: since,
+        }
+    }
+}
+
+///
+///Half the DNSKEY TTL or the time until the signatures expire, whichever is sooner, between an hour
+///and 15 days. After a failure a tenth of that, up to a day. RFC5011 2.3
+///
+fn refresh_interval(records: &[DnsAnswer], answered: bool, now: u64) -> u64 {
+    let limit = records.iter()
+                       .filter(|rr| rr.atype == RecordType::RRSIG)
+                       .filter_map(|rr| Rrsig::from_rdata(&rr.rdata))
+                       .filter(|rrsig| rrsig.type_covered == RecordType::DNSKEY)
+                       .map(|rrsig| (rrsig.original_ttl as u64).min(rrsig.expiration.wrapping_sub(now as u32) as u64))
+                       .min();
+    match limit {
+        Some(limit) if answered => (limit / 2).clamp(MIN_REFRESH, MAX_REFRESH),
+        Some(limit) => (limit / 10).clamp(MIN_REFRESH, MAX_RETRY),
+        None => MIN_REFRESH,
+    }
+}
+
+///The keys in a state file written by save
+fn load(path: &str) -> Result<Vec<TrackedKey>, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("Failed to read trust anchor state {:?}. {:?}", path, e))?;
+    let mut keys = Vec::new();
+    for (i, line) in text.lines().enumerate().filter(|&(_, line)| !line.trim().is_empty() && !line.starts_with(';')) {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        let error = || format!("{}: bad line {}", path, i + 1);
+        if parts.len() != 6 {
+            return Err(error());
+        }
+        keys.push(TrackedKey {
+            state: KeyState::from_name(parts[0]).ok_or_else(error)?,
+            since: parts[1].parse().map_err(|_| error())?,
+            key: Dnskey {
+                flags: parts[2].parse().map_err(|_| error())?,
+                protocol: parts[3].parse().map_err(|_| error())?,
+                algorithm: parts[4].parse().map_err(|_| error())?,
+                public_key: parse_base64(parts[5]).ok_or_else(error)?,
+            },
+        });
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use dns::dnssec::{Algorithm, Ds, SigningKey};
+    use zone::dnssec::sign_rrset;
+
+    const NOW: u64 = 1_500_000_000;
+    const DAY: u64 = 86400;
+
+    fn owner() -> DnsName {
+        DnsName::from_string("example.org".to_owned())
+    }
+
+    ///A key signing key, and the same key revoked
+    fn key() -> (Arc<SigningKey>, Arc<SigningKey>) {
+        let flags = Dnskey::ZONE | Dnskey::SEP;
+        let (key, pkcs8) = SigningKey::generate(Algorithm::ED25519, flags).unwrap();
+        (Arc::new(key), Arc::new(SigningKey::from_pkcs8(&pkcs8, flags | Dnskey::REVOKE).unwrap()))
+    }
+
+    ///The zone's DNSKEY RRset as of now, with RRSIGs by the signers
+    fn dnskeys(published: &[&Arc<SigningKey>], signers: &[&Arc<SigningKey>], now: u64) -> Vec<DnsAnswer> {
+        let mut records = published.iter()
+                                   .map(|key| DnsAnswer::with_rdata(owner(), RecordType::DNSKEY, RecordClass::IN, 3600, key.dnskey.to_rdata()))
+                                   .collect::<Vec<_>>();
+        let rrsigs = sign_rrset(&records, &owner(), signers, now as u32 - 3600, now as u32 + 14 * DAY as u32).unwrap();
+        records.extend(rrsigs);
+        records
+    }
+
+    fn managed(initial: &Arc<SigningKey>, file: &str) -> ManagedAnchor {
+        let state_file = env::temp_dir().join(file);
+        let _ = fs::remove_file(&state_file);
+        let mut anchor = TrustAnchor::new(owner());
+        anchor.ds.push(Ds::for_key(&owner(), &initial.dnskey));
+        ManagedAnchor::new(anchor, state_file.to_str().unwrap(), Token(TrustAnchors::TOKEN_START))
+    }
+
+    fn states(managed: &ManagedAnchor) -> Vec<(u16, KeyState)> {
+        managed.keys().iter().map(|tracked| (tracked.key.key_tag(), tracked.state)).collect()
+    }
+
+    #[test]
+    fn new_keys_wait_for_the_hold_down() {
+        let ((k1, _), (k2, _)) = (key(), key());
+        let mut managed = managed(&k1, "koala_anchor_hold_down.state");
+        assert!(managed.anchor().trusts(&k1.dnskey));
+
+        assert!(managed.update(&dnskeys(&[&k1, &k2], &[&k1], NOW), NOW));
+        assert_eq!(vec![(k1.tag(), KeyState::Valid), (k2.tag(), KeyState::AddPend)], states(&managed));
+        assert_eq!(vec![k1.dnskey.clone()], managed.anchor().keys);
+        assert!(managed.anchor().ds.is_empty());
+
+        let later = NOW + 10 * DAY;
+        assert!(!managed.update(&dnskeys(&[&k1, &k2], &[&k1], later), later));
+        let later = NOW + 31 * DAY;
+        assert!(managed.update(&dnskeys(&[&k1, &k2], &[&k1], later), later));
+        assert_eq!(vec![(k1.tag(), KeyState::Valid), (k2.tag(), KeyState::Valid)], states(&managed));
+        assert!(managed.anchor().trusts(&k2.dnskey));
+    }
+
+    #[test]
+    fn pending_key_withdrawn() {
+        let ((k1, _), (k2, _)) = (key(), key());
+        let mut managed = managed(&k1, "koala_anchor_withdrawn.state");
+        managed.update(&dnskeys(&[&k1, &k2], &[&k1], NOW), NOW);
+        let later = NOW + DAY;
+        assert!(managed.update(&dnskeys(&[&k1], &[&k1], later), later));
+        assert_eq!(vec![(k1.tag(), KeyState::Valid)], states(&managed));
+        //back again, it starts the hold-down over
+        let later = NOW + 31 * DAY;
+        managed.update(&dnskeys(&[&k1, &k2], &[&k1], later), later);
+        assert_eq!(vec![(k1.tag(), KeyState::Valid), (k2.tag(), KeyState::AddPend)], states(&managed));
+    }
+
+    #[test]
+    fn untrusted_changes_ignored() {
+        let ((k1, _), (k2, _)) = (key(), key());
+        let mut managed = managed(&k1, "koala_anchor_untrusted.state");
+        managed.update(&dnskeys(&[&k1], &[&k1], NOW), NOW);
+        //signed by the new key only
+        assert!(!managed.update(&dnskeys(&[&k1, &k2], &[&k2], NOW + DAY), NOW + DAY));
+        //expired signatures
+        assert!(!managed.update(&dnskeys(&[&k1, &k2], &[&k1], NOW), NOW + 20 * DAY));
+        assert_eq!(vec![(k1.tag(), KeyState::Valid)], states(&managed));
+    }
+
+    #[test]
+    fn revoked_keys() {
+        let ((k1, k1_revoked), (k2, _)) = (key(), key());
+        let mut managed = managed(&k1, "koala_anchor_revoked.state");
+        managed.update(&dnskeys(&[&k1, &k2], &[&k1], NOW), NOW);
+        let later = NOW + 31 * DAY;
+        managed.update(&dnskeys(&[&k1, &k2], &[&k1], later), later);
+
+        //the revoked key has to sign the revocation itself, else it's only missing
+        let later = NOW + 32 * DAY;
+        managed.update(&dnskeys(&[&k1_revoked, &k2], &[&k2], later), later);
+        assert_eq!(vec![(k1.tag(), KeyState::Missing), (k2.tag(), KeyState::Valid)], states(&managed));
+        assert!(managed.update(&dnskeys(&[&k1_revoked, &k2], &[&k1_revoked, &k2], later), later));
+        assert_eq!(vec![(k1.tag(), KeyState::Revoked), (k2.tag(), KeyState::Valid)], states(&managed));
+        assert_eq!(vec![k2.dnskey.clone()], managed.anchor().keys);
+        //and isn't trusted again, even unrevoked and signed by a trusted key
+        let later = NOW + 33 * DAY;
+        managed.update(&dnskeys(&[&k1, &k2], &[&k2], later), later);
+        assert!(!managed.anchor().trusts(&k1.dnskey));
+        //forgotten after the remove hold-down
+        let later = NOW + 63 * DAY;
+        assert!(managed.update(&dnskeys(&[&k2], &[&k2], later), later));
+        assert_eq!(vec![(k2.tag(), KeyState::Valid)], states(&managed));
+    }
+
+    #[test]
+    fn missing_keys_stay_trusted() {
+        let ((k1, _), (k2, _)) = (key(), key());
+        let mut managed = managed(&k1, "koala_anchor_missing.state");
+        managed.update(&dnskeys(&[&k1, &k2], &[&k1], NOW), NOW);
+        let later = NOW + 31 * DAY;
+        managed.update(&dnskeys(&[&k1, &k2], &[&k1], later), later);
+        assert!(managed.update(&dnskeys(&[&k2], &[&k2], later), later));
+        assert_eq!(vec![(k1.tag(), KeyState::Missing), (k2.tag(), KeyState::Valid)], states(&managed));
+        assert!(managed.anchor().trusts(&k1.dnskey));
+        assert!(managed.update(&dnskeys(&[&k1, &k2], &[&k1], later), later));
+        assert_eq!(vec![(k1.tag(), KeyState::Valid), (k2.tag(), KeyState::Valid)], states(&managed));
+    }
+
+    #[test]
+    fn state_survives_restart() {
+        let ((k1, _), (k2, _), (k3, _)) = (key(), key(), key());
+        let mut managed = managed(&k1, "koala_anchor_restart.state");
+        managed.update(&dnskeys(&[&k1, &k2], &[&k1], NOW), NOW);
+        managed.save().unwrap();
+
+        //started again with a configured anchor the zone's moved on from
+        let mut initial = TrustAnchor::new(owner());
+        initial.keys.push(k3.dnskey.clone());
+        let restarted = ManagedAnchor::new(initial, &managed.state_file, Token(TrustAnchors::TOKEN_START));
+        assert_eq!(managed.keys(), restarted.keys());
+        assert_eq!(vec![k1.dnskey.clone()], restarted.anchor().keys);
+
+        fs::write(&managed.state_file, "valid 1 2 3\n").unwrap();
+        assert!(load(&managed.state_file).is_err());
+    }
+
+    #[test]
+    fn negative_anchors() {
+        let name = |s: &str| DnsName::from_string(s.to_owned());
+        let mut config = TrustAnchorConfig {
+            anchor: TrustAnchor::new(name(".")),
+            state_file: None,
+        };
+        config.anchor.ds.push(Ds::for_key(&name("."), &key().0.dnskey));
+        let params = RequestParams {
+            timeout: 100,
+            upstream_addr: SocketAddr::from_str("127.0.0.1:53").unwrap(),
+        };
+        let anchors = TrustAnchors::new(&[config], &[name("broken.example.net")], params);
+        assert!(anchors.validates(&name("www.example.org")));
+        assert!(anchors.validates(&name("example.net")));
+        assert!(!anchors.validates(&name("broken.example.net")));
+        assert!(!anchors.validates(&name("www.Broken.example.net")));
+        assert!(!TrustAnchors::new(&[], &[], params).validates(&name("www.example.org")));
+    }
+
+    #[test]
+    fn refresh_intervals() {
+        let (k1, _) = key();
+        let records = dnskeys(&[&k1], &[&k1], NOW);
+        assert_eq!(MIN_REFRESH, refresh_interval(&records, true, NOW));
+        let mut long_ttl = records.clone();
+        for rr in &mut long_ttl {
+            rr.ttl = 2 * DAY as u32;
+        }
+        let long_ttl = dnskeys_with_ttl(&long_ttl);
+        assert_eq!(DAY, refresh_interval(&long_ttl, true, NOW));
+        assert_eq!(DAY / 5, refresh_interval(&long_ttl, false, NOW));
+        assert_eq!(MIN_REFRESH, refresh_interval(&[], false, NOW));
+    }
+
+    ///Resigned, so the RRSIG's original TTL is the records'
+    fn dnskeys_with_ttl(records: &[DnsAnswer]) -> Vec<DnsAnswer> {
+        let (k1, _) = key();
+        let mut rrset = records.iter().filter(|rr| rr.atype == RecordType::DNSKEY).cloned().collect::<Vec<_>>();
+        let rrsigs = sign_rrset(&rrset, &owner(), &[&k1], NOW as u32, NOW as u32 + 14 * DAY as u32).unwrap();
+        rrset.extend(rrsigs);
+        rrset
+    }
+}
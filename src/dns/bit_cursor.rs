@@ -34,6 +34,11 @@ impl BitCursor {
 
 
 
+    ///The z field of the header is 3 bits. RFC1035 4.1.1
+    pub fn next_u3(&mut self) -> u8 {
+        self.read_and_advance(3) as u8
+    }
+
     pub fn next_u4(&mut self) -> u8 {
         self.read_and_advance(4) as u8
     }
@@ -52,6 +57,10 @@ impl BitCursor {
     //     return self.write_and_advance(bit_cnt, val);
     // }
 
+    pub fn write_u3(&mut self, val: u8) -> bool {
+        self.write_and_advance(3, val as u16)
+    }
+
     pub fn write_u4(&mut self, val: u8) -> bool {
         self.write_and_advance(4, val as u16)
     }
@@ -176,7 +185,7 @@ mod tests {
         assert_eq!(false, cursor.next_bool()); //tc
         assert_eq!(true, cursor.next_bool()); //rd
         assert_eq!(false, cursor.next_bool()); //ra
-        assert_eq!(0, cursor.next_u4());       //z
+        assert_eq!(0, cursor.next_u3());       //z
         assert_eq!(0, cursor.next_u4());       //rcode
     }
 
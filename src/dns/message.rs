@@ -1,6 +1,7 @@
 use dns::bit_cursor::BitCursor;
 use dns::dns_packet::DnsPacket;
 use dns::mut_dns_packet::MutDnsPacket;
+use dns::record::{RecordType, u16_bytes};
 use buf::*;
 use std::iter;
 use std::str::FromStr;
@@ -14,16 +15,8 @@ pub struct DnsMessage {
     pub header: DnsHeader,
     pub questions: Vec<DnsQuestion>,
     pub answers: Vec<DnsAnswer>,
-    pub msg_type: DnsMessageType,
-}
-
-#[derive(Debug)]
-#[derive(Clone)]
-#[derive(Eq)]
-#[derive(PartialEq)]
-pub enum DnsMessageType {
-    Query,
-    Reply,
+    pub authorities: Vec<DnsAnswer>,
+    pub additionals: Vec<DnsAnswer>,
 }
 
 #[derive(Debug)]
@@ -49,6 +42,7 @@ pub struct DnsHeader {
 #[derive(Eq)]
 #[derive(PartialEq)]
 #[derive(PartialOrd)]
+#[derive(Hash)]
 pub struct DnsAnswer {
     pub name: DnsName,
     pub atype: u16,
@@ -71,6 +65,7 @@ pub struct DnsQuestion {
 #[derive(Eq)]
 #[derive(PartialEq)]
 #[derive(PartialOrd)]
+#[derive(Hash)]
 pub struct DnsName {
     labels: Vec<String>
 }
@@ -79,23 +74,32 @@ impl IntoBytes for DnsMessage {
 
     fn write(&self, mut packet: &mut MutDnsPacket) -> usize {
         
-        let mut pos = self.header.write(packet);
+        //counts are taken from the sections so they can't disagree with what is written
+        let mut header = self.header.clone();
+        header.qdcount = self.questions.len() as u16;
+        header.ancount = self.answers.len() as u16;
+        header.nscount = self.authorities.len() as u16;
+        header.arcount = self.additionals.len() as u16;
+        let mut pos = header.write(packet);
 
         //Replies also have the question in the answer msg
         for question in &self.questions {
             pos = question.write(packet);
         }            
-        if self.msg_type == DnsMessageType::Reply {
-            //TODO: apply outbound compression
-            for answer in &self.answers {
-                pos = answer.write(packet);
-            }    
-        }        
+        //TODO: apply outbound compression
+        for answer in self.answers.iter().chain(&self.authorities).chain(&self.additionals) {
+            pos = answer.write(packet);
+        }    
         pos
     }
 }
 
 impl DnsHeader {
+    ///Authentic data. The answer was validated. Bits of z, which also holds the reserved bit. RFC4035 3.2
+    pub const AD: u8 = 0b010;
+    ///Checking disabled. The client does its own validation and wants answers that fail ours too
+    pub const CD: u8 = 0b001;
+
     #[allow(similar_names)]
     pub fn new_error(request_header: DnsHeader, rcode: u8) -> DnsHeader {
         DnsHeader {
@@ -115,6 +119,32 @@ impl DnsHeader {
         }
     }   
 
+    ///The header for a reply to a request. Flags such as aa are the caller's to set
+    ///A header for a query we send. Counts are filled in when the message is written
+    pub fn new_query(id: u16) -> DnsHeader {
+        DnsHeader {
+            id: id,
+            qr: false,
+            opcode: 0,
+            aa: false,
+            tc: false,
+            rd: false,
+            ra: false,
+            z: 0,
+            rcode: 0,
+            qdcount: 0,
+            ancount: 0,
+            nscount: 0,
+            arcount: 0,
+        }
+    }
+
+    pub fn new_reply(request_header: &DnsHeader, rcode: u8) -> DnsHeader {
+        let mut header = Self::new_error(request_header.clone(), rcode);
+        header.aa = false;
+        header
+    }
+
     #[allow(similar_names)]
     fn parse(packet: &mut DnsPacket) -> DnsHeader {
         let mut id: u16 = 0;
@@ -147,7 +177,7 @@ impl DnsHeader {
                     tc = cursor.next_bool();
                     rd = cursor.next_bool();
                     ra = cursor.next_bool();
-                    z = cursor.next_u4();
+                    z = cursor.next_u3();
                     rcode = cursor.next_u4();
                 }
                 6 => qdcount = cursor.next_u16(),
@@ -192,7 +222,7 @@ impl IntoBytes for DnsHeader {
             bit_cursor.write_bool(self.tc); //tc
             bit_cursor.write_bool(self.rd); //rd
             bit_cursor.write_bool(self.ra); //ra
-            bit_cursor.write_u4(self.z); //z
+            bit_cursor.write_u3(self.z); //z
             bit_cursor.write_u4(self.rcode); //rcode
             bit_cursor.seek(0);
             packet.seek(2);
@@ -213,39 +243,35 @@ impl DnsMessage {
         //      msg is either valid, or it's not
         let mut packet = DnsPacket::new(buf);
         let header = DnsHeader::parse(&mut packet);
-        if header.qr {
-            //answer
-            let questions = Self::parse_questions(&mut packet, header.qdcount);
-            let answers = Self::parse_answers(&mut packet, header.ancount);
-            Self::new_reply(header, questions, answers)
-        } else {
-            let questions = Self::parse_questions(&mut packet, header.qdcount);
-            Self::new_query(header, questions)
-        }
+        let questions = Self::parse_questions(&mut packet, header.qdcount);
+        let answers = Self::parse_answers(&mut packet, header.ancount);
+        let authorities = Self::parse_answers(&mut packet, header.nscount);
+        let additionals = Self::parse_answers(&mut packet, header.arcount);
+        let mut msg = Self::new(header, questions, answers);
+        msg.authorities = authorities;
+        msg.additionals = additionals;
+        msg
     }
 
     pub fn new_error(header: DnsHeader) -> DnsMessage {
-        Self::new(header, vec![], vec![], DnsMessageType::Reply)
+        Self::new(header, vec![], vec![])
     }
 
-    fn new_query(header: DnsHeader, questions: Vec<DnsQuestion>) -> DnsMessage {
-        Self::new(header, questions, vec![], DnsMessageType::Query)
+    pub fn new_query(header: DnsHeader, questions: Vec<DnsQuestion>) -> DnsMessage {
+        Self::new(header, questions, vec![])
     }
 
     pub fn new_reply(header: DnsHeader, questions: Vec<DnsQuestion>, answers: Vec<DnsAnswer>) -> DnsMessage {
-        Self::new(header, questions, answers, DnsMessageType::Reply)
+        Self::new(header, questions, answers)
     }
 
-    fn new(header: DnsHeader,
-           questions: Vec<DnsQuestion>,
-           answers: Vec<DnsAnswer>,
-           msg_type: DnsMessageType)
-           -> DnsMessage {
+    fn new(header: DnsHeader, questions: Vec<DnsQuestion>, answers: Vec<DnsAnswer>) -> DnsMessage {
         DnsMessage {
             header: header,
             questions: questions,
             answers: answers,
-            msg_type: msg_type,
+            authorities: Vec::new(),
+            additionals: Vec::new(),
         }
     }
 
@@ -269,6 +295,23 @@ impl DnsMessage {
         answers
     }
 
+    ///The bytes before the last record of the message, and that record. A TSIG is always last, and
+    ///is signed over the bytes before it
+    pub fn split_last_record(buf: &[u8]) -> Option<(&[u8], DnsAnswer)> {
+        let mut packet = DnsPacket::new(buf);
+        let header = DnsHeader::parse(&mut packet);
+        let records = header.ancount as usize + header.nscount as usize + header.arcount as usize;
+        if records == 0 {
+            return None;
+        }
+        Self::parse_questions(&mut packet, header.qdcount);
+        for _ in 0..records - 1 {
+            DnsAnswer::parse(&mut packet);
+        }
+        let pos = packet.pos();
+        Some((&buf[..pos], DnsAnswer::parse(&mut packet)))
+    }
+
     pub fn first_question(&self) -> Option<&DnsQuestion> {
         self.questions.get(0)
     }
@@ -276,9 +319,45 @@ impl DnsMessage {
     pub fn first_answer(&self) -> Option<&DnsAnswer> {
         self.answers.get(0)
     }
+
+    ///The EDNS OPT pseudo-record, if the sender included one. RFC6891 6.1.1
+    pub fn edns(&self) -> Option<&DnsAnswer> {
+        self.additionals.iter().find(|rr| rr.atype == RecordType::OPT)
+    }
+
+    ///The DO bit. The sender wants DNSSEC records. RFC3225 3
+    pub fn dnssec_ok(&self) -> bool {
+        self.edns().map_or(false, |opt| opt.ttl & DnsAnswer::EDNS_DO != 0)
+    }
 }
 
 impl DnsAnswer {
+    ///The DO flag in the TTL of an OPT record
+    pub const EDNS_DO: u32 = 0x8000;
+    ///The UDP payload size we advertise in replies
+    pub const EDNS_PAYLOAD: u16 = 4096;
+    ///The EDNS option code of an Extended DNS Error. RFC8914 2
+    const EDE_OPTION: u16 = 15;
+    ///EDE info code for an answer past its TTL, as upstream couldn't be reached. RFC8914 4.4
+    pub const EDE_STALE_ANSWER: u16 = 3;
+
+    ///
+    ///An OPT record for a reply. The class is our UDP payload size and the TTL holds the extended
+    ///rcode, version 0 and the flags. RFC6891 6.1.3
+    ///
+    pub fn new_opt(dnssec_ok: bool) -> DnsAnswer {
+        let flags = if dnssec_ok { Self::EDNS_DO } else { 0 };
+        Self::with_rdata(DnsName::from(Vec::new()), RecordType::OPT, Self::EDNS_PAYLOAD, flags, Vec::new())
+    }
+
+    ///Add an Extended DNS Error to an OPT record, with no extra text. RFC8914 2
+    pub fn add_ede(&mut self, info_code: u16) {
+        let (option, length) = (Self::EDE_OPTION, 2u16);
+        self.rdata.extend_from_slice(&[(option >> 8) as u8, option as u8, (length >> 8) as u8, length as u8]);
+        self.rdata.extend_from_slice(&[(info_code >> 8) as u8, info_code as u8]);
+        self.rdlength = self.rdata.len() as u16;
+    }
+
     pub fn new(name: DnsName,
            atype: u16,
            aclass: u16,
@@ -296,14 +375,64 @@ impl DnsAnswer {
         }
     }   
 
+    ///A record with rdlength taken from the rdata
+    pub fn with_rdata(name: DnsName, atype: u16, aclass: u16, ttl: u32, rdata: Vec<u8>) -> DnsAnswer {
+        let rdlength = rdata.len() as u16;
+        Self::new(name, atype, aclass, ttl, rdlength, rdata)
+    }
+
+    ///The bytes it takes in a message, without compression. Cheaper than to_bytes when only the length is needed
+    pub fn wire_len(&self) -> usize {
+        self.name.wire_len() + 10 + self.rdata.len()
+    }
+
+    ///Same owner, type, class and rdata. TTL is ignored, as it is when comparing RRs in an RRset
+    pub fn same_rr(&self, other: &DnsAnswer) -> bool {
+        self.name.eq_ignore_case(&other.name) && self.atype == other.atype && self.aclass == other.aclass &&
+        self.rdata == other.rdata
+    }
+
     fn parse(packet: &mut DnsPacket) -> DnsAnswer {
         let name = DnsName::parse(packet);
         let atype = packet.next_u16().unwrap_or_default();
         let aclass = packet.next_u16().unwrap_or_default();
         let ttl = packet.next_u32().unwrap_or_default();
         let rdlength = packet.next_u16().unwrap_or_default();
+        let start = packet.pos();
         let rdata = packet.next_bytes(rdlength as usize);
-        Self::new(name, atype, aclass, ttl, rdlength, rdata)
+        match Self::expand_names(packet.buf(), start, atype) {
+            Some(expanded) => Self::with_rdata(name, atype, aclass, ttl, expanded),
+            None => Self::new(name, atype, aclass, ttl, rdlength, rdata),
+        }
+    }
+
+    ///
+    ///Names in the rdata of the RFC1035 types can be compressed, pointing back into the rest of the
+    ///message. They're expanded so the rdata stands alone when it's cached, sent on or its signature
+    ///checked. None for the other types, which are never compressed. RFC3597 4
+    ///
+    fn expand_names(buf: &[u8], pos: usize, atype: u16) -> Option<Vec<u8>> {
+        //bytes before the first name, how many names, and the bytes after
+        let (prefix, names, suffix) = match atype {
+            RecordType::NS | RecordType::CNAME | RecordType::PTR => (0, 1, 0),
+            RecordType::MX => (2, 1, 0),
+            RecordType::SOA => (0, 2, 20),
+            _ => return None,
+        };
+        let mut packet = DnsPacket::new_at(buf, pos);
+        let mut rdata = Vec::new();
+        if prefix > 0 {
+            rdata.extend(u16_bytes(packet.next_u16()?).iter());
+        }
+        for _ in 0..names {
+            rdata.extend(DnsName::parse(&mut packet).to_bytes());
+        }
+        let rest = packet.next_bytes(suffix);
+        if rest.len() != suffix {
+            return None;
+        }
+        rdata.extend(rest);
+        Some(rdata)
     }
 }
 
@@ -333,7 +462,7 @@ impl IntoBytes for DnsQuestion {
 }
 
 impl DnsQuestion {
-    fn new(qname: DnsName, qtype: u16, qclass: u16) -> DnsQuestion {
+    pub fn new(qname: DnsName, qtype: u16, qclass: u16) -> DnsQuestion {
         DnsQuestion {
             qname: qname,
             qtype: qtype,
@@ -360,11 +489,12 @@ impl DnsName {
 
    
     pub fn from_string(string: String) -> DnsName {
-        let labels = string.split('.').map(|s| s.to_owned()).collect();
+        //empty labels (the root, or a trailing dot) aren't written as labels, the terminator covers them
+        let labels = string.split('.').filter(|s| !s.is_empty()).map(|s| s.to_owned()).collect();
         Self::from(labels)
     }
 
-    fn from(labels: Vec<String>) -> DnsName {
+    pub fn from(labels: Vec<String>) -> DnsName {
         DnsName {
             labels: labels
         }
@@ -374,9 +504,50 @@ impl DnsName {
         self.labels.join(".")
     }
 
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    ///Each label and its length octet, then the terminator
+    pub fn wire_len(&self) -> usize {
+        self.labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1
+    }
+
+    ///Names compare case-insensitively (RFC 4343), so keys and lookups use the lowercase form
+    pub fn to_lowercase(&self) -> DnsName {
+        DnsName::from(self.labels.iter().map(|l| l.to_lowercase()).collect())
+    }
+
+    pub fn eq_ignore_case(&self, other: &DnsName) -> bool {
+        self.labels.len() == other.labels.len() &&
+        self.labels.iter().zip(&other.labels).all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+
+    ///True if self is other, or is below other. Case-insensitive
+    pub fn is_subdomain_of(&self, other: &DnsName) -> bool {
+        if other.labels.len() > self.labels.len() {
+            return false;
+        }
+        let skip = self.labels.len() - other.labels.len();
+        self.labels[skip..].iter().zip(&other.labels).all(|(a, b)| a.to_lowercase() == b.to_lowercase())
+    }
+
+    ///The name with the leftmost label removed. None for the root
+    pub fn parent(&self) -> Option<DnsName> {
+        if self.labels.is_empty() {
+            return None;
+        }
+        Some(DnsName::from(self.labels[1..].to_vec()))
+    }
+
+    ///The name at the start of buf. E.g. the rdata of a CNAME. Names we store are uncompressed
+    pub fn from_bytes(buf: &[u8]) -> DnsName {
+        Self::parse(&mut DnsPacket::new(buf))
+    }
+
     ///A series of labels separatd by dots
     // labels may be actual labels, or pointers to previous instances of labels
-    fn parse(packet: &mut DnsPacket) -> DnsName {
+    pub fn parse(packet: &mut DnsPacket) -> DnsName {
         let byte = packet.peek_u8().unwrap_or_default();
         if Self::is_pointer(byte) {
             return Self::parse_pointer(packet);
@@ -392,6 +563,13 @@ impl DnsName {
             match packet.next_u8() {
                 // terminated with 00000000
                 Some(0) | None => more_labels = false,
+                //the rest of the name is elsewhere in the message
+                Some(byte) if Self::is_pointer(byte) => {
+                    let pos = packet.pos() - 1;
+                    packet.seek(pos);
+                    labels.extend(Self::parse_pointer(packet).labels);
+                    more_labels = false;
+                }
                 Some(len) => {
                     match Self::parse_label(packet, len as usize) {
                         Ok(label) => labels.push(label),
@@ -431,9 +609,11 @@ impl DnsName {
     }
 
     fn parse_pointer(packet: &mut DnsPacket) -> DnsName {
+        let pointer_pos = packet.pos();
         let offset = Self::parse_offset(packet.next_u16().unwrap_or_default());
         let current_pos = packet.pos();
-        if packet.seek(offset as usize) {
+        //pointers only go back, so a loop of them can't be followed forever
+        if (offset as usize) < pointer_pos && packet.seek(offset as usize) {
             let name = Self::parse(packet);
             packet.seek(current_pos);
             return name;
@@ -461,8 +641,12 @@ impl IntoBytes for DnsName {
 
 pub trait IntoBytes {
     fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_max(4096)
+    }
+    ///For messages larger than the default buffer. E.g. a zone transfer message can be up to 64KiB
+    fn to_bytes_max(&self, max_len: usize) -> Vec<u8> {
         //a zero'd buffer so the len() checks see enough room
-        let mut buf = iter::repeat(0).take(4096).collect::<Vec<_>>();
+        let mut buf = iter::repeat(0).take(max_len).collect::<Vec<_>>();
         let byte_count;
         {
             let mut packet = MutDnsPacket::new(&mut buf);
@@ -472,7 +656,7 @@ pub trait IntoBytes {
         buf.truncate(byte_count);
         buf
     }
-    fn write(&self, mut packet: &mut MutDnsPacket) -> usize;
+    fn write(&self, packet: &mut MutDnsPacket) -> usize;
 }
 
 
@@ -482,12 +666,29 @@ mod tests {
     use buf::*;
     use test::Bencher;
 
+    #[test]
+    fn wire_len() {
+        let name = DnsName::from_string("www.example.org".to_owned());
+        assert_eq!(name.to_bytes().len(), name.wire_len());
+        assert_eq!(1, DnsName::from_string(String::new()).wire_len());
+        let answer = DnsAnswer::with_rdata(name, RecordType::TXT, 1, 60, vec![3, 97, 98, 99]);
+        assert_eq!(answer.to_bytes().len(), answer.wire_len());
+    }
+
     #[test]
     fn to_bytes() {
        let msg = DnsMessage::parse(&test_query_buf());
        println!("bytes: {:?}", msg.to_bytes());
     }
 
+    #[test]
+    fn extended_error() {
+        let mut opt = DnsAnswer::new_opt(false);
+        opt.add_ede(DnsAnswer::EDE_STALE_ANSWER);
+        assert_eq!(vec![0, 15, 0, 2, 0, 3], opt.rdata);
+        assert_eq!(6, opt.rdlength);
+    }
+
     #[test]
     fn round_trip() {
         let mut query = test_query_buf();
@@ -500,6 +701,33 @@ mod tests {
         
     }
 
+    #[test]
+    fn rcode_round_trip() {
+        let msg = DnsMessage::parse(&test_query_buf());
+        let header = DnsHeader::new_error(msg.header, 3);
+        let bytes = header.to_bytes();
+        //rcode is the low 4 bits of the 4th byte
+        assert_eq!(3, bytes[3] & 0b0000_1111);
+        let parsed = DnsMessage::parse(&bytes).header;
+        assert_eq!(3, parsed.rcode);
+        assert_eq!(0, parsed.z);
+        assert!(parsed.qr);
+        assert!(parsed.rd);
+    }
+
+    #[test]
+    fn sections_round_trip() {
+        let reply = DnsMessage::parse(&test_reply_buf());
+        let mut msg = DnsMessage::new_reply(reply.header.clone(), reply.questions.clone(), vec![]);
+        msg.authorities = reply.answers[..1].to_vec();
+        msg.additionals = reply.answers[1..].to_vec();
+        let parsed = DnsMessage::parse(&msg.to_bytes());
+        assert_eq!(0, parsed.header.ancount);
+        assert_eq!(1, parsed.header.nscount);
+        assert_eq!(2, parsed.header.arcount);
+        assert_eq!(reply.answers[2], parsed.additionals[1]);
+    }
+
     fn test_query_buf() -> Vec<u8> {
         //
         // 00001000 01110001 00000001 00000000 00000000 00000001 00000000 00000000 00000000
@@ -578,8 +806,31 @@ mod tests {
     }
 
     // todo: test with multiple questions. We ignore... shoudl probably FORMATFAIL
-    // todo: test with part pointers. i.e, only part of the name has pointers
-    // see example page 30 of RFC1035
+
+    #[test]
+    fn part_pointers() {
+        //yahoo.com CNAME www.yahoo.com, the owner a pointer to the question and the rdata
+        //"www" then a pointer to it. See example page 30 of RFC1035
+        let mut reply = test_reply_buf()[..27].to_vec();
+        reply[7] = 1;
+        reply.extend_from_slice(&[192, 12, 0, 5, 0, 1, 0, 0, 0, 10, 0, 6, 3, 119, 119, 119, 192, 12]);
+        let msg = DnsMessage::parse(&reply);
+        let cname = &msg.answers[0];
+        assert_eq!("yahoo.com", cname.name.to_string());
+        assert_eq!("www.yahoo.com", DnsName::from_bytes(&cname.rdata).to_string());
+        assert_eq!(cname.rdata.len(), cname.rdlength as usize);
+        //the re-written message stands alone
+        assert_eq!(msg.answers, DnsMessage::parse(&msg.to_bytes()).answers);
+    }
+
+    #[test]
+    fn pointer_loop() {
+        //a name that points at itself
+        let mut reply = test_reply_buf()[..12].to_vec();
+        reply[5] = 1;
+        reply.extend_from_slice(&[192, 12, 0, 1, 0, 1]);
+        assert!(DnsMessage::parse(&reply).questions[0].qname.labels().is_empty());
+    }
 
 
     #[bench]
@@ -2,4 +2,8 @@ pub mod dns_packet;
 pub mod message;
 pub mod mut_dns_packet;
 pub mod bit_cursor;
-pub mod record;
\ No newline at end of file
+pub mod record;
+pub mod tsig;
+pub mod dnssec;
+pub mod validate;
+pub mod anchors;
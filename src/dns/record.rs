@@ -1,3 +1,168 @@
+use dns::dns_packet::DnsPacket;
+use dns::message::{DnsName, IntoBytes};
+use buf::*;
+
+///Type values from RFC1035 3.2.2 and later RFCs. Only the ones we do something with
+pub struct RecordType;
+impl RecordType {
+    pub const A: u16 = 1;
+    pub const NS: u16 = 2;
+    pub const CNAME: u16 = 5;
+    pub const SOA: u16 = 6;
+    pub const PTR: u16 = 12;
+    pub const MX: u16 = 15;
+    pub const TXT: u16 = 16;
+    pub const AAAA: u16 = 28;
+    pub const SRV: u16 = 33;
+    pub const OPT: u16 = 41;
+    pub const DS: u16 = 43;
+    pub const RRSIG: u16 = 46;
+    pub const NSEC: u16 = 47;
+    pub const DNSKEY: u16 = 48;
+    pub const NSEC3: u16 = 50;
+    pub const NSEC3PARAM: u16 = 51;
+    pub const TSIG: u16 = 250;
+    pub const IXFR: u16 = 251;
+    pub const AXFR: u16 = 252;
+    pub const MAILB: u16 = 253;
+    pub const MAILA: u16 = 254;
+    pub const ANY: u16 = 255;
+
+    const NAMES: &'static [(u16, &'static str)] = &[(Self::A, "A"),
+                                                   (Self::NS, "NS"),
+                                                   (Self::CNAME, "CNAME"),
+                                                   (Self::SOA, "SOA"),
+                                                   (Self::PTR, "PTR"),
+                                                   (Self::MX, "MX"),
+                                                   (Self::TXT, "TXT"),
+                                                   (Self::AAAA, "AAAA"),
+                                                   (Self::SRV, "SRV"),
+                                                   (Self::OPT, "OPT"),
+                                                   (Self::DS, "DS"),
+                                                   (Self::RRSIG, "RRSIG"),
+                                                   (Self::NSEC, "NSEC"),
+                                                   (Self::DNSKEY, "DNSKEY"),
+                                                   (Self::NSEC3, "NSEC3"),
+                                                   (Self::NSEC3PARAM, "NSEC3PARAM"),
+                                                   (Self::TSIG, "TSIG"),
+                                                   (Self::IXFR, "IXFR"),
+                                                   (Self::AXFR, "AXFR"),
+                                                   (Self::ANY, "ANY")];
+
+    ///Mnemonic to type value. E.g. "aaaa" -> 28
+    pub fn from_name(name: &str) -> Option<u16> {
+        let upper = name.to_uppercase();
+        match Self::NAMES.iter().find(|&&(_, n)| n == upper) {
+            Some(&(t, _)) => Some(t),
+            None if upper.starts_with("TYPE") => upper[4..].parse().ok(),
+            None => None,
+        }
+    }
+
+    ///Type value to mnemonic, or the RFC3597 TYPEnn form for ones we don't know
+    pub fn name(rtype: u16) -> String {
+        match Self::NAMES.iter().find(|&&(t, _)| t == rtype) {
+            Some(&(_, n)) => n.to_owned(),
+            None => format!("TYPE{}", rtype),
+        }
+    }
+}
+
+pub struct RecordClass;
+impl RecordClass {
+    pub const IN: u16 = 1;
+    pub const NONE: u16 = 254;
+    pub const ANY: u16 = 255;
+}
+
+///Response codes. RFC1035 4.1.1
+pub struct Rcode;
+impl Rcode {
+    pub const NOERROR: u8 = 0;
+    pub const FORMERR: u8 = 1;
+    pub const SERVFAIL: u8 = 2;
+    pub const NXDOMAIN: u8 = 3;
+    pub const NOTIMP: u8 = 4;
+    pub const REFUSED: u8 = 5;
+    pub const YXDOMAIN: u8 = 6;
+    pub const YXRRSET: u8 = 7;
+    pub const NXRRSET: u8 = 8;
+    pub const NOTAUTH: u8 = 9;
+    pub const NOTZONE: u8 = 10;
+}
+
+pub struct Opcode;
+impl Opcode {
+    pub const QUERY: u8 = 0;
+    pub const NOTIFY: u8 = 4;
+    pub const UPDATE: u8 = 5;
+}
+
+///The rdata of an SOA record. RFC1035 3.3.13
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Soa {
+    pub mname: DnsName,
+    pub rname: DnsName,
+    pub serial: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+impl Soa {
+    ///Names in the rdata must be uncompressed. Records from the zone store always are
+    pub fn from_rdata(rdata: &[u8]) -> Option<Soa> {
+        let mut packet = DnsPacket::new(rdata);
+        let mname = DnsName::parse(&mut packet);
+        let rname = DnsName::parse(&mut packet);
+        let serial = packet.next_u32();
+        let refresh = packet.next_u32();
+        let retry = packet.next_u32();
+        let expire = packet.next_u32();
+        let minimum = packet.next_u32();
+        match (serial, refresh, retry, expire, minimum) {
+            (Some(serial), Some(refresh), Some(retry), Some(expire), Some(minimum)) => {
+                Some(Soa {
+                    mname: mname,
+                    rname: rname,
+                    serial: serial,
+                    refresh: refresh,
+                    retry: retry,
+                    expire: expire,
+                    minimum: minimum,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn to_rdata(&self) -> Vec<u8> {
+        let mut rdata = self.mname.to_bytes();
+        rdata.extend(self.rname.to_bytes());
+        for val in &[self.serial, self.refresh, self.retry, self.expire, self.minimum] {
+            rdata.extend(u32_bytes(*val).iter());
+        }
+        rdata
+    }
+}
+
+///Serial number arithmetic. RFC1982 3.2. True if s1 is older than s2, allowing for wrap around
+pub fn serial_lt(s1: u32, s2: u32) -> bool {
+    const HALF: u32 = 1 << 31;
+    (s1 < s2 && s2 - s1 < HALF) || (s1 > s2 && s1 - s2 > HALF)
+}
+
+pub fn u16_bytes(val: u16) -> [u8; 2] {
+    [(val >> 8) as u8, val as u8]
+}
+
+pub fn u32_bytes(val: u32) -> [u8; 4] {
+    [(val >> 24) as u8, (val >> 16) as u8, (val >> 8) as u8, val as u8]
+}
+
 // #[derive(Eq)]
 // #[derive(PartialEq)]
 // #[derive(PartialOrd)]
@@ -23,4 +188,35 @@
 // pub struct ARecord {
 // 	pub base: Record,
 // 	pub address: [u32]
-// }
\ No newline at end of file
+// }
+
+#[cfg(test)]
+mod test {
+    use super::{serial_lt, Soa};
+    use dns::message::DnsName;
+
+    #[test]
+    fn soa_round_trip() {
+        let soa = Soa {
+            mname: DnsName::from_string("ns1.example.org".to_owned()),
+            rname: DnsName::from_string("hostmaster.example.org".to_owned()),
+            serial: 2016050101,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: 300,
+        };
+        assert_eq!(Some(soa.clone()), Soa::from_rdata(&soa.to_rdata()));
+        assert_eq!(None, Soa::from_rdata(&soa.to_rdata()[..30]));
+    }
+
+    #[test]
+    fn serial_arithmetic() {
+        assert!(serial_lt(1, 2));
+        assert!(!serial_lt(2, 1));
+        assert!(!serial_lt(2, 2));
+        //wraps around
+        assert!(serial_lt(u32::max_value(), 1));
+        assert!(!serial_lt(1, u32::max_value()));
+    }
+}
@@ -0,0 +1,531 @@
+use std::net::IpAddr;
+use ring::hmac;
+use cidr::Cidr;
+use dns::dns_packet::DnsPacket;
+use dns::message::*;
+use dns::record::*;
+use buf::*;
+use clock::now;
+
+///TSIG error field values. RFC8945 3
+pub struct TsigError;
+impl TsigError {
+    pub const BADSIG: u16 = 16;
+    pub const BADKEY: u16 = 17;
+    pub const BADTIME: u16 = 18;
+}
+
+///Seconds a signature's time may be either side of ours. RFC8945 10
+pub const FUDGE: u16 = 300;
+///Room to leave in a message for the TSIG added when it's signed
+pub const MAX_LEN: usize = 512;
+///Messages of a multi-message response that may go unsigned between signed ones. RFC8945 5.3.1
+const MAX_UNSIGNED: usize = 99;
+
+///
+///A shared secret for signing messages, named in the config and known to the other server.
+///The algorithm is one of the HMAC ones from RFC8945 6.
+///
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct TsigKey {
+    pub name: DnsName,
+    pub algorithm: DnsName,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    pub fn new(name: &str, algorithm: &str, secret: Vec<u8>) -> Result<TsigKey, String> {
+        let key = TsigKey {
+            name: DnsName::from_string(name.to_owned()).to_lowercase(),
+            algorithm: DnsName::from_string(algorithm.to_owned()).to_lowercase(),
+            secret: secret,
+        };
+        key.hmac_algorithm()?;
+        Ok(key)
+    }
+
+    fn hmac_algorithm(&self) -> Result<hmac::Algorithm, String> {
+        match self.algorithm.to_string().as_str() {
+            "hmac-sha1" => Ok(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY),
+            "hmac-sha256" => Ok(hmac::HMAC_SHA256),
+            "hmac-sha384" => Ok(hmac::HMAC_SHA384),
+            "hmac-sha512" => Ok(hmac::HMAC_SHA512),
+            other => Err(format!("Unsupported TSIG algorithm {:?}", other)),
+        }
+    }
+
+    fn hmac_key(&self) -> hmac::Key {
+        //checked in new
+        hmac::Key::new(self.hmac_algorithm().unwrap_or(hmac::HMAC_SHA256), &self.secret)
+    }
+
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        hmac::sign(&self.hmac_key(), data).as_ref().to_vec()
+    }
+
+    fn verify_mac(&self, data: &[u8], mac: &[u8]) -> bool {
+        hmac::verify(&self.hmac_key(), data, mac).is_ok()
+    }
+}
+
+///The keys from the config, for checking the TSIGs on requests
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Default)]
+pub struct Keyring {
+    keys: Vec<TsigKey>,
+}
+
+impl Keyring {
+    pub fn new(keys: Vec<TsigKey>) -> Keyring {
+        Keyring { keys: keys }
+    }
+
+    pub fn get(&self, name: &DnsName) -> Option<&TsigKey> {
+        self.keys.iter().find(|key| key.name.eq_ignore_case(name))
+    }
+}
+
+///The rdata of a TSIG record. RFC8945 4.2
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Tsig {
+    pub key_name: DnsName,
+    pub algorithm: DnsName,
+    pub time_signed: u64, //48 bits
+    pub fudge: u16,
+    pub mac: Vec<u8>,
+    pub original_id: u16,
+    pub error: u16,
+    pub other: Vec<u8>,
+}
+
+impl Tsig {
+    pub fn from_record(rr: &DnsAnswer) -> Option<Tsig> {
+        if rr.atype != RecordType::TSIG {
+            return None;
+        }
+        let mut packet = DnsPacket::new(&rr.rdata);
+        let algorithm = DnsName::parse(&mut packet);
+        let time_signed = ((packet.next_u16()? as u64) << 32) + packet.next_u32()? as u64;
+        let fudge = packet.next_u16()?;
+        let mac_len = packet.next_u16()? as usize;
+        let mac = packet.next_bytes(mac_len);
+        let original_id = packet.next_u16()?;
+        let error = packet.next_u16()?;
+        let other_len = packet.next_u16()? as usize;
+        let other = packet.next_bytes(other_len);
+        if mac.len() != mac_len || other.len() != other_len {
+            return None;
+        }
+        Some(Tsig {
+            key_name: rr.name.clone(),
+            algorithm: algorithm,
+            time_signed: time_signed,
+            fudge: fudge,
+            mac: mac,
+            original_id: original_id,
+            error: error,
+            other: other,
+        })
+    }
+
+    pub fn to_record(&self) -> DnsAnswer {
+        let mut rdata = self.algorithm.to_bytes();
+        rdata.extend(time_bytes(self.time_signed).iter());
+        rdata.extend(u16_bytes(self.fudge).iter());
+        rdata.extend(u16_bytes(self.mac.len() as u16).iter());
+        rdata.extend(&self.mac);
+        rdata.extend(u16_bytes(self.original_id).iter());
+        rdata.extend(u16_bytes(self.error).iter());
+        rdata.extend(u16_bytes(self.other.len() as u16).iter());
+        rdata.extend(&self.other);
+        DnsAnswer::with_rdata(self.key_name.clone(), RecordType::TSIG, RecordClass::ANY, 0, rdata)
+    }
+
+    ///The variables after the message in the MAC of the first message. RFC8945 4.3.3
+    fn variables(&self) -> Vec<u8> {
+        let mut data = self.key_name.to_lowercase().to_bytes();
+        data.extend(u16_bytes(RecordClass::ANY).iter());
+        data.extend(u32_bytes(0).iter());
+        data.extend(self.algorithm.to_lowercase().to_bytes());
+        data.extend(self.timers());
+        data.extend(u16_bytes(self.error).iter());
+        data.extend(u16_bytes(self.other.len() as u16).iter());
+        data.extend(&self.other);
+        data
+    }
+
+    ///The part of the TSIG the later messages of a multi-message response cover. RFC8945 5.3.1
+    fn timers(&self) -> Vec<u8> {
+        let mut data = time_bytes(self.time_signed).to_vec();
+        data.extend(u16_bytes(self.fudge).iter());
+        data
+    }
+
+    fn in_time(&self, now: u64) -> bool {
+        now.abs_diff(self.time_signed) <= self.fudge as u64
+    }
+}
+
+///What's covered by a MAC. A prior MAC, then the message, then the TSIG variables or timers
+fn mac_data(prior_mac: Option<&[u8]>, message: &[u8], tsig: &Tsig, timers_only: bool) -> Vec<u8> {
+    let mut data = Vec::new();
+    if let Some(mac) = prior_mac {
+        data.extend(u16_bytes(mac.len() as u16).iter());
+        data.extend(mac);
+    }
+    data.extend(message);
+    data.extend(if timers_only { tsig.timers() } else { tsig.variables() });
+    data
+}
+
+fn time_bytes(time: u64) -> [u8; 6] {
+    [(time >> 40) as u8, (time >> 32) as u8, (time >> 24) as u8, (time >> 16) as u8, (time >> 8) as u8, time as u8]
+}
+
+///
+///A message's TSIG, and the message as it was before the TSIG was added. That has the original
+///ID and one less additional record. None if the message isn't signed. RFC8945 4.3.1
+///
+pub fn split(bytes: &[u8]) -> Option<(Vec<u8>, Tsig)> {
+    const ID_AT: usize = 0;
+    const ARCOUNT_AT: usize = 10;
+    let arcount = DnsPacket::new_at(bytes, ARCOUNT_AT).next_u16()?;
+    if arcount == 0 {
+        return None;
+    }
+    let (before, rr) = DnsMessage::split_last_record(bytes)?;
+    let tsig = Tsig::from_record(&rr)?;
+    let mut unsigned = before.to_vec();
+    unsigned[ID_AT..ID_AT + 2].copy_from_slice(&u16_bytes(tsig.original_id));
+    unsigned[ARCOUNT_AT..ARCOUNT_AT + 2].copy_from_slice(&u16_bytes(arcount - 1));
+    Some((unsigned, tsig))
+}
+
+///
+///Signs a request, or the messages of a response to a signed request. Each message of a
+///multi-message response is signed, covering the MAC of the one before. RFC8945 5.3
+///
+pub struct Signer {
+    key: TsigKey,
+    prior_mac: Option<Vec<u8>>, //the request's MAC when responding, then each message's
+    signed: usize,
+}
+
+impl Signer {
+    ///For a request
+    pub fn new(key: TsigKey) -> Signer {
+        Signer {
+            key: key,
+            prior_mac: None,
+            signed: 0,
+        }
+    }
+
+    pub fn for_response(key: TsigKey, request_mac: Vec<u8>) -> Signer {
+        Signer {
+            key: key,
+            prior_mac: Some(request_mac),
+            signed: 0,
+        }
+    }
+
+    pub fn key_name(&self) -> &DnsName {
+        &self.key.name
+    }
+
+    ///The message with a TSIG added
+    pub fn sign(&mut self, msg: &DnsMessage) -> DnsMessage {
+        self.sign_with(msg, now(), 0, Vec::new())
+    }
+
+    fn sign_with(&mut self, msg: &DnsMessage, time_signed: u64, error: u16, other: Vec<u8>) -> DnsMessage {
+        let mut tsig = Tsig {
+            key_name: self.key.name.clone(),
+            algorithm: self.key.algorithm.clone(),
+            time_signed: time_signed,
+            fudge: FUDGE,
+            mac: Vec::new(),
+            original_id: msg.header.id,
+            error: error,
+            other: other,
+        };
+        let bytes = msg.to_bytes_max(::zone::transfer::MAX_MESSAGE_LEN);
+        let data = mac_data(self.prior_mac.as_deref(), &bytes, &tsig, self.signed > 0);
+        tsig.mac = self.key.mac(&data);
+        self.prior_mac = Some(tsig.mac.clone());
+        self.signed += 1;
+        let mut signed = msg.clone();
+        signed.additionals.push(tsig.to_record());
+        signed
+    }
+
+    ///Checks the response to the request just signed
+    pub fn verifier(&self) -> Verifier {
+        Verifier {
+            key: self.key.clone(),
+            prior_mac: self.prior_mac.clone().unwrap_or_default(),
+            verified: 0,
+            unsigned: Vec::new(),
+            unsigned_count: 0,
+        }
+    }
+}
+
+///
+///Checks the response to a signed request, each of its messages in turn. The messages of a
+///multi-message response after the first may be unsigned, as long as a signed one follows. RFC8945 5.3.1
+///
+pub struct Verifier {
+    key: TsigKey,
+    prior_mac: Vec<u8>,
+    verified: usize,
+    unsigned: Vec<u8>, //messages since the last signed one
+    unsigned_count: usize,
+}
+
+impl Verifier {
+    pub fn verify(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.verify_at(bytes, now())
+    }
+
+    fn verify_at(&mut self, bytes: &[u8], now: u64) -> Result<(), String> {
+        let (message, tsig) = match split(bytes) {
+            Some(split) => split,
+            None if self.verified > 0 && self.unsigned_count < MAX_UNSIGNED => {
+                self.unsigned.extend(bytes);
+                self.unsigned_count += 1;
+                return Ok(());
+            }
+            None => return Err(format!("Response isn't signed with key {:?}", self.key.name.to_string())),
+        };
+        if !tsig.key_name.eq_ignore_case(&self.key.name) || !tsig.algorithm.eq_ignore_case(&self.key.algorithm) {
+            return Err(format!("Response is signed with key {:?}, not {:?}", tsig.key_name.to_string(), self.key.name.to_string()));
+        }
+        if tsig.error != 0 {
+            return Err(format!("Response has TSIG error {}", tsig.error));
+        }
+        let mut covered = ::std::mem::take(&mut self.unsigned);
+        covered.extend(message);
+        let data = mac_data(Some(&self.prior_mac), &covered, &tsig, self.verified > 0);
+        if !self.key.verify_mac(&data, &tsig.mac) {
+            return Err("Response TSIG doesn't verify".to_owned());
+        }
+        if !tsig.in_time(now) {
+            return Err(format!("Response TSIG time {} is more than {}s from ours", tsig.time_signed, tsig.fudge));
+        }
+        self.prior_mac = tsig.mac;
+        self.verified += 1;
+        self.unsigned_count = 0;
+        Ok(())
+    }
+
+    ///True if the last message checked was signed. A response can't end with unsigned messages
+    pub fn is_complete(&self) -> bool {
+        self.verified > 0 && self.unsigned_count == 0
+    }
+}
+
+///What checking the TSIG on a request found
+pub enum Auth {
+    Unsigned,
+    ///Verified. The signer signs the response
+    Signed(Signer),
+    ///Not verified. The error is one of TsigError
+    Failed(Failure),
+}
+
+pub struct Failure {
+    pub error: u16,
+    tsig: Tsig,
+    key: Option<TsigKey>,
+}
+
+impl Failure {
+    ///A NOTAUTH reply with the TSIG error. Only BADTIME is signed, as with the others we couldn't
+    ///check the request came from the key's holder. RFC8945 5.2
+    pub fn reply(&self, query: &DnsMessage) -> DnsMessage {
+        let header = DnsHeader::new_reply(&query.header, Rcode::NOTAUTH);
+        let reply = DnsMessage::new_reply(header, query.questions.clone(), Vec::new());
+        match self.key {
+            Some(ref key) if self.error == TsigError::BADTIME => {
+                let now = now();
+                let mut signer = Signer::for_response(key.clone(), self.tsig.mac.clone());
+                signer.sign_with(&reply, self.tsig.time_signed, self.error, time_bytes(now).to_vec())
+            }
+            _ => {
+                let tsig = Tsig {
+                    mac: Vec::new(),
+                    original_id: query.header.id,
+                    error: self.error,
+                    other: Vec::new(),
+                    ..self.tsig.clone()
+                };
+                let mut reply = reply;
+                reply.additionals.push(tsig.to_record());
+                reply
+            }
+        }
+    }
+}
+
+///Check the TSIG on a request, if it has one, with the key it names. RFC8945 5.2
+pub fn verify_request(bytes: &[u8], keys: &Keyring) -> Auth {
+    verify_request_at(bytes, keys, now())
+}
+
+fn verify_request_at(bytes: &[u8], keys: &Keyring, now: u64) -> Auth {
+    let (message, tsig) = match split(bytes) {
+        Some(split) => split,
+        None => return Auth::Unsigned,
+    };
+    let failed = |error: u16, key: Option<&TsigKey>| {
+        warn!("Request signed with {:?} failed TSIG check. Error {}", tsig.key_name.to_string(), error);
+        Auth::Failed(Failure {
+            error: error,
+            tsig: tsig.clone(),
+            key: key.cloned(),
+        })
+    };
+    let key = match keys.get(&tsig.key_name) {
+        Some(key) if key.algorithm.eq_ignore_case(&tsig.algorithm) => key,
+        _ => return failed(TsigError::BADKEY, None),
+    };
+    if !key.verify_mac(&mac_data(None, &message, &tsig, false), &tsig.mac) {
+        return failed(TsigError::BADSIG, None);
+    }
+    if !tsig.in_time(now) {
+        return failed(TsigError::BADTIME, Some(key));
+    }
+    Auth::Signed(Signer::for_response(key.clone(), tsig.mac))
+}
+
+///
+///Whether a policy of networks and a key allows a request. With a key, the request must be signed
+///with it, and the networks, if there are any, narrow it further. Without, the client must be in
+///the networks.
+///
+pub fn permitted(networks: &[Cidr], key: Option<&TsigKey>, client: &IpAddr, signed_by: Option<&DnsName>) -> bool {
+    match key {
+        Some(key) => {
+            signed_by.map_or(false, |name| name.eq_ignore_case(&key.name)) &&
+            (networks.is_empty() || Cidr::any_contains(networks, client))
+        }
+        None => Cidr::any_contains(networks, client),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(name: &str, secret: &[u8]) -> TsigKey {
+        TsigKey::new(name, "hmac-sha256", secret.to_vec()).unwrap()
+    }
+
+    fn query() -> DnsMessage {
+        let question = DnsQuestion::new(DnsName::from_string("example.org".to_owned()), RecordType::AXFR, RecordClass::IN);
+        DnsMessage::new_query(DnsHeader::new_query(1234), vec![question])
+    }
+
+    fn reply(i: u8) -> DnsMessage {
+        let mut reply = query();
+        reply.header.qr = true;
+        reply.answers.push(DnsAnswer::with_rdata(DnsName::from_string("example.org".to_owned()), RecordType::A, 1, 60, vec![10, 0, 0, i]));
+        reply
+    }
+
+    #[test]
+    fn algorithms() {
+        assert!(TsigKey::new("k", "HMAC-SHA512.", vec![1]).is_ok());
+        assert!(TsigKey::new("k", "hmac-md5.sig-alg.reg.int", vec![1]).is_err());
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let keys = Keyring::new(vec![key("xfr", b"secret"), key("other", b"other")]);
+        let mut signer = Signer::new(key("xfr", b"secret"));
+        let bytes = signer.sign(&query()).to_bytes();
+        let mut responder = match verify_request(&bytes, &keys) {
+            Auth::Signed(responder) => responder,
+            _ => panic!("Didn't verify"),
+        };
+        assert_eq!("xfr", responder.key_name().to_string());
+
+        //a multi-message response, with one unsigned message part way through. The next MAC covers it
+        let mut verifier = signer.verifier();
+        let first = responder.sign(&reply(1));
+        let mut tsig = Tsig::from_record(first.additionals.last().unwrap()).unwrap();
+        let mut covered = reply(2).to_bytes_max(::zone::transfer::MAX_MESSAGE_LEN);
+        covered.extend(reply(3).to_bytes_max(::zone::transfer::MAX_MESSAGE_LEN));
+        let prior = tsig.mac.clone();
+        tsig.mac = responder.key.mac(&mac_data(Some(&prior), &covered, &tsig, true));
+        let mut last = reply(3);
+        last.additionals.push(tsig.to_record());
+        let messages = vec![first.to_bytes(), reply(2).to_bytes(), last.to_bytes()];
+        verifier.verify(&messages[0]).unwrap();
+        verifier.verify(&messages[1]).unwrap();
+        assert!(!verifier.is_complete());
+        verifier.verify(&messages[2]).unwrap();
+        assert!(verifier.is_complete());
+
+        //out of order
+        let mut verifier = signer.verifier();
+        assert!(verifier.verify(&messages[2]).is_err());
+    }
+
+    #[test]
+    fn request_failures() {
+        let keys = Keyring::new(vec![key("xfr", b"secret")]);
+        let error = |bytes: &[u8], now: u64| {
+            match verify_request_at(bytes, &keys, now) {
+                Auth::Failed(failure) => Some(failure.error),
+                _ => None,
+            }
+        };
+        assert!(error(&query().to_bytes(), now()).is_none());
+        let wrong_secret = Signer::new(key("xfr", b"guess")).sign(&query()).to_bytes();
+        assert_eq!(Some(TsigError::BADSIG), error(&wrong_secret, now()));
+        let unknown = Signer::new(key("nope", b"secret")).sign(&query()).to_bytes();
+        assert_eq!(Some(TsigError::BADKEY), error(&unknown, now()));
+
+        let mut signer = Signer::new(key("xfr", b"secret"));
+        let bytes = signer.sign(&query()).to_bytes();
+        assert_eq!(Some(TsigError::BADTIME), error(&bytes, now() + FUDGE as u64 + 1));
+        //the ID is covered as the original ID in the TSIG, so change the question instead
+        let mut tampered = bytes.clone();
+        tampered[13] ^= 1;
+        assert_eq!(Some(TsigError::BADSIG), error(&tampered, now()));
+
+        //BADTIME is signed. The client can tell it's from the server, but the error fails it
+        let failure = match verify_request_at(&bytes, &keys, now() + 1000) {
+            Auth::Failed(failure) => failure,
+            Auth::Signed(_) => panic!("Request signed 1000s ago was accepted"),
+            Auth::Unsigned => panic!("Signed request taken as unsigned"),
+        };
+        let reply = failure.reply(&query());
+        assert_eq!(Rcode::NOTAUTH, reply.header.rcode);
+        let tsig = Tsig::from_record(reply.additionals.last().unwrap()).unwrap();
+        assert_eq!(TsigError::BADTIME, tsig.error);
+        assert!(!tsig.mac.is_empty());
+        assert!(signer.verifier().verify(&reply.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn policy() {
+        let xfr = key("xfr", b"secret");
+        let nets = vec!["10.0.0.0/8".parse().unwrap()];
+        let inside = "10.1.1.1".parse().unwrap();
+        let outside = "192.168.1.1".parse().unwrap();
+        assert!(permitted(&nets, None, &inside, None));
+        assert!(!permitted(&nets, None, &outside, None));
+        assert!(!permitted(&nets, Some(&xfr), &inside, None));
+        assert!(permitted(&nets, Some(&xfr), &inside, Some(&xfr.name)));
+        assert!(!permitted(&nets, Some(&xfr), &outside, Some(&xfr.name)));
+        assert!(permitted(&[], Some(&xfr), &outside, Some(&xfr.name)));
+    }
+}
@@ -0,0 +1,884 @@
+use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use dns::message::*;
+use dns::record::*;
+use dns::dnssec::*;
+
+///What validating an answer made of it. RFC4035 4.3
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+#[derive(Eq)]
+#[derive(PartialOrd)]
+#[derive(Ord)]
+pub enum Security {
+    ///Signed, with a chain of trust to it from an anchor
+    Secure,
+    ///In a zone below a delegation the parent has proven has no DS
+    Insecure,
+    ///Should be signed, but the signatures or the chain to them are missing, expired or wrong
+    Bogus,
+    ///Not validated. No anchor covers the name, or validation is off
+    Indeterminate,
+}
+
+impl Default for Security {
+    fn default() -> Security {
+        Security::Indeterminate
+    }
+}
+
+///A key trusted without a chain of trust to it, given as a DS or DNSKEY. RFC4033 2
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct TrustAnchor {
+    pub owner: DnsName,
+    pub ds: Vec<Ds>,
+    pub keys: Vec<Dnskey>,
+}
+
+impl TrustAnchor {
+    pub fn new(owner: DnsName) -> TrustAnchor {
+        TrustAnchor {
+            owner: owner.to_lowercase(),
+            ds: Vec::new(),
+            keys: Vec::new(),
+        }
+    }
+
+    ///True if key is one of ours, or one of our DS is for it
+    pub fn trusts(&self, key: &Dnskey) -> bool {
+        self.keys.contains(key) || self.ds.iter().any(|ds| ds.matches(&self.owner, key) == Some(true))
+    }
+
+    ///The anchor nearest above name, if any are
+    pub fn closest<'a>(anchors: &'a [TrustAnchor], name: &DnsName) -> Option<&'a TrustAnchor> {
+        anchors.iter()
+               .filter(|anchor| name.is_subdomain_of(&anchor.owner))
+               .max_by_key(|anchor| anchor.owner.labels().len())
+    }
+}
+
+///What validation needs next. A record set fetched from upstream, or nothing as it's done
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Step {
+    Fetch(DnsName, u16),
+    Done(Security),
+}
+
+///Whether a name is a zone cut, and if it is whether the child is signed
+enum Delegation {
+    Secure(Vec<Ds>),
+    Insecure,
+    NotCut,
+}
+
+///
+///Validates an upstream answer. Each RRset in it must have an RRSIG made by a key of its zone, and
+///that zone's keys are trusted through the DS its parent publishes for them, and so on up to a trust
+///anchor. An unsigned answer is only accepted if on the way down from the anchor there's a delegation
+///the parent proves has no DS. Negative answers need their NSEC or NSEC3 proofs. RFC4035 5
+///
+///There's no I/O here. step() says which DNSKEY or DS record sets it needs, and the caller fetches
+///them through the forwarder and hands them over with add().
+///
+pub struct Validation {
+    question: DnsQuestion,
+    response: DnsMessage,
+    anchor: Option<TrustAnchor>,
+    now: u32,
+    fetched: HashMap<(DnsName, u16), DnsMessage>,
+    ///Fetched responses already proven, such as those from the cache
+    secure: HashSet<(DnsName, u16)>,
+    ///The zone keys of zones we've followed the chain of trust to
+    trusted: HashMap<DnsName, Vec<Dnskey>>,
+}
+
+impl Validation {
+    pub fn new(question: DnsQuestion, response: DnsMessage, anchors: &[TrustAnchor], now: u32) -> Validation {
+        let anchor = TrustAnchor::closest(anchors, &question.qname).cloned();
+        Validation {
+            question: question,
+            response: response,
+            anchor: anchor,
+            now: now,
+            fetched: HashMap::new(),
+            secure: HashSet::new(),
+            trusted: HashMap::new(),
+        }
+    }
+
+    ///The response to a Fetch. Anything but an answer to it, such as a SERVFAIL when upstream timed out,
+    ///leaves the chain broken
+    pub fn add(&mut self, name: &DnsName, rtype: u16, response: DnsMessage) {
+        self.fetched.insert((name.to_lowercase(), rtype), response);
+    }
+
+    ///Records for a Fetch that were validated before, so needn't be again
+    pub fn add_secure(&mut self, name: &DnsName, rtype: u16, records: Vec<DnsAnswer>) {
+        let question = DnsQuestion::new(name.clone(), rtype, RecordClass::IN);
+        let response = DnsMessage::new_reply(DnsHeader::new_query(0), vec![question], records);
+        self.add(name, rtype, response);
+        self.secure.insert((name.to_lowercase(), rtype));
+    }
+
+    ///The fetched responses proven secure on the way, for caching
+    pub fn secured(&self) -> Vec<&DnsMessage> {
+        self.secure.iter().filter_map(|key| self.fetched.get(key)).collect()
+    }
+
+    pub fn step(&mut self) -> Step {
+        match self.validate() {
+            Ok(security) => Step::Done(security),
+            Err(step) => step,
+        }
+    }
+
+    fn validate(&mut self) -> Result<Security, Step> {
+        let anchor_owner = match self.anchor {
+            Some(ref anchor) => anchor.owner.clone(),
+            None => return Ok(Security::Indeterminate),
+        };
+        let response = self.response.clone();
+        let rcode = response.header.rcode;
+        if rcode != Rcode::NOERROR && rcode != Rcode::NXDOMAIN {
+            return Ok(Security::Indeterminate);
+        }
+        let qname = self.question.qname.clone();
+        debug!("Validating {:?} {} under {:?}", qname.to_string(), self.question.qtype, anchor_owner.to_string());
+        let has_rrsigs = response.answers.iter().chain(&response.authorities).any(|rr| rr.atype == RecordType::RRSIG);
+        if !has_rrsigs {
+            return self.prove_insecure(&qname);
+        }
+        let mut security = Security::Secure;
+        let mut next_closers = Vec::new();
+        for (rrset, rrsigs) in rrsets(&response.answers) {
+            let (rrset_security, next_closer) = self.verify_expanded(&rrset, &rrsigs)?;
+            security = security.max(rrset_security);
+            next_closers.extend(next_closer);
+        }
+        for (rrset, rrsigs) in rrsets(&response.authorities) {
+            //the parent's NS at a referral isn't signed
+            if rrsigs.is_empty() && rrset[0].atype == RecordType::NS {
+                continue;
+            }
+            security = security.max(self.verify_rrset(&rrset, &rrsigs)?);
+        }
+        if security == Security::Secure && self.is_negative() && !self.denied(&response)? {
+            debug!("No proof for the negative answer to {:?}", qname.to_string());
+            return Err(Step::Done(Security::Bogus));
+        }
+        //a wildcard answer needs proof there's no closer name it should have come from. RFC4035 5.3.4, RFC5155 8.8
+        if security == Security::Secure &&
+           next_closers.iter().any(|name| !covers_nsec(&response, name) && covering_nsec3(&response, name).is_none()) {
+            debug!("No proof the wildcard answer to {:?} has no closer match", qname.to_string());
+            return Err(Step::Done(Security::Bogus));
+        }
+        Ok(security)
+    }
+
+    ///NXDOMAIN, or no records of the type asked for at the end of any CNAMEs
+    fn is_negative(&self) -> bool {
+        let qtype = self.question.qtype;
+        let sname = self.sname();
+        self.response.header.rcode == Rcode::NXDOMAIN ||
+        (qtype != RecordType::CNAME && qtype != RecordType::ANY &&
+         !self.response.answers.iter().any(|rr| rr.atype == qtype && rr.name.eq_ignore_case(&sname)))
+    }
+
+    ///The name the answer is for, following CNAMEs from the question. RFC1034 4.3.2
+    fn sname(&self) -> DnsName {
+        let mut sname = self.question.qname.clone();
+        for _ in 0..self.response.answers.len() {
+            match self.response.answers.iter().find(|rr| rr.atype == RecordType::CNAME && rr.name.eq_ignore_case(&sname)) {
+                Some(cname) => sname = DnsName::from_bytes(&cname.rdata),
+                None => break,
+            }
+        }
+        sname
+    }
+
+    fn fetched(&self, name: &DnsName, rtype: u16) -> Result<&DnsMessage, Step> {
+        self.fetched.get(&(name.to_lowercase(), rtype)).ok_or_else(|| Step::Fetch(name.clone(), rtype))
+    }
+
+    ///Secure if an RRSIG we can trust verifies. Insecure if the signer's zone is
+    fn verify_rrset(&mut self, rrset: &[DnsAnswer], rrsigs: &[Rrsig]) -> Result<Security, Step> {
+        self.verify_expanded(rrset, rrsigs).map(|(security, _)| security)
+    }
+
+    ///As verify_rrset, with the next closer name if the RRSIG that verified is for a wildcard the RRset was expanded from
+    fn verify_expanded(&mut self, rrset: &[DnsAnswer], rrsigs: &[Rrsig]) -> Result<(Security, Option<DnsName>), Step> {
+        let owner = rrset[0].name.clone();
+        if rrsigs.is_empty() {
+            debug!("{:?} {} isn't signed", owner.to_string(), rrset[0].atype);
+            return self.prove_insecure(&owner).map(|security| (security, None));
+        }
+        let usable = rrsigs.iter().filter(|rrsig| self.usable(rrsig, &rrset[0])).cloned().collect::<Vec<_>>();
+        for rrsig in &usable {
+            let keys = match self.trusted_keys(&rrsig.signer)? {
+                Some(keys) => keys,
+                None => return Ok((Security::Insecure, None)),
+            };
+            if verifies(rrsig, rrset, &keys) {
+                let next_closer = if rrsig.labels < rrsig_labels(&owner) {
+                    let labels = owner.labels();
+                    Some(DnsName::from(labels[labels.len() - rrsig.labels as usize - 1..].to_vec()))
+                } else {
+                    None
+                };
+                return Ok((Security::Secure, next_closer));
+            }
+        }
+        debug!("No RRSIG verifies {:?} {}", owner.to_string(), rrset[0].atype);
+        Err(Step::Done(Security::Bogus))
+    }
+
+    ///An RRSIG that's current and could have been made by the zone the RRset is in. RFC4035 5.3.1
+    fn usable(&self, rrsig: &Rrsig, rr: &DnsAnswer) -> bool {
+        let in_anchor = self.anchor.as_ref().map_or(false, |anchor| rrsig.signer.is_subdomain_of(&anchor.owner));
+        in_anchor && rrsig.type_covered == rr.atype && rr.name.is_subdomain_of(&rrsig.signer) &&
+        rrsig.labels <= rrsig_labels(&rr.name) && Algorithm::is_supported(rrsig.algorithm) && in_validity(rrsig, self.now)
+    }
+
+    ///
+    ///The zone keys of zone, if there's a chain of trust to them. None if the zone is insecure.
+    ///The DNSKEY RRset must be signed by a key the anchor or the parent's DS is for
+    ///
+    fn trusted_keys(&mut self, zone: &DnsName) -> Result<Option<Vec<Dnskey>>, Step> {
+        let zone = zone.to_lowercase();
+        if let Some(keys) = self.trusted.get(&zone) {
+            return Ok(Some(keys.clone()));
+        }
+        let (dnskeys, rrsigs) = rrset(&self.fetched(&zone, RecordType::DNSKEY)?.answers, &zone, RecordType::DNSKEY);
+        if dnskeys.is_empty() {
+            debug!("No DNSKEYs for {:?}", zone.to_string());
+            return Err(Step::Done(Security::Bogus));
+        }
+        //a revoked key can only sign its own revocation. RFC5011 2.1
+        let keys = dnskeys.iter()
+                          .filter_map(|rr| Dnskey::from_rdata(&rr.rdata))
+                          .filter(|key| !key.is_revoked())
+                          .collect::<Vec<_>>();
+        if !self.secure.contains(&(zone.clone(), RecordType::DNSKEY)) {
+            let entry_keys = match self.anchor {
+                Some(ref anchor) if anchor.owner == zone => keys.iter().filter(|key| anchor.trusts(key)).cloned().collect::<Vec<_>>(),
+                _ => {
+                    match self.delegation(&zone)? {
+                        Delegation::Secure(ds) => {
+                            keys.iter()
+                                .filter(|key| ds.iter().any(|ds| ds.matches(&zone, key) == Some(true)))
+                                .cloned()
+                                .collect()
+                        }
+                        Delegation::Insecure => return Ok(None),
+                        Delegation::NotCut => {
+                            debug!("{:?} signs, but isn't a zone", zone.to_string());
+                            return Err(Step::Done(Security::Bogus));
+                        }
+                    }
+                }
+            };
+            let self_signed = rrsigs.iter().any(|rrsig| {
+                rrsig.signer.eq_ignore_case(&zone) && self.usable(rrsig, &dnskeys[0]) && verifies(rrsig, &dnskeys, &entry_keys)
+            });
+            if !self_signed {
+                debug!("No trusted key signs the DNSKEYs of {:?}", zone.to_string());
+                return Err(Step::Done(Security::Bogus));
+            }
+            self.secure.insert((zone.clone(), RecordType::DNSKEY));
+        }
+        let zone_keys = keys.into_iter().filter(|key| key.flags & Dnskey::ZONE != 0).collect::<Vec<_>>();
+        self.trusted.insert(zone, zone_keys.clone());
+        Ok(Some(zone_keys))
+    }
+
+    ///
+    ///What the parent says about name. Its DS records, validated, or a signed denial of them. A DS
+    ///set with none we can use makes the child insecure. RFC4035 5.2
+    ///
+    fn delegation(&mut self, name: &DnsName) -> Result<Delegation, Step> {
+        let response = self.fetched(name, RecordType::DS)?.clone();
+        let (ds_set, rrsigs) = rrset(&response.answers, name, RecordType::DS);
+        if ds_set.is_empty() {
+            return self.no_ds(&response, name);
+        }
+        if !self.secure.contains(&(name.clone(), RecordType::DS)) {
+            //a DS is signed by the parent, so the chain goes up from here
+            let rrsigs = rrsigs.into_iter().filter(|rrsig| !rrsig.signer.eq_ignore_case(name)).collect::<Vec<_>>();
+            let security = match (rrsigs.is_empty(), name.parent()) {
+                //fine if the parent is insecure too. Not name itself, or we'd be back here
+                (true, Some(parent)) => self.prove_insecure(&parent)?,
+                _ => self.verify_rrset(&ds_set, &rrsigs)?,
+            };
+            if security == Security::Insecure {
+                return Ok(Delegation::Insecure);
+            }
+            self.secure.insert((name.clone(), RecordType::DS));
+        }
+        let usable = ds_set.iter()
+                           .filter_map(|rr| Ds::from_rdata(&rr.rdata))
+                           .filter(|ds| ds.is_supported())
+                           .collect::<Vec<_>>();
+        if usable.is_empty() {
+            debug!("No DS for {:?} we can use", name.to_string());
+            return Ok(Delegation::Insecure);
+        }
+        Ok(Delegation::Secure(usable))
+    }
+
+    ///A response without a DS for name. It must be a signed denial
+    fn no_ds(&mut self, response: &DnsMessage, name: &DnsName) -> Result<Delegation, Step> {
+        let rcode = response.header.rcode;
+        if rcode != Rcode::NOERROR && rcode != Rcode::NXDOMAIN {
+            debug!("DS query for {:?} failed. rcode {}", name.to_string(), rcode);
+            return Err(Step::Done(Security::Bogus));
+        }
+        let mut signed = false;
+        for (rrset, rrsigs) in rrsets(&response.authorities) {
+            //the child can't deny its own DS
+            let rrsigs = rrsigs.into_iter().filter(|rrsig| !rrsig.signer.eq_ignore_case(name)).collect::<Vec<_>>();
+            if rrsigs.is_empty() {
+                continue;
+            }
+            if self.verify_rrset(&rrset, &rrsigs)? == Security::Insecure {
+                return Ok(Delegation::Insecure);
+            }
+            signed = true;
+        }
+        if !signed {
+            debug!("Unsigned denial of DS for {:?}", name.to_string());
+            return Err(Step::Done(Security::Bogus));
+        }
+        let delegation = match_nsec(response, name).map(|types| delegation_from_types(&types))
+            .or_else(|| match_nsec3(response, name).map(|types| delegation_from_types(&types)));
+        if let Some(delegation) = delegation {
+            return delegation;
+        }
+        if covers_nsec(response, name) {
+            return Ok(Delegation::NotCut);
+        }
+        if let Some(nsec3) = covering_nsec3(response, name) {
+            //an unsigned delegation may be skipped by the chain. RFC5155 8.6
+            if nsec3.flags & Nsec3Params::OPT_OUT != 0 {
+                return Ok(Delegation::Insecure);
+            }
+            return Ok(Delegation::NotCut);
+        }
+        debug!("No proof there's no DS for {:?}", name.to_string());
+        Err(Step::Done(Security::Bogus))
+    }
+
+    ///
+    ///An unsigned answer for name is fine if there's an insecure delegation between it and the anchor.
+    ///Walk down from the anchor asking for DS records until there is one, or we get to name
+    ///
+    fn prove_insecure(&mut self, name: &DnsName) -> Result<Security, Step> {
+        let anchor_labels = self.anchor.as_ref().map_or(0, |anchor| anchor.owner.labels().len());
+        let labels = name.to_lowercase().labels().to_vec();
+        for i in (0..labels.len().saturating_sub(anchor_labels)).rev() {
+            let candidate = DnsName::from(labels[i..].to_vec());
+            if let Delegation::Insecure = self.delegation(&candidate)? {
+                debug!("{:?} is below the insecure delegation {:?}", name.to_string(), candidate.to_string());
+                return Ok(Security::Insecure);
+            }
+        }
+        debug!("{:?} should be signed but isn't", name.to_string());
+        Err(Step::Done(Security::Bogus))
+    }
+
+    ///
+    ///The NSEC or NSEC3 records prove the negative answer. For NXDOMAIN, that the name isn't there and
+    ///there's no wildcard that could have made it. For no data, that the name has no records of the
+    ///type, or for a DS that it's an opt-out delegation. RFC4035 5.4, RFC5155 8
+    ///
+    fn denied(&self, response: &DnsMessage) -> Result<bool, Step> {
+        let sname = self.sname();
+        let qtype = self.question.qtype;
+        if response.header.rcode == Rcode::NXDOMAIN {
+            if covers_nsec(response, &sname) {
+                let encloser = nsec_closest_encloser(response, &sname);
+                return Ok(covers_nsec(response, &wildcard(&encloser)));
+            }
+            return Ok(match nsec3_closest_encloser(response, &sname) {
+                Some(encloser) => covering_nsec3(response, &wildcard(&encloser)).is_some(),
+                None => false,
+            });
+        }
+        let lacks_type = |types: Vec<u16>| !types.contains(&qtype) && !types.contains(&RecordType::CNAME);
+        if let Some(types) = match_nsec(response, &sname) {
+            return Ok(lacks_type(types));
+        }
+        //an empty non-terminal. The names below it come after it in the chain
+        if nsecs(response).iter().any(|&(ref owner, ref nsec)| {
+            canonical_cmp(owner, &sname) == Ordering::Less && nsec.next.is_subdomain_of(&sname) && !nsec.next.eq_ignore_case(&sname)
+        }) {
+            return Ok(true);
+        }
+        if let Some(types) = match_nsec3(response, &sname) {
+            return Ok(lacks_type(types));
+        }
+        if qtype == RecordType::DS {
+            if let Some(encloser) = nsec3_closest_encloser(response, &sname) {
+                let next_closer = DnsName::from(sname.labels()[sname.labels().len() - encloser.labels().len() - 1..].to_vec());
+                return Ok(covering_nsec3(response, &next_closer).map_or(false, |nsec3| nsec3.flags & Nsec3Params::OPT_OUT != 0));
+            }
+        }
+        Ok(false)
+    }
+}
+
+///A name's NS and no DS or SOA make it an unsigned delegation. With a DS it would have been in the answer
+fn delegation_from_types(types: &[u16]) -> Result<Delegation, Step> {
+    if types.contains(&RecordType::DS) {
+        return Err(Step::Done(Security::Bogus));
+    }
+    if types.contains(&RecordType::NS) && !types.contains(&RecordType::SOA) {
+        return Ok(Delegation::Insecure);
+    }
+    Ok(Delegation::NotCut)
+}
+
+///True if one of the keys made the RRSIG over the RRset. A wildcard answer is signed as the wildcard. RFC4035 5.3.2
+fn verifies(rrsig: &Rrsig, rrset: &[DnsAnswer], keys: &[Dnskey]) -> bool {
+    let owner = &rrset[0].name;
+    let mut signed = rrset.to_vec();
+    let labels = rrsig_labels(owner) as usize;
+    if (rrsig.labels as usize) < labels {
+        let mut wildcard_labels = vec!["*".to_owned()];
+        wildcard_labels.extend(owner.labels()[owner.labels().len() - rrsig.labels as usize..].iter().cloned());
+        for rr in &mut signed {
+            rr.name = DnsName::from(wildcard_labels.clone());
+        }
+    }
+    let data = rrsig.signed_data(&signed);
+    keys.iter()
+        .filter(|key| key.algorithm == rrsig.algorithm && key.key_tag() == rrsig.key_tag)
+        .any(|key| key.verify(&data, &rrsig.signature))
+}
+
+///Times are serial numbers, so they wrap. RFC4034 3.1.5
+fn in_validity(rrsig: &Rrsig, now: u32) -> bool {
+    now.wrapping_sub(rrsig.inception) as i32 >= 0 && rrsig.expiration.wrapping_sub(now) as i32 >= 0
+}
+
+///
+///True if a current RRSIG by the zone over its DNSKEY RRset was made by one of keys. For checking
+///the RRset on its own, as a trust anchor's keys are when they're tracked. RFC5011 2.2
+///
+pub fn self_signed(records: &[DnsAnswer], zone: &DnsName, keys: &[Dnskey], now: u32) -> bool {
+    let (dnskeys, rrsigs) = rrset(records, zone, RecordType::DNSKEY);
+    !dnskeys.is_empty() &&
+    rrsigs.iter().any(|rrsig| rrsig.signer.eq_ignore_case(zone) && in_validity(rrsig, now) && verifies(rrsig, &dnskeys, keys))
+}
+
+///The RRsets of a section, each with the RRSIGs covering it
+fn rrsets(records: &[DnsAnswer]) -> Vec<(Vec<DnsAnswer>, Vec<Rrsig>)> {
+    let mut keys = Vec::<(DnsName, u16)>::new();
+    for rr in records.iter().filter(|rr| rr.atype != RecordType::RRSIG && rr.atype != RecordType::OPT) {
+        let key = (rr.name.to_lowercase(), rr.atype);
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+    keys.iter().map(|&(ref name, rtype)| rrset(records, name, rtype)).collect()
+}
+
+///The records of a type at name, and the RRSIGs over them
+fn rrset(records: &[DnsAnswer], name: &DnsName, rtype: u16) -> (Vec<DnsAnswer>, Vec<Rrsig>) {
+    let at_name = records.iter().filter(|rr| rr.name.eq_ignore_case(name));
+    let rrset = at_name.clone().filter(|rr| rr.atype == rtype).cloned().collect::<Vec<_>>();
+    let rrsigs = at_name.filter(|rr| rr.atype == RecordType::RRSIG)
+                        .filter_map(|rr| Rrsig::from_rdata(&rr.rdata))
+                        .filter(|rrsig| rrsig.type_covered == rtype)
+                        .collect();
+    (rrset, rrsigs)
+}
+
+fn wildcard(encloser: &DnsName) -> DnsName {
+    let mut labels = vec!["*".to_owned()];
+    labels.extend(encloser.labels().iter().cloned());
+    DnsName::from(labels)
+}
+
+fn nsecs(response: &DnsMessage) -> Vec<(DnsName, Nsec)> {
+    response.authorities
+            .iter()
+            .filter(|rr| rr.atype == RecordType::NSEC)
+            .filter_map(|rr| Nsec::from_rdata(&rr.rdata).map(|nsec| (rr.name.clone(), nsec)))
+            .collect()
+}
+
+///The types at name, if an NSEC is for it
+fn match_nsec(response: &DnsMessage, name: &DnsName) -> Option<Vec<u16>> {
+    nsecs(response).into_iter().find(|&(ref owner, _)| owner.eq_ignore_case(name)).map(|(_, nsec)| nsec.types)
+}
+
+///An NSEC says there's nothing between its owner and the next name. The last one wraps round to the apex
+fn covers_nsec(response: &DnsMessage, name: &DnsName) -> bool {
+    nsecs(response).iter().any(|&(ref owner, ref nsec)| {
+        let after_owner = canonical_cmp(owner, name) == Ordering::Less;
+        let before_next = canonical_cmp(name, &nsec.next) == Ordering::Less;
+        let last = canonical_cmp(&nsec.next, owner) != Ordering::Greater;
+        after_owner && (before_next || (last && name.is_subdomain_of(&nsec.next)))
+    })
+}
+
+///The closest encloser an NSEC covering name implies. The longest ancestor name shares with the owner or next name
+fn nsec_closest_encloser(response: &DnsMessage, name: &DnsName) -> DnsName {
+    let shared = |other: &DnsName| {
+        let mut ancestor = name.clone();
+        while !other.is_subdomain_of(&ancestor) {
+            ancestor = ancestor.parent().unwrap_or_else(|| DnsName::from(Vec::new()));
+        }
+        ancestor
+    };
+    nsecs(response).iter()
+                   .filter(|&&(ref owner, _)| canonical_cmp(owner, name) == Ordering::Less)
+                   .flat_map(|&(ref owner, ref nsec)| vec![shared(owner), shared(&nsec.next)])
+                   .max_by_key(|ancestor| ancestor.labels().len())
+                   .unwrap_or_else(|| DnsName::from(Vec::new()))
+}
+
+fn nsec3s(response: &DnsMessage) -> Vec<(DnsName, Nsec3)> {
+    response.authorities
+            .iter()
+            .filter(|rr| rr.atype == RecordType::NSEC3)
+            .filter_map(|rr| Nsec3::from_rdata(&rr.rdata).map(|nsec3| (rr.name.to_lowercase(), nsec3)))
+            .collect()
+}
+
+///The hash of name with an NSEC3's parameters, as it is in owner names
+fn nsec3_hash(nsec3: &Nsec3, name: &DnsName) -> String {
+    let params = Nsec3Params {
+        iterations: nsec3.iterations,
+        salt: nsec3.salt.clone(),
+        opt_out: false,
+    };
+    base32hex(&params.hash(name))
+}
+
+///The types at name, if an NSEC3 is for its hash
+fn match_nsec3(response: &DnsMessage, name: &DnsName) -> Option<Vec<u16>> {
+    nsec3s(response).into_iter()
+                    .find(|&(ref owner, ref nsec3)| {
+                        owner.parent().map_or(false, |zone| name.is_subdomain_of(&zone)) &&
+                        owner.labels().first() == Some(&nsec3_hash(nsec3, name))
+                    })
+                    .map(|(_, nsec3)| nsec3.types)
+}
+
+///An NSEC3 whose span of hashes has name's. base32hex sorts as the hashes do
+fn covering_nsec3(response: &DnsMessage, name: &DnsName) -> Option<Nsec3> {
+    nsec3s(response).into_iter()
+                    .find(|&(ref owner, ref nsec3)| {
+                        let owner_hash = match owner.labels().first() {
+                            Some(label) => label.clone(),
+                            None => return false,
+                        };
+                        let hash = nsec3_hash(nsec3, name);
+                        let next = base32hex(&nsec3.next_hashed);
+                        if owner_hash < next {
+                            owner_hash < hash && hash < next
+                        } else {
+                            owner_hash < hash || hash < next
+                        }
+                    })
+                    .map(|(_, nsec3)| nsec3)
+}
+
+///
+///The closest encloser proof. An ancestor of name that an NSEC3 matches, and one covering the next
+///closer name, the one a label longer. RFC5155 8.3
+///
+fn nsec3_closest_encloser(response: &DnsMessage, name: &DnsName) -> Option<DnsName> {
+    let mut next_closer = name.clone();
+    let mut encloser = name.parent()?;
+    loop {
+        if match_nsec3(response, &encloser).is_some() {
+            return covering_nsec3(response, &next_closer).map(|_| encloser);
+        }
+        next_closer = encloser.clone();
+        encloser = encloser.parent()?;
+    }
+}
+
+///Whether the query asked for no validation. RFC4035 3.2.2
+pub fn checking_disabled(query: &DnsMessage) -> bool {
+    query.header.z & DnsHeader::CD != 0
+}
+
+///
+///The reply to a client from an answer that's been through validation. AD is only set for clients
+///that show they understand it by setting DO or AD. Those without DO don't get the DNSSEC records.
+///RFC6840 5.7, 5.8 and RFC4035 3.2.1
+///
+pub fn reply(query: &DnsMessage, mut msg: DnsMessage, security: Security) -> DnsMessage {
+    let wants_ad = query.dnssec_ok() || query.header.z & DnsHeader::AD != 0;
+    msg.header.id = query.header.id;
+    msg.header.z = query.header.z & DnsHeader::CD;
+    if security == Security::Secure && wants_ad {
+        msg.header.z |= DnsHeader::AD;
+    }
+    if !query.dnssec_ok() {
+        let qtype = query.first_question().map_or(0, |q| q.qtype);
+        let dnssec_type = |rtype: u16| {
+            rtype != qtype && (rtype == RecordType::RRSIG || rtype == RecordType::NSEC || rtype == RecordType::NSEC3)
+        };
+        msg.answers.retain(|rr| !dnssec_type(rr.atype));
+        msg.authorities.retain(|rr| !dnssec_type(rr.atype));
+        msg.additionals.retain(|rr| !dnssec_type(rr.atype));
+    }
+    msg.additionals.retain(|rr| rr.atype != RecordType::OPT);
+    if query.edns().is_some() {
+        msg.additionals.push(DnsAnswer::new_opt(query.dnssec_ok()));
+    }
+    msg
+}
+
+///The query sent upstream for a client's. With DO, for the RRSIGs, and CD, as we validate it ourselves. RFC4035 4.6, 4.9.2
+pub fn upstream_query(query: &DnsMessage) -> DnsMessage {
+    let mut upstream = query.clone();
+    upstream.header.z |= DnsHeader::CD;
+    upstream.additionals.retain(|rr| rr.atype != RecordType::OPT);
+    upstream.additionals.push(DnsAnswer::new_opt(true));
+    upstream
+}
+
+///The query for a DNSKEY or DS the chain of trust needs
+pub fn fetch_query(id: u16, name: &DnsName, rtype: u16) -> DnsMessage {
+    let mut header = DnsHeader::new_query(id);
+    header.rd = true;
+    upstream_query(&DnsMessage::new_query(header, vec![DnsQuestion::new(name.clone(), rtype, RecordClass::IN)]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use settings::ZoneConfig;
+    use zone::Zone;
+    use zone::test::signed_zone;
+
+    const NOW: u32 = 1_500_000_000;
+
+    fn name(s: &str) -> DnsName {
+        DnsName::from_string(s.to_owned())
+    }
+
+    fn keys() -> Vec<Arc<SigningKey>> {
+        vec![Arc::new(SigningKey::generate(Algorithm::ED25519, Dnskey::ZONE | Dnskey::SEP).unwrap().0),
+             Arc::new(SigningKey::generate(Algorithm::ECDSAP256SHA256, Dnskey::ZONE).unwrap().0)]
+    }
+
+    const SOA: &'static str = "$TTL 60\n@ SOA ns1 hostmaster 1 3600 600 86400 60\n  NS ns1\nns1 A 10.0.0.1\nwww A 10.0.0.2\n";
+
+    ///example.org signed with NSEC. Below it sec.example.org signed with NSEC3 opt-out and a DS in
+    ///the parent, and insecure.example.org unsigned. Returns the zones and an anchor for example.org
+    fn setup() -> (Vec<Zone>, TrustAnchor) {
+        let (parent_keys, child_keys) = (keys(), keys());
+        let child_ds = Ds::for_key(&name("sec.example.org"), &child_keys[0].dnskey).to_text(&name("sec.example.org"));
+        let parent = format!("{}sec NS ns.sec\n{}\ninsecure NS ns.insecure\n", SOA, child_ds);
+        let mut nsec3 = ZoneConfig::default();
+        nsec3.nsec3 = Some(Nsec3Params {
+            iterations: 2,
+            salt: vec![0xbe, 0xef],
+            opt_out: true,
+        });
+        let zones = vec![signed_zone("example.org", &parent, ZoneConfig::default(), parent_keys.clone(), NOW),
+                         signed_zone("sec.example.org", &format!("{}unsigned NS ns.unsigned\n", SOA), nsec3, child_keys, NOW),
+                         Zone::from_records(name("insecure.example.org"),
+                                            ZoneConfig::default(),
+                                            ::zone::master::parse(SOA, Some(&name("insecure.example.org"))).unwrap())
+                             .unwrap()];
+        let mut anchor = TrustAnchor::new(name("example.org"));
+        anchor.keys.push(parent_keys[0].dnskey.clone());
+        (zones, anchor)
+    }
+
+    ///A stand-in for upstream. The zone nearest the name answers, or the parent for a DS
+    fn upstream(zones: &[Zone], qname: &DnsName, qtype: u16) -> DnsMessage {
+        let owner = if qtype == RecordType::DS { qname.parent().unwrap() } else { qname.clone() };
+        let zone = zones.iter()
+                        .filter(|zone| owner.is_subdomain_of(&zone.origin))
+                        .max_by_key(|zone| zone.origin.labels().len())
+                        .unwrap();
+        let mut result = zone.lookup(qname, qtype);
+        zone.add_dnssec(&mut result);
+        let mut msg = DnsMessage::new_reply(DnsHeader::new_reply(&DnsHeader::new_query(1), result.rcode),
+                                            vec![DnsQuestion::new(qname.clone(), qtype, RecordClass::IN)],
+                                            result.answers);
+        msg.authorities = result.authorities;
+        msg
+    }
+
+    ///Validate a response, fetching what it needs from the zones. The result and the fetches made
+    fn validate_with(zones: &[Zone], anchors: &[TrustAnchor], response: DnsMessage, now: u32) -> (Security, Vec<(DnsName, u16)>) {
+        let question = response.questions[0].clone();
+        let mut validation = Validation::new(question, response, anchors, now);
+        let mut fetches = Vec::new();
+        loop {
+            match validation.step() {
+                Step::Fetch(name, rtype) => {
+                    assert!(!fetches.contains(&(name.clone(), rtype)), "{:?} {} again", name, rtype);
+                    validation.add(&name, rtype, upstream(zones, &name, rtype));
+                    fetches.push((name, rtype));
+                }
+                Step::Done(security) => return (security, fetches),
+            }
+        }
+    }
+
+    fn validate(zones: &[Zone], anchor: &TrustAnchor, qname: &str, qtype: u16) -> Security {
+        validate_with(zones, &[anchor.clone()], upstream(zones, &name(qname), qtype), NOW + 60).0
+    }
+
+    #[test]
+    fn secure() {
+        let (zones, anchor) = setup();
+        assert_eq!(Security::Secure, validate(&zones, &anchor, "www.example.org", RecordType::A));
+        assert_eq!(Security::Secure, validate(&zones, &anchor, "example.org", RecordType::DNSKEY));
+        //through the DS to the child
+        let (security, fetches) = validate_with(&zones, &[anchor.clone()], upstream(&zones, &name("www.sec.example.org"), RecordType::A), NOW);
+        assert_eq!(Security::Secure, security);
+        assert_eq!(vec![(name("sec.example.org"), RecordType::DNSKEY),
+                        (name("sec.example.org"), RecordType::DS),
+                        (name("example.org"), RecordType::DNSKEY)],
+                   fetches);
+        //the anchor can be a DS
+        let mut ds_anchor = TrustAnchor::new(name("example.org"));
+        ds_anchor.ds.push(Ds::for_key(&name("example.org"), &anchor.keys[0]));
+        assert_eq!(Security::Secure, validate(&zones, &ds_anchor, "www.sec.example.org", RecordType::A));
+    }
+
+    #[test]
+    fn secure_denials() {
+        let (zones, anchor) = setup();
+        //NSEC
+        assert_eq!(Security::Secure, validate(&zones, &anchor, "nope.example.org", RecordType::A));
+        assert_eq!(Security::Secure, validate(&zones, &anchor, "www.example.org", RecordType::MX));
+        assert_eq!(Security::Secure, validate(&zones, &anchor, "insecure.example.org", RecordType::DS));
+        //NSEC3
+        assert_eq!(Security::Secure, validate(&zones, &anchor, "nope.sec.example.org", RecordType::A));
+        assert_eq!(Security::Secure, validate(&zones, &anchor, "www.sec.example.org", RecordType::TXT));
+        assert_eq!(Security::Secure, validate(&zones, &anchor, "unsigned.sec.example.org", RecordType::DS));
+    }
+
+    #[test]
+    fn insecure() {
+        let (zones, anchor) = setup();
+        //below a delegation without a DS, proven by NSEC or opt-out NSEC3
+        assert_eq!(Security::Insecure, validate(&zones, &anchor, "www.insecure.example.org", RecordType::A));
+        let unsigned = zones[0].lookup(&name("ns1.example.org"), RecordType::A);
+        let mut response = upstream(&zones, &name("host.unsigned.sec.example.org"), RecordType::A);
+        response.answers = vec![DnsAnswer::with_rdata(name("host.unsigned.sec.example.org"), RecordType::A, RecordClass::IN, 60, unsigned.answers[0].rdata.clone())];
+        response.authorities.clear();
+        assert_eq!(Security::Insecure, validate_with(&zones, &[anchor.clone()], response, NOW).0);
+        //nothing to validate against
+        assert_eq!(Security::Indeterminate, validate_with(&zones, &[], upstream(&zones, &name("www.example.org"), RecordType::A), NOW).0);
+    }
+
+    #[test]
+    fn bogus() {
+        let (zones, anchor) = setup();
+        let www = upstream(&zones, &name("www.example.org"), RecordType::A);
+        //changed on the way
+        let mut changed = www.clone();
+        changed.answers[0].rdata = vec![192, 0, 2, 1];
+        assert_eq!(Security::Bogus, validate_with(&zones, &[anchor.clone()], changed, NOW).0);
+        //signatures stripped from a name that isn't below an insecure delegation
+        let mut stripped = www.clone();
+        stripped.answers.retain(|rr| rr.atype != RecordType::RRSIG);
+        assert_eq!(Security::Bogus, validate_with(&zones, &[anchor.clone()], stripped, NOW).0);
+        //expired, or not yet valid
+        assert_eq!(Security::Bogus, validate_with(&zones, &[anchor.clone()], www.clone(), NOW + 30 * 86400).0);
+        assert_eq!(Security::Bogus, validate_with(&zones, &[anchor.clone()], www.clone(), NOW - 2 * 86400).0);
+        //an anchor for other keys
+        let mut other = anchor.clone();
+        other.keys = vec![keys()[0].dnskey.clone()];
+        assert_eq!(Security::Bogus, validate_with(&zones, &[other], www.clone(), NOW).0);
+        //a denial without its proof
+        let mut nxdomain = upstream(&zones, &name("nope.example.org"), RecordType::A);
+        nxdomain.authorities.retain(|rr| rr.atype == RecordType::SOA || (rr.atype == RecordType::RRSIG && Rrsig::from_rdata(&rr.rdata).unwrap().type_covered == RecordType::SOA));
+        assert_eq!(Security::Bogus, validate_with(&zones, &[anchor.clone()], nxdomain, NOW).0);
+        //a NODATA for a type that's there
+        let mut nodata = upstream(&zones, &name("www.example.org"), RecordType::MX);
+        nodata.questions[0].qtype = RecordType::A;
+        assert_eq!(Security::Bogus, validate_with(&zones, &[anchor.clone()], nodata, NOW).0);
+        //the child's keys don't match the DS
+        let mut wrong_ds = setup();
+        wrong_ds.0[1] = signed_zone("sec.example.org", SOA, ZoneConfig::default(), keys(), NOW);
+        assert_eq!(Security::Bogus, validate(&wrong_ds.0, &wrong_ds.1, "www.sec.example.org", RecordType::A));
+        //upstream failed to answer for the chain
+        let mut validation = Validation::new(www.questions[0].clone(), www.clone(), &[anchor.clone()], NOW);
+        assert_eq!(Step::Fetch(name("example.org"), RecordType::DNSKEY), validation.step());
+        let failed = DnsMessage::new_error(DnsHeader::new_error(DnsHeader::new_query(1), Rcode::SERVFAIL));
+        validation.add(&name("example.org"), RecordType::DNSKEY, failed);
+        assert_eq!(Step::Done(Security::Bogus), validation.step());
+    }
+
+    #[test]
+    fn wildcards() {
+        let zone_keys = keys();
+        let zones = vec![signed_zone("example.org", &format!("{}*.wild A 10.0.0.9\n", SOA), ZoneConfig::default(), zone_keys.clone(), NOW)];
+        let mut anchor = TrustAnchor::new(name("example.org"));
+        anchor.keys.push(zone_keys[0].dnskey.clone());
+        //the wildcard's records as they'd come expanded for host.wild, with the NSECs saying host.wild isn't there
+        let mut expanded = upstream(&zones, &name("*.wild.example.org"), RecordType::A);
+        expanded.questions[0].qname = name("host.wild.example.org");
+        for rr in &mut expanded.answers {
+            rr.name = name("host.wild.example.org");
+        }
+        expanded.authorities = upstream(&zones, &name("host.wild.example.org"), RecordType::A).authorities;
+        expanded.authorities.retain(|rr| rr.atype == RecordType::NSEC || (rr.atype == RecordType::RRSIG && Rrsig::from_rdata(&rr.rdata).unwrap().type_covered == RecordType::NSEC));
+        assert_eq!(Security::Secure, validate_with(&zones, &[anchor.clone()], expanded.clone(), NOW).0);
+        //without the proof, it could be hiding a name that's there
+        let mut bare = expanded.clone();
+        bare.authorities.clear();
+        assert_eq!(Security::Bogus, validate_with(&zones, &[anchor.clone()], bare, NOW).0);
+        //asked for by its own name it's not an expansion
+        assert_eq!(Security::Secure, validate(&zones, &anchor, "*.wild.example.org", RecordType::A));
+    }
+
+    #[test]
+    fn cached_keys() {
+        let (zones, anchor) = setup();
+        let mut validation = Validation::new(DnsQuestion::new(name("www.sec.example.org"), RecordType::A, RecordClass::IN),
+                                             upstream(&zones, &name("www.sec.example.org"), RecordType::A),
+                                             &[anchor],
+                                             NOW);
+        let dnskeys = upstream(&zones, &name("sec.example.org"), RecordType::DNSKEY).answers;
+        validation.add_secure(&name("sec.example.org"), RecordType::DNSKEY, dnskeys);
+        assert_eq!(Step::Done(Security::Secure), validation.step());
+        assert_eq!(1, validation.secured().len());
+    }
+
+    #[test]
+    fn replies() {
+        let (zones, _) = setup();
+        let response = upstream(&zones, &name("www.example.org"), RecordType::A);
+        let mut query = DnsMessage::new_query(DnsHeader::new_query(9), response.questions.clone());
+        //no DO, no AD and no DNSSEC records
+        let plain = reply(&query, response.clone(), Security::Secure);
+        assert_eq!((9, 0), (plain.header.id, plain.header.z));
+        assert!(plain.answers.iter().all(|rr| rr.atype == RecordType::A));
+        assert!(plain.edns().is_none());
+        query.header.z = DnsHeader::AD;
+        assert_eq!(DnsHeader::AD, reply(&query, response.clone(), Security::Secure).header.z);
+        assert_eq!(0, reply(&query, response.clone(), Security::Insecure).header.z);
+        //DO gets them all
+        query.header.z = DnsHeader::CD;
+        query.additionals.push(DnsAnswer::new_opt(true));
+        let full = reply(&query, response.clone(), Security::Secure);
+        assert_eq!(DnsHeader::AD | DnsHeader::CD, full.header.z);
+        assert_eq!(response.answers, full.answers);
+        assert!(full.dnssec_ok());
+        assert!(checking_disabled(&query));
+
+        let upstream = upstream_query(&DnsMessage::new_query(DnsHeader::new_query(9), response.questions.clone()));
+        assert!(upstream.dnssec_ok() && checking_disabled(&upstream));
+    }
+}
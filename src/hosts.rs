@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::SystemTime;
+use dns::message::{DnsAnswer, DnsName, IntoBytes};
+use dns::record::*;
+use clock;
+use settings::HostsConfig;
+use zone::reverse;
+
+///
+///Names from files in the /etc/hosts format, an address then its names on each line. E.g.
+///
+///  192.168.1.1   router router.lan
+///  192.168.1.20  printer.lan
+///
+///The first name on a line is the one its address's PTR is to. When files have the same address, the
+///first file's name is. Files are reloaded when they change, and one that can't be read is empty
+///until it can be.
+///
+///Or from DHCP lease files, with each lease's hostname under a local domain until the lease expires
+///
+#[derive(Default)]
+pub struct Hosts {
+    files: Vec<HostsFile>,
+    ///Addresses by lowercase name
+    names: HashMap<String, Vec<Address>>,
+    ///The names an address's PTR can be to, in file order. The first whose lease hasn't ended is answered
+    addrs: HashMap<IpAddr, Vec<(DnsName, Address)>>,
+}
+
+struct HostsFile {
+    path: String,
+    ttl: u32,
+    ///Set for a lease file. Its hostnames are answered under it
+    lease_domain: Option<DnsName>,
+    modified: Option<SystemTime>, //when last read
+    entries: Vec<HostEntry>,
+}
+
+///An address and its names from a line of a file
+struct HostEntry {
+    addr: IpAddr,
+    names: Vec<DnsName>,
+    ///Seconds since the epoch the lease ends at. None for hosts files and leases that don't end
+    expires: Option<u64>,
+}
+
+#[derive(Clone)]
+#[derive(Copy)]
+struct Address {
+    addr: IpAddr,
+    ttl: u32,
+    expires: Option<u64>,
+}
+
+impl Address {
+    ///The TTL to answer with, no longer than the lease has left. None once it's expired
+    fn ttl_at(&self, now: u64) -> Option<u32> {
+        match self.expires {
+            Some(expires) if expires <= now => None,
+            Some(expires) => Some(::std::cmp::min(self.ttl as u64, expires - now) as u32),
+            None => Some(self.ttl),
+        }
+    }
+}
+
+impl Hosts {
+    pub fn new(configs: &[HostsConfig]) -> Hosts {
+        let mut hosts = Hosts::default();
+        for config in configs {
+            for path in &config.files {
+                let mut file = HostsFile {
+                    path: path.clone(),
+                    ttl: config.ttl,
+                    lease_domain: config.lease_domain.as_ref().map(|domain| DnsName::from_string(domain.clone())),
+                    modified: None,
+                    entries: Vec::new(),
+                };
+                file.load();
+                hosts.files.push(file);
+            }
+        }
+        hosts.index();
+        hosts
+    }
+
+    ///Reload files that have changed since they were read
+    pub fn reload_changed(&mut self) {
+        let mut changed = false;
+        for file in self.files.iter_mut().filter(|file| file.changed()) {
+            info!("{:?} changed. Reloading", file.path);
+            file.load();
+            changed = true;
+        }
+        if changed {
+            self.index();
+        }
+    }
+
+    ///
+    ///The answers to a query, if the name is one of ours. A and AAAA queries get the name's addresses
+    ///of that type, and PTR queries the name for an address. Empty if the name has none of the type
+    ///
+    pub fn lookup(&self, qname: &DnsName, qtype: u16) -> Option<Vec<DnsAnswer>> {
+        self.lookup_at(qname, qtype, clock::now())
+    }
+
+    fn lookup_at(&self, qname: &DnsName, qtype: u16, now: u64) -> Option<Vec<DnsAnswer>> {
+        if let Some(addr) = reverse::address_of(qname) {
+            let (name, ttl) = self.addrs
+                                  .get(&addr)?
+                                  .iter()
+                                  .filter_map(|&(ref name, address)| address.ttl_at(now).map(|ttl| (name, ttl)))
+                                  .next()?;
+            return match qtype {
+                RecordType::PTR | RecordType::ANY => {
+                    Some(vec![DnsAnswer::with_rdata(qname.clone(), RecordType::PTR, RecordClass::IN, ttl, name.to_bytes())])
+                }
+                _ => Some(Vec::new()),
+            };
+        }
+        let mut current = Vec::new();
+        for address in self.names.get(&qname.to_lowercase().to_string())? {
+            //an address can be listed again, e.g. by a later lease, so the first current one is answered
+            match address.ttl_at(now) {
+                Some(ttl) if !current.iter().any(|&(addr, _)| addr == address.addr) => current.push((address.addr, ttl)),
+                _ => (),
+            }
+        }
+        //only expired leases, so not one of ours any more
+        if current.is_empty() {
+            return None;
+        }
+        Some(current.into_iter()
+                    .filter_map(|(addr, ttl)| {
+                        match (addr, qtype) {
+                            (IpAddr::V4(ip), RecordType::A) | (IpAddr::V4(ip), RecordType::ANY) => {
+                                Some(DnsAnswer::with_rdata(qname.clone(), RecordType::A, RecordClass::IN, ttl, ip.octets().to_vec()))
+                            }
+                            (IpAddr::V6(ip), RecordType::AAAA) | (IpAddr::V6(ip), RecordType::ANY) => {
+                                Some(DnsAnswer::with_rdata(qname.clone(), RecordType::AAAA, RecordClass::IN, ttl, ip.octets().to_vec()))
+                            }
+                            _ => None,
+                        }
+                    })
+                    .collect())
+    }
+
+    fn index(&mut self) {
+        self.names.clear();
+        self.addrs.clear();
+        for file in &self.files {
+            for entry in &file.entries {
+                let address = Address {
+                    addr: entry.addr,
+                    ttl: file.ttl,
+                    expires: entry.expires,
+                };
+                for name in &entry.names {
+                    self.names.entry(name.to_lowercase().to_string()).or_insert_with(Vec::new).push(address);
+                }
+                self.addrs.entry(entry.addr).or_insert_with(Vec::new).push((entry.names[0].clone(), address));
+            }
+        }
+        debug!("{} names from hosts and lease files", self.names.len());
+    }
+}
+
+impl HostsFile {
+    fn load(&mut self) {
+        self.modified = Self::file_modified(&self.path);
+        let mut text = String::new();
+        match File::open(&self.path).and_then(|mut f| f.read_to_string(&mut text)) {
+            Ok(_) => {
+                self.entries = match self.lease_domain {
+                    Some(ref domain) => parse_leases(&text, domain),
+                    None => parse(&text, &self.path),
+                };
+                info!("{} addresses from {:?}", self.entries.len(), self.path);
+            }
+            Err(e) => {
+                error!("Failed to read {:?}. {:?}", self.path, e);
+                self.entries.clear();
+            }
+        }
+    }
+
+    fn changed(&self) -> bool {
+        Self::file_modified(&self.path) != self.modified
+    }
+
+    fn file_modified(path: &str) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    }
+}
+
+///The address and names on each line. Lines that don't start with an address are logged and skipped
+fn parse(text: &str, path: &str) -> Vec<HostEntry> {
+    let mut entries = Vec::new();
+    for (i, raw_line) in text.lines().enumerate() {
+        let line = raw_line.split('#').next().unwrap_or_default();
+        let mut parts = line.split_whitespace();
+        let addr = match parts.next() {
+            Some(addr) => addr,
+            None => continue,
+        };
+        let names = parts.map(|name| DnsName::from_string(name.to_owned())).collect::<Vec<_>>();
+        match IpAddr::from_str(addr) {
+            Ok(addr) if !names.is_empty() => {
+                entries.push(HostEntry {
+                    addr: addr,
+                    names: names,
+                    expires: None,
+                })
+            }
+            _ => warn!("{}: line {}: expected an address and names, got {:?}", path, i + 1, raw_line),
+        }
+    }
+    entries
+}
+
+///
+///The leases with a hostname in a dnsmasq lease file, each line "expiry mac address hostname client-id",
+///or the "# interface duid iaid hostname expiry assigned length address/length..." lines of an odhcpd
+///state file. Expiry is seconds since the epoch, or 0 for dnsmasq and -1 for odhcpd if the lease doesn't
+///end. Hostnames of * or - are for clients that didn't give one. Other lines are skipped
+///
+fn parse_leases(text: &str, domain: &DnsName) -> Vec<HostEntry> {
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        let (expiry, never, hostname, addrs) = if parts.first() == Some(&"#") && parts.len() >= 9 {
+            (parts[5], "-1", parts[4], &parts[8..])
+        } else if parts.len() >= 4 {
+            (parts[0], "0", parts[3], &parts[2..3])
+        } else {
+            continue;
+        };
+        if hostname == "*" || hostname == "-" {
+            continue;
+        }
+        let expires = match expiry.parse::<i64>() {
+            Ok(_) if expiry == never => None,
+            Ok(expiry) => Some(::std::cmp::max(expiry, 0) as u64),
+            Err(_) => {
+                debug!("Not a lease {:?}", line);
+                continue;
+            }
+        };
+        let name = DnsName::from_string(format!("{}.{}", hostname, domain.to_string()));
+        for addr in addrs {
+            //odhcpd gives the prefix length
+            match IpAddr::from_str(addr.split('/').next().unwrap_or_default()) {
+                Ok(addr) => {
+                    entries.push(HostEntry {
+                        addr: addr,
+                        names: vec![name.clone()],
+                        expires: expires,
+                    })
+                }
+                Err(_) => debug!("Not a lease address {:?} in {:?}", addr, line),
+            }
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::Hosts;
+    use clock;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::net::IpAddr;
+    use std::thread;
+    use std::time::Duration;
+    use dns::message::DnsName;
+    use dns::record::RecordType;
+    use settings::HostsConfig;
+
+    const HOSTS: &'static str = "# the LAN\n127.0.0.1 localhost\n::1 localhost ip6-localhost\n\
+                                 192.168.1.1\trouter Router.lan # the gateway\n192.168.1.20 printer.lan\n\
+                                 fe80::1%lo0 bad\nnot-an-address x\n192.168.1.30\n";
+
+    fn name(s: &str) -> DnsName {
+        DnsName::from_string(s.to_owned())
+    }
+
+    fn write(file_name: &str, text: &str) -> String {
+        let path = env::temp_dir().join(file_name);
+        File::create(&path).and_then(|mut f| f.write_all(text.as_bytes())).unwrap();
+        path.display().to_string()
+    }
+
+    fn hosts(files: Vec<String>, ttl: u32) -> Hosts {
+        Hosts::new(&[HostsConfig {
+                         files: files,
+                         ttl: ttl,
+                         lease_domain: None,
+                     }])
+    }
+
+    fn leases(text: &str, ttl: u32) -> Hosts {
+        Hosts::new(&[HostsConfig {
+                         files: vec![write("koala_leases_test", text)],
+                         ttl: ttl,
+                         lease_domain: Some("lan".to_owned()),
+                     }])
+    }
+
+    ///The type and rdata as text of each answer
+    fn answers(hosts: &Hosts, qname: &str, qtype: u16) -> Option<Vec<(u16, String)>> {
+        answers_at(hosts, qname, qtype, clock::now())
+    }
+
+    fn answers_at(hosts: &Hosts, qname: &str, qtype: u16, now: u64) -> Option<Vec<(u16, String)>> {
+        hosts.lookup_at(&name(qname), qtype, now).map(|answers| {
+            answers.iter()
+                   .map(|rr| {
+                       let text = match rr.rdata.len() {
+                           4 => IpAddr::from([rr.rdata[0], rr.rdata[1], rr.rdata[2], rr.rdata[3]]).to_string(),
+                           16 => {
+                               let mut octets = [0u8; 16];
+                               octets.copy_from_slice(&rr.rdata);
+                               IpAddr::from(octets).to_string()
+                           }
+                           _ => DnsName::from_bytes(&rr.rdata).to_string(),
+                       };
+                       (rr.atype, text)
+                   })
+                   .collect()
+        })
+    }
+
+    #[test]
+    fn answers_from_hosts_file() {
+        let hosts = hosts(vec![write("koala_hosts_test", HOSTS)], 120);
+        assert_eq!(Some(vec![(RecordType::A, "192.168.1.1".to_owned())]), answers(&hosts, "ROUTER.lan", RecordType::A));
+        assert_eq!(Some(vec![(RecordType::AAAA, "::1".to_owned())]), answers(&hosts, "localhost", RecordType::AAAA));
+        assert_eq!(Some(vec![(RecordType::A, "127.0.0.1".to_owned()), (RecordType::AAAA, "::1".to_owned())]),
+                   answers(&hosts, "localhost", RecordType::ANY));
+        //the name, without the type
+        assert_eq!(Some(Vec::new()), answers(&hosts, "printer.lan", RecordType::AAAA));
+        assert_eq!(Some(Vec::new()), answers(&hosts, "printer.lan", RecordType::MX));
+        assert_eq!(None, answers(&hosts, "bad", RecordType::A));
+        assert_eq!(None, answers(&hosts, "x", RecordType::A));
+        assert_eq!(None, answers(&hosts, "lan", RecordType::A));
+        assert_eq!(120, hosts.lookup(&name("router"), RecordType::A).unwrap()[0].ttl);
+    }
+
+    #[test]
+    fn reverse() {
+        let hosts = hosts(vec![write("koala_hosts_reverse_test", HOSTS)], 60);
+        //the first name on the line
+        assert_eq!(Some(vec![(RecordType::PTR, "router".to_owned())]), answers(&hosts, "1.1.168.192.in-addr.arpa", RecordType::PTR));
+        assert_eq!(Some(vec![(RecordType::PTR, "localhost".to_owned())]),
+                   answers(&hosts, "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa", RecordType::PTR));
+        assert_eq!(Some(Vec::new()), answers(&hosts, "20.1.168.192.in-addr.arpa", RecordType::A));
+        assert_eq!(None, answers(&hosts, "30.1.168.192.in-addr.arpa", RecordType::PTR));
+    }
+
+    #[test]
+    fn several_files() {
+        let first = write("koala_hosts_first", "192.168.1.1 router\n");
+        let second = write("koala_hosts_second", "192.168.1.1 gateway\n192.168.1.2 router\n");
+        let hosts = hosts(vec![first, second, "/nonexistent/hosts".to_owned()], 60);
+        assert_eq!(Some(vec![(RecordType::A, "192.168.1.1".to_owned()), (RecordType::A, "192.168.1.2".to_owned())]),
+                   answers(&hosts, "router", RecordType::A));
+        assert_eq!(Some(vec![(RecordType::PTR, "router".to_owned())]), answers(&hosts, "1.1.168.192.in-addr.arpa", RecordType::PTR));
+        assert_eq!(Some(vec![(RecordType::A, "192.168.1.1".to_owned())]), answers(&hosts, "gateway", RecordType::A));
+    }
+
+    #[test]
+    fn reloads_changed() {
+        let path = write("koala_hosts_reload_test", "192.168.1.1 router\n");
+        let mut hosts = hosts(vec![path.clone()], 60);
+        hosts.reload_changed();
+        assert!(answers(&hosts, "router", RecordType::A).is_some());
+        //so the modified time differs on file systems with coarse times
+        thread::sleep(Duration::from_millis(1100));
+        write("koala_hosts_reload_test", "192.168.1.5 nas\n");
+        hosts.reload_changed();
+        assert_eq!(None, answers(&hosts, "router", RecordType::A));
+        assert_eq!(Some(vec![(RecordType::A, "192.168.1.5".to_owned())]), answers(&hosts, "nas", RecordType::A));
+    }
+
+    #[test]
+    fn lease_files() {
+        let text = "1700000600 aa:bb:cc:dd:ee:01 192.168.1.20 laptop 01:aa:bb:cc:dd:ee:01\n\
+                    0 aa:bb:cc:dd:ee:02 192.168.1.21 tv *\n\
+                    1700000600 aa:bb:cc:dd:ee:03 192.168.1.22 * *\n\
+                    duid 00:01:00:01:2a:bb:cc:dd:ee:ff:00:11\n\
+                    1700000600 5612 fd00::20 laptop 00:01:00:01:aa:bb\n\
+                    # br-lan 000100012abbccdd 5613 tablet 1700000900 3 128 fd00::30/128 fd00::31/128\n\
+                    # br-lan 01aabbccddee04 ipv4 phone -1 4 32 192.168.1.23/32\n\
+                    # br-lan 01aabbccddee05 ipv4 - 1700000900 5 32 192.168.1.24/32\n";
+        let hosts = leases(text, 120);
+        let now = 1700000000;
+        assert_eq!(Some(vec![(RecordType::A, "192.168.1.20".to_owned()), (RecordType::AAAA, "fd00::20".to_owned())]),
+                   answers_at(&hosts, "laptop.LAN", RecordType::ANY, now));
+        assert_eq!(Some(vec![(RecordType::PTR, "laptop.lan".to_owned())]), answers_at(&hosts, "20.1.168.192.in-addr.arpa", RecordType::PTR, now));
+        assert_eq!(Some(vec![(RecordType::AAAA, "fd00::30".to_owned()), (RecordType::AAAA, "fd00::31".to_owned())]),
+                   answers_at(&hosts, "tablet.lan", RecordType::AAAA, now));
+        assert_eq!(Some(vec![(RecordType::A, "192.168.1.23".to_owned())]), answers_at(&hosts, "phone.lan", RecordType::A, now));
+        //only under the domain, and not without a hostname
+        assert_eq!(None, answers_at(&hosts, "laptop", RecordType::A, now));
+        assert_eq!(None, answers_at(&hosts, "22.1.168.192.in-addr.arpa", RecordType::PTR, now));
+        assert_eq!(None, answers_at(&hosts, "24.1.168.192.in-addr.arpa", RecordType::PTR, now));
+
+        //the TTL doesn't outlast the lease, and expired leases aren't answered
+        assert_eq!(120, hosts.lookup_at(&name("laptop.lan"), RecordType::A, now).unwrap()[0].ttl);
+        assert_eq!(100, hosts.lookup_at(&name("laptop.lan"), RecordType::A, now + 500).unwrap()[0].ttl);
+        assert_eq!(None, answers_at(&hosts, "laptop.lan", RecordType::A, now + 600));
+        assert_eq!(None, answers_at(&hosts, "20.1.168.192.in-addr.arpa", RecordType::PTR, now + 600));
+        assert!(answers_at(&hosts, "tablet.lan", RecordType::AAAA, now + 600).is_some());
+        //never expire
+        assert!(answers_at(&hosts, "tv.lan", RecordType::A, u32::max_value() as u64).is_some());
+        assert!(answers_at(&hosts, "phone.lan", RecordType::A, u32::max_value() as u64).is_some());
+    }
+
+    #[test]
+    fn address_leased_again() {
+        //the laptop's lease ended, and the address went to the phone
+        let text = "1700000100 aa:bb:cc:dd:ee:01 192.168.1.20 laptop *
+                    1700000600 aa:bb:cc:dd:ee:02 192.168.1.20 phone *
+                    1700000100 aa:bb:cc:dd:ee:03 192.168.1.21 tablet *
+                    1700000600 aa:bb:cc:dd:ee:03 192.168.1.21 tablet *
+";
+        let hosts = leases(text, 120);
+        let ptr = |now: u64| answers_at(&hosts, "20.1.168.192.in-addr.arpa", RecordType::PTR, now);
+        assert_eq!(Some(vec![(RecordType::PTR, "laptop.lan".to_owned())]), ptr(1700000000));
+        assert_eq!(Some(vec![(RecordType::PTR, "phone.lan".to_owned())]), ptr(1700000200));
+        assert_eq!(None, ptr(1700000600));
+        //renewed, so answered once while either lease is current
+        assert_eq!(Some(vec![(RecordType::A, "192.168.1.21".to_owned())]), answers_at(&hosts, "tablet.lan", RecordType::A, 1700000000));
+        assert_eq!(Some(vec![(RecordType::A, "192.168.1.21".to_owned())]), answers_at(&hosts, "tablet.lan", RecordType::A, 1700000200));
+    }
+}
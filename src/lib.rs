@@ -1,8 +1,5 @@
 //to support both a lib and bin build
 #![feature(test)]
-#![feature(associated_consts)]
-#![feature(plugin)]
-//#![plugin(clippy)]
 #[allow(unknown_lints)] //until can build clippy with nightly on travis
 extern crate getopts;
 extern crate mio;
@@ -11,6 +8,7 @@ extern crate log;
 extern crate env_logger;
 extern crate test;
 extern crate time;
+extern crate ring;
 
 mod command_line;
 pub mod server;
@@ -20,3 +18,10 @@ mod request;
 mod buf;
 pub mod servers;
 mod cache;
+pub mod cidr;
+pub mod settings;
+mod zone;
+mod view;
+mod hosts;
+mod admin;
+mod clock;
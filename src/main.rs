@@ -1,7 +1,4 @@
 #![feature(test)]
-#![feature(associated_consts)]
-#![feature(plugin)]
-// #![plugin(clippy)]
 #[allow(unknown_lints)] //until can build clippy with nightly on travis
 extern crate getopts;
 extern crate mio;
@@ -10,6 +7,7 @@ extern crate log;
 extern crate env_logger;
 extern crate test;
 extern crate time;
+extern crate ring;
 
 mod command_line;
 mod server;
@@ -19,6 +17,13 @@ mod request;
 mod buf;
 mod servers;
 mod cache;
+mod cidr;
+mod settings;
+mod zone;
+mod view;
+mod hosts;
+mod admin;
+mod clock;
 
 use server::ServerOps;
 use std::env;
@@ -42,5 +47,6 @@ fn main() {
 
     let config = command_line::parse_args();
     let mut server = server::Server::new(config.port, config.server, config.timeout, config.master_file);
+    server.settings = config.settings;
     server.start();
 }
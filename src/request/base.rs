@@ -3,19 +3,22 @@ use std::io::Error;
 use mio::{Token, Timeout, Handler, EventSet, Evented, PollOpt};
 use server_mio::RequestCtx;
 use dns::message::*;
+use dns::record::Rcode;
 
 pub struct RawRequest {
     pub token: Token,
     pub bytes: Vec<u8>,
-    pub query: Option<DnsMessage>
+    pub query: Option<DnsMessage>,
+    pub client: SocketAddr
 }
 
 impl RawRequest {
-    pub fn new(token: Token, bytes: Vec<u8>) -> RawRequest {
+    pub fn new(token: Token, bytes: Vec<u8>, client: SocketAddr) -> RawRequest {
         RawRequest {
             token: token,
             bytes: bytes,
-            query: None
+            query: None,
+            client: client
         }
     }
 }
@@ -151,6 +154,15 @@ impl ForwardedRequestBase {
         }        
     }
 
+    ///Change the events for a socket already registered. Registering it again fails with epoll
+    pub fn reregister_upstream(&mut self, ctx: &mut RequestCtx, events: EventSet, sock: &dyn Evented) {
+        let poll_opt = PollOpt::edge() | PollOpt::oneshot();
+        match ctx.event_loop.reregister(sock, ctx.token, events, poll_opt) {
+            Ok(_) => debug!("Re-registered upstream {:?} {:?}", ctx.token, events),
+            Err(e) => error!("Failed to re-register upstream socket. {}", e)
+        }
+    }
+
     pub fn buffer_response(&mut self, buf: &[u8], count: usize) -> Response  {
         let mut bytes = Vec::with_capacity(count);
         bytes.extend_from_slice(&buf);
@@ -164,7 +176,7 @@ impl ForwardedRequestBase {
         self.set_state(ForwardedRequestState::Error);
         debug!("{}", err_msg);
         let req = DnsMessage::parse(&self.query_buf);
-        let header = DnsHeader::new_error(req.header, 4);
+        let header = DnsHeader::new_error(req.header, Rcode::SERVFAIL);
         let msg = DnsMessage::new_error(header);
         let bytes = msg.to_bytes();
         Response::with_source(self.token, bytes, msg, Source::System)
@@ -188,6 +200,15 @@ impl ForwardedRequestBase {
        None
     }
     
+    ///A multi-message response, such as a zone transfer, joined into one message. It can be
+    ///bigger than a message can be, so the bytes are the messages as received, each with its
+    ///length prefix, for checking their signatures rather than sending on
+    pub fn on_receive_msg(&mut self, ctx: &mut RequestCtx, msg: DnsMessage, bytes: Vec<u8>) -> Response {
+        self.clear_timeout(ctx);
+        self.set_state(ForwardedRequestState::ResponseReceived);
+        Response::with_source(self.token, bytes, msg, Source::Upstream)
+    }
+
     pub fn on_receive_err(&mut self, ctx: &mut RequestCtx, e: Error) -> Response {
         self.clear_timeout(ctx);
         self.error_with(format!("Receive failed on {:?}. {:?}", ctx.token, e))        
@@ -201,7 +222,7 @@ impl ForwardedRequestBase {
     pub fn on_forward(&mut self, ctx: &mut RequestCtx, count: usize, sock: &Evented) -> Option<Response> {
         debug!("Sent {:?} bytes", count);
         self.set_state(ForwardedRequestState::Forwarded);
-        self.register_upstream(ctx, EventSet::readable(), sock);
+        self.reregister_upstream(ctx, EventSet::readable(), sock);
         // TODO: No, don't just timeout forwarded requests, time out the whole request,
         // be it cached, authorative or forwarded
         self.set_timeout(ctx);
@@ -1,15 +1,19 @@
-
 use mio::tcp::{TcpStream};
-use std::io::{Read, Write};
+use mio::{EventSet, TryRead};
+use std::io::Write;
 use request::base::*;
 use server_mio::RequestCtx;
+use dns::message::*;
+use zone::transfer;
 
 pub struct TcpRequestFactory;
 impl RequestFactory for TcpRequestFactory {
-    
+
     fn new_with(&self, request: ForwardedRequestBase) -> Box<ForwardedRequest> {
         let req = TcpRequest {
             upstream_socket: None,
+            received: Vec::new(),
+            messages: Vec::new(),
             base: request,
         };
         Box::new(req)
@@ -18,6 +22,8 @@ impl RequestFactory for TcpRequestFactory {
 
 pub struct TcpRequest {
     upstream_socket: Option<TcpStream>,
+    received: Vec<u8>, //bytes not yet making up a whole message
+    messages: Vec<Vec<u8>>, //whole messages received, without the length prefix
     base: ForwardedRequestBase,
 }
 
@@ -43,23 +49,26 @@ impl ForwardedRequest for TcpRequest {
         }
     }
 
+    ///
+    ///A response can take more than one read, and a zone transfer is many messages, so bytes are
+    ///buffered until the response is whole. Until then we wait for the socket to be readable again
+    ///
     fn receive(&mut self, ctx: &mut RequestCtx) -> Option<Response> {
-        const PREFIX_LEN: usize = 2;
-        debug_assert!(ctx.events.is_readable());        
-        let mut buf = [0; 4096];
-        if let Some(ref mut sock) = self.upstream_socket {
-            return match sock.read(&mut buf) {
-                Ok(count) => {
-                    //store the response without the prefix
-                    debug!("Received {} bytes", count);
-                    if count < PREFIX_LEN {
-                        warn!("tcp: Only received length prefix. No content");
-                        return None;
-                    }
-                    self.base.on_receive(ctx, count - PREFIX_LEN , &buf[PREFIX_LEN..count])
-                 },
-                Err(e) => Some(self.base.on_receive_err(ctx, e))
-            }
+        debug_assert!(ctx.events.is_readable());
+        let closed = match self.read_available() {
+            Ok(closed) => closed,
+            Err(e) => return Some(self.base.on_receive_err(ctx, e)),
+        };
+        self.split_messages();
+        if let Some(response) = self.response(ctx) {
+            return Some(response);
+        }
+        if closed {
+            self.base.clear_timeout(ctx);
+            return Some(self.base.error_with(format!("Upstream closed {:?} before the response was complete", ctx.token)));
+        }
+        if let Some(ref sock) = self.upstream_socket {
+            self.base.reregister_upstream(ctx, EventSet::readable(), sock);
         }
         None
     }
@@ -67,11 +76,12 @@ impl ForwardedRequest for TcpRequest {
     fn forward(&mut self, ctx: &mut RequestCtx) -> Option<Response> {
         debug_assert!(ctx.events.is_writable());
         if let Some(ref mut sock) = self.upstream_socket {
-            // prefix with length
-            Self::prefix_with_length(&mut self.base.query_buf);
-            let len = self.base.query_buf.len() as usize;
+            // prefix with length. query_buf is left as is for error responses
+            let mut prefixed = self.base.query_buf.clone();
+            Self::prefix_with_length(&mut prefixed);
+            let len = prefixed.len();
             debug!("{:?} bytes to send (inc 2b prefix)", len);
-            return match sock.write_all(&self.base.query_buf.as_slice()) {
+            return match sock.write_all(&prefixed.as_slice()) {
                 Ok(_) => self.base.on_forward(ctx, len, sock),
                 Err(e) => Some(self.base.on_forward_err(ctx, e))
             }
@@ -81,7 +91,7 @@ impl ForwardedRequest for TcpRequest {
 }
 
 impl TcpRequest {
-    
+
     //TODO: duplicated
     fn prefix_with_length(buf: &mut Vec<u8>) {
         //TCP responses are prefixed with a 2-byte length
@@ -90,4 +100,61 @@ impl TcpRequest {
         buf.insert(0, len.swap_bytes() as u8);
         debug!("Added 2b prefix of len: {:?}", len);
     }
+
+    ///Read until the socket would block. True if upstream closed the connection
+    fn read_available(&mut self) -> Result<bool, ::std::io::Error> {
+        let mut buf = [0; 4096];
+        if let Some(ref mut sock) = self.upstream_socket {
+            loop {
+                match sock.try_read(&mut buf)? {
+                    Some(0) => return Ok(true),
+                    Some(count) => {
+                        debug!("Received {} bytes", count);
+                        self.received.extend_from_slice(&buf[..count]);
+                    }
+                    None => return Ok(false),
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn split_messages(&mut self) {
+        const PREFIX_LEN: usize = 2;
+        while self.received.len() >= PREFIX_LEN {
+            let len = ((self.received[0] as usize) << 8) + self.received[1] as usize;
+            if self.received.len() < PREFIX_LEN + len {
+                debug!("Have {} of {} bytes of the next message", self.received.len() - PREFIX_LEN, len);
+                return;
+            }
+            let rest = self.received.split_off(PREFIX_LEN + len);
+            let msg = self.received.split_off(PREFIX_LEN);
+            self.received = rest;
+            self.messages.push(msg);
+        }
+    }
+
+    ///The response, if all of it has been received
+    fn response(&mut self, ctx: &mut RequestCtx) -> Option<Response> {
+        let query = DnsMessage::parse(&self.base.query_buf);
+        let is_transfer = query.first_question().map_or(false, |q| transfer::is_transfer(q.qtype));
+        if !is_transfer {
+            return match self.messages.first() {
+                Some(bytes) => self.base.on_receive(ctx, bytes.len(), bytes),
+                None => None,
+            };
+        }
+        let messages = self.messages.iter().map(|bytes| DnsMessage::parse(bytes)).collect::<Vec<_>>();
+        if !transfer::is_complete(&query, &messages) {
+            debug!("{} messages of the transfer so far", messages.len());
+            return None;
+        }
+        let mut bytes = Vec::new();
+        for msg in &self.messages {
+            let mut prefixed = msg.clone();
+            Self::prefix_with_length(&mut prefixed);
+            bytes.extend(prefixed);
+        }
+        transfer::join(messages).map(|msg| self.base.on_receive_msg(ctx, msg, bytes))
+    }
 }
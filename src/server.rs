@@ -1,7 +1,9 @@
 use std::thread::JoinHandle;
 use std::net::SocketAddr;
+use std::sync::mpsc;
 use server_mio::MioServer;
 use mio::Sender;
+use settings::Settings;
 
 ///
 /// Rust DNS server.
@@ -16,7 +18,18 @@ pub struct Server {
     pub upstream_server: SocketAddr,
     pub timeout: u64,
     pub master_file: String,
-    pub sender: Option<Sender<String>>,
+    pub settings: Settings,
+    pub sender: Option<Sender<Control>>,
+}
+
+///What can be sent to the server's event loop
+#[derive(Debug)]
+pub enum Control {
+    ///Only tests stop the server, so it's never sent in the binary
+    #[allow(dead_code)]
+    Stop,
+    ///An admin command, and where to send what it output
+    Admin(String, mpsc::Sender<String>),
 }
 
 pub trait ServerOps {
@@ -33,6 +46,7 @@ impl ServerOps for Server {
             upstream_server: upstream_server,
             timeout: timeout,
             master_file: master_file,
+            settings: Settings::default(),
             sender: None,
         }        
     }
@@ -51,7 +65,11 @@ impl ServerOps for Server {
               self.upstream_server);
         let address_str = format!("0.0.0.0:{:?}", self.port);
         let address = address_str.parse().unwrap_or_else(|e| panic!("Couldn't parse address {:?} {:?}", address_str, e));
-        let (tx, run_handle) = MioServer::start(address, self.upstream_server, self.timeout);
+        let (tx, run_handle) = MioServer::start(address,
+                                                self.upstream_server,
+                                                self.timeout,
+                                                self.master_file.clone(),
+                                                self.settings.clone());
         self.sender = Some(tx);
         info!("Joining on run handle");
         run_handle
@@ -59,7 +77,7 @@ impl ServerOps for Server {
 
     fn stop(&mut self) {
         match self.sender {
-            Some(ref x) => x.send(format!("{:?}", "Stop!")).unwrap(),
+            Some(ref x) => x.send(Control::Stop).unwrap(),
             None => warn!("Sender is null. Have you called start?"),
         };
     }
@@ -7,39 +7,80 @@ use std::thread;
 use std::thread::JoinHandle;
 use std::sync::{Arc, RwLock};
 use mio::Sender;
+use time::{Duration, SteadyTime};
+use log::LogLevel;
 use request::base::{RequestParams};
 use cache::*;
+use settings::Settings;
+use server::Control;
+use admin;
+use dns::tsig::Keyring;
+use dns::anchors::TrustAnchors;
+use zone::ZoneStore;
+use zone::secondary::Secondaries;
+use zone::notify::Notifier;
 use servers::udp::UdpServer;
 use servers::tcp::TcpServer;
+use view::{View, Views};
+use hosts::Hosts;
 
 pub struct MioServer {
     udp_server: UdpServer,
     tcp_server: TcpServer,
-    cache: SharedCache
+    notifier: Notifier,
+    cache: SharedCache,
+    zones: SharedZones,
+    secondaries: SharedSecondaries,
+    anchors: SharedAnchors,
+    views: SharedViews,
+    hosts: SharedHosts,
+    snapshot_interval: Duration,
+    last_snapshot: SteadyTime
 }
 
 impl Handler for MioServer {
     type Timeout = Token;
-    type Message = String;
+    type Message = Control;
 
     fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
         //TODO: Expensive to clone the cache ref for every request? Can be stored on ServerBase. It's on
         //RequestCtx for convenience so pipeline stages can just take the ctx as a parm and have everything
-        let mut ctx = RequestCtx::new(event_loop, events, token, self.cache.clone());
+        let mut ctx = self.ctx(event_loop, events, token);
         debug!("MioServer.ready() {:?}", ctx.events);
         match token {
             UdpServer::UDP_SERVER_TOKEN => self.udp_server.server_ready(&mut ctx),
             TcpServer::TCP_SERVER_TOKEN => self.tcp_server.server_ready(&mut ctx),
             udp_tok if self.udp_server.owns(udp_tok) => self.udp_server.request_ready(&mut ctx),
             tcp_tok if self.tcp_server.owns(tcp_tok) => self.tcp_server.request_ready(&mut ctx),
+            sec_tok if self.secondaries_own(sec_tok) => self.secondaries.write().unwrap().ready(&mut ctx),
+            not_tok if self.notifier.owns(not_tok) => self.notifier.ready(&mut ctx),
+            anchor_tok if self.anchors_own(anchor_tok) => self.anchors.write().unwrap().ready(&mut ctx),
             unknown_tok => error!("Unknown token {:?}", unknown_tok)
         }
+        self.after_ready(&mut ctx);
     }
 
     #[allow(unused_variables)]
     fn timeout(&mut self, event_loop: &mut EventLoop<Self>, token: Self::Timeout) {
+        if token == MioServer::MAINTENANCE_TOKEN {
+            self.maintenance(event_loop);
+            return;
+        }
         info!("Got timeout: {:?}", token);
-        let mut ctx = RequestCtx::new(event_loop, EventSet::none(), token, self.cache.clone());
+        let mut ctx = self.ctx(event_loop, EventSet::none(), token);
+        if self.secondaries_own(token) {
+            self.secondaries.write().unwrap().timeout(&mut ctx);
+            self.after_ready(&mut ctx);
+            return;
+        }
+        if self.notifier.owns(token) {
+            self.notifier.timeout(&mut ctx);
+            return;
+        }
+        if self.anchors_own(token) {
+            self.anchors.write().unwrap().timeout(&mut ctx);
+            return;
+        }
         match token {
             udp_tok if self.udp_server.base.owns(udp_tok) => self.udp_server.base.timeout(&mut ctx),
             tcp_tok if self.tcp_server.base.owns(tcp_tok) => self.tcp_server.base.timeout(&mut ctx),
@@ -48,47 +89,164 @@ impl Handler for MioServer {
         self.ready(ctx.event_loop, token, EventSet::none());
     }
 
-    fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: String) {
-        //For tests. Could implement SIG handling
-        info!("Got a message {}", msg);
-        if msg == "Stop!" {
-            event_loop.shutdown()
+    fn notify(&mut self, event_loop: &mut EventLoop<Self>, msg: Control) {
+        //Stop is for tests. Could implement SIG handling
+        match msg {
+            Control::Stop => {
+                info!("Told to stop");
+                event_loop.shutdown()
+            }
+            Control::Admin(command, output) => {
+                info!("Admin command {:?}", command);
+                //listing or counting a Redis cache scans it, so it's run aside rather than holding up queries
+                let views = self.views.clone();
+                let _ = thread::Builder::new()
+                    .name("dns_srv_admin_command".to_string())
+                    .spawn(move || output.send(admin::run(&command, &views)));
+            }
         }
     }
 }
 
-pub type SharedCache = Arc<RwLock<Cache>>;
+///The backend locks what it needs to itself
+pub type SharedCache = Arc<dyn CacheBackend>;
+pub type SharedZones = Arc<RwLock<ZoneStore>>;
+pub type SharedSecondaries = Arc<RwLock<Secondaries>>;
+pub type SharedAnchors = Arc<RwLock<TrustAnchors>>;
+///Views don't change once the server's started, only what's in their zones and caches
+pub type SharedViews = Arc<Views>;
+pub type SharedHosts = Arc<RwLock<Hosts>>;
 pub struct RequestCtx<'a> {
     pub event_loop: &'a mut EventLoop<MioServer>,
     pub events: EventSet,
     pub token: Token,
-    pub cache: SharedCache
+    ///The default view's cache and zones. Secondaries and the NOTIFYs for changed zones are only for these
+    pub cache: SharedCache,
+    pub zones: SharedZones,
+    pub secondaries: SharedSecondaries,
+    pub anchors: SharedAnchors,
+    pub views: SharedViews,
+    pub hosts: SharedHosts,
+    ///The client a request is from, if the ctx is for one. It picks the view that answers it
+    pub client: Option<SocketAddr>
 }
 
 impl<'a> RequestCtx<'a> {
     pub fn new(event_loop: &mut EventLoop<MioServer>,
             events: EventSet,
             token: Token,
-            cache: SharedCache)
+            cache: SharedCache,
+            zones: SharedZones,
+            secondaries: SharedSecondaries,
+            anchors: SharedAnchors,
+            views: SharedViews,
+            hosts: SharedHosts)
             -> RequestCtx {
         RequestCtx {
             event_loop: event_loop,
             events: events,
             token: token,
-            cache: cache
+            cache: cache,
+            zones: zones,
+            secondaries: secondaries,
+            anchors: anchors,
+            views: views,
+            hosts: hosts,
+            client: None
         }
     }
+
+    ///The view the client is in. The default view if it's in none, or there's no client
+    pub fn view(&self) -> &View {
+        match self.client {
+            Some(client) => self.views.find(&client.ip()),
+            None => self.views.default_view(),
+        }
+    }
+
+    pub fn in_default_view(&self) -> bool {
+        self.client.map_or(true, |client| self.views.in_default(&client.ip()))
+    }
 }
 
 impl MioServer {
+    ///Timer for periodic work that isn't tied to a request, like reloading changed zone files
+    ///and refreshing secondary zones
+    pub const MAINTENANCE_TOKEN: Token = Token(2);
+    const MAINTENANCE_INTERVAL_MS: u64 = 1000;
+
+    fn maintenance(&mut self, event_loop: &mut EventLoop<MioServer>) {
+        match self.zones.write() {
+            Ok(mut zones) => {
+                zones.reload_changed();
+                zones.compact_journals();
+                zones.refresh_signatures();
+            }
+            Err(e) => error!("Zone store lock poisoned. Error was {}", e),
+        }
+        self.views.maintain();
+        //counting a Redis cache means scanning it, so only when it's logged
+        if log_enabled!(LogLevel::Debug) {
+            for (name, stats) in self.views.cache_stats() {
+                debug!("Cache of view {}: {:?}", name, stats);
+            }
+        }
+        if SteadyTime::now() - self.last_snapshot >= self.snapshot_interval {
+            self.views.save_snapshots();
+            self.last_snapshot = SteadyTime::now();
+        }
+        match self.hosts.write() {
+            Ok(mut hosts) => hosts.reload_changed(),
+            Err(e) => error!("Hosts lock poisoned. Error was {}", e),
+        }
+        {
+            let mut ctx = self.ctx(event_loop, EventSet::none(), MioServer::MAINTENANCE_TOKEN);
+            self.secondaries.write().unwrap().tick(&mut ctx);
+            self.notifier.send_changes(&mut ctx);
+            self.anchors.write().unwrap().tick(&mut ctx);
+        }
+        MioServer::schedule_maintenance(event_loop);
+    }
+
+    fn ctx<'a>(&self, event_loop: &'a mut EventLoop<MioServer>, events: EventSet, token: Token) -> RequestCtx<'a> {
+        RequestCtx::new(event_loop, events, token, self.cache.clone(), self.zones.clone(), self.secondaries.clone(), self.anchors.clone(), self.views.clone(), self.hosts.clone())
+    }
+
+    fn secondaries_own(&self, token: Token) -> bool {
+        self.secondaries.read().unwrap().owns(token)
+    }
+
+    fn anchors_own(&self, token: Token) -> bool {
+        self.anchors.read().unwrap().owns(token)
+    }
+
+    ///Act on what a request may have changed. A NOTIFY received, or a zone with a new serial
+    fn after_ready(&mut self, ctx: &mut RequestCtx) {
+        if self.secondaries.read().unwrap().notified() {
+            self.secondaries.write().unwrap().tick(ctx);
+        }
+        if self.zones.read().unwrap().has_changed() {
+            self.notifier.send_changes(ctx);
+        }
+    }
+
+    fn schedule_maintenance(event_loop: &mut EventLoop<MioServer>) {
+        let _ = event_loop.timeout_ms(MioServer::MAINTENANCE_TOKEN, MioServer::MAINTENANCE_INTERVAL_MS)
+                          .map_err(|e| error!("Failed to schedule maintenance {:?}", e));
+    }
 
     pub fn start(address: SocketAddr,
                  upstream_server: SocketAddr,
-                 timeout: u64)
-                 -> (Sender<String>, JoinHandle<()>) {
+                 timeout: u64,
+                 master_file: String,
+                 settings: Settings)
+                 -> (Sender<Control>, JoinHandle<()>) {
 
         let mut event_loop = EventLoop::<MioServer>::new().unwrap();        
         let sender = event_loop.channel();
+        if let Some(addr) = settings.admin {
+            admin::listen(addr, sender.clone());
+        }
         let run_handle = thread::Builder::new()
             .name("dns_srv_net_io".to_string())
             .spawn(move || {
@@ -100,8 +258,8 @@ impl MioServer {
                     upstream_addr: upstream_server,
                 };
 
-                let udp_server = UdpServer::new(address, max_connections, params);
-                let tcp_server = TcpServer::new(address, max_connections, params);
+                let udp_server = UdpServer::new(address, max_connections);
+                let tcp_server = TcpServer::new(address, max_connections);
 
                 //TODO: event loop per core.
 
@@ -115,15 +273,30 @@ impl MioServer {
                                             EventSet::readable(),
                                             PollOpt::edge() | PollOpt::oneshot());
 
-                let cache = Cache::default();
+                let cache = backend(&settings.cache, "default");
+                let mut zones = ZoneStore::load(&settings.zones, &master_file);
+                zones.keys = Keyring::new(settings.keys.clone());
+                let zones = Arc::new(RwLock::new(zones));
+                let views = settings.views.iter().map(|config| View::new(config, &settings.keys, &settings.cache, params)).collect();
+                let default_view = View::default_for(zones.clone(), cache.clone(), params);
                 let mut mio_server = MioServer {
                     udp_server: udp_server,
                     tcp_server: tcp_server,
-                    cache: Arc::new(RwLock::new(cache))
+                    notifier: Notifier::default(),
+                    cache: cache,
+                    zones: zones,
+                    secondaries: Arc::new(RwLock::new(Secondaries::new(&settings.zones))),
+                    anchors: Arc::new(RwLock::new(TrustAnchors::new(&settings.trust_anchors, &settings.negative_trust_anchors, params))),
+                    views: Arc::new(Views::new(views, default_view)),
+                    hosts: Arc::new(RwLock::new(Hosts::new(&settings.hosts))),
+                    snapshot_interval: Duration::seconds(settings.cache.snapshot_interval as i64),
+                    last_snapshot: SteadyTime::now()
                 };
+                MioServer::schedule_maintenance(&mut event_loop);
                 info!("Start server...");
                 let result = event_loop.run(&mut mio_server);
                 info!("{:?}", result);
+                mio_server.views.save_snapshots();
                 drop(mio_server.udp_server);
                 drop(mio_server.tcp_server);
             })
@@ -1,17 +1,39 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use mio::{EventLoop, EventSet, Token, PollOpt, Evented};
-use server_mio::{MioServer,RequestCtx};
+use server_mio::{MioServer,RequestCtx,SharedCache};
 use request::base::*;
 use cache::*;
 use dns::message::*;
+use dns::record::{RecordClass, Rcode};
+use clock;
+use dns::validate::{self, Security, Step, Validation};
 use servers::pipeline::*;
+use view::View;
+
+///A client's answer from upstream, waiting on the DNSKEY and DS records to validate it
+struct Pending {
+    query: DnsMessage,
+    msg: DnsMessage,
+    validation: Validation,
+}
 
 
 pub struct ServerBase {
     pub request_factory: Box<RequestFactory>,
     pub forwarded: HashMap<Token, Box<ForwardedRequest>>,
     pub responses: Vec<Response>,
-    pub params: RequestParams,
+    ///Client queries forwarded for validation, by client token
+    to_validate: HashMap<Token, DnsMessage>,
+    pending: HashMap<Token, Pending>,
+    ///Forwarded DNSKEY and DS queries, with the client token they're for
+    fetches: HashMap<Token, (Token, DnsName, u16)>,
+    ///Clients of the queries forwarded, by client token. Their view caches the answer
+    clients: HashMap<Token, SocketAddr>,
+    ///Forwarded client queries there's a stale answer for, to answer with should upstream fail. RFC8767
+    stale: HashMap<Token, DnsMessage>,
+    ///Forwarded queries refreshing a stale answer, with its key. Their answers are cached, not sent
+    refreshes: HashMap<Token, CacheKey>,
     server_token: Token,
     last_request: Token,
     pipeline: RequestPipeline,
@@ -22,13 +44,18 @@ impl ServerBase {
 
     const REQUEST_TOKEN_START: usize = 10;
 
-    pub fn new(factory: Box<RequestFactory>, params: RequestParams, token: Token, max_connections: usize) -> ServerBase {
+    pub fn new(factory: Box<RequestFactory>, token: Token, max_connections: usize) -> ServerBase {
         debug!("New server listening on {:?}", token);
         ServerBase {            
             request_factory: factory,
             forwarded: HashMap::<Token, Box<ForwardedRequest>>::new(), //TODO: max forwards setting
             responses: Vec::<Response>::new(),
-            params: params,
+            to_validate: HashMap::new(),
+            pending: HashMap::new(),
+            fetches: HashMap::new(),
+            clients: HashMap::new(),
+            stale: HashMap::new(),
+            refreshes: HashMap::new(),
             server_token: token,
             last_request: Token(Self::REQUEST_TOKEN_START), //Some number clearly different from the starting token
             pipeline: RequestPipeline::default(),
@@ -38,20 +65,159 @@ impl ServerBase {
 
     pub fn process(&mut self, mut request: &mut RawRequest, mut ctx: &mut RequestCtx) {
         if let Some(response) = self.pipeline.process(&mut request, ctx) {
+            let cached = response.source == Source::Cache;
             self.queue_response(ctx, response);
+            if cached {
+                self.prefetch(request, ctx);
+            }
             return;            
         } 
-        //No response, forward upstream
-        let mut forward = self.build_forward_request(request.token, &request.bytes);
+        //No response. Answer stale if upstream failed for it lately, else forward upstream
+        if self.serve_stale(request, ctx) {
+            return;
+        }
+        let params = ctx.view().params;
+        let mut forward = match Self::validating(request, ctx) {
+            Some(query) => {
+                let forward = self.build_forward_request(request.token, &validate::upstream_query(&query).to_bytes(), params);
+                self.to_validate.insert(request.token, query);
+                forward
+            }
+            None => self.build_forward_request(request.token, &request.bytes, params),
+        };
+        self.clients.insert(request.token, request.client);
         debug!("Added {:?} to forwarded", forward.get().token);
         if let Some(response) = forward.ready(&mut ctx) {
             //Could get an error straight off...
-            self.queue_response(&ctx, response);
+            self.queue_response(ctx, response);
             return;
         }
         self.forwarded.insert(forward.get().token, forward);
     }
 
+    ///
+    ///For a query with an expired answer in the cache. Once upstream has failed for it, it's answered
+    ///stale without waiting on upstream, and refreshed in the background once the failure is a while
+    ///old. Until then the query is forwarded as usual, to be answered stale if that fails. RFC8767 4
+    ///A validated answer is refreshed by forwarding the client's query, so the new one is validated
+    ///
+    fn serve_stale(&mut self, request: &RawRequest, ctx: &mut RequestCtx) -> bool {
+        let query = match request.query {
+            Some(ref query) => query,
+            None => return false,
+        };
+        let key = match query.first_question() {
+            Some(question) => CacheKey::from(question),
+            None => return false,
+        };
+        let (msg, failed, retry_due) = match ctx.view().cache.get(&key) {
+            Some(ref entry) if entry.calc_ttl() == 0 && entry.security != Security::Bogus => {
+                (stale_reply(query, entry), entry.failed_upstream(), entry.retry_due())
+            }
+            _ => return false,
+        };
+        let refreshing = self.refreshes.values().any(|refresh| *refresh == key);
+        if !failed || (retry_due && !refreshing && Self::validating(request, ctx).is_some()) {
+            self.stale.insert(request.token, query.clone());
+            return false;
+        }
+        debug!("Answering {:?} stale", key);
+        if retry_due && !refreshing {
+            self.refresh(ctx, request.client, key, query);
+        }
+        self.queue_response(ctx, Response::with_source(request.token, msg.to_bytes(), msg, Source::Cache));
+        true
+    }
+
+    ///Refresh a popular answer just given from the cache, if it's about to expire, so the next client
+    ///doesn't have to wait on upstream. A validated answer is left to expire, as the refresh isn't validated
+    fn prefetch(&mut self, request: &RawRequest, ctx: &mut RequestCtx) {
+        let query = match request.query {
+            Some(ref query) => query,
+            None => return,
+        };
+        let key = match query.first_question() {
+            Some(question) => CacheKey::from(question),
+            None => return,
+        };
+        if ctx.view().cache.prefetch_due(&key) && !self.refreshes.values().any(|refresh| *refresh == key) && Self::validating(request, ctx).is_none() {
+            debug!("Prefetching {:?}", key);
+            self.refresh(ctx, request.client, key, query);
+        }
+    }
+
+    ///Ask upstream for a stale or nearly expired answer again, without a client waiting on it
+    fn refresh(&mut self, ctx: &mut RequestCtx, client: SocketAddr, key: CacheKey, query: &DnsMessage) {
+        let token = self.next_token();
+        debug!("Refreshing {:?} with {:?}", key, token);
+        let mut query = query.clone();
+        query.header.id = ::time::precise_time_ns() as u16;
+        let mut forward = self.build_forward_request(token, &query.to_bytes(), ctx.view().params);
+        self.clients.insert(token, client);
+        self.refreshes.insert(token, key);
+        let response = {
+            let mut req_ctx = RequestCtx::new(ctx.event_loop,
+                                              EventSet::readable(),
+                                              token,
+                                              ctx.cache.clone(),
+                                              ctx.zones.clone(),
+                                              ctx.secondaries.clone(),
+                                              ctx.anchors.clone(),
+                                              ctx.views.clone(),
+                                              ctx.hosts.clone());
+            forward.ready(&mut req_ctx)
+        };
+        match response {
+            Some(response) => self.queue_response(ctx, response),
+            None => {
+                self.forwarded.insert(token, forward);
+            }
+        }
+    }
+
+    ///Cache a refreshed answer, replacing the old one, or note that upstream failed again
+    fn refreshed(&mut self, ctx: &mut RequestCtx, key: &CacheKey, response: Response) {
+        let cache = &self.view(ctx, response.token).cache;
+        if Self::failed(&response) || !Self::cache(&**cache, &response.msg, Security::Indeterminate) {
+            cache.upstream_failed(key);
+        }
+        self.clients.remove(&response.token);
+    }
+
+    ///The stale answer to a forwarded query upstream failed for, noting that it did
+    fn stale_answer(&self, ctx: &RequestCtx, client: Token, query: &DnsMessage) -> Option<DnsMessage> {
+        let key = CacheKey::from(query.first_question()?);
+        let cache = &self.view(ctx, client).cache;
+        cache.upstream_failed(&key);
+        cache.get(&key)
+             .filter(|entry| entry.calc_ttl() == 0)
+             .map(|entry| stale_reply(query, &entry))
+    }
+
+    ///Upstream couldn't be reached, or couldn't answer. RFC8767 4
+    fn failed(response: &Response) -> bool {
+        response.source == Source::System || response.msg.header.rcode == Rcode::SERVFAIL
+    }
+
+    ///The query, if there's a trust anchor for its name so the answer is to be validated
+    fn validating(request: &RawRequest, ctx: &RequestCtx) -> Option<DnsMessage> {
+        let query = match request.query {
+            Some(ref query) => query,
+            None => return None,
+        };
+        let anchors = match ctx.anchors.read() {
+            Ok(anchors) => anchors,
+            Err(e) => {
+                error!("Couldn't get read lock {:?}", e);
+                return None;
+            }
+        };
+        match query.first_question() {
+            Some(question) if anchors.validates(&question.qname) => Some(query.clone()),
+            _ => None,
+        }
+    }
+
     pub fn register(&self, event_loop: &mut EventLoop<MioServer>,
                 socket: &Evented,
                 events: EventSet,
@@ -72,21 +238,45 @@ impl ServerBase {
         self.register(event_loop, sock, events, self.server_token, true);
     }
 
-    pub fn queue_response(&mut self, ctx: &RequestCtx, response: Response) {
+    pub fn queue_response(&mut self, ctx: &mut RequestCtx, mut response: Response) {
+        if let Some((client, name, rtype)) = self.fetches.remove(&response.token) {
+            self.fetched(ctx, client, &name, rtype, response);
+            return;
+        }
+        if let Some(key) = self.refreshes.remove(&response.token) {
+            self.refreshed(ctx, &key, response);
+            return;
+        }
+        if let Some(query) = self.to_validate.remove(&response.token) {
+            if response.source == Source::Upstream && !Self::failed(&response) {
+                self.stale.remove(&response.token);
+                self.start_validation(ctx, query, response);
+                return;
+            }
+        }
+        if let Some(query) = self.stale.remove(&response.token) {
+            if Self::failed(&response) {
+                if let Some(msg) = self.stale_answer(ctx, response.token, &query) {
+                    debug!("Upstream failed for {:?}. Answering stale", response.token);
+                    response = Response::with_source(response.token, msg.to_bytes(), msg, Source::Cache);
+                }
+            }
+        }
         if response.source == Source::Upstream {
             debug!("Upstream response. Will cache...");
             if let Some(cache_entry) = CacheEntry::from(&response.msg) {
-                ctx.cache.write().unwrap().upsert(cache_entry.key.clone(), cache_entry);    
+                self.view(ctx, response.token).cache.upsert(cache_entry.key.clone(), cache_entry);    
             }            
         }
+        self.clients.remove(&response.token);
         self.responses.push(response);
         debug!("queued response {:?}", ctx.token);        
     }
 
-    pub fn build_forward_request(&mut self, token: Token, bytes: &[u8]) -> Box<ForwardedRequest> {
+    pub fn build_forward_request(&mut self, token: Token, bytes: &[u8], params: RequestParams) -> Box<ForwardedRequest> {
         let mut buf = Vec::<u8>::with_capacity(bytes.len());
         buf.extend_from_slice(bytes);
-        let request = ForwardedRequestBase::new(token, buf, self.params);
+        let request = ForwardedRequestBase::new(token, buf, params);
         self.request_factory.new_with(request)
     }
 
@@ -115,7 +305,144 @@ impl ServerBase {
         }
         if let Some(response) = response_opt {
             self.forwarded.remove(&ctx.token);
-            self.queue_response(&ctx, response);            
+            self.queue_response(ctx, response);            
+        }
+    }
+
+    fn start_validation(&mut self, ctx: &mut RequestCtx, query: DnsMessage, response: Response) {
+        let question = match query.first_question() {
+            Some(question) => question.clone(),
+            None => return,
+        };
+        debug!("Validating answer to {:?}", question.qname);
+        let anchors = match ctx.anchors.read() {
+            Ok(anchors) => anchors.anchors(),
+            Err(e) => {
+                error!("Couldn't get read lock {:?}", e);
+                Vec::new()
+            }
+        };
+        let validation = Validation::new(question, response.msg.clone(), &anchors, clock::now() as u32);
+        self.pending.insert(response.token, Pending {
+            query: query,
+            msg: response.msg,
+            validation: validation,
+        });
+        self.advance(ctx, response.token);
+    }
+
+    fn fetched(&mut self, ctx: &mut RequestCtx, client: Token, name: &DnsName, rtype: u16, response: Response) {
+        debug!("Fetched {:?} {:?} for {:?}", name, rtype, client);
+        if let Some(pending) = self.pending.get_mut(&client) {
+            pending.validation.add(name, rtype, response.msg);
+        }
+        self.advance(ctx, client);
+    }
+
+    ///Fetch what the validation needs next, from the cache if it was validated before, else upstream
+    fn advance(&mut self, ctx: &mut RequestCtx, client: Token) {
+        loop {
+            let step = match self.pending.get_mut(&client) {
+                Some(pending) => pending.validation.step(),
+                None => return,
+            };
+            let (name, rtype) = match step {
+                Step::Done(security) => {
+                    self.finish(ctx, client, security);
+                    return;
+                }
+                Step::Fetch(name, rtype) => (name, rtype),
+            };
+            let (cache, params) = {
+                let view = self.view(ctx, client);
+                (view.cache.clone(), view.params)
+            };
+            if let Some(answers) = Self::cached_secure(&cache, &name, rtype) {
+                if let Some(pending) = self.pending.get_mut(&client) {
+                    pending.validation.add_secure(&name, rtype, answers);
+                }
+                continue;
+            }
+            let token = self.next_token();
+            debug!("Fetching {:?} {:?} with {:?} to validate {:?}", name, rtype, token, client);
+            let query = validate::fetch_query(::time::precise_time_ns() as u16, &name, rtype);
+            let mut forward = self.build_forward_request(token, &query.to_bytes(), params);
+            self.fetches.insert(token, (client, name, rtype));
+            let response = {
+                let mut req_ctx = RequestCtx::new(ctx.event_loop,
+                                                  EventSet::readable(),
+                                                  token,
+                                                  ctx.cache.clone(),
+                                                  ctx.zones.clone(),
+                                                  ctx.secondaries.clone(),
+                                                  ctx.anchors.clone(),
+                                                  ctx.views.clone(),
+                                                  ctx.hosts.clone());
+                forward.ready(&mut req_ctx)
+            };
+            match response {
+                //failed straight off, so the chain is broken
+                Some(response) => {
+                    if let Some((_, name, rtype)) = self.fetches.remove(&token) {
+                        if let Some(pending) = self.pending.get_mut(&client) {
+                            pending.validation.add(&name, rtype, response.msg);
+                        }
+                    }
+                }
+                None => {
+                    self.forwarded.insert(token, forward);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn cached_secure(cache: &SharedCache, name: &DnsName, rtype: u16) -> Option<Vec<DnsAnswer>> {
+        let key = CacheKey::new(name.to_string(), rtype, RecordClass::IN);
+        cache.get(&key)
+             .and_then(|entry| if entry.security == Security::Secure && entry.calc_ttl() > 0 && !entry.is_negative() { Some(entry.answers) } else { None })
+    }
+
+    ///Cache the answer with how it validated, along with the records proven on the way, and reply.
+    ///Bogus answers are a SERVFAIL unless the client asked us not to check. RFC4035 5.5
+    fn finish(&mut self, ctx: &mut RequestCtx, client: Token, security: Security) {
+        let pending = match self.pending.remove(&client) {
+            Some(pending) => pending,
+            None => return,
+        };
+        debug!("Answer for {:?} is {:?}", client, security);
+        self.stale.remove(&client);
+        let cache = &self.view(ctx, client).cache;
+        for msg in pending.validation.secured() {
+            Self::cache(&**cache, msg, Security::Secure);
+        }
+        Self::cache(&**cache, &pending.msg, security);
+        let msg = if security == Security::Bogus && !validate::checking_disabled(&pending.query) {
+            DnsMessage::new_error(DnsHeader::new_error(pending.query.header.clone(), Rcode::SERVFAIL))
+        } else {
+            validate::reply(&pending.query, pending.msg, security)
+        };
+        self.clients.remove(&client);
+        self.responses.push(Response::with_source(client, msg.to_bytes(), msg, Source::Upstream));
+    }
+
+    ///The view of the client a forwarded query is for
+    fn view<'a>(&self, ctx: &'a RequestCtx, client: Token) -> &'a View {
+        match self.clients.get(&client) {
+            Some(addr) => ctx.views.find(&addr.ip()),
+            None => ctx.views.default_view(),
+        }
+    }
+
+    ///Cache the answer, if it can be. False if it can't
+    fn cache(cache: &dyn CacheBackend, msg: &DnsMessage, security: Security) -> bool {
+        match CacheEntry::from(msg) {
+            Some(mut cache_entry) => {
+                cache_entry.security = security;
+                cache.upsert(cache_entry.key.clone(), cache_entry);
+                true
+            }
+            None => false,
         }
     }
 
@@ -2,6 +2,11 @@ use server_mio::{RequestCtx};
 use request::base::*;
 use cache::*;
 use dns::message::*;
+use dns::record::{Opcode, Rcode, RecordType};
+use dns::tsig::{self, Auth};
+use dns::validate::{self, Security};
+use zone::LookupResult;
+use zone::{notify, transfer, update};
 
 pub trait PipelineStage {
     fn process(&self, request: &mut RawRequest, ctx: &RequestCtx) -> Option<Response>;
@@ -12,7 +17,9 @@ pub struct RequestPipeline {
 }
 
 struct ParseStage;
+struct OpcodeStage;
 struct AuthorityStage;
+struct HostsStage;
 struct CacheStage;
 struct ForwardStage;
 
@@ -21,7 +28,9 @@ impl Default for RequestPipeline {
         
         let mut stages = Vec::<Box<PipelineStage>>::new();
         stages.push(Box::new(ParseStage));
+        stages.push(Box::new(OpcodeStage));
         stages.push(Box::new(AuthorityStage));
+        stages.push(Box::new(HostsStage));
         stages.push(Box::new(CacheStage));
         stages.push(Box::new(ForwardStage));
         
@@ -53,49 +62,173 @@ impl PipelineStage for ParseStage {
     }
 }
 
+impl PipelineStage for OpcodeStage {
+    fn process(&self, request: &mut RawRequest, ctx: &RequestCtx) -> Option<Response> {
+        let msg = match request.query {
+            Some(ref query) => {
+                match query.header.opcode {
+                    Opcode::QUERY => return None,
+                    Opcode::NOTIFY | Opcode::UPDATE => self.signed(query, request, ctx),
+                    opcode => {
+                        debug!("Opcode {} not implemented", opcode);
+                        DnsMessage::new_error(DnsHeader::new_reply(&query.header, Rcode::NOTIMP))
+                    }
+                }
+            }
+            None => return None,
+        };
+        Some(Response::with_source(request.token, msg.to_bytes(), msg, Source::Authoritive))
+    }
+}
+
+impl OpcodeStage {
+    ///
+    ///NOTIFY and UPDATE may be signed with a TSIG. A signed request gets a signed reply, and one
+    ///whose TSIG doesn't check out gets the TSIG error. RFC8945 5.2
+    ///
+    fn signed(&self, query: &DnsMessage, request: &RawRequest, ctx: &RequestCtx) -> DnsMessage {
+        let keys = match ctx.view().zones.read() {
+            Ok(zones) => zones.keys.clone(),
+            Err(e) => {
+                error!("Couldn't get read lock {:?}", e);
+                return DnsMessage::new_error(DnsHeader::new_reply(&query.header, Rcode::SERVFAIL));
+            }
+        };
+        match tsig::verify_request(&request.bytes, &keys) {
+            Auth::Unsigned => self.respond(query, request, ctx, None),
+            Auth::Signed(mut signer) => {
+                let reply = self.respond(query, request, ctx, Some(signer.key_name()));
+                signer.sign(&reply)
+            }
+            Auth::Failed(failure) => failure.reply(query),
+        }
+    }
+
+    fn respond(&self, query: &DnsMessage, request: &RawRequest, ctx: &RequestCtx, signed_by: Option<&DnsName>) -> DnsMessage {
+        if query.header.opcode == Opcode::UPDATE {
+            self.update(query, request, ctx, signed_by)
+        } else {
+            self.notify(query, request, ctx, signed_by)
+        }
+    }
+
+    ///A primary telling us a zone we're a secondary for has changed. RFC1996. Secondaries are only in the default view
+    fn notify(&self, query: &DnsMessage, request: &RawRequest, ctx: &RequestCtx, signed_by: Option<&DnsName>) -> DnsMessage {
+        let rcode = match query.first_question() {
+            Some(question) if !ctx.in_default_view() => {
+                warn!("NOTIFY for {:?} from {:?}, which is in view {}", question.qname.to_string(), request.client, ctx.view().name);
+                Rcode::NOTAUTH
+            }
+            Some(question) => {
+                match ctx.secondaries.write() {
+                    Ok(mut secondaries) => secondaries.notify(&question.qname, &request.client.ip(), signed_by),
+                    Err(e) => {
+                        error!("Couldn't get write lock {:?}", e);
+                        Rcode::SERVFAIL
+                    }
+                }
+            }
+            None => Rcode::FORMERR,
+        };
+        notify::reply(query, rcode)
+    }
+
+    ///A client adding or deleting records in one of its view's zones. RFC2136
+    fn update(&self, query: &DnsMessage, request: &RawRequest, ctx: &RequestCtx, signed_by: Option<&DnsName>) -> DnsMessage {
+        match ctx.view().zones.write() {
+            Ok(mut zones) => update::respond(query, &mut zones, &request.client.ip(), signed_by),
+            Err(e) => {
+                error!("Couldn't get write lock {:?}", e);
+                DnsMessage::new_error(DnsHeader::new_reply(&query.header, Rcode::SERVFAIL))
+            }
+        }
+    }
+}
+
 impl PipelineStage for AuthorityStage {
     #[allow(unused_variables)]
     fn process(&self, request: &mut RawRequest, ctx: &RequestCtx) -> Option<Response> {        
-        self.get_authoritive(request)
+        self.get_authoritive(request, ctx)
     }
 }
 
 impl AuthorityStage {
 
-    fn get_authoritive(&self, request: &RawRequest) -> Option<Response> {
+    fn get_authoritive(&self, request: &RawRequest, ctx: &RequestCtx) -> Option<Response> {
         
         if let Some(ref query) = request.query {
             if let Some(question) = query.first_question() {
                 debug!("Checking for authoritive answer to {:?}", question.qname);
-                if question.qname.to_string() == "example.org"  {                    
-                    let msg = self.test_answer(&query.header, &question);
+                let zones = match ctx.view().zones.read() {
+                    Ok(zones) => zones,
+                    Err(e) => {
+                        error!("Couldn't get read lock {:?}", e);
+                        return None;
+                    }
+                };
+                if transfer::is_transfer(question.qtype) {
+                    //zone transfers over tcp are handled by TcpServer before the pipeline
+                    let msg = transfer::respond_udp(query, &zones);
+                    return Some(Response::with_source(request.token, msg.to_bytes(), msg, Source::Authoritive));
+                }
+                if let Some(zone) = zones.find(&question.qname) {
+                    let mut result = zone.lookup(&question.qname, question.qtype);
+                    if query.dnssec_ok() {
+                        zone.add_dnssec(&mut result);
+                    }
+                    let msg = self.answer(query, question, result);
                     debug!("Yes. Will answer with authoritive answer. {:?}", msg);
                     return Some(Response::with_source(request.token, msg.to_bytes(), msg, Source::Authoritive));
                 }
+                if let Some(result) = zones.synthesize_ptr(&question.qname, question.qtype) {
+                    let msg = self.answer(query, question, result);
+                    debug!("Will answer with a PTR made from a forward zone. {:?}", msg);
+                    return Some(Response::with_source(request.token, msg.to_bytes(), msg, Source::Authoritive));
+                }
             }
         }
         None
     }
 
-    fn test_answer(&self, query_header: &DnsHeader, question: &DnsQuestion) -> DnsMessage {
-        let mut answer_header = query_header.clone();
-        answer_header.id = query_header.id;
-        answer_header.qr = true;
-        answer_header.ancount = 1;
-        answer_header.aa = true; //authoritive
-        answer_header.ra = true;
-
-        let mut answers = Vec::<DnsAnswer>::new();
-        let answer = DnsAnswer {
-            name: question.qname.clone(),
-            aclass: 1, //A
-            atype: 1,  //IN(ternet)
-            ttl: 300,
-            rdlength: 4,
-            rdata: vec![93, 184, 216, 34]
+    fn answer(&self, query: &DnsMessage, question: &DnsQuestion, result: LookupResult) -> DnsMessage {
+        let mut answer_header = DnsHeader::new_reply(&query.header, result.rcode);
+        answer_header.aa = result.aa;
+        let mut msg = DnsMessage::new_reply(answer_header, vec![question.clone()], result.answers);
+        msg.authorities = result.authorities;
+        msg.additionals = result.additionals;
+        //EDNS in the query gets EDNS in the reply. RFC6891 7
+        if query.edns().is_some() {
+            msg.additionals.push(DnsAnswer::new_opt(query.dnssec_ok()));
+        }
+        msg
+    }
+}
+
+impl PipelineStage for HostsStage {
+    ///The hosts and lease files are like the zones outside views, only for clients in no view
+    fn process(&self, request: &mut RawRequest, ctx: &RequestCtx) -> Option<Response> {
+        if !ctx.in_default_view() {
+            return None;
+        }
+        let query = request.query.as_ref()?;
+        let question = query.first_question()?;
+        let answers = match ctx.hosts.read() {
+            Ok(hosts) => hosts.lookup(&question.qname, question.qtype),
+            Err(e) => {
+                error!("Couldn't get read lock {:?}", e);
+                None
+            }
         };
-        answers.push(answer);
-        DnsMessage::new_reply(answer_header, vec![question.clone()], answers)        
+        answers.map(|answers| {
+            let mut answer_header = DnsHeader::new_reply(&query.header, Rcode::NOERROR);
+            answer_header.aa = true;
+            let mut msg = DnsMessage::new_reply(answer_header, vec![question.clone()], answers);
+            if query.edns().is_some() {
+                msg.additionals.push(DnsAnswer::new_opt(query.dnssec_ok()));
+            }
+            debug!("Will answer from the hosts and lease files. {:?}", msg);
+            Response::with_source(request.token, msg.to_bytes(), msg, Source::Authoritive)
+        })
     }
 }
 
@@ -103,34 +236,26 @@ impl PipelineStage for CacheStage {
     #[allow(unused_variables)]
     fn process(&self, request: &mut RawRequest, ctx: &RequestCtx) -> Option<Response> {        
         debug!("Entered cache stage");        
-        match ctx.cache.read() {
-            Ok(cache) => {
-                let query = DnsMessage::parse(&request.bytes);
-                if let Some(question) = query.first_question() {
-                    let key = CacheKey::from(&question);
-                    if let Some(entry) = cache.get(&key) {
-
-                        if entry.calc_ttl() == 0 {
-                            //Expired. Will be removed on next upsert
-                            debug!("expired");
-                            return None;
-                        }
-
-                        //TODO: cache the whole message?
-                        let mut answer_header = query.header.clone();
-                        answer_header.id = query.header.id;
-                        answer_header.qr = true;
-                        answer_header.ra = true;
-                        answer_header.ancount = entry.answers.len() as u16;
-                        let mut answers = entry.answers.clone();
-                        Self::adjust_ttl(entry.calc_ttl(), &mut answers);
-                        let msg = DnsMessage::new_reply(answer_header, query.questions.clone(), answers);
-                        debug!("Will answer with {:?} based on key {:?}", msg, entry.key);
-                        return Some(Response::with_source(ctx.token, msg.to_bytes(), msg, Source::Cache));
-                    } 
+        let query = DnsMessage::parse(&request.bytes);
+        if let Some(question) = query.first_question() {
+            let key = CacheKey::from(&question);
+            if let Some(entry) = ctx.view().cache.get(&key) {
+
+                if entry.calc_ttl() == 0 {
+                    //Expired. Will be removed on next upsert
+                    debug!("expired");
+                    return None;
                 }
-            }
-            Err(e) => error!("Couldn't get read lock {:?}", e)
+
+                if entry.security == Security::Bogus && !validate::checking_disabled(&query) {
+                    let msg = DnsMessage::new_error(DnsHeader::new_error(query.header.clone(), Rcode::SERVFAIL));
+                    return Some(Response::with_source(ctx.token, msg.to_bytes(), msg, Source::Cache));
+                }
+
+                let msg = cached_reply(&query, &entry);
+                debug!("Will answer with {:?} based on key {:?}", msg, entry.key);
+                return Some(Response::with_source(ctx.token, msg.to_bytes(), msg, Source::Cache));
+            } 
         }
         debug!("No cache hit");
         None
@@ -138,14 +263,50 @@ impl PipelineStage for CacheStage {
 }
 
 impl CacheStage {
-    fn adjust_ttl(ttl: u32, answers: &mut Vec<DnsAnswer>) {
+    fn adjust_ttl(ttl: &dyn Fn(u32) -> u32, answers: &mut Vec<DnsAnswer>) {
         for answer in answers {
-            debug!("Adjusting ttl {} -> {}", answer.ttl, ttl);
-            answer.ttl = ttl;
+            debug!("Adjusting ttl {} -> {}", answer.ttl, ttl(answer.ttl));
+            answer.ttl = ttl(answer.ttl);
         }
     }
 }
 
+///The reply to the query from a cache entry, each record's TTL less the time it's been cached, so a CNAME
+///and the shorter lived record it points to each count down from their own. For a negative entry, the
+///SOA's TTL is how long the client may cache it too. RFC2308 5
+pub fn cached_reply(query: &DnsMessage, entry: &CacheEntry) -> DnsMessage {
+    let age = entry.age();
+    reply_from(query, entry, &|ttl| ttl.saturating_sub(age))
+}
+
+///The reply from an expired cache entry, as upstream couldn't be reached. A short TTL and, if the
+///client sent an OPT, the stale answer EDE. RFC8767 4, RFC8914 4.4
+pub fn stale_reply(query: &DnsMessage, entry: &CacheEntry) -> DnsMessage {
+    let mut msg = reply_from(query, entry, &|_| STALE_TTL);
+    if let Some(opt) = msg.additionals.iter_mut().find(|rr| rr.atype == RecordType::OPT) {
+        opt.add_ede(DnsAnswer::EDE_STALE_ANSWER);
+    }
+    msg
+}
+
+///The reply with the records' TTLs as ttl has them
+fn reply_from(query: &DnsMessage, entry: &CacheEntry, ttl: &dyn Fn(u32) -> u32) -> DnsMessage {
+    //TODO: cache the whole message?
+    let mut answer_header = query.header.clone();
+    answer_header.id = query.header.id;
+    answer_header.qr = true;
+    answer_header.ra = true;
+    answer_header.rcode = entry.rcode;
+    answer_header.ancount = entry.answers.len() as u16;
+    let mut answers = entry.answers.clone();
+    CacheStage::adjust_ttl(ttl, &mut answers);
+    let mut authorities = entry.authorities.clone();
+    CacheStage::adjust_ttl(ttl, &mut authorities);
+    let mut msg = DnsMessage::new_reply(answer_header, query.questions.clone(), answers);
+    msg.authorities = authorities;
+    validate::reply(query, msg, entry.security)
+}
+
 impl PipelineStage for ForwardStage {
     #[allow(unused_variables)]
     fn process(&self, request: &mut RawRequest, ctx: &RequestCtx) -> Option<Response> {        
@@ -153,3 +314,42 @@ impl PipelineStage for ForwardStage {
         None 
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{cached_reply, stale_reply};
+    use std::thread;
+    use std::time::Duration;
+    use cache::{CacheBackend, CacheEntry, CacheKey, ShardedCache, STALE_TTL};
+    use dns::message::{DnsAnswer, DnsHeader, DnsMessage, DnsName, DnsQuestion, IntoBytes};
+    use dns::record::RecordType;
+    use settings::CacheConfig;
+
+    #[test]
+    fn records_decay_separately() {
+        let name = |name: &str| DnsName::from_string(name.to_owned());
+        let query = DnsMessage::new_query(DnsHeader::new_query(5), vec![DnsQuestion::new(name("www.example.org"), RecordType::A, 1)]);
+        let mut reply = query.clone();
+        reply.header.qr = true;
+        reply.answers.push(DnsAnswer::with_rdata(name("www.example.org"), RecordType::CNAME, 1, 3600, name("cdn.example.net").to_bytes()));
+        reply.answers.push(DnsAnswer::with_rdata(name("cdn.example.net"), RecordType::A, 1, 60, vec![10, 0, 0, 5]));
+        let cache = ShardedCache::new(&CacheConfig::default());
+        let entry = CacheEntry::from(&reply).unwrap();
+        cache.upsert(entry.key.clone(), entry);
+
+        //found by the question, though the chain starts with a CNAME
+        let key = CacheKey::from(query.first_question().unwrap());
+        let entry = cache.get(&key).unwrap();
+        //expires with the A record
+        assert!(entry.calc_ttl() >= 59 && entry.calc_ttl() <= 60, "ttl {}", entry.calc_ttl());
+
+        thread::sleep(Duration::from_millis(1010));
+        let entry = cache.get(&key).unwrap();
+        let ttls = |msg: DnsMessage| msg.answers.iter().map(|rr| rr.ttl).collect::<Vec<_>>();
+        let age = entry.age();
+        assert!(age >= 1 && age <= 2, "age {}", age);
+        assert_eq!(vec![3600 - age, 60 - age], ttls(cached_reply(&query, &entry)));
+        assert_eq!(5, cached_reply(&query, &entry).header.id);
+        assert_eq!(vec![STALE_TTL, STALE_TTL], ttls(stale_reply(&query, &entry)));
+    }
+}
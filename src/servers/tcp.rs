@@ -1,31 +1,45 @@
 use std::net::SocketAddr;
 use std::io::Write;
 use server_mio::{RequestCtx};
-use mio::{EventSet, Token, TryRead};
+use mio::{EventSet, Token, TryRead, TryWrite};
 use mio::tcp::{TcpStream,TcpListener};
 use std::collections::HashMap;
 use request::base::*;
 use request::tcp::{TcpRequestFactory};
 use servers::base::*;
+use dns::message::*;
+use dns::record::*;
+use dns::tsig::{self, Auth};
+use zone::transfer::{self, MAX_MESSAGE_LEN};
 
 pub struct TcpServer {
     pub server_socket: TcpListener,
     pub base: ServerBase,
     pending: HashMap<Token, TcpStream>,
     accepted: HashMap<Token, TcpStream>,
+    transfers: HashMap<Token, Transfer>,
+}
+
+///A multi-message response (zone transfer) being written to a client.
+///It can be bigger than the socket will take at once, so is written as the socket becomes writable
+struct Transfer {
+    stream: TcpStream,
+    bytes: Vec<u8>,
+    written: usize,
 }
 
 impl TcpServer {
     pub const TCP_SERVER_TOKEN: Token = Token(0);
 
-    pub fn new(addr: SocketAddr, max_connections: usize, params: RequestParams) -> TcpServer {
+    pub fn new(addr: SocketAddr, max_connections: usize) -> TcpServer {
         let listener = Self::bind_tcp(addr);
         let factory = Box::new(TcpRequestFactory);
         TcpServer {
             server_socket: listener,
             pending: HashMap::<Token, TcpStream>::new(),
             accepted: HashMap::<Token, TcpStream>::new(),
-            base: ServerBase::new(factory, params, Self::TCP_SERVER_TOKEN, max_connections),
+            transfers: HashMap::<Token, Transfer>::new(),
+            base: ServerBase::new(factory, Self::TCP_SERVER_TOKEN, max_connections),
         }
     }
 
@@ -46,6 +60,10 @@ impl TcpServer {
             self.accept_pending(ctx);
             return;
         }
+        if self.transfers.contains_key(&ctx.token) {
+            self.continue_transfer(ctx);
+            return;
+        }
         self.base.request_ready(ctx);
         self.send_all();
     }
@@ -66,9 +84,22 @@ impl TcpServer {
     fn accept_pending(&mut self, ctx: &mut RequestCtx) {
         match self.pending.remove(&ctx.token) {
             Some(mut stream) => {
+                let client = match stream.peer_addr() {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        error!("No peer address for {:?}. Closing. {:?}", ctx.token, e);
+                        return;
+                    }
+                };
                 let bytes = Self::receive_tcp(&mut stream);
+                let query = DnsMessage::parse(&bytes);
+                ctx.client = Some(client);
+                if query.first_question().map_or(false, |q| transfer::is_transfer(q.qtype)) {
+                    self.start_transfer(ctx, stream, &query, &bytes, client);
+                    return;
+                }
                 self.accepted.insert(ctx.token, stream);
-                let mut request = RawRequest::new(ctx.token, bytes);
+                let mut request = RawRequest::new(ctx.token, bytes, client);
                 self.base.process(&mut request, ctx);
                 debug!("tcp accepted {:?}", ctx.token);               
                 //TODO: send now? or register as writable. favour fast response or throughput?
@@ -92,7 +123,76 @@ impl TcpServer {
     }
 
    pub fn owns(&self, token: Token) -> bool {
-        self.pending.contains_key(&token) || self.base.owns(token)
+        self.pending.contains_key(&token) || self.transfers.contains_key(&token) || self.base.owns(token)
+    }
+
+    ///
+    ///Zone transfers are a stream of messages, so they don't go through the pipeline. If the query
+    ///is signed each message of the response is too. RFC8945 5.3.1. The zones are the client's view's
+    ///
+    fn start_transfer(&mut self, ctx: &mut RequestCtx, stream: TcpStream, query: &DnsMessage, query_bytes: &[u8], client: SocketAddr) {
+        let messages = match ctx.view().zones.read() {
+            Ok(zones) => {
+                match tsig::verify_request(query_bytes, &zones.keys) {
+                    Auth::Unsigned => transfer::respond(query, &zones, &client.ip(), None),
+                    Auth::Signed(mut signer) => {
+                        transfer::respond(query, &zones, &client.ip(), Some(signer.key_name()))
+                            .iter()
+                            .map(|msg| signer.sign(msg))
+                            .collect()
+                    }
+                    Auth::Failed(failure) => vec![failure.reply(query)],
+                }
+            }
+            Err(e) => {
+                error!("Couldn't get read lock {:?}", e);
+                vec![transfer::error(query, Rcode::SERVFAIL)]
+            }
+        };
+        let mut bytes = Vec::<u8>::new();
+        for msg in messages {
+            let mut msg_bytes = msg.to_bytes_max(MAX_MESSAGE_LEN);
+            Self::prefix_with_length(&mut msg_bytes);
+            bytes.extend(msg_bytes);
+        }
+        debug!("{:?} bytes in zone transfer", bytes.len());
+        self.transfers.insert(ctx.token, Transfer {
+            stream: stream,
+            bytes: bytes,
+            written: 0,
+        });
+        self.continue_transfer(ctx);
+    }
+
+    fn continue_transfer(&mut self, ctx: &mut RequestCtx) {
+        let done = match self.transfers.get_mut(&ctx.token) {
+            Some(transfer) => {
+                match transfer.stream.try_write(&transfer.bytes[transfer.written..]) {
+                    Ok(Some(n)) => {
+                        transfer.written += n;
+                        debug!("{:?} of {:?} transfer bytes sent to {:?}", transfer.written, transfer.bytes.len(), ctx.token);
+                        if transfer.written < transfer.bytes.len() {
+                            self.base.register(ctx.event_loop, &transfer.stream, EventSet::writable(), ctx.token, true);
+                        }
+                        transfer.written == transfer.bytes.len()
+                    }
+                    Ok(None) => {
+                        debug!("Transfer socket would block. Waiting...");
+                        self.base.register(ctx.event_loop, &transfer.stream, EventSet::writable(), ctx.token, true);
+                        false
+                    }
+                    Err(e) => {
+                        error!("Zone transfer to {:?} failed. {:?}", transfer.stream.peer_addr(), e);
+                        true
+                    }
+                }
+            }
+            None => false,
+        };
+        if done {
+            //dropping the stream closes the connection
+            self.transfers.remove(&ctx.token);
+        }
     }
 
     fn prefix_with_length(buf: &mut Vec<u8>) {
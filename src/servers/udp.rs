@@ -15,12 +15,12 @@ pub struct UdpServer {
 
 impl UdpServer{
     pub const UDP_SERVER_TOKEN: Token = Token(1);
-    pub fn new(addr: SocketAddr, max_connections: usize, params: RequestParams) -> UdpServer {
+    pub fn new(addr: SocketAddr, max_connections: usize) -> UdpServer {
         let server_socket = Self::bind_udp(addr);
         let factory = Box::new(UdpRequestFactory);
         UdpServer {
             server_socket: server_socket,
-            base: ServerBase::new(factory, params, Self::UDP_SERVER_TOKEN, max_connections),
+            base: ServerBase::new(factory, Self::UDP_SERVER_TOKEN, max_connections),
             accepted: HashMap::<Token, SocketAddr>::new()
         }
     }
@@ -35,7 +35,7 @@ impl UdpServer{
     pub fn accept(&mut self) -> Option<(SocketAddr,RawRequest)> {
         if let Some((addr, buf)) = self.receive(&self.server_socket) {
             let token = self.base.next_token();
-            let req = RawRequest::new(token, buf);
+            let req = RawRequest::new(token, buf, addr);
             return Some((addr, req));
         }
         None
@@ -68,7 +68,16 @@ impl UdpServer{
     pub fn server_ready(&mut self, ctx: &mut RequestCtx)  {
         if let Some((addr, mut req)) = self.accept() {                
             self.accepted.insert(req.token, addr);
-            let mut req_ctx = RequestCtx::new(ctx.event_loop, EventSet::readable(), req.token, ctx.cache.clone());
+            let mut req_ctx = RequestCtx::new(ctx.event_loop,
+                                           EventSet::readable(),
+                                           req.token,
+                                           ctx.cache.clone(),
+                                           ctx.zones.clone(),
+                                           ctx.secondaries.clone(),
+                                           ctx.anchors.clone(),
+                                           ctx.views.clone(),
+                                           ctx.hosts.clone());
+            req_ctx.client = Some(addr);
             self.base.process(&mut req, &mut req_ctx);                    
         }
         self.send_all();        
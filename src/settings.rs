@@ -0,0 +1,988 @@
+use std::fs::File;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use cidr::Cidr;
+use cache::{DEFAULT_MAX_BYTES, DEFAULT_MAX_ENTRIES, DEFAULT_SHARDS};
+use dns::tsig::TsigKey;
+use dns::message::DnsName;
+use dns::record::RecordType;
+use dns::dnssec::{Algorithm, Dnskey, Ds, Nsec3Params};
+use dns::validate::TrustAnchor;
+use zone::master;
+
+///
+///Settings from the config file given with -c. It's an ini style file of sections,
+///each with a type and a name. E.g.
+///
+///  [zone example.org]
+///  file = /etc/koala/example.org.zone
+///  allow-transfer = 127.0.0.1, 10.0.0.0/8
+///  notify = 10.0.0.2, 10.0.0.3:5353
+///  allow-update = 192.168.1.0/24
+///  update-names = *.lan.example.org
+///  ksk = /etc/koala/example.org.ksk.pem
+///  zsk = /etc/koala/example.org.zsk.pem
+///  nsec3 = 10 aabbccdd opt-out
+///  reverse-ptr = yes
+///
+///  [zone example.net]
+///  primary = 10.0.0.53
+///  primary-key = xfr
+///
+///  [key xfr]
+///  algorithm = hmac-sha256
+///  secret = c2VjcmV0IHNoYXJlZCB3aXRoIHRoZSBwcmltYXJ5
+///
+///  [trust-anchor .]
+///  ds = 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+///  state-file = /var/lib/koala/root.state
+///
+///  [trust-anchor example.com]
+///  file = /etc/koala/example.com.key
+///
+///  [negative-trust-anchor broken.example.net]
+///
+///  [view guest]
+///  match-clients = 192.168.2.0/24
+///  upstream = 9.9.9.9
+///
+///  [zone example.org]
+///  view = guest
+///  file = /etc/koala/guest.example.org.zone
+///
+///  [hosts]
+///  file = /etc/hosts, /tmp/hosts/dhcp
+///  ttl = 60
+///
+///  [leases]
+///  file = /tmp/dhcp.leases
+///  domain = lan
+///
+///  [cache]
+///  max-entries = 10000
+///  max-bytes = 8M
+///  shards = 16
+///  serve-stale = 86400
+///  prefetch = 10
+///  prefetch-hits = 3
+///  redis = 127.0.0.1:6379
+///  snapshot = /var/lib/koala/cache
+///  snapshot-interval = 300
+///  min-ttl = 30
+///  max-ttl = 86400
+///  negative-max-ttl = 3600
+///
+///  [ttl parental.example.net]
+///  max-ttl = 300
+///
+///  [admin]
+///  listen = 127.0.0.1:5380
+///
+///Lists are comma or space separated. Lines starting with # or ; are comments.
+///
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Default)]
+pub struct Settings {
+    pub zones: Vec<ZoneConfig>,
+    ///TSIG keys. Zones name the ones they use
+    pub keys: Vec<TsigKey>,
+    ///Upstream answers below these are validated
+    pub trust_anchors: Vec<TrustAnchorConfig>,
+    ///Except for answers below these, from zones known to be broken. RFC7646
+    pub negative_trust_anchors: Vec<DnsName>,
+    ///Clients answered differently from everyone else. Zones not in a view are for the clients in none
+    pub views: Vec<ViewConfig>,
+    ///Files of local names in the /etc/hosts format, and DHCP lease files
+    pub hosts: Vec<HostsConfig>,
+    pub cache: CacheConfig,
+    ///Where commands to look at and flush the caches are taken. Only on a loopback address, as anyone who can connect can send them
+    pub admin: Option<SocketAddr>,
+}
+
+///Limits on each cache. A view's is as big as the default one
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct CacheConfig {
+    pub max_entries: usize,
+    ///Roughly. What the entries take in memory is estimated
+    pub max_bytes: usize,
+    ///The in-memory cache is split into this many, each with its own lock and share of the limits
+    pub shards: usize,
+    ///Seconds an expired answer is kept to answer with when upstream fails. 0 for none. RFC8767
+    pub serve_stale: u32,
+    ///An answer is fetched again before it expires when a query for it comes in the last this percent of its TTL. 0 for never
+    pub prefetch: u32,
+    ///and it's had at least this many hits
+    pub prefetch_hits: usize,
+    ///Keep the cache in this Redis server, rather than in memory, so several servers can share it
+    pub redis: Option<SocketAddr>,
+    ///Save the cache to this file every snapshot_interval seconds and on stopping, and load it on starting
+    pub snapshot: Option<PathBuf>,
+    pub snapshot_interval: u32,
+    pub ttl: TtlLimits,
+    ///Limits for the names under these, from [ttl name] sections. The longest name a name is under wins
+    pub ttl_overrides: Vec<(DnsName, TtlLimits)>,
+}
+
+///The TTLs answers are cached for are kept within these, so a TTL of 0 or 1 doesn't make caching
+///pointless and a week long one doesn't outlive a change of policy. Negative answers have their own. RFC2308 5
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+pub struct TtlLimits {
+    pub min: u32,
+    pub max: u32,
+    pub negative_min: u32,
+    pub negative_max: u32,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_bytes: DEFAULT_MAX_BYTES,
+            shards: DEFAULT_SHARDS,
+            serve_stale: 0,
+            prefetch: 0,
+            prefetch_hits: 3,
+            redis: None,
+            snapshot: None,
+            snapshot_interval: 300,
+            ttl: TtlLimits::default(),
+            ttl_overrides: Vec::new(),
+        }
+    }
+}
+
+impl CacheConfig {
+    ///The limits for answers for the name
+    pub fn ttl_limits(&self, name: &DnsName) -> TtlLimits {
+        self.ttl_overrides
+            .iter()
+            .filter(|&&(ref suffix, _)| name.is_subdomain_of(suffix))
+            .max_by_key(|limits| limits.0.labels().len())
+            .map_or(self.ttl, |limits| limits.1)
+    }
+}
+
+///A week for answers, as BIND has it, and three hours for negative ones. RFC2308 5
+impl Default for TtlLimits {
+    fn default() -> TtlLimits {
+        TtlLimits {
+            min: 0,
+            max: 7 * 24 * 3600,
+            negative_min: 0,
+            negative_max: 3 * 3600,
+        }
+    }
+}
+
+impl TtlLimits {
+    pub fn clamp(&self, ttl: u32, negative: bool) -> u32 {
+        if negative {
+            ttl.max(self.negative_min).min(self.negative_max)
+        } else {
+            ttl.max(self.min).min(self.max)
+        }
+    }
+}
+
+///Hosts or lease files, answered from with the same TTL
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct HostsConfig {
+    pub files: Vec<String>,
+    pub ttl: u32,
+    ///Set for dnsmasq or odhcpd lease files. Their hostnames are answered under it
+    pub lease_domain: Option<String>,
+}
+
+///Clients in some networks answered from their own zones, upstream and cache
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Default)]
+pub struct ViewConfig {
+    pub name: String,
+    ///Clients in these networks are in the view. A client in more than one is in the first
+    pub match_clients: Vec<Cidr>,
+    ///Where queries the view's zones don't answer go. The server's upstream if not set
+    pub upstream: Option<SocketAddr>,
+    ///The zones with view = name. They're loaded from files, a view can't have secondary zones
+    pub zones: Vec<ZoneConfig>,
+}
+
+///Keys trusted for a zone, and whether they're kept up to date as the zone rolls them over
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct TrustAnchorConfig {
+    pub anchor: TrustAnchor,
+    ///Track the zone's keys, keeping what's been seen of them in this file. RFC5011
+    pub state_file: Option<String>,
+}
+
+///An authoritative zone loaded from a master file, or transferred from a primary if it's a secondary
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Default)]
+pub struct ZoneConfig {
+    pub origin: String,
+    pub file: String,
+    ///Clients allowed to AXFR the zone. Empty means no one
+    pub allow_transfer: Vec<Cidr>,
+    ///Set for a secondary zone. The server to transfer it from
+    pub primary: Option<SocketAddr>,
+    ///Secondaries to send a NOTIFY to when the serial changes
+    pub notify: Vec<SocketAddr>,
+    ///Clients allowed to send dynamic updates. Empty means no one
+    pub allow_update: Vec<Cidr>,
+    ///Names updates may change. A name, or *.name for the names below it. Empty means any in the zone
+    pub update_names: Vec<String>,
+    ///Transfers must be signed with this key. NOTIFYs to secondaries are signed with it
+    pub transfer_key: Option<TsigKey>,
+    ///Updates must be signed with this key
+    pub update_key: Option<TsigKey>,
+    ///Requests to the primary are signed with this key, and its responses and NOTIFYs must be
+    pub primary_key: Option<TsigKey>,
+    ///PEM file of the key signing key. The zone is signed if it has a ksk or a zsk
+    pub ksk: Option<String>,
+    ///PEM file of the zone signing key
+    pub zsk: Option<String>,
+    ///For keys made when a key file doesn't exist yet. ECDSA P-256 if not set
+    pub dnssec_algorithm: Option<u8>,
+    ///Deny names with NSEC3 instead of NSEC
+    pub nsec3: Option<Nsec3Params>,
+    ///The view the zone is served in. None for clients in no view
+    pub view: Option<String>,
+    ///Answer PTR queries for the zone's addresses from its A and AAAA records
+    pub reverse_ptr: bool,
+}
+
+impl Settings {
+    pub fn load(path: &str) -> Result<Settings, String> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("Failed to read config file {:?}. {:?}", path, e))?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        let sections = Section::parse_all(text)?;
+        //keys first, so zones can name them wherever they are in the file
+        for section in sections.iter().filter(|section| section.kind == "key") {
+            settings.keys.push(parse_key(section)?);
+        }
+        //and views, for their zones
+        for section in sections.iter().filter(|section| section.kind == "view") {
+            let view = parse_view(section)?;
+            if settings.views.iter().any(|other| other.name == view.name) {
+                return Err(format!("[view {}] is given more than once", view.name));
+            }
+            settings.views.push(view);
+        }
+        for section in &sections {
+            match section.kind.as_str() {
+                "zone" => {
+                    let zone = ZoneConfig::from_section(section, &settings.keys)?;
+                    match zone.view.clone() {
+                        Some(name) => {
+                            let view = settings.views
+                                               .iter_mut()
+                                               .find(|view| view.name == name)
+                                               .ok_or(format!("No [view {}] for [zone {}]", name, section.name))?;
+                            view.zones.push(zone);
+                        }
+                        None => settings.zones.push(zone),
+                    }
+                }
+                "hosts" | "leases" => settings.hosts.push(parse_hosts(section)?),
+                "key" | "view" | "ttl" => (),
+                "cache" => settings.cache = parse_cache(section)?,
+                "admin" => settings.admin = parse_admin(section)?,
+                "trust-anchor" => settings.trust_anchors.push(parse_trust_anchor(section)?),
+                "negative-trust-anchor" => {
+                    if section.name.is_empty() {
+                        return Err("A [negative-trust-anchor] needs a name".to_owned());
+                    }
+                    settings.negative_trust_anchors.push(DnsName::from_string(section.name.clone()).to_lowercase());
+                }
+                other => return Err(format!("Unknown section type [{} {}]", other, section.name)),
+            }
+        }
+        //after [cache], as the limits a [ttl] section doesn't set are its
+        for section in sections.iter().filter(|section| section.kind == "ttl") {
+            let name = parse_ttl_override(section, settings.cache.ttl)?;
+            settings.cache.ttl_overrides.push(name);
+        }
+        Ok(settings)
+    }
+}
+
+impl ZoneConfig {
+    pub fn new(origin: String, file: String) -> ZoneConfig {
+        ZoneConfig {
+            origin: origin,
+            file: file,
+            allow_transfer: Vec::new(),
+            primary: None,
+            notify: Vec::new(),
+            allow_update: Vec::new(),
+            update_names: Vec::new(),
+            transfer_key: None,
+            update_key: None,
+            primary_key: None,
+            ksk: None,
+            zsk: None,
+            dnssec_algorithm: None,
+            nsec3: None,
+            view: None,
+            reverse_ptr: false,
+        }
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.ksk.is_some() || self.zsk.is_some()
+    }
+
+    fn from_section(section: &Section, keys: &[TsigKey]) -> Result<ZoneConfig, String> {
+        let mut config = ZoneConfig::new(section.name.clone(), String::new());
+        let find_key = |name: &str| {
+            keys.iter()
+                .find(|key| key.name.to_string().eq_ignore_ascii_case(name.trim_end_matches('.')))
+                .cloned()
+                .ok_or(format!("No [key {}] for [zone {}]", name, section.name))
+        };
+        for &(ref key, ref val) in &section.entries {
+            match key.as_str() {
+                "file" => config.file = val.clone(),
+                "allow-transfer" => config.allow_transfer = parse_networks(val)?,
+                "primary" => config.primary = Some(parse_server(val)?),
+                "notify" => config.notify = parse_servers(val)?,
+                "allow-update" => config.allow_update = parse_networks(val)?,
+                "update-names" => config.update_names = parse_list(val),
+                "transfer-key" => config.transfer_key = Some(find_key(val)?),
+                "update-key" => config.update_key = Some(find_key(val)?),
+                "primary-key" => config.primary_key = Some(find_key(val)?),
+                "ksk" => config.ksk = Some(val.clone()),
+                "zsk" => config.zsk = Some(val.clone()),
+                "dnssec-algorithm" => {
+                    config.dnssec_algorithm = Some(Algorithm::from_name(val)
+                        .ok_or(format!("Unknown dnssec-algorithm {:?} in [zone {}]", val, section.name))?)
+                }
+                "nsec3" => config.nsec3 = Some(parse_nsec3(val)?),
+                "view" => config.view = Some(val.clone()),
+                "reverse-ptr" => config.reverse_ptr = parse_bool(val)?,
+                _ => warn!("Ignoring unknown setting {:?} in [zone {}]", key, section.name),
+            }
+        }
+        if config.file.is_empty() && !config.is_secondary() {
+            return Err(format!("[zone {}] has no file or primary", section.name));
+        }
+        if config.is_secondary() && config.origin.is_empty() {
+            return Err("A secondary [zone] needs a name".to_owned());
+        }
+        if config.is_signed() && (!config.allow_transfer.is_empty() || !config.notify.is_empty() || config.transfer_key.is_some()) {
+            return Err(format!("[zone {}] is signed, so can't be transferred", section.name));
+        }
+        if config.is_secondary() && config.view.is_some() {
+            return Err(format!("[zone {}] is a secondary, so can't be in a view", section.name));
+        }
+        if !config.notify.is_empty() && config.view.is_some() {
+            return Err(format!("[zone {}] is in a view, so can't notify", section.name));
+        }
+        Ok(config)
+    }
+}
+
+fn parse_key(section: &Section) -> Result<TsigKey, String> {
+    let mut algorithm = "hmac-sha256".to_owned();
+    let mut secret = None;
+    for &(ref key, ref val) in &section.entries {
+        match key.as_str() {
+            "algorithm" => algorithm = val.clone(),
+            "secret" => secret = Some(parse_base64(val).ok_or(format!("[key {}] secret isn't base64", section.name))?),
+            _ => warn!("Ignoring unknown setting {:?} in [key {}]", key, section.name),
+        }
+    }
+    match secret {
+        Some(secret) if !section.name.is_empty() => TsigKey::new(&section.name, &algorithm, secret),
+        _ => Err(format!("[key {}] needs a name and a secret", section.name)),
+    }
+}
+
+fn parse_cache(section: &Section) -> Result<CacheConfig, String> {
+    let mut config = CacheConfig::default();
+    for &(ref key, ref val) in &section.entries {
+        let error = || format!("Bad {} {:?} in [cache]", key, val);
+        match key.as_str() {
+            "max-entries" => config.max_entries = val.parse().map_err(|_| error())?,
+            "max-bytes" => config.max_bytes = parse_size(val).ok_or_else(error)?,
+            "shards" => config.shards = val.parse().map_err(|_| error())?,
+            "serve-stale" => config.serve_stale = val.parse().map_err(|_| error())?,
+            "prefetch" => config.prefetch = val.parse().ok().filter(|percent| *percent <= 100).ok_or_else(error)?,
+            "prefetch-hits" => config.prefetch_hits = val.parse().map_err(|_| error())?,
+            "redis" => config.redis = Some(val.parse().map_err(|_| error())?),
+            "snapshot" => config.snapshot = Some(PathBuf::from(val)),
+            "snapshot-interval" => config.snapshot_interval = val.parse().map_err(|_| error())?,
+            _ if parse_ttl_limit(&mut config.ttl, key, val).is_some() => (),
+            _ if TTL_KEYS.contains(&key.as_str()) => return Err(error()),
+            _ => warn!("Ignoring unknown setting {:?} in [cache]", key),
+        }
+    }
+    if config.max_entries == 0 || config.max_bytes == 0 || config.shards == 0 || config.snapshot_interval == 0 {
+        return Err("[cache] limits must be more than 0".to_owned());
+    }
+    check_ttl_limits(&config.ttl, "[cache]")?;
+    Ok(config)
+}
+
+const TTL_KEYS: [&'static str; 4] = ["min-ttl", "max-ttl", "negative-min-ttl", "negative-max-ttl"];
+
+///Set the limit if the key is for one and the value's a number
+fn parse_ttl_limit(limits: &mut TtlLimits, key: &str, val: &str) -> Option<()> {
+    let ttl = val.parse().ok()?;
+    match key {
+        "min-ttl" => limits.min = ttl,
+        "max-ttl" => limits.max = ttl,
+        "negative-min-ttl" => limits.negative_min = ttl,
+        "negative-max-ttl" => limits.negative_max = ttl,
+        _ => return None,
+    }
+    Some(())
+}
+
+fn check_ttl_limits(limits: &TtlLimits, section: &str) -> Result<(), String> {
+    if limits.min > limits.max || limits.negative_min > limits.negative_max {
+        return Err(format!("{} has a min TTL over its max", section));
+    }
+    Ok(())
+}
+
+///A [ttl name] section. The TTL limits for the names under name, those it doesn't set as in [cache]
+fn parse_ttl_override(section: &Section, mut limits: TtlLimits) -> Result<(DnsName, TtlLimits), String> {
+    if section.name.is_empty() {
+        return Err("A [ttl] needs a name".to_owned());
+    }
+    for &(ref key, ref val) in &section.entries {
+        if parse_ttl_limit(&mut limits, key, val).is_none() {
+            return Err(format!("Bad {} {:?} in [ttl {}]", key, val, section.name));
+        }
+    }
+    check_ttl_limits(&limits, &format!("[ttl {}]", section.name))?;
+    Ok((DnsName::from_string(section.name.clone()).to_lowercase(), limits))
+}
+
+fn parse_admin(section: &Section) -> Result<Option<SocketAddr>, String> {
+    let mut listen = None;
+    for &(ref key, ref val) in &section.entries {
+        match key.as_str() {
+            "listen" => {
+                let addr = val.parse::<SocketAddr>().map_err(|_| format!("Bad listen {:?} in [admin]", val))?;
+                if !addr.ip().is_loopback() {
+                    return Err(format!("[admin] can only listen on a loopback address, not {}", addr));
+                }
+                listen = Some(addr);
+            }
+            _ => warn!("Ignoring unknown setting {:?} in [admin]", key),
+        }
+    }
+    Ok(listen)
+}
+
+///A number of bytes, optionally with a K, M or G suffix for KiB, MiB or GiB. E.g. 512K
+fn parse_size(val: &str) -> Option<usize> {
+    let val = val.trim();
+    let (digits, multiplier) = match val.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&val[..val.len() - 1], 1 << 10),
+        Some('M') => (&val[..val.len() - 1], 1 << 20),
+        Some('G') => (&val[..val.len() - 1], 1 << 30),
+        _ => (val, 1),
+    };
+    digits.parse::<usize>().ok().and_then(|n| n.checked_mul(multiplier))
+}
+
+///A [hosts] or [leases] section. Lease hostnames are under the lan domain, as OpenWRT has them, if not set
+fn parse_hosts(section: &Section) -> Result<HostsConfig, String> {
+    let mut config = HostsConfig {
+        files: Vec::new(),
+        ttl: 60,
+        lease_domain: if section.kind == "leases" { Some("lan".to_owned()) } else { None },
+    };
+    for &(ref key, ref val) in &section.entries {
+        match key.as_str() {
+            "file" => config.files.extend(parse_list(val)),
+            "ttl" => config.ttl = val.parse().map_err(|_| format!("Bad ttl {:?} in [{}]", val, section.kind))?,
+            "domain" if config.lease_domain.is_some() => config.lease_domain = Some(val.trim_end_matches('.').to_owned()),
+            _ => warn!("Ignoring unknown setting {:?} in [{}]", key, section.kind),
+        }
+    }
+    if config.files.is_empty() {
+        return Err(format!("[{}] needs a file", section.kind));
+    }
+    Ok(config)
+}
+
+fn parse_view(section: &Section) -> Result<ViewConfig, String> {
+    let mut view = ViewConfig::default();
+    view.name = section.name.clone();
+    for &(ref key, ref val) in &section.entries {
+        match key.as_str() {
+            "match-clients" => view.match_clients.extend(parse_networks(val)?),
+            "upstream" => view.upstream = Some(parse_server(val)?),
+            _ => warn!("Ignoring unknown setting {:?} in [view {}]", key, section.name),
+        }
+    }
+    if view.name.is_empty() || view.match_clients.is_empty() {
+        return Err(format!("[view {}] needs a name and match-clients", section.name));
+    }
+    Ok(view)
+}
+
+///
+///Keys trusted for the zone named, as the rdata of DS or DNSKEY records. E.g. "ds = 20326 8 2 E06D44B8..."
+///or "dnskey = 257 3 13 mdsswUyr...". Either can be given more than once, or read from a file
+///
+fn parse_trust_anchor(section: &Section) -> Result<TrustAnchorConfig, String> {
+    let mut anchor = TrustAnchor::new(DnsName::from_string(section.name.clone()));
+    let mut state_file = None;
+    for &(ref key, ref val) in &section.entries {
+        let parts = parse_list(val);
+        let error = || format!("Bad {} {:?} in [trust-anchor {}]", key, val, section.name);
+        match key.as_str() {
+            "ds" if parts.len() >= 4 => {
+                anchor.ds.push(Ds {
+                    key_tag: parts[0].parse().map_err(|_| error())?,
+                    algorithm: parts[1].parse().map_err(|_| error())?,
+                    digest_type: parts[2].parse().map_err(|_| error())?,
+                    digest: parse_hex(&parts[3..].concat()).ok_or_else(error)?,
+                })
+            }
+            "dnskey" if parts.len() >= 4 => {
+                anchor.keys.push(Dnskey {
+                    flags: parts[0].parse().map_err(|_| error())?,
+                    protocol: parts[1].parse().map_err(|_| error())?,
+                    algorithm: parts[2].parse().map_err(|_| error())?,
+                    public_key: parse_base64(&parts[3..].concat()).ok_or_else(error)?,
+                })
+            }
+            "ds" | "dnskey" => return Err(error()),
+            "file" => load_anchor_file(val, &mut anchor)?,
+            "state-file" => state_file = Some(val.clone()),
+            _ => warn!("Ignoring unknown setting {:?} in [trust-anchor {}]", key, section.name),
+        }
+    }
+    if section.name.is_empty() || (anchor.ds.is_empty() && anchor.keys.is_empty()) {
+        return Err(format!("[trust-anchor {}] needs a name and a ds, dnskey or file", section.name));
+    }
+    Ok(TrustAnchorConfig {
+        anchor: anchor,
+        state_file: state_file,
+    })
+}
+
+///
+///DS or DNSKEY records of the anchor's zone from a master file, as the root zone's are published.
+///E.g. ". IN DS 20326 8 2 E06D44B8...". Records of other types or names are ignored
+///
+fn load_anchor_file(path: &str, anchor: &mut TrustAnchor) -> Result<(), String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("Failed to read trust anchor file {:?}. {:?}", path, e))?;
+    //the records needn't have a TTL
+    let records = master::parse(&format!("$TTL 0\n{}", text), Some(&anchor.owner)).map_err(|e| format!("{}: {}", path, e))?;
+    let owner = anchor.owner.clone();
+    for rr in records.iter().filter(|rr| rr.name.eq_ignore_case(&owner)) {
+        match rr.atype {
+            RecordType::DS => anchor.ds.extend(Ds::from_rdata(&rr.rdata)),
+            RecordType::DNSKEY => anchor.keys.extend(Dnskey::from_rdata(&rr.rdata)),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+///NSEC3 iterations and salt as hex, or - for no salt, then optionally opt-out. E.g. "10 aabbccdd opt-out"
+fn parse_nsec3(val: &str) -> Result<Nsec3Params, String> {
+    let parts = parse_list(val);
+    let error = || format!("Bad nsec3 {:?}, expected iterations, a hex salt or - and optionally opt-out", val);
+    let opt_out = match parts.get(2).map(|s| s.as_str()) {
+        Some("opt-out") if parts.len() == 3 => true,
+        None if parts.len() == 2 => false,
+        _ => return Err(error()),
+    };
+    let iterations = parts[0].parse().map_err(|_| error())?;
+    let salt = if parts[1] == "-" {
+        Vec::new()
+    } else if parts[1].len() <= 510 {
+        parse_hex(&parts[1]).ok_or_else(error)?
+    } else {
+        return Err(error());
+    };
+    Ok(Nsec3Params {
+        iterations: iterations,
+        salt: salt,
+        opt_out: opt_out,
+    })
+}
+
+pub fn parse_hex(val: &str) -> Option<Vec<u8>> {
+    if val.len() % 2 != 0 || !val.is_ascii() {
+        return None;
+    }
+    (0..val.len()).step_by(2).map(|i| u8::from_str_radix(&val[i..i + 2], 16).ok()).collect()
+}
+
+///Standard base64, as keys are given by tsig-keygen and the like. RFC4648 4
+pub fn parse_base64(val: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let chars = val.trim_end_matches('=').as_bytes();
+    let mut bytes = Vec::with_capacity(chars.len() * 3 / 4);
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for c in chars {
+        bits = (bits << 6) | ALPHABET.iter().position(|a| a == c)? as u32;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+///The reverse of parse_base64, with padding
+pub fn to_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, b)| bits | (*b as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(bits >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+pub fn parse_list(val: &str) -> Vec<String> {
+    val.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+        .collect()
+}
+
+pub fn parse_bool(val: &str) -> Result<bool, String> {
+    match val.to_lowercase().as_str() {
+        "yes" | "true" | "on" => Ok(true),
+        "no" | "false" | "off" => Ok(false),
+        _ => Err(format!("{:?} is not yes or no", val)),
+    }
+}
+
+pub fn parse_networks(val: &str) -> Result<Vec<Cidr>, String> {
+    parse_list(val).iter().map(|s| Cidr::from_str(s)).collect()
+}
+
+///An address with an optional port. Port 53 if there isn't one
+pub fn parse_server(val: &str) -> Result<SocketAddr, String> {
+    SocketAddr::from_str(val)
+        .or_else(|_| IpAddr::from_str(val).map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("{:?} is not an address", val))
+}
+
+pub fn parse_servers(val: &str) -> Result<Vec<SocketAddr>, String> {
+    parse_list(val).iter().map(|s| parse_server(s)).collect()
+}
+
+struct Section {
+    kind: String,
+    name: String,
+    entries: Vec<(String, String)>,
+}
+
+impl Section {
+    fn parse_all(text: &str) -> Result<Vec<Section>, String> {
+        let mut sections = Vec::<Section>::new();
+        for (i, raw_line) in text.lines().enumerate() {
+            let line = raw_line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                let header = &line[1..line.len() - 1];
+                let mut parts = header.split_whitespace();
+                let kind = parts.next().unwrap_or_default().to_lowercase();
+                let name = parts.next().unwrap_or_default().to_owned();
+                sections.push(Section {
+                    kind: kind,
+                    name: name,
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or_default().trim().to_lowercase();
+            let val = match parts.next() {
+                Some(val) => val.trim().to_owned(),
+                None => return Err(format!("Line {}: expected key = value, got {:?}", i + 1, line)),
+            };
+            match sections.last_mut() {
+                Some(section) => section.entries.push((key, val)),
+                None => return Err(format!("Line {}: {:?} is not in a section", i + 1, key)),
+            }
+        }
+        Ok(sections)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Settings, TtlLimits, parse_base64, parse_size, to_base64};
+    use cache::{DEFAULT_MAX_ENTRIES, DEFAULT_SHARDS};
+    use dns::message::DnsName;
+    use std::path::PathBuf;
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_zone() {
+        let text = "# comment\n[zone example.org]\nfile = /tmp/example.org.zone\nallow-transfer = 127.0.0.1, 10.0.0.0/8\nnotify = 10.0.0.2 10.0.0.3:5353\n";
+        let settings = Settings::parse(text).unwrap();
+        assert_eq!(1, settings.zones.len());
+        let zone = &settings.zones[0];
+        assert_eq!("example.org", zone.origin);
+        assert_eq!("/tmp/example.org.zone", zone.file);
+        assert_eq!(2, zone.allow_transfer.len());
+        assert!(zone.allow_transfer[1].contains(&IpAddr::from_str("10.9.8.7").unwrap()));
+        assert_eq!(vec![53, 5353], zone.notify.iter().map(|addr| addr.port()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn parse_update_policy() {
+        let text = "[zone example.org]\nfile = x\nallow-update = 192.168.1.0/24\nupdate-names = *.lan.example.org, printer.example.org\n";
+        let zone = &Settings::parse(text).unwrap().zones[0];
+        assert!(zone.allow_update[0].contains(&IpAddr::from_str("192.168.1.20").unwrap()));
+        assert_eq!(vec!["*.lan.example.org", "printer.example.org"], zone.update_names);
+        assert!(Settings::parse("[zone example.org]\nfile = x\nallow-update = nope\n").is_err());
+    }
+
+    #[test]
+    fn parse_dnssec() {
+        let text = "[zone example.org]\nfile = x\nksk = k.pem\nzsk = z.pem\ndnssec-algorithm = ed25519\nnsec3 = 10 AABBccdd\n";
+        let zone = &Settings::parse(text).unwrap().zones[0];
+        assert!(zone.is_signed());
+        assert_eq!(Some("z.pem".to_owned()), zone.zsk);
+        assert_eq!(Some(15), zone.dnssec_algorithm);
+        let nsec3 = zone.nsec3.clone().unwrap();
+        assert_eq!((10, vec![0xaa, 0xbb, 0xcc, 0xdd], false), (nsec3.iterations, nsec3.salt, nsec3.opt_out));
+        assert!(Settings::parse("[zone example.org]\nfile = x\nnsec3 = 0 - opt-out\n").unwrap().zones[0].nsec3.as_ref().unwrap().opt_out);
+        assert!(Settings::parse("[zone example.org]\nfile = x\nnsec3 = 0 - opt-in\n").is_err());
+        assert!(Settings::parse("[zone example.org]\nfile = x\nnsec3 = 0 -\n").unwrap().zones[0].nsec3.as_ref().unwrap().salt.is_empty());
+        assert!(Settings::parse("[zone example.org]\nfile = x\nnsec3 = 0 abc\n").is_err());
+        assert!(Settings::parse("[zone example.org]\nfile = x\ndnssec-algorithm = rsamd5\n").is_err());
+        assert!(!Settings::parse("[zone example.org]\nfile = x\n").unwrap().zones[0].is_signed());
+        assert!(Settings::parse("[zone example.org]\nfile = x\nzsk = z.pem\nallow-transfer = 10.0.0.2\n").is_err());
+    }
+
+    #[test]
+    fn parse_reverse_ptr() {
+        assert!(Settings::parse("[zone lan]\nfile = x\nreverse-ptr = yes\n").unwrap().zones[0].reverse_ptr);
+        assert!(!Settings::parse("[zone lan]\nfile = x\nreverse-ptr = False\n").unwrap().zones[0].reverse_ptr);
+        assert!(!Settings::parse("[zone lan]\nfile = x\n").unwrap().zones[0].reverse_ptr);
+        assert!(Settings::parse("[zone lan]\nfile = x\nreverse-ptr = maybe\n").is_err());
+    }
+
+    #[test]
+    fn parse_trust_anchors() {
+        let text = "[trust-anchor .]\nds = 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D\n\
+                    [trust-anchor Example.org.]\ndnskey = 257 3 15 l02Woi0iS8Aa25FQ kUd9RMzZHJpBoRQwAQEX1SxZJA4=\n";
+        let anchors = Settings::parse(text).unwrap().trust_anchors.into_iter().map(|config| config.anchor).collect::<Vec<_>>();
+        assert!(anchors[0].owner.labels().is_empty());
+        assert_eq!((20326, 8, 2, 32), (anchors[0].ds[0].key_tag, anchors[0].ds[0].algorithm, anchors[0].ds[0].digest_type, anchors[0].ds[0].digest.len()));
+        assert_eq!("example.org", anchors[1].owner.to_string());
+        assert_eq!(3613, anchors[1].keys[0].key_tag());
+        assert!(Settings::parse("[trust-anchor .]\n").is_err());
+        assert!(Settings::parse("[trust-anchor .]\nds = 20326 8 2 E06\n").is_err());
+        assert!(Settings::parse("[trust-anchor .]\ndnskey = 257 3 15\n").is_err());
+    }
+
+    #[test]
+    fn parse_trust_anchor_file() {
+        let file = env::temp_dir().join("koala_settings_test.key");
+        File::create(&file)
+            .and_then(|mut f| {
+                f.write_all(b"; from the zone\n. IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D\n\
+                              . 172800 IN DNSKEY 257 3 15 l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=\nother. IN DS 1 8 2 00\n")
+            })
+            .unwrap();
+        let text = format!("[trust-anchor .]\nfile = {}\nstate-file = /tmp/root.state\n[negative-trust-anchor Broken.example.net]\n", file.display());
+        let settings = Settings::parse(&text).unwrap();
+        let config = &settings.trust_anchors[0];
+        assert_eq!((1, 1), (config.anchor.ds.len(), config.anchor.keys.len()));
+        assert_eq!(20326, config.anchor.ds[0].key_tag);
+        assert_eq!(Some("/tmp/root.state".to_owned()), config.state_file);
+        assert_eq!("broken.example.net", settings.negative_trust_anchors[0].to_string());
+        assert!(Settings::parse("[trust-anchor .]\nfile = /nonexistent\n").is_err());
+        assert!(Settings::parse("[negative-trust-anchor]\n").is_err());
+    }
+
+    #[test]
+    fn parse_keys() {
+        let text = "[zone example.org]\nfile = x\ntransfer-key = xfr.\nupdate-key = ddns\n\
+                    [key xfr]\nsecret = c2VjcmV0\n[key ddns]\nalgorithm = hmac-sha512\nsecret = YWI=\n";
+        let settings = Settings::parse(text).unwrap();
+        assert_eq!(2, settings.keys.len());
+        let zone = &settings.zones[0];
+        assert_eq!(settings.keys[0], zone.transfer_key.clone().unwrap());
+        assert_eq!("hmac-sha512", zone.update_key.as_ref().unwrap().algorithm.to_string());
+        assert_eq!(Some(b"secret".to_vec()), parse_base64("c2VjcmV0"));
+        assert_eq!(Some(b"ab".to_vec()), parse_base64("YWI="));
+        assert!(parse_base64("c2V*").is_none());
+        assert_eq!("YWI=", to_base64(b"ab"));
+        assert_eq!("c2VjcmV0Lg==", to_base64(b"secret."));
+
+        assert!(Settings::parse("[zone example.org]\nfile = x\ntransfer-key = nope\n").is_err());
+        assert!(Settings::parse("[key xfr]\nalgorithm = hmac-md5\nsecret = YWI=\n").is_err());
+        assert!(Settings::parse("[key xfr]\n").is_err());
+    }
+
+    #[test]
+    fn parse_secondary() {
+        let text = "[zone example.net]\nprimary = 10.0.0.53\n[zone example.com]\nprimary = 127.0.0.1:10053\n";
+        let settings = Settings::parse(text).unwrap();
+        assert!(settings.zones[0].is_secondary());
+        assert_eq!("10.0.0.53:53", settings.zones[0].primary.unwrap().to_string());
+        assert_eq!(10053, settings.zones[1].primary.unwrap().port());
+        assert!(Settings::parse("[zone example.net]\nprimary = nope\n").is_err());
+    }
+
+    #[test]
+    fn parse_views() {
+        let text = "[zone example.org]\nview = guest\nfile = guest.zone\n[zone example.org]\nfile = lan.zone\n\
+                    [view guest]\nmatch-clients = 192.168.2.0/24, fd00:2::/64\nupstream = 9.9.9.9\n[view iot]\nmatch-clients = 10.9.0.0/16\n";
+        let settings = Settings::parse(text).unwrap();
+        assert_eq!(vec!["lan.zone"], settings.zones.iter().map(|zone| zone.file.as_str()).collect::<Vec<_>>());
+        let guest = &settings.views[0];
+        assert_eq!("guest", guest.name);
+        assert!(guest.match_clients[0].contains(&IpAddr::from_str("192.168.2.7").unwrap()));
+        assert_eq!(2, guest.match_clients.len());
+        assert_eq!("9.9.9.9:53", guest.upstream.unwrap().to_string());
+        assert_eq!(vec!["guest.zone"], guest.zones.iter().map(|zone| zone.file.as_str()).collect::<Vec<_>>());
+        assert!(settings.views[1].upstream.is_none());
+        assert!(settings.views[1].zones.is_empty());
+
+        assert!(Settings::parse("[zone example.org]\nview = nope\nfile = x\n").is_err());
+        assert!(Settings::parse("[view guest]\n").is_err());
+        assert!(Settings::parse("[view guest]\nmatch-clients = nope\n").is_err());
+        assert!(Settings::parse("[view a]\nmatch-clients = 10.0.0.0/8\n[view a]\nmatch-clients = 10.0.0.0/8\n").is_err());
+        assert!(Settings::parse("[view a]\nmatch-clients = 10.0.0.0/8\n[zone example.net]\nview = a\nprimary = 10.0.0.53\n").is_err());
+        assert!(Settings::parse("[view a]\nmatch-clients = 10.0.0.0/8\n[zone example.net]\nview = a\nfile = x\nnotify = 10.0.0.2\n").is_err());
+    }
+
+    #[test]
+    fn parse_hosts() {
+        let settings = Settings::parse("[hosts]\nfile = /etc/hosts, /tmp/hosts/dhcp\n[hosts]\nfile = /tmp/more\nttl = 0\n").unwrap();
+        assert_eq!(vec!["/etc/hosts", "/tmp/hosts/dhcp"], settings.hosts[0].files);
+        assert_eq!((60, 0), (settings.hosts[0].ttl, settings.hosts[1].ttl));
+        assert!(Settings::parse("[hosts]\n").is_err());
+        assert!(Settings::parse("[hosts]\nfile = x\nttl = -1\n").is_err());
+        assert_eq!(None, settings.hosts[0].lease_domain);
+
+        let settings = Settings::parse("[leases]\nfile = /tmp/dhcp.leases\n[leases]\nfile = /tmp/odhcpd\ndomain = home.arpa.\n").unwrap();
+        assert_eq!(Some("lan".to_owned()), settings.hosts[0].lease_domain);
+        assert_eq!(Some("home.arpa".to_owned()), settings.hosts[1].lease_domain);
+        assert!(Settings::parse("[leases]\ndomain = lan\n").is_err());
+    }
+
+    #[test]
+    fn parse_cache() {
+        let settings = Settings::parse("[cache]\nmax-entries = 500\nmax-bytes = 2M\nserve-stale = 3600\n").unwrap();
+        assert_eq!((500, 2 * 1024 * 1024), (settings.cache.max_entries, settings.cache.max_bytes));
+        assert_eq!(3600, settings.cache.serve_stale);
+        assert_eq!(0, Settings::parse("").unwrap().cache.serve_stale);
+        let settings = Settings::parse("[cache]\nprefetch = 10\nprefetch-hits = 5\n").unwrap();
+        assert_eq!((10, 5), (settings.cache.prefetch, settings.cache.prefetch_hits));
+        assert!(Settings::parse("[cache]\nprefetch = 110\n").is_err());
+        assert_eq!(None, settings.cache.redis);
+        let settings = Settings::parse("[cache]\nredis = 127.0.0.1:6379\n").unwrap();
+        assert_eq!(Some("127.0.0.1:6379".parse().unwrap()), settings.cache.redis);
+        assert!(Settings::parse("[cache]\nredis = localhost\n").is_err());
+        let settings = Settings::parse("[cache]\nsnapshot = /var/lib/koala/cache\nsnapshot-interval = 60\n").unwrap();
+        assert_eq!(Some(PathBuf::from("/var/lib/koala/cache")), settings.cache.snapshot);
+        assert_eq!(60, settings.cache.snapshot_interval);
+        assert_eq!(300, Settings::parse("").unwrap().cache.snapshot_interval);
+        assert_eq!(Some(512 * 1024), parse_size("512k"));
+        assert_eq!(Some(1000), parse_size("1000"));
+        assert_eq!(None, parse_size("lots"));
+        assert_eq!(DEFAULT_MAX_ENTRIES, Settings::parse("").unwrap().cache.max_entries);
+        assert!(Settings::parse("[cache]\nmax-entries = 0\n").is_err());
+        assert_eq!(DEFAULT_SHARDS, Settings::parse("").unwrap().cache.shards);
+        assert_eq!(4, Settings::parse("[cache]\nshards = 4\n").unwrap().cache.shards);
+        assert!(Settings::parse("[cache]\nshards = 0\n").is_err());
+        assert!(Settings::parse("[cache]\nmax-bytes = 1T\n").is_err());
+    }
+
+    #[test]
+    fn parse_ttl() {
+        let text = "[ttl cdn.example.com]\nmin-ttl = 300\n\n\
+                    [cache]\nmin-ttl = 30\nmax-ttl = 86400\nnegative-max-ttl = 600\n\n\
+                    [ttl parental.example.net]\nmax-ttl = 60\nnegative-max-ttl = 60\n";
+        let cache = Settings::parse(text).unwrap().cache;
+        assert_eq!(TtlLimits { min: 30, max: 86400, negative_min: 0, negative_max: 600 }, cache.ttl);
+        let name = |name: &str| DnsName::from_string(name.to_owned());
+        assert_eq!(30, cache.ttl_limits(&name("www.example.org")).clamp(1, false));
+        assert_eq!(86400, cache.ttl_limits(&name("www.example.org")).clamp(604800, false));
+        assert_eq!(0, cache.ttl_limits(&name("www.example.org")).clamp(0, true));
+        assert_eq!(600, cache.ttl_limits(&name("www.example.org")).clamp(3600, true));
+        //what the section doesn't set comes from [cache], wherever that is
+        assert_eq!(TtlLimits { min: 300, max: 86400, negative_min: 0, negative_max: 600 }, cache.ttl_limits(&name("a.CDN.example.com")));
+        assert_eq!(60, cache.ttl_limits(&name("www.parental.example.net")).clamp(86400, false));
+        assert_eq!(TtlLimits::default(), Settings::parse("").unwrap().cache.ttl);
+
+        assert!(Settings::parse("[cache]\nmin-ttl = 600\nmax-ttl = 60\n").is_err());
+        assert!(Settings::parse("[cache]\nmax-ttl = week\n").is_err());
+        assert!(Settings::parse("[ttl example.com]\nmax = 60\n").is_err());
+        assert!(Settings::parse("[ttl]\nmax-ttl = 60\n").is_err());
+    }
+
+    #[test]
+    fn parse_admin() {
+        assert_eq!(None, Settings::parse("").unwrap().admin);
+        let settings = Settings::parse("[admin]\nlisten = 127.0.0.1:5380\n").unwrap();
+        assert_eq!(Some("127.0.0.1:5380".parse().unwrap()), settings.admin);
+        assert!(Settings::parse("[admin]\nlisten = [::1]:5380\n").is_ok());
+        assert!(Settings::parse("[admin]\nlisten = 0.0.0.0:5380\n").is_err());
+        assert!(Settings::parse("[admin]\nlisten = localhost\n").is_err());
+    }
+
+    #[test]
+    fn zone_needs_file() {
+        assert!(Settings::parse("[zone example.org]\n").is_err());
+    }
+
+    #[test]
+    fn entry_outside_section() {
+        assert!(Settings::parse("file = x\n").is_err());
+    }
+
+    #[test]
+    fn unknown_section() {
+        assert!(Settings::parse("[zoo example.org]\nfile = x\n").is_err());
+    }
+}
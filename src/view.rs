@@ -0,0 +1,179 @@
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use cache::{self, CacheStats};
+use cidr::Cidr;
+use dns::tsig::{Keyring, TsigKey};
+use request::base::RequestParams;
+use server_mio::{SharedCache, SharedZones};
+use settings::{CacheConfig, ViewConfig};
+use zone::ZoneStore;
+
+///
+///Zones, an upstream and a cache for the clients in some networks, so they can be answered
+///differently from everyone else. E.g. guests on a LAN, who shouldn't see its internal names
+///
+pub struct View {
+    pub name: String,
+    pub match_clients: Vec<Cidr>,
+    pub zones: SharedZones,
+    pub cache: SharedCache,
+    ///Where queries go that the view's zones and cache can't answer
+    pub params: RequestParams,
+}
+
+impl View {
+    pub fn new(config: &ViewConfig, keys: &[TsigKey], cache: &CacheConfig, params: RequestParams) -> View {
+        let mut zones = ZoneStore::load(&config.zones, "");
+        zones.keys = Keyring::new(keys.to_vec());
+        View {
+            name: config.name.clone(),
+            match_clients: config.match_clients.clone(),
+            zones: Arc::new(RwLock::new(zones)),
+            cache: cache::backend(cache, &config.name),
+            params: RequestParams {
+                timeout: params.timeout,
+                upstream_addr: config.upstream.unwrap_or(params.upstream_addr),
+            },
+        }
+    }
+
+    ///For clients in no other view. The zones that aren't in a view, and the server's upstream
+    pub fn default_for(zones: SharedZones, cache: SharedCache, params: RequestParams) -> View {
+        View {
+            name: "default".to_owned(),
+            match_clients: Vec::new(),
+            zones: zones,
+            cache: cache,
+            params: params,
+        }
+    }
+}
+
+///The configured views, in the order they're matched, and the default one
+pub struct Views {
+    views: Vec<View>,
+    default: View,
+}
+
+impl Views {
+    pub fn new(views: Vec<View>, default: View) -> Views {
+        Views {
+            views: views,
+            default: default,
+        }
+    }
+
+    ///The first view the client is in, or the default
+    pub fn find(&self, client: &IpAddr) -> &View {
+        self.views
+            .iter()
+            .find(|view| Cidr::any_contains(&view.match_clients, client))
+            .unwrap_or(&self.default)
+    }
+
+    pub fn default_view(&self) -> &View {
+        &self.default
+    }
+
+    ///Whether the client is in no configured view
+    pub fn in_default(&self, client: &IpAddr) -> bool {
+        !self.views.iter().any(|view| Cidr::any_contains(&view.match_clients, client))
+    }
+
+    ///The configured views, then the default
+    pub fn all(&self) -> impl Iterator<Item = &View> {
+        self.views.iter().chain(Some(&self.default))
+    }
+
+    ///The name and cache stats of each view, the default last
+    pub fn cache_stats(&self) -> Vec<(String, CacheStats)> {
+        self.all()
+            .map(|view| (view.name.clone(), view.cache.stats()))
+            .collect()
+    }
+
+    ///Save every view's cache, for those that have a snapshot file
+    pub fn save_snapshots(&self) {
+        for view in self.all() {
+            match view.cache.save_snapshot() {
+                Ok(0) => (),
+                Ok(count) => debug!("Saved {} cache entries for view {}", count, view.name),
+                Err(e) => error!("{}", e),
+            }
+        }
+    }
+
+    ///Reload changed zone files and the like, as is done for the default view's zones. Expire every view's cache
+    pub fn maintain(&self) {
+        for view in self.all() {
+            view.cache.remove_expired();
+        }
+        for view in &self.views {
+            match view.zones.write() {
+                Ok(mut zones) => {
+                    zones.reload_changed();
+                    zones.compact_journals();
+                    zones.refresh_signatures();
+                }
+                Err(e) => error!("Zone store lock poisoned for view {}. Error was {}", view.name, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{View, Views};
+    use std::net::{IpAddr, SocketAddr};
+    use std::str::FromStr;
+    use std::sync::{Arc, RwLock};
+    use cache::ShardedCache;
+    use request::base::RequestParams;
+    use settings::{CacheConfig, ViewConfig};
+    use zone::ZoneStore;
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    fn params() -> RequestParams {
+        RequestParams {
+            timeout: 200,
+            upstream_addr: SocketAddr::from_str("8.8.8.8:53").unwrap(),
+        }
+    }
+
+    fn view(name: &str, networks: &str, upstream: Option<&str>) -> View {
+        let mut config = ViewConfig::default();
+        config.name = name.to_owned();
+        config.match_clients = networks.split(',').map(|net| net.parse().unwrap()).collect();
+        config.upstream = upstream.map(|addr| SocketAddr::from_str(addr).unwrap());
+        View::new(&config, &[], &CacheConfig::default(), params())
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let default = View::default_for(Arc::new(RwLock::new(ZoneStore::default())), Arc::new(ShardedCache::new(&CacheConfig::default())), params());
+        let views = Views::new(vec![view("guest", "192.168.2.0/24,fd00:2::/64", Some("9.9.9.9:53")),
+                                    view("lan", "192.168.0.0/16", None)],
+                               default);
+        assert_eq!("guest", views.find(&ip("192.168.2.10")).name);
+        assert_eq!("guest", views.find(&ip("fd00:2::10")).name);
+        assert_eq!("lan", views.find(&ip("192.168.1.10")).name);
+        assert_eq!("default", views.find(&ip("10.0.0.1")).name);
+        assert_eq!("default", views.default_view().name);
+        assert!(views.in_default(&ip("10.0.0.1")));
+        assert!(!views.in_default(&ip("192.168.1.10")));
+    }
+
+    #[test]
+    fn own_upstream_and_cache() {
+        let guest = view("guest", "192.168.2.0/24", Some("9.9.9.9:53"));
+        let lan = view("lan", "192.168.0.0/16", None);
+        assert_eq!("9.9.9.9:53", guest.params.upstream_addr.to_string());
+        assert_eq!(params().upstream_addr, lan.params.upstream_addr);
+        assert_eq!(200, guest.params.timeout);
+        assert!(!Arc::ptr_eq(&guest.cache, &lan.cache));
+        assert!(!Arc::ptr_eq(&guest.zones, &lan.zones));
+    }
+}
@@ -0,0 +1,635 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use dns::dnssec::*;
+use dns::message::*;
+use dns::record::*;
+use settings::ZoneConfig;
+use zone::{Denial, Zone};
+
+///How long a signature is good for from when it's made
+pub const SIGNATURE_VALIDITY_SECS: u32 = 14 * 24 * 3600;
+///Signatures start before now, for validators with slow clocks
+const INCEPTION_OFFSET_SECS: u32 = 3600;
+///Signatures are remade when they have less than this left
+pub const RESIGN_BEFORE_SECS: u32 = 3 * 24 * 3600;
+
+///The RRSIGs over one RRset, kept with what they signed so they can be reused while it's unchanged
+struct Signature {
+    rrset: Vec<DnsAnswer>,
+    key_tags: Vec<u16>,
+    rrsigs: Vec<DnsAnswer>,
+    expiration: u32,
+}
+
+///
+///The DNSSEC records of a signed zone. The DNSKEY, NSEC, NSEC3 and NSEC3PARAM records, which aren't
+///in the zone's own records so they're never journaled, transferred or written to the master file,
+///and the RRSIGs over each authoritative RRset.
+///
+pub struct Signed {
+    records: BTreeMap<String, Vec<DnsAnswer>>,
+    signatures: HashMap<(String, u16), Signature>,
+    chain: Chain,
+    ///When the first signature needs remaking
+    pub refresh_at: u32,
+}
+
+///The owners of the NSEC or NSEC3 records in order, for finding the one that matches or covers a name
+enum Chain {
+    ///Names in canonical order
+    Nsec(Vec<DnsName>),
+    ///The hashes and the owner keys of their NSEC3s, in hash order
+    Nsec3(Nsec3Params, Vec<(Vec<u8>, String)>),
+}
+
+impl Signed {
+    ///The records made when signing with owner key. Keys are lowercase names, as in Zone
+    pub fn records_at(&self, key: &str) -> &[DnsAnswer] {
+        self.records.get(key).map_or(&[], |rrs| rrs.as_slice())
+    }
+
+    ///The RRSIGs over the RRset of the type at key. Empty if it isn't signed, e.g. it's glue
+    pub fn rrsigs(&self, key: &str, rtype: u16) -> &[DnsAnswer] {
+        self.signatures.get(&(key.to_owned(), rtype)).map_or(&[], |signature| signature.rrsigs.as_slice())
+    }
+
+    pub fn needs_refresh(&self, now: u32) -> bool {
+        !serial_lt(now, self.refresh_at)
+    }
+
+    ///
+    ///The records that prove the denial. For a delegation with a DS it's the DS. Without one it's
+    ///the proof there's no DS, so the child is known to be unsigned. RFC4035 3.1.3 and RFC5155 7.2
+    ///
+    pub fn proof(&self, zone: &Zone, denial: &Denial) -> Vec<DnsAnswer> {
+        if let Denial::Delegation(ref cut) = *denial {
+            let ds = zone.find(cut, RecordType::DS);
+            if !ds.is_empty() {
+                return ds;
+            }
+        }
+        let proof = match self.chain {
+            Chain::Nsec(ref names) => {
+                match *denial {
+                    //the NSEC covering the name, and the one covering the wildcard that could have matched it
+                    Denial::Name(ref name) => {
+                        let wildcard = wildcard(&closest_encloser(name, |name| zone.exists(name)));
+                        vec![self.nsec(names, name), self.nsec(names, &wildcard)]
+                    }
+                    //the NSEC at the name. An empty non-terminal hasn't one, so the NSEC covering it
+                    Denial::Type(ref name, _) | Denial::Delegation(ref name) => vec![self.nsec(names, name)],
+                }
+            }
+            Chain::Nsec3(ref params, ref hashes) => {
+                match *denial {
+                    Denial::Name(ref name) => {
+                        let mut proof = self.closest_encloser_proof(params, hashes, name);
+                        let encloser = closest_encloser(name, |name| self.nsec3_matching(params, hashes, name).is_some());
+                        proof.push(self.nsec3_covering(params, hashes, &wildcard(&encloser)));
+                        proof
+                    }
+                    //an opted out delegation has no NSEC3. The opt-out span covering it is the proof. RFC5155 7.2.4
+                    Denial::Type(ref name, _) | Denial::Delegation(ref name) => {
+                        match self.nsec3_matching(params, hashes, name) {
+                            Some(nsec3) => vec![Some(nsec3)],
+                            None => self.closest_encloser_proof(params, hashes, name),
+                        }
+                    }
+                }
+            }
+        };
+        let mut records = Vec::<DnsAnswer>::new();
+        for rr in proof.into_iter().filter_map(|rr| rr) {
+            if !records.contains(rr) {
+                records.push(rr.clone());
+            }
+        }
+        records
+    }
+
+    ///The NSEC at the name, or if there isn't one, the one before it in canonical order that covers it
+    fn nsec(&self, names: &[DnsName], name: &DnsName) -> Option<&DnsAnswer> {
+        let i = match names.binary_search_by(|owner| canonical_cmp(owner, name)) {
+            Ok(i) => i,
+            //the apex is first and every name in the zone is after it, so i > 0
+            Err(i) => i.saturating_sub(1),
+        };
+        let owner = names.get(i)?;
+        self.records_at(&Zone::key(owner)).iter().find(|rr| rr.atype == RecordType::NSEC)
+    }
+
+    fn nsec3_matching(&self, params: &Nsec3Params, hashes: &[(Vec<u8>, String)], name: &DnsName) -> Option<&DnsAnswer> {
+        let hash = params.hash(name);
+        let i = hashes.binary_search_by(|&(ref other, _)| other.cmp(&hash)).ok()?;
+        self.nsec3_at(&hashes[i].1)
+    }
+
+    ///The NSEC3 whose span has the name's hash. The last one wraps around to cover hashes before the first
+    fn nsec3_covering(&self, params: &Nsec3Params, hashes: &[(Vec<u8>, String)], name: &DnsName) -> Option<&DnsAnswer> {
+        let hash = params.hash(name);
+        match hashes.binary_search_by(|&(ref other, _)| other.cmp(&hash)) {
+            Ok(_) => None,
+            Err(0) => hashes.last().and_then(|&(_, ref key)| self.nsec3_at(key)),
+            Err(i) => self.nsec3_at(&hashes[i - 1].1),
+        }
+    }
+
+    fn nsec3_at(&self, key: &str) -> Option<&DnsAnswer> {
+        self.records_at(key).iter().find(|rr| rr.atype == RecordType::NSEC3)
+    }
+
+    ///
+    ///The NSEC3 matching the closest encloser of a name, the nearest ancestor with one, and the one
+    ///covering the next closer name, the ancestor a label below it. RFC5155 7.2.1
+    ///
+    fn closest_encloser_proof(&self,
+                              params: &Nsec3Params,
+                              hashes: &[(Vec<u8>, String)],
+                              name: &DnsName)
+                              -> Vec<Option<&DnsAnswer>> {
+        let encloser = closest_encloser(name, |name| self.nsec3_matching(params, hashes, name).is_some());
+        let next_closer = DnsName::from(name.labels()[name.labels().len() - encloser.labels().len() - 1..].to_vec());
+        vec![self.nsec3_matching(params, hashes, &encloser), self.nsec3_covering(params, hashes, &next_closer)]
+    }
+}
+
+///The nearest ancestor of name that exists. The apex always does
+fn closest_encloser<F: Fn(&DnsName) -> bool>(name: &DnsName, exists: F) -> DnsName {
+    let mut encloser = name.parent();
+    while let Some(candidate) = encloser {
+        if exists(&candidate) || candidate.labels().is_empty() {
+            return candidate;
+        }
+        encloser = candidate.parent();
+    }
+    DnsName::from(Vec::new())
+}
+
+fn wildcard(name: &DnsName) -> DnsName {
+    let mut labels = vec!["*".to_owned()];
+    labels.extend(name.labels().iter().cloned());
+    DnsName::from(labels)
+}
+
+///Types made when signing, that aren't kept in the zone's records
+pub fn is_generated(rtype: u16) -> bool {
+    match rtype {
+        RecordType::DNSKEY | RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3 | RecordType::NSEC3PARAM => true,
+        _ => false,
+    }
+}
+
+///The zone's keys from the files in its config. Missing files get new keys
+pub fn load_keys(config: &ZoneConfig) -> Result<Vec<Arc<SigningKey>>, String> {
+    let algorithm = config.dnssec_algorithm.unwrap_or(Algorithm::ECDSAP256SHA256);
+    let mut keys = Vec::new();
+    if let Some(ref path) = config.ksk {
+        keys.push(Arc::new(SigningKey::load(path, Dnskey::ZONE | Dnskey::SEP, algorithm)?));
+    }
+    if let Some(ref path) = config.zsk {
+        keys.push(Arc::new(SigningKey::load(path, Dnskey::ZONE, algorithm)?));
+    }
+    Ok(keys)
+}
+
+///
+///Sign the zone. Every authoritative RRset gets an RRSIG from each key, and the names are chained
+///with NSEC, or NSEC3 if the config has parameters for it. At a delegation only the DS is signed,
+///and glue below it isn't signed or chained. RFC4035 2
+///
+///Signatures in previous are reused for RRsets that haven't changed, unless they're due for remaking.
+///
+pub fn sign(zone: &Zone, keys: &[Arc<SigningKey>], previous: Option<&Signed>, now: u32) -> Result<Signed, String> {
+    let soa_rr = zone.soa_record().ok_or(format!("Zone {:?} has no SOA", zone.origin.to_string()))?;
+    let soa = Soa::from_rdata(&soa_rr.rdata).ok_or(format!("Zone {:?} has a bad SOA", zone.origin.to_string()))?;
+    if keys.is_empty() {
+        return Err(format!("Zone {:?} has no keys to sign with", zone.origin.to_string()));
+    }
+    //for the records we make. The negative TTL, as NSEC and NSEC3 are used for denial. RFC4034 4
+    let ttl = ::std::cmp::min(soa_rr.ttl, soa.minimum);
+    let origin_key = Zone::key(&zone.origin);
+
+    let mut records = BTreeMap::<String, Vec<DnsAnswer>>::new();
+    let dnskeys = keys.iter()
+                      .map(|key| DnsAnswer::with_rdata(zone.origin.clone(), RecordType::DNSKEY, RecordClass::IN, ttl, key.dnskey.to_rdata()))
+                      .collect::<Vec<_>>();
+    records.insert(origin_key.clone(), dnskeys);
+
+    //the names in the chain and the types there. Glue isn't in it
+    let mut names = Vec::<(DnsName, Vec<u16>)>::new();
+    for (key, rrs) in &zone.records {
+        let name = DnsName::from_string(key.clone());
+        if is_glue(zone, &name) {
+            continue;
+        }
+        let mut types = rrs.iter().map(|rr| rr.atype).collect::<Vec<_>>();
+        if *key == origin_key {
+            types.push(RecordType::DNSKEY);
+        }
+        types.sort();
+        types.dedup();
+        names.push((name, types));
+    }
+
+    let chain = match zone.config.nsec3 {
+        Some(ref params) => Chain::Nsec3(params.clone(), add_nsec3(zone, params, &names, ttl, &mut records)),
+        None => Chain::Nsec(add_nsec(&names, ttl, &mut records)),
+    };
+
+    let ksks = keys.iter().filter(|key| key.dnskey.is_sep()).collect::<Vec<_>>();
+    let zsks = keys.iter().filter(|key| !key.dnskey.is_sep()).collect::<Vec<_>>();
+    let all = keys.iter().collect::<Vec<_>>();
+    let inception = now.wrapping_sub(INCEPTION_OFFSET_SECS);
+    let expiration = now.wrapping_add(SIGNATURE_VALIDITY_SECS);
+
+    let mut signatures = HashMap::new();
+    let zone_rrs = zone.records.iter().filter(|&(key, _)| !is_glue(zone, &DnsName::from_string(key.clone())));
+    for (key, rrs) in zone_rrs.chain(records.iter()) {
+        let cut = *key != origin_key && rrs.iter().any(|rr| rr.atype == RecordType::NS);
+        for (rtype, rrset) in rrsets(rrs) {
+            //at a cut the NS belongs to the child. Only what the parent is authoritative for is signed
+            if cut && !(rtype == RecordType::DS || rtype == RecordType::NSEC) {
+                continue;
+            }
+            let signers = match rtype {
+                RecordType::DNSKEY if !ksks.is_empty() => &ksks,
+                RecordType::DNSKEY => &all,
+                _ if !zsks.is_empty() => &zsks,
+                _ => &all,
+            };
+            let key_tags = signers.iter().map(|key| key.tag()).collect::<Vec<_>>();
+            let reusable = previous.and_then(|previous| previous.signatures.get(&(key.clone(), rtype))).and_then(|old| {
+                let fresh = serial_lt(now.wrapping_add(RESIGN_BEFORE_SECS), old.expiration);
+                if fresh && old.key_tags == key_tags && same_rrset(&old.rrset, &rrset) {
+                    Some(Signature {
+                        rrset: old.rrset.clone(),
+                        key_tags: old.key_tags.clone(),
+                        rrsigs: old.rrsigs.clone(),
+                        expiration: old.expiration,
+                    })
+                } else {
+                    None
+                }
+            });
+            let signature = match reusable {
+                Some(signature) => signature,
+                None => {
+                    Signature {
+                        rrsigs: sign_rrset(&rrset, &zone.origin, signers, inception, expiration)?,
+                        rrset: rrset,
+                        key_tags: key_tags,
+                        expiration: expiration,
+                    }
+                }
+            };
+            signatures.insert((key.clone(), rtype), signature);
+        }
+    }
+
+    let refresh_at = signatures.values()
+                               .map(|signature| signature.expiration.wrapping_sub(RESIGN_BEFORE_SECS))
+                               .min_by_key(|at| at.wrapping_sub(now))
+                               .unwrap_or(expiration);
+    Ok(Signed {
+        records: records,
+        signatures: signatures,
+        chain: chain,
+        refresh_at: refresh_at,
+    })
+}
+
+///One RRSIG per key over the RRset. The owner's TTL is the RRSIG's and the original TTL. RFC4034 3
+pub fn sign_rrset(rrset: &[DnsAnswer],
+                  signer: &DnsName,
+                  keys: &[&Arc<SigningKey>],
+                  inception: u32,
+                  expiration: u32)
+                  -> Result<Vec<DnsAnswer>, String> {
+    let first = match rrset.first() {
+        Some(first) => first,
+        None => return Ok(Vec::new()),
+    };
+    let mut rrsigs = Vec::new();
+    for key in keys {
+        let mut rrsig = Rrsig {
+            type_covered: first.atype,
+            algorithm: key.dnskey.algorithm,
+            labels: rrsig_labels(&first.name),
+            original_ttl: first.ttl,
+            expiration: expiration,
+            inception: inception,
+            key_tag: key.tag(),
+            signer: signer.clone(),
+            signature: Vec::new(),
+        };
+        rrsig.signature = key.sign(&rrsig.signed_data(rrset))?;
+        rrsigs.push(DnsAnswer::with_rdata(first.name.clone(), RecordType::RRSIG, first.aclass, first.ttl, rrsig.to_rdata()));
+    }
+    Ok(rrsigs)
+}
+
+///
+///An NSEC at each name, pointing to the next in canonical order and from the last back to the apex.
+///Returns the names in order.
+///
+fn add_nsec(names: &[(DnsName, Vec<u16>)], ttl: u32, records: &mut BTreeMap<String, Vec<DnsAnswer>>) -> Vec<DnsName> {
+    let mut ordered = names.iter().collect::<Vec<_>>();
+    ordered.sort_by(|a, b| canonical_cmp(&a.0, &b.0));
+    for (i, &&(ref name, ref types)) in ordered.iter().enumerate() {
+        let next = &ordered[(i + 1) % ordered.len()].0;
+        let mut types = types.clone();
+        types.push(RecordType::NSEC);
+        types.push(RecordType::RRSIG);
+        let nsec = Nsec {
+            next: next.clone(),
+            types: types,
+        };
+        records.entry(Zone::key(name))
+               .or_insert_with(Vec::new)
+               .push(DnsAnswer::with_rdata(name.clone(), RecordType::NSEC, RecordClass::IN, ttl, nsec.to_rdata()));
+    }
+    ordered.into_iter().map(|&(ref name, _)| name.clone()).collect()
+}
+
+///
+///An NSEC3 at the hash of each name, and of the empty non-terminals between them and the apex,
+///each pointing to the next hash. With opt-out, delegations without a DS are left out. The apex
+///gets the NSEC3PARAM. Returns the hashes in order, with the owner keys of their NSEC3s. RFC5155 7.1
+///
+fn add_nsec3(zone: &Zone,
+             params: &Nsec3Params,
+             names: &[(DnsName, Vec<u16>)],
+             ttl: u32,
+             records: &mut BTreeMap<String, Vec<DnsAnswer>>)
+             -> Vec<(Vec<u8>, String)> {
+    let mut all = names.iter().map(|&(ref name, ref types)| (Zone::key(name), types.clone())).collect::<BTreeMap<_, _>>();
+    for &(ref name, _) in names {
+        let mut parent = name.parent();
+        while let Some(name) = parent {
+            if name.labels().len() <= zone.origin.labels().len() {
+                break;
+            }
+            all.entry(Zone::key(&name)).or_insert_with(Vec::new);
+            parent = name.parent();
+        }
+    }
+    let origin_key = Zone::key(&zone.origin);
+    if params.opt_out {
+        all.retain(|key, types| *key == origin_key || !types.contains(&RecordType::NS) || types.contains(&RecordType::DS));
+    }
+    let mut hashed = all.into_iter()
+                        .map(|(key, mut types)| {
+                            let name = DnsName::from_string(key.clone());
+                            let cut = key != origin_key && types.contains(&RecordType::NS);
+                            //RRSIG if anything at the name is signed. At a cut without a DS nothing is
+                            if !types.is_empty() && (!cut || types.contains(&RecordType::DS)) {
+                                types.push(RecordType::RRSIG);
+                            }
+                            if key == origin_key {
+                                types.push(RecordType::NSEC3PARAM);
+                            }
+                            (params.hash(&name), types)
+                        })
+                        .collect::<Vec<_>>();
+    hashed.sort();
+    let mut owners = Vec::new();
+    for i in 0..hashed.len() {
+        let nsec3 = Nsec3 {
+            flags: if params.opt_out { Nsec3Params::OPT_OUT } else { 0 },
+            iterations: params.iterations,
+            salt: params.salt.clone(),
+            next_hashed: hashed[(i + 1) % hashed.len()].0.clone(),
+            types: hashed[i].1.clone(),
+        };
+        let owner = nsec3_owner(&hashed[i].0, &zone.origin);
+        owners.push((hashed[i].0.clone(), Zone::key(&owner)));
+        records.entry(Zone::key(&owner))
+               .or_insert_with(Vec::new)
+               .push(DnsAnswer::with_rdata(owner, RecordType::NSEC3, RecordClass::IN, ttl, nsec3.to_rdata()));
+    }
+    //a TTL of 0 so it isn't cached. RFC5155 4
+    records.entry(origin_key)
+           .or_insert_with(Vec::new)
+           .push(DnsAnswer::with_rdata(zone.origin.clone(), RecordType::NSEC3PARAM, RecordClass::IN, 0, params.to_rdata()));
+    owners
+}
+
+///A name below a zone cut. The parent only has its A and AAAA to help find the child's servers
+fn is_glue(zone: &Zone, name: &DnsName) -> bool {
+    match name.parent() {
+        Some(ref parent) if parent.labels().len() > zone.origin.labels().len() => zone.delegation(parent).is_some(),
+        _ => false,
+    }
+}
+
+///The records at a name grouped by type, in type order
+fn rrsets(rrs: &[DnsAnswer]) -> BTreeMap<u16, Vec<DnsAnswer>> {
+    let mut rrsets = BTreeMap::<u16, Vec<DnsAnswer>>::new();
+    for rr in rrs {
+        rrsets.entry(rr.atype).or_insert_with(Vec::new).push(rr.clone());
+    }
+    rrsets
+}
+
+fn same_rrset(a: &[DnsAnswer], b: &[DnsAnswer]) -> bool {
+    a.len() == b.len() && a.iter().all(|rr| b.iter().any(|other| rr.same_rr(other) && rr.ttl == other.ttl))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use zone::{Denial, Zone};
+    use zone::test::{test_zone, zone_with};
+
+    fn name(s: &str) -> DnsName {
+        DnsName::from_string(s.to_owned())
+    }
+
+    fn keys() -> Vec<Arc<SigningKey>> {
+        vec![Arc::new(SigningKey::generate(Algorithm::ED25519, Dnskey::ZONE | Dnskey::SEP).unwrap().0),
+             Arc::new(SigningKey::generate(Algorithm::ECDSAP256SHA256, Dnskey::ZONE).unwrap().0)]
+    }
+
+    const NOW: u32 = 1_500_000_000;
+
+    fn verify(zone: &Zone, signed: &Signed, keys: &[Arc<SigningKey>], key: &str, rrset: &[DnsAnswer]) {
+        let rrsigs = signed.rrsigs(key, rrset[0].atype);
+        assert_eq!(1, rrsigs.len(), "{} {}", key, rrset[0].atype);
+        let rrsig = Rrsig::from_rdata(&rrsigs[0].rdata).unwrap();
+        assert_eq!(zone.origin, rrsig.signer);
+        assert!(rrsig.inception < NOW && NOW < rrsig.expiration);
+        let signer = keys.iter().find(|k| k.tag() == rrsig.key_tag).unwrap();
+        //KSKs sign the DNSKEYs and ZSKs everything else
+        assert_eq!(rrset[0].atype == RecordType::DNSKEY, signer.dnskey.is_sep());
+        assert!(signer.dnskey.verify(&rrsig.signed_data(rrset), &rrsig.signature));
+    }
+
+    #[test]
+    fn signs_with_nsec() {
+        let zone = test_zone();
+        let keys = keys();
+        let signed = sign(&zone, &keys, None, NOW).unwrap();
+        for (key, rrs) in zone.records.iter().chain(signed.records.iter()) {
+            for (rtype, rrset) in rrsets(rrs) {
+                match key.as_str() {
+                    "ns.sub.example.org" => assert!(signed.rrsigs(key, rtype).is_empty()),
+                    "sub.example.org" if rtype == RecordType::NS => assert!(signed.rrsigs(key, rtype).is_empty()),
+                    _ => verify(&zone, &signed, &keys, key, &rrset),
+                }
+            }
+        }
+        assert_eq!(2, signed.records_at("example.org").iter().filter(|rr| rr.atype == RecordType::DNSKEY).count());
+
+        //the chain in canonical order, skipping the glue and back to the apex
+        let mut name = zone.origin.clone();
+        let mut chain = Vec::new();
+        loop {
+            let nsec = signed.records_at(&Zone::key(&name)).iter().find(|rr| rr.atype == RecordType::NSEC).unwrap();
+            let nsec = Nsec::from_rdata(&nsec.rdata).unwrap();
+            chain.push((name.to_string(), nsec.types.clone()));
+            name = nsec.next;
+            if name == zone.origin {
+                break;
+            }
+        }
+        let names = chain.iter().map(|&(ref name, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["example.org", "alias.example.org", "a.b.example.org", "ext.example.org", "ns1.example.org",
+                        "sub.example.org", "www.example.org"],
+                   names);
+        assert_eq!(vec![RecordType::NS, RecordType::SOA, RecordType::RRSIG, RecordType::NSEC, RecordType::DNSKEY], chain[0].1);
+        assert_eq!(vec![RecordType::NS, RecordType::RRSIG, RecordType::NSEC], chain[5].1);
+    }
+
+    #[test]
+    fn signs_with_nsec3() {
+        let mut zone = test_zone();
+        let params = Nsec3Params {
+            iterations: 1,
+            salt: vec![0xab],
+            opt_out: false,
+        };
+        zone.config.nsec3 = Some(params.clone());
+        let keys = keys();
+        let signed = sign(&zone, &keys, None, NOW).unwrap();
+        assert!(signed.records_at("example.org").iter().all(|rr| rr.atype != RecordType::NSEC));
+        assert_eq!(1, signed.records_at("example.org").iter().filter(|rr| rr.atype == RecordType::NSEC3PARAM).count());
+
+        //b.example.org is an empty non-terminal, so it's there with no types
+        let b = Zone::key(&nsec3_owner(&params.hash(&name("b.example.org")), &zone.origin));
+        let nsec3 = Nsec3::from_rdata(&signed.records_at(&b)[0].rdata).unwrap();
+        assert!(nsec3.types.is_empty());
+        verify(&zone, &signed, &keys, &b, signed.records_at(&b));
+
+        let nsec3s = signed.records.values().flat_map(|rrs| rrs.iter()).filter(|rr| rr.atype == RecordType::NSEC3).count();
+        assert_eq!(8, nsec3s);
+        let glue = Zone::key(&nsec3_owner(&params.hash(&name("ns.sub.example.org")), &zone.origin));
+        assert!(signed.records_at(&glue).is_empty());
+    }
+
+    #[test]
+    fn reuses_signatures() {
+        let zone = test_zone();
+        let keys = keys();
+        let first = sign(&zone, &keys, None, NOW).unwrap();
+        assert_eq!(NOW + SIGNATURE_VALIDITY_SECS - RESIGN_BEFORE_SECS, first.refresh_at);
+        assert!(!first.needs_refresh(NOW + 60));
+        assert!(first.needs_refresh(first.refresh_at));
+
+        let mut changed = zone.clone();
+        changed.add(DnsAnswer::with_rdata(name("www.example.org"), RecordType::A, RecordClass::IN, 3600, vec![10, 0, 0, 4]));
+        let second = sign(&changed, &keys, Some(&first), NOW + 60).unwrap();
+        let rdata = |signed: &Signed, key: &str, rtype: u16| signed.rrsigs(key, rtype)[0].rdata.clone();
+        assert_eq!(rdata(&first, "ns1.example.org", RecordType::A), rdata(&second, "ns1.example.org", RecordType::A));
+        assert!(rdata(&first, "www.example.org", RecordType::A) != rdata(&second, "www.example.org", RecordType::A));
+
+        //near expiry everything is remade
+        let third = sign(&changed, &keys, Some(&second), first.refresh_at).unwrap();
+        assert!(rdata(&second, "ns1.example.org", RecordType::A) != rdata(&third, "ns1.example.org", RecordType::A));
+        assert!(sign(&zone, &[], None, NOW).is_err());
+    }
+
+    fn nsec_next(rr: &DnsAnswer) -> String {
+        Nsec::from_rdata(&rr.rdata).unwrap().next.to_string()
+    }
+
+    #[test]
+    fn nsec_proofs() {
+        let zone = test_zone();
+        let signed = sign(&zone, &keys(), None, NOW).unwrap();
+        let owners = |denial: Denial| {
+            signed.proof(&zone, &denial).iter().map(|rr| (rr.name.to_string(), nsec_next(rr))).collect::<Vec<_>>()
+        };
+        let pair = |owner: &str, next: &str| (owner.to_owned(), next.to_owned());
+        //ext < nope < ns1, and *.example.org is before everything but the apex
+        assert_eq!(vec![pair("ext.example.org", "ns1.example.org"), pair("example.org", "alias.example.org")],
+                   owners(Denial::Name(name("nope.example.org"))));
+        assert_eq!(vec![pair("www.example.org", "example.org")], owners(Denial::Type(name("www.example.org"), RecordType::MX)));
+        //the empty non-terminal b.example.org is covered by the NSEC before it
+        assert_eq!(vec![pair("alias.example.org", "a.b.example.org")], owners(Denial::Type(name("b.example.org"), RecordType::A)));
+        assert_eq!(vec![pair("sub.example.org", "www.example.org")], owners(Denial::Delegation(name("sub.example.org"))));
+    }
+
+    ///True if the NSEC3 matches, or with covers, covers the hash of name
+    fn nsec3_has(rr: &DnsAnswer, params: &Nsec3Params, name: &DnsName, covers: bool) -> bool {
+        let owner = rr.name.labels()[0].clone();
+        let next = base32hex(&Nsec3::from_rdata(&rr.rdata).unwrap().next_hashed);
+        let hash = base32hex(&params.hash(name));
+        //base32hex sorts the same as the hashes
+        match covers {
+            false => owner == hash,
+            true if owner < next => owner < hash && hash < next,
+            true => hash > owner || hash < next,
+        }
+    }
+
+    #[test]
+    fn nsec3_proofs() {
+        let mut zone = zone_with(1, "www A 10.0.0.2\nsub NS ns.sub\nns.sub A 10.0.1.1\nsec NS ns.sec\nsec DS 60485 5 1 2BB183AF\n");
+        let params = Nsec3Params {
+            iterations: 2,
+            salt: vec![0xca, 0xfe],
+            opt_out: true,
+        };
+        zone.config.nsec3 = Some(params.clone());
+        let signed = sign(&zone, &keys(), None, NOW).unwrap();
+        let nsec3s = signed.records.values().flat_map(|rrs| rrs.iter()).filter(|rr| rr.atype == RecordType::NSEC3).collect::<Vec<_>>();
+        //the apex, ns1, www and sec. sub has no DS so it's opted out
+        assert_eq!(4, nsec3s.len());
+        assert!(nsec3s.iter().all(|rr| Nsec3::from_rdata(&rr.rdata).unwrap().flags == Nsec3Params::OPT_OUT));
+
+        //closest encloser the apex, the next closer name nope.example.org, and the wildcard under the apex
+        let proof = signed.proof(&zone, &Denial::Name(name("x.nope.example.org")));
+        assert!(proof.iter().any(|rr| nsec3_has(rr, &params, &zone.origin, false)));
+        assert!(proof.iter().any(|rr| nsec3_has(rr, &params, &name("nope.example.org"), true)));
+        assert!(proof.iter().any(|rr| nsec3_has(rr, &params, &name("*.example.org"), true)));
+        assert!(proof.len() <= 3);
+
+        let proof = signed.proof(&zone, &Denial::Type(name("www.example.org"), RecordType::MX));
+        assert_eq!(1, proof.len());
+        assert!(nsec3_has(&proof[0], &params, &name("www.example.org"), false));
+
+        //an opted out delegation is covered by an opt-out span
+        let proof = signed.proof(&zone, &Denial::Delegation(name("sub.example.org")));
+        assert!(proof.iter().any(|rr| nsec3_has(rr, &params, &zone.origin, false)));
+        assert!(proof.iter().any(|rr| nsec3_has(rr, &params, &name("sub.example.org"), true)));
+
+        //a signed delegation has its DS instead
+        let proof = signed.proof(&zone, &Denial::Delegation(name("sec.example.org")));
+        assert_eq!(vec![RecordType::DS], proof.iter().map(|rr| rr.atype).collect::<Vec<_>>());
+        assert!(!signed.rrsigs("sec.example.org", RecordType::DS).is_empty());
+    }
+
+    #[test]
+    fn ds_at_cut() {
+        let zone = zone_with(1, "sub NS ns.sub\nns.sub A 10.0.1.1\nsec NS ns.sec\nsec DS 60485 5 1 2BB183AF\n");
+        let result = zone.lookup(&name("sec.example.org"), RecordType::DS);
+        assert!(result.aa);
+        assert_eq!(RecordType::DS, result.answers[0].atype);
+        let result = zone.lookup(&name("sub.example.org"), RecordType::DS);
+        assert!(result.aa && result.answers.is_empty());
+        assert_eq!(Some(Denial::Type(name("sub.example.org"), RecordType::DS)), result.denial);
+        let result = zone.lookup(&name("host.sec.example.org"), RecordType::DS);
+        assert_eq!(Some(Denial::Delegation(name("sec.example.org"))), result.denial);
+    }
+}
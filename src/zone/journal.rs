@@ -0,0 +1,382 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use dns::message::*;
+use dns::record::*;
+use zone::Zone;
+use zone::transfer::MAX_MESSAGE_LEN;
+
+///How many versions of a zone IXFR clients can catch up from
+const MAX_DIFFS: usize = 100;
+///Length and checksum before each diff in a journal file
+const ENTRY_HEADER_LEN: usize = 8;
+
+///
+///The changes between two versions (SOA serials) of a zone. RFC1995 4
+///The old SOA is deleted with the deleted records and the new one added with the added.
+///
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub struct Diff {
+    pub from: DnsAnswer,
+    pub to: DnsAnswer,
+    pub deleted: Vec<DnsAnswer>,
+    pub added: Vec<DnsAnswer>,
+}
+
+impl Diff {
+    ///None if either zone has no SOA
+    pub fn between(old: &Zone, new: &Zone) -> Option<Diff> {
+        let (from, to) = match (old.soa_record(), new.soa_record()) {
+            (Some(from), Some(to)) => (from.clone(), to.clone()),
+            _ => return None,
+        };
+        let old_records = old.records().into_iter().collect::<HashSet<_>>();
+        let new_records = new.records().into_iter().collect::<HashSet<_>>();
+        Some(Diff {
+            from: from,
+            to: to,
+            deleted: old_records.difference(&new_records).map(|&rr| rr.clone()).collect(),
+            added: new_records.difference(&old_records).map(|&rr| rr.clone()).collect(),
+        })
+    }
+
+    ///The diffs in the answers of an incremental IXFR response. The current SOA, then for each
+    ///version, the old SOA, deleted, new SOA, added, and the current SOA again. RFC1995 4
+    pub fn from_ixfr(answers: &[DnsAnswer]) -> Result<Vec<Diff>, String> {
+        if answers.len() < 2 {
+            return Err("IXFR response too short".to_owned());
+        }
+        let mut diffs = Vec::<Diff>::new();
+        let mut deleting = false;
+        for rr in &answers[1..answers.len() - 1] {
+            if rr.atype == RecordType::SOA {
+                deleting = !deleting;
+                if deleting {
+                    diffs.push(Diff {
+                        from: rr.clone(),
+                        to: rr.clone(),
+                        deleted: Vec::new(),
+                        added: Vec::new(),
+                    });
+                } else if let Some(diff) = diffs.last_mut() {
+                    diff.to = rr.clone();
+                }
+                continue;
+            }
+            match diffs.last_mut() {
+                Some(diff) if deleting => diff.deleted.push(rr.clone()),
+                Some(diff) => diff.added.push(rr.clone()),
+                None => return Err("IXFR response doesn't start with an SOA".to_owned()),
+            }
+        }
+        if deleting {
+            return Err("IXFR response ends part way through a diff".to_owned());
+        }
+        Ok(diffs)
+    }
+
+    ///A diff from its records, in the order records() gives them
+    pub fn from_records(records: &[DnsAnswer]) -> Result<Diff, String> {
+        if records.is_empty() {
+            return Err("No records for a diff".to_owned());
+        }
+        //from_ixfr skips the current SOA either side of the diffs
+        let mut diffs = Self::from_ixfr(&[&records[..1], records, &records[..1]].concat())?;
+        match (diffs.pop(), diffs.is_empty()) {
+            (Some(diff), true) => Ok(diff),
+            _ => Err(format!("Expected the records of one diff. Got {}", records.len())),
+        }
+    }
+
+    pub fn from_serial(&self) -> u32 {
+        Soa::from_rdata(&self.from.rdata).map_or(0, |soa| soa.serial)
+    }
+
+    pub fn to_serial(&self) -> u32 {
+        Soa::from_rdata(&self.to.rdata).map_or(0, |soa| soa.serial)
+    }
+
+    ///In IXFR order. Old SOA, deleted, new SOA, added
+    pub fn records(&self) -> Vec<DnsAnswer> {
+        let mut records = vec![self.from.clone()];
+        records.extend(self.deleted.iter().cloned());
+        records.push(self.to.clone());
+        records.extend(self.added.iter().cloned());
+        records
+    }
+}
+
+///The most recent diffs of a zone, oldest first
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Default)]
+pub struct Journal {
+    diffs: VecDeque<Diff>,
+}
+
+impl Journal {
+    pub fn push(&mut self, diff: Diff) {
+        debug!("Journaled serial {} -> {}. {} deleted {} added",
+               diff.from_serial(),
+               diff.to_serial(),
+               diff.deleted.len(),
+               diff.added.len());
+        self.diffs.push_back(diff);
+        while self.diffs.len() > MAX_DIFFS {
+            self.diffs.pop_front();
+        }
+    }
+
+    ///The diffs that take a zone from serial to the latest version. None if serial is older
+    ///than the journal or not a version we had
+    pub fn since(&self, serial: u32) -> Option<Vec<&Diff>> {
+        let start = self.diffs.iter().position(|diff| diff.from_serial() == serial)?;
+        let mut chain = Vec::<&Diff>::new();
+        for diff in self.diffs.iter().skip(start) {
+            if let Some(prev) = chain.last() {
+                if prev.to_serial() != diff.from_serial() {
+                    warn!("Gap in journal between serials {} and {}", prev.to_serial(), diff.from_serial());
+                    return None;
+                }
+            }
+            chain.push(diff);
+        }
+        Some(chain)
+    }
+
+    pub fn len(&self) -> usize {
+        self.diffs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.diffs.is_empty()
+    }
+}
+
+///
+///The diffs made to a zone at runtime, appended to a file beside its master file so they
+///survive a restart. Each is written and synced before the change is made, as its length, a
+///checksum and the records as a DNS message. A torn write at the end, from losing power part way
+///through, fails the checksum and is dropped.
+///
+pub struct JournalFile {
+    path: String,
+}
+
+impl JournalFile {
+    pub fn new(path: String) -> JournalFile {
+        JournalFile { path: path }
+    }
+
+    ///The journal of the zone in the master file
+    pub fn for_master_file(file: &str) -> JournalFile {
+        Self::new(format!("{}.jnl", file))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    ///A diff too big for one message is refused rather than written cut short, as the checksum
+    ///would pass and the records left out would come back as garbage when it's replayed
+    pub fn append(&self, diff: &Diff) -> Result<(), String> {
+        let mut msg = DnsMessage::new_query(DnsHeader::new_query(0), Vec::new());
+        msg.answers = diff.records();
+        let payload = msg.to_bytes_max(MAX_MESSAGE_LEN);
+        //names aren't compressed, so it's the header then each record in full
+        let expected = 12 + msg.answers.iter().map(DnsAnswer::wire_len).sum::<usize>();
+        if expected > MAX_MESSAGE_LEN || payload.len() != expected {
+            return Err(format!("Change of {} records is too large for journal {:?}", msg.answers.len(), self.path));
+        }
+        let mut entry = u32_bytes(payload.len() as u32).to_vec();
+        entry.extend(u32_bytes(checksum(&payload)).iter());
+        entry.extend(payload);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(&entry).and_then(|_| file.sync_data()))
+            .map_err(|e| format!("Failed to append to journal {:?}. {:?}", self.path, e))
+    }
+
+    ///The diffs in the file, oldest first. An incomplete or corrupt entry and any after it are
+    ///cut off, so later appends follow the last good one
+    pub fn read(&self) -> Result<Vec<Diff>, String> {
+        if !Path::new(&self.path).exists() {
+            return Ok(Vec::new());
+        }
+        let mut bytes = Vec::new();
+        File::open(&self.path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(|e| format!("Failed to read journal {:?}. {:?}", self.path, e))?;
+        let mut diffs = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            match Self::entry(&bytes[pos..]) {
+                Some((diff, len)) => {
+                    diffs.push(diff);
+                    pos += len;
+                }
+                None => {
+                    warn!("Journal {:?} has a damaged entry at byte {}. Dropping the last {} bytes",
+                          self.path,
+                          pos,
+                          bytes.len() - pos);
+                    self.truncate(pos as u64)?;
+                    break;
+                }
+            }
+        }
+        Ok(diffs)
+    }
+
+    ///The diff at the start of bytes and the length of its entry. None if it's incomplete or damaged
+    fn entry(bytes: &[u8]) -> Option<(Diff, usize)> {
+        if bytes.len() < ENTRY_HEADER_LEN {
+            return None;
+        }
+        let len = read_u32(&bytes[0..4]) as usize;
+        let payload = bytes.get(ENTRY_HEADER_LEN..ENTRY_HEADER_LEN + len)?;
+        if checksum(payload) != read_u32(&bytes[4..8]) {
+            return None;
+        }
+        let msg = DnsMessage::parse(payload);
+        Diff::from_records(&msg.answers).ok().map(|diff| (diff, ENTRY_HEADER_LEN + len))
+    }
+
+    ///Empty the journal, once its diffs are in the master file
+    pub fn clear(&self) -> Result<(), String> {
+        if Path::new(&self.path).exists() {
+            self.truncate(0)?;
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), String> {
+        OpenOptions::new()
+            .write(true)
+            .open(&self.path)
+            .and_then(|file| file.set_len(len).and_then(|_| file.sync_all()))
+            .map_err(|e| format!("Failed to truncate journal {:?}. {:?}", self.path, e))
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().take(4).fold(0, |val, &b| (val << 8) + b as u32)
+}
+
+///32 bit FNV-1a. Enough to tell a torn or garbled entry from a whole one
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x01000193))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Diff, Journal, JournalFile, MAX_DIFFS};
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use dns::record::*;
+    use zone::test::zone_with;
+
+    #[test]
+    fn between() {
+        let old = zone_with(1, "www A 10.0.0.2\n");
+        let new = zone_with(2, "www A 10.0.0.3\nmail A 10.0.0.4\n");
+        let diff = Diff::between(&old, &new).unwrap();
+        assert_eq!(1, diff.from_serial());
+        assert_eq!(2, diff.to_serial());
+        assert_eq!(1, diff.deleted.len());
+        assert_eq!(vec![10, 0, 0, 2], diff.deleted[0].rdata);
+        assert_eq!(2, diff.added.len());
+        let records = diff.records();
+        assert_eq!(RecordType::SOA, records[0].atype);
+        assert_eq!(RecordType::SOA, records[2].atype);
+    }
+
+    #[test]
+    fn from_ixfr() {
+        let zones = vec![zone_with(1, ""), zone_with(2, "a A 1.1.1.1\n"), zone_with(3, "b A 1.1.1.1\n")];
+        let diffs = vec![Diff::between(&zones[0], &zones[1]).unwrap(), Diff::between(&zones[1], &zones[2]).unwrap()];
+        let soa = zones[2].soa_record().unwrap().clone();
+        let mut answers = vec![soa.clone()];
+        for diff in &diffs {
+            answers.extend(diff.records());
+        }
+        answers.push(soa);
+        assert_eq!(diffs, Diff::from_ixfr(&answers).unwrap());
+
+        answers.remove(1);
+        assert!(Diff::from_ixfr(&answers).is_err());
+    }
+
+    #[test]
+    fn since() {
+        let zones = vec![zone_with(1, ""), zone_with(2, "a A 1.1.1.1\n"), zone_with(3, "b A 1.1.1.1\n")];
+        let mut journal = Journal::default();
+        journal.push(Diff::between(&zones[0], &zones[1]).unwrap());
+        journal.push(Diff::between(&zones[1], &zones[2]).unwrap());
+        assert_eq!(2, journal.since(1).unwrap().len());
+        assert_eq!(1, journal.since(2).unwrap().len());
+        assert!(journal.since(3).is_none());
+        assert!(journal.since(0).is_none());
+    }
+
+    #[test]
+    fn journal_file() {
+        let path = env::temp_dir().join("koala_journal_test.jnl").to_string_lossy().into_owned();
+        let _ = fs::remove_file(&path);
+        let file = JournalFile::new(path.clone());
+        assert!(file.read().unwrap().is_empty());
+
+        let zones = vec![zone_with(1, ""), zone_with(2, "a A 1.1.1.1\n"), zone_with(3, "b TXT \"b\"\n")];
+        let diffs = vec![Diff::between(&zones[0], &zones[1]).unwrap(), Diff::between(&zones[1], &zones[2]).unwrap()];
+        for diff in &diffs {
+            file.append(diff).unwrap();
+        }
+        assert_eq!(diffs, file.read().unwrap());
+
+        //power lost part way through writing a third
+        let whole = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0, 0, 1, 0, 7, 7, 7]).unwrap();
+        assert_eq!(diffs, file.read().unwrap());
+        assert_eq!(whole, fs::metadata(&path).unwrap().len());
+
+        file.clear().unwrap();
+        assert!(file.read().unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn large_diffs() {
+        let path = env::temp_dir().join("koala_journal_large.jnl").to_string_lossy().into_owned();
+        let _ = fs::remove_file(&path);
+        let file = JournalFile::new(path.clone());
+        let records = |count: usize| (0..count).map(|i| format!("host{} TXT \"some text for host {}\"\n", i, i)).collect::<String>();
+
+        //well past 4KiB
+        let diff = Diff::between(&zone_with(1, ""), &zone_with(2, &records(300))).unwrap();
+        file.append(&diff).unwrap();
+        assert_eq!(vec![diff], file.read().unwrap());
+
+        //too large for a message, so nothing is written
+        let whole = fs::metadata(&path).unwrap().len();
+        let huge = Diff::between(&zone_with(2, ""), &zone_with(3, &records(3000))).unwrap();
+        assert!(file.append(&huge).is_err());
+        assert_eq!(whole, fs::metadata(&path).unwrap().len());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bounded() {
+        let mut journal = Journal::default();
+        let diff = Diff::between(&zone_with(1, ""), &zone_with(2, "")).unwrap();
+        for _ in 0..MAX_DIFFS + 5 {
+            journal.push(diff.clone());
+        }
+        assert_eq!(MAX_DIFFS, journal.len());
+    }
+}
@@ -0,0 +1,556 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use dns::message::{DnsAnswer, DnsName, IntoBytes};
+use dns::record::*;
+use dns::dnssec::{self, Dnskey, Ds};
+use settings::{parse_base64, to_base64};
+
+///
+///Master file parsing. RFC1035 5.1
+///
+///Supports $ORIGIN, $TTL, @, relative names, a blank owner meaning the previous owner,
+///parentheses over multiple lines, ; comments, quoted strings and TTLs with units (1h30m).
+///$INCLUDE isn't supported.
+///
+pub fn load(path: &str, origin: Option<&DnsName>) -> Result<Vec<DnsAnswer>, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("Failed to read master file {:?}. {:?}", path, e))?;
+    parse(&text, origin).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn parse(text: &str, origin: Option<&DnsName>) -> Result<Vec<DnsAnswer>, String> {
+    let mut parser = MasterParser::new(origin);
+    for entry in entries(text)? {
+        let line = entry.line;
+        parser.entry(entry).map_err(|e| format!("Line {}: {}", line, e))?;
+    }
+    Ok(parser.records)
+}
+
+///A TTL in seconds, or with units. E.g. 3600, 1h, 1d12h, 2w
+pub fn parse_ttl(text: &str) -> Option<u32> {
+    if !text.chars().next().map_or(false, |c| c.is_digit(10)) {
+        return None;
+    }
+    let mut total: u32 = 0;
+    let mut current: u32 = 0;
+    let mut has_digits = false;
+    for c in text.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            current = current.checked_mul(10).and_then(|n| n.checked_add(digit))?;
+            has_digits = true;
+            continue;
+        }
+        let multiplier = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return None,
+        };
+        if !has_digits {
+            return None;
+        }
+        total = current.checked_mul(multiplier).and_then(|n| total.checked_add(n))?;
+        current = 0;
+        has_digits = false;
+    }
+    total.checked_add(current)
+}
+
+///One logical line. Parentheses may have joined several physical lines
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    indented: bool,
+    tokens: Vec<String>,
+}
+
+fn entries(text: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::<Entry>::new();
+    let mut tokens = Vec::<String>::new();
+    let mut token = String::new();
+    let mut line = 1;
+    let mut entry_line = 1;
+    let mut line_start = true;
+    let mut indented = false;
+    let mut in_quotes = false;
+    let mut in_comment = false;
+    let mut escaped = false;
+    let mut parens = 0;
+
+    for c in text.chars() {
+        if line_start {
+            entry_line = line;
+            indented = c == ' ' || c == '\t';
+            line_start = false;
+        }
+        if in_quotes {
+            if escaped {
+                token.push(c);
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_quotes = false;
+                tokens.push(token.clone());
+                token.clear();
+            } else {
+                token.push(c);
+            }
+            if c == '\n' {
+                line += 1;
+            }
+            continue;
+        }
+        if in_comment && c != '\n' {
+            continue;
+        }
+        match c {
+            '\n' => {
+                in_comment = false;
+                flush(&mut token, &mut tokens);
+                line += 1;
+                if parens == 0 {
+                    if !tokens.is_empty() {
+                        entries.push(Entry {
+                            line: entry_line,
+                            indented: indented,
+                            tokens: tokens.clone(),
+                        });
+                        tokens.clear();
+                    }
+                    line_start = true;
+                }
+            }
+            ';' => in_comment = true,
+            '"' => {
+                flush(&mut token, &mut tokens);
+                in_quotes = true;
+            }
+            '(' => {
+                flush(&mut token, &mut tokens);
+                parens += 1;
+            }
+            ')' => {
+                flush(&mut token, &mut tokens);
+                if parens == 0 {
+                    return Err(format!("Line {}: unbalanced )", line));
+                }
+                parens -= 1;
+            }
+            ' ' | '\t' | '\r' => flush(&mut token, &mut tokens),
+            _ => token.push(c),
+        }
+    }
+    if in_quotes || parens > 0 {
+        return Err(format!("Line {}: unterminated quote or (", entry_line));
+    }
+    flush(&mut token, &mut tokens);
+    if !tokens.is_empty() {
+        entries.push(Entry {
+            line: entry_line,
+            indented: indented,
+            tokens: tokens,
+        });
+    }
+    Ok(entries)
+}
+
+fn flush(token: &mut String, tokens: &mut Vec<String>) {
+    if !token.is_empty() {
+        tokens.push(token.clone());
+        token.clear();
+    }
+}
+
+struct MasterParser {
+    origin: Option<DnsName>,
+    default_ttl: Option<u32>,
+    last_owner: Option<DnsName>,
+    last_ttl: Option<u32>,
+    records: Vec<DnsAnswer>,
+}
+
+impl MasterParser {
+    fn new(origin: Option<&DnsName>) -> MasterParser {
+        MasterParser {
+            origin: origin.cloned(),
+            default_ttl: None,
+            last_owner: None,
+            last_ttl: None,
+            records: Vec::new(),
+        }
+    }
+
+    fn entry(&mut self, entry: Entry) -> Result<(), String> {
+        let tokens = entry.tokens;
+        if tokens[0].starts_with('$') {
+            return self.directive(&tokens);
+        }
+
+        let mut pos = 0;
+        let owner = if entry.indented {
+            self.last_owner.clone().ok_or("No previous owner for a record with a blank owner")?
+        } else {
+            pos += 1;
+            self.name(&tokens[0])?
+        };
+
+        //TTL and class can come in either order, and are both optional
+        let mut ttl = None;
+        while pos < tokens.len() {
+            if let Some(val) = parse_ttl(&tokens[pos]) {
+                ttl = Some(val);
+            } else if tokens[pos].eq_ignore_ascii_case("IN") {
+                //the only class we serve
+            } else {
+                break;
+            }
+            pos += 1;
+        }
+
+        let type_name = tokens.get(pos).ok_or("Missing record type")?;
+        let rtype = RecordType::from_name(type_name).ok_or(format!("Unsupported record type {:?}", type_name))?;
+        let rdata = self.rdata(rtype, &tokens[pos + 1..])?;
+
+        let soa_minimum = if rtype == RecordType::SOA {
+            Soa::from_rdata(&rdata).map(|soa| soa.minimum)
+        } else {
+            None
+        };
+        let ttl = ttl.or(self.default_ttl)
+                     .or(self.last_ttl)
+                     .or(soa_minimum)
+                     .ok_or("No TTL for record and no $TTL")?;
+
+        self.last_owner = Some(owner.clone());
+        self.last_ttl = Some(ttl);
+        self.records.push(DnsAnswer::with_rdata(owner, rtype, RecordClass::IN, ttl, rdata));
+        Ok(())
+    }
+
+    fn directive(&mut self, tokens: &[String]) -> Result<(), String> {
+        let arg = tokens.get(1).ok_or(format!("{} needs an argument", tokens[0]))?;
+        match tokens[0].to_uppercase().as_str() {
+            "$ORIGIN" => {
+                let origin = self.name(arg)?;
+                self.origin = Some(origin);
+            }
+            "$TTL" => self.default_ttl = Some(parse_ttl(arg).ok_or(format!("Invalid $TTL {:?}", arg))?),
+            other => return Err(format!("Unsupported directive {}", other)),
+        }
+        Ok(())
+    }
+
+    fn name(&self, text: &str) -> Result<DnsName, String> {
+        if text == "@" {
+            return self.origin.clone().ok_or("@ used without an origin".to_owned());
+        }
+        if text.ends_with('.') {
+            return Ok(DnsName::from_string(text.to_owned()));
+        }
+        match self.origin {
+            Some(ref origin) => Ok(DnsName::from_string(format!("{}.{}", text, origin.to_string()))),
+            None => Err(format!("Relative name {:?} used without an origin", text)),
+        }
+    }
+
+    fn rdata(&self, rtype: u16, args: &[String]) -> Result<Vec<u8>, String> {
+        let arg = |i: usize| args.get(i).map(|s| s.as_str()).ok_or(format!("Missing rdata for {}", RecordType::name(rtype)));
+        let number = |i: usize| -> Result<u32, String> {
+            let text = arg(i)?;
+            parse_ttl(text).ok_or(format!("Invalid number {:?}", text))
+        };
+        if args.first().map_or(false, |arg| arg == "\\#") {
+            return Self::generic_rdata(args);
+        }
+        let rdata = match rtype {
+            RecordType::A => {
+                Ipv4Addr::from_str(arg(0)?).map_err(|e| format!("Invalid A {:?} {:?}", args, e))?.octets().to_vec()
+            }
+            RecordType::AAAA => {
+                Ipv6Addr::from_str(arg(0)?).map_err(|e| format!("Invalid AAAA {:?} {:?}", args, e))?.octets().to_vec()
+            }
+            RecordType::NS | RecordType::CNAME | RecordType::PTR => self.name(arg(0)?)?.to_bytes(),
+            RecordType::MX => {
+                let mut rdata = u16_bytes(number(0)? as u16).to_vec();
+                rdata.extend(self.name(arg(1)?)?.to_bytes());
+                rdata
+            }
+            RecordType::SRV => {
+                let mut rdata = Vec::new();
+                for i in 0..3 {
+                    rdata.extend(u16_bytes(number(i)? as u16).iter());
+                }
+                rdata.extend(self.name(arg(3)?)?.to_bytes());
+                rdata
+            }
+            RecordType::TXT => {
+                if args.is_empty() {
+                    return Err("TXT needs at least one string".to_owned());
+                }
+                let mut rdata = Vec::new();
+                for text in args {
+                    if text.len() > 255 {
+                        return Err(format!("TXT string longer than 255 {:?}", text));
+                    }
+                    rdata.push(text.len() as u8);
+                    rdata.extend(text.as_bytes());
+                }
+                rdata
+            }
+            RecordType::SOA => {
+                let soa = Soa {
+                    mname: self.name(arg(0)?)?,
+                    rname: self.name(arg(1)?)?,
+                    serial: number(2)?,
+                    refresh: number(3)?,
+                    retry: number(4)?,
+                    expire: number(5)?,
+                    minimum: number(6)?,
+                };
+                soa.to_rdata()
+            }
+            //the digest may be split by spaces. RFC4034 5.3
+            RecordType::DS => {
+                let ds = Ds {
+                    key_tag: number(0)? as u16,
+                    algorithm: number(1)? as u8,
+                    digest_type: number(2)? as u8,
+                    digest: Self::hex(&args.get(3..).unwrap_or_default().concat())?,
+                };
+                ds.to_rdata()
+            }
+            //as is the base64 public key. RFC4034 2.2
+            RecordType::DNSKEY => {
+                let public_key = args.get(3..).unwrap_or_default().concat();
+                let key = Dnskey {
+                    flags: number(0)? as u16,
+                    protocol: number(1)? as u8,
+                    algorithm: number(2)? as u8,
+                    public_key: parse_base64(&public_key).ok_or(format!("Invalid base64 {:?}", public_key))?,
+                };
+                key.to_rdata()
+            }
+            _ => return Err(format!("Unsupported record type {}", RecordType::name(rtype))),
+        };
+        Ok(rdata)
+    }
+
+    ///The RFC3597 form for any type. \# then the length, then the rdata in hex
+    fn generic_rdata(args: &[String]) -> Result<Vec<u8>, String> {
+        let len = args.get(1).and_then(|len| len.parse::<usize>().ok()).ok_or(format!("Invalid \\# length {:?}", args))?;
+        let hex = args[2..].concat();
+        if hex.len() != len * 2 {
+            return Err(format!("\\# rdata isn't {} bytes {:?}", len, args));
+        }
+        Self::hex(&hex)
+    }
+
+    fn hex(hex: &str) -> Result<Vec<u8>, String> {
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(format!("Invalid hex {:?}", hex));
+        }
+        (0..hex.len() / 2).map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|e| format!("Invalid hex {:?} {:?}", hex, e)))
+                          .collect()
+    }
+}
+
+///
+///Replace the master file at path with text. It's written to a temporary file that is synced then
+///renamed over the old one, so a crash leaves either the old file or the new, not part of one.
+///
+pub fn save(path: &str, text: &str) -> Result<(), String> {
+    let temp = format!("{}.tmp", path);
+    File::create(&temp)
+        .and_then(|mut file| file.write_all(text.as_bytes()).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(&temp, path))
+        .map_err(|e| format!("Failed to write master file {:?}. {:?}", path, e))?;
+    //the rename is only durable once the directory is synced
+    let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if let Err(e) = File::open(dir).and_then(|dir| dir.sync_all()) {
+        warn!("Failed to sync {:?}. {:?}", dir, e);
+    }
+    Ok(())
+}
+
+///
+///A master file of the records, that parses back to the same records. Owner names are written
+///in full, one record per line, with types we can't show written in the RFC3597 form.
+///
+pub fn write(origin: &DnsName, records: &[&DnsAnswer]) -> String {
+    let mut text = format!("$ORIGIN {}.\n", origin.to_string());
+    for rr in records {
+        text.push_str(&format!("{}. {} IN {} {}\n",
+                               rr.name.to_string(),
+                               rr.ttl,
+                               RecordType::name(rr.atype),
+                               rdata_text(rr.atype, &rr.rdata)));
+    }
+    text
+}
+
+fn rdata_text(rtype: u16, rdata: &[u8]) -> String {
+    let name = |buf: &[u8]| format!("{}.", DnsName::from_bytes(buf).to_string());
+    let number = |i: usize| ((rdata[i] as u16) << 8) + rdata[i + 1] as u16;
+    match rtype {
+        RecordType::A if rdata.len() == 4 => Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]).to_string(),
+        RecordType::AAAA if rdata.len() == 16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(rdata);
+            Ipv6Addr::from(octets).to_string()
+        }
+        RecordType::NS | RecordType::CNAME | RecordType::PTR => name(rdata),
+        RecordType::MX if rdata.len() > 2 => format!("{} {}", number(0), name(&rdata[2..])),
+        RecordType::SRV if rdata.len() > 6 => format!("{} {} {} {}", number(0), number(2), number(4), name(&rdata[6..])),
+        RecordType::SOA if Soa::from_rdata(rdata).is_some() => {
+            let soa = Soa::from_rdata(rdata).unwrap();
+            format!("{}. {}. {} {} {} {} {}",
+                    soa.mname.to_string(),
+                    soa.rname.to_string(),
+                    soa.serial,
+                    soa.refresh,
+                    soa.retry,
+                    soa.expire,
+                    soa.minimum)
+        }
+        RecordType::DS if Ds::from_rdata(rdata).is_some() => {
+            let ds = Ds::from_rdata(rdata).unwrap();
+            format!("{} {} {} {}", ds.key_tag, ds.algorithm, ds.digest_type, dnssec::hex(&ds.digest))
+        }
+        RecordType::DNSKEY if Dnskey::from_rdata(rdata).is_some() => {
+            let key = Dnskey::from_rdata(rdata).unwrap();
+            format!("{} {} {} {}", key.flags, key.protocol, key.algorithm, to_base64(&key.public_key))
+        }
+        RecordType::TXT if txt_strings(rdata).is_some() => {
+            txt_strings(rdata).unwrap()
+                              .iter()
+                              .map(|text| format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\"")))
+                              .collect::<Vec<_>>()
+                              .join(" ")
+        }
+        _ => {
+            let hex = rdata.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+            format!("\\# {} {}", rdata.len(), hex)
+        }
+    }
+}
+
+///The character strings of TXT rdata. None if they aren't valid UTF-8 or overrun the rdata
+fn txt_strings(rdata: &[u8]) -> Option<Vec<String>> {
+    let mut strings = Vec::new();
+    let mut pos = 0;
+    while pos < rdata.len() {
+        let end = pos + 1 + rdata[pos] as usize;
+        if end > rdata.len() {
+            return None;
+        }
+        strings.push(String::from_utf8(rdata[pos + 1..end].to_vec()).ok()?);
+        pos = end;
+    }
+    Some(strings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, parse_ttl, write};
+    use dns::message::DnsName;
+    use dns::record::*;
+
+    fn test_zone() -> &'static str {
+        r#"$ORIGIN example.org.
+$TTL 1h
+@   IN  SOA ns1 hostmaster ( 2016050101 ; serial
+            3600 600 86400 300 )
+    IN  NS  ns1
+    IN  MX  10 mail.example.org.
+ns1     A   10.0.0.1
+www 300 IN  A   10.0.0.2
+            AAAA fd00::2
+txt     TXT "hello world" "with ; semicolon"
+"#
+    }
+
+    #[test]
+    fn parse_zone() {
+        let records = parse(test_zone(), None).unwrap();
+        assert_eq!(7, records.len());
+
+        let soa = Soa::from_rdata(&records[0].rdata).unwrap();
+        assert_eq!("example.org", records[0].name.to_string());
+        assert_eq!("ns1.example.org", soa.mname.to_string());
+        assert_eq!(2016050101, soa.serial);
+        assert_eq!(300, soa.minimum);
+        assert_eq!(3600, records[0].ttl);
+
+        //blank owner is the previous owner
+        assert_eq!("example.org", records[1].name.to_string());
+        assert_eq!(RecordType::NS, records[1].atype);
+
+        let www = &records[4];
+        assert_eq!("www.example.org", www.name.to_string());
+        assert_eq!(300, www.ttl);
+        assert_eq!(vec![10, 0, 0, 2], www.rdata);
+        assert_eq!(RecordType::AAAA, records[5].atype);
+        //$TTL, not the previous record's
+        assert_eq!(3600, records[5].ttl);
+        assert_eq!(16, records[5].rdlength);
+    }
+
+    #[test]
+    fn parse_txt() {
+        let records = parse(test_zone(), None).unwrap();
+        let txt = &records[6];
+        assert_eq!(RecordType::TXT, txt.atype);
+        assert_eq!(11, txt.rdata[0]);
+        assert_eq!(b"with ; semicolon", &txt.rdata[13..]);
+    }
+
+    #[test]
+    fn origin_param() {
+        let origin = DnsName::from_string("example.com".to_owned());
+        let records = parse("$TTL 60\nwww A 1.2.3.4\n", Some(&origin)).unwrap();
+        assert_eq!("www.example.com", records[0].name.to_string());
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("www 60 A 1.2.3.4\n", None).is_err()); //no origin
+        assert!(parse("$ORIGIN a.\nwww A 1.2.3.4\n", None).is_err()); //no ttl
+        assert!(parse("$ORIGIN a.\n$TTL 60\nwww A 1.2.3\n", None).is_err());
+        assert!(parse("$ORIGIN a.\n$TTL 60\nwww HINFO x y\n", None).is_err());
+        assert!(parse("$ORIGIN a.\n$TTL 60\n@ SOA ( a b 1 2 3 4 5\n", None).is_err());
+    }
+
+    #[test]
+    fn write_round_trip() {
+        let mut text = test_zone().to_owned();
+        text.push_str("srv 60 SRV 1 2 53 ns1\nquote 60 TXT \"say \\\"hi\\\" \\\\\"\nhinfo 60 TYPE13 \\# 4 0141 0142\n\
+                       sub 60 DS 60485 5 1 2BB183AF5F22588179A53B0A 98631FAD1A292118\n\
+                       @ 60 DNSKEY 257 3 15 l02Woi0iS8Aa25FQ kUd9RMzZHJpBoRQwAQEX1SxZJA4=\n");
+        let records = parse(&text, None).unwrap();
+        assert_eq!(vec![0x01, 0x41, 0x01, 0x42], records[9].rdata);
+        assert_eq!(vec![0xec, 0x45, 5, 1, 0x2b, 0xb1], records[10].rdata[..6].to_vec());
+        assert_eq!(24, records[10].rdlength);
+        assert_eq!(vec![1, 1, 3, 15, 0x97, 0x4d], records[11].rdata[..6].to_vec());
+        let origin = DnsName::from_string("example.org".to_owned());
+        let written = write(&origin, &records.iter().collect::<Vec<_>>());
+        assert_eq!(records, parse(&written, None).unwrap());
+    }
+
+    #[test]
+    fn ttl_units() {
+        assert_eq!(Some(3600), parse_ttl("3600"));
+        assert_eq!(Some(5400), parse_ttl("1h30m"));
+        assert_eq!(Some(1209600), parse_ttl("2W"));
+        assert_eq!(None, parse_ttl("A"));
+        assert_eq!(None, parse_ttl("1x"));
+    }
+}
@@ -0,0 +1,944 @@
+pub mod master;
+pub mod transfer;
+pub mod journal;
+pub mod secondary;
+pub mod notify;
+pub mod update;
+pub mod dnssec;
+pub mod reverse;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use dns::message::*;
+use dns::record::*;
+use dns::dnssec::{Ds, SigningKey};
+use dns::tsig::Keyring;
+use clock;
+use settings::ZoneConfig;
+use zone::dnssec::Signed;
+use zone::journal::{Diff, Journal, JournalFile};
+use zone::reverse::ReverseIndex;
+
+///A CNAME chain longer than this is treated as a loop
+const MAX_CNAME_CHAIN: usize = 8;
+///Runtime changes are written into the master file once there are this many in the journal file
+const COMPACT_AFTER_DIFFS: usize = 50;
+///or the oldest has been there this long
+const COMPACT_AFTER_SECS: u64 = 300;
+
+///
+///An authoritative zone held in memory. Records are kept by lowercase owner name so lookups
+///are case-insensitive and a transfer walks the names in a stable order.
+///
+#[derive(Clone)]
+pub struct Zone {
+    pub origin: DnsName,
+    pub config: ZoneConfig,
+    pub journal: Journal,
+    records: BTreeMap<String, Vec<DnsAnswer>>,
+    modified: Option<SystemTime>, //of the master file when loaded
+    uncompacted: usize, //diffs in the journal file that aren't in the master file yet
+    uncompacted_since: Option<Instant>,
+    keys: Vec<Arc<SigningKey>>, //loaded when the zone is first signed
+    signed: Option<Arc<Signed>>,
+}
+
+///The sections of a reply to a query for data in a zone
+#[derive(Debug)]
+pub struct LookupResult {
+    pub rcode: u8,
+    pub aa: bool,
+    pub answers: Vec<DnsAnswer>,
+    pub authorities: Vec<DnsAnswer>,
+    pub additionals: Vec<DnsAnswer>,
+    ///What a signed zone has to prove about the answer
+    pub denial: Option<Denial>,
+}
+
+///What the NSEC or NSEC3 records in a negative answer from a signed zone prove. RFC4035 3.1.3
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(PartialEq)]
+pub enum Denial {
+    ///The name doesn't exist
+    Name(DnsName),
+    ///The name exists, without the type
+    Type(DnsName, u16),
+    ///A referral to the child zone at the cut. It has a DS, or a proof that there isn't one
+    Delegation(DnsName),
+}
+
+impl LookupResult {
+    fn new() -> LookupResult {
+        LookupResult {
+            rcode: Rcode::NOERROR,
+            aa: true,
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+            denial: None,
+        }
+    }
+}
+
+impl Zone {
+    pub fn new(origin: DnsName, config: ZoneConfig) -> Zone {
+        Zone {
+            origin: origin.to_lowercase(),
+            config: config,
+            journal: Journal::default(),
+            records: BTreeMap::new(),
+            modified: None,
+            uncompacted: 0,
+            uncompacted_since: None,
+            keys: Vec::new(),
+            signed: None,
+        }
+    }
+
+    ///Records outside the zone are dropped. The zone must have an SOA at the apex
+    pub fn from_records(origin: DnsName, config: ZoneConfig, records: Vec<DnsAnswer>) -> Result<Zone, String> {
+        let mut zone = Zone::new(origin, config);
+        for record in records {
+            if !record.name.is_subdomain_of(&zone.origin) {
+                warn!("Ignoring {:?}. It is outside of zone {:?}", record.name.to_string(), zone.origin.to_string());
+                continue;
+            }
+            zone.add(record);
+        }
+        if zone.soa().is_none() {
+            return Err(format!("Zone {:?} has no SOA record", zone.origin.to_string()));
+        }
+        Ok(zone)
+    }
+
+    ///Load the zone's master file, then the changes in its journal file. The origin is the config's,
+    ///or if that's empty, the owner of the SOA
+    pub fn load(config: &ZoneConfig) -> Result<Zone, String> {
+        let origin = if config.origin.is_empty() {
+            None
+        } else {
+            Some(DnsName::from_string(config.origin.clone()))
+        };
+        let modified = Self::file_modified(&config.file);
+        let records = master::load(&config.file, origin.as_ref())?;
+        let origin = match origin {
+            Some(origin) => origin,
+            None => {
+                match records.iter().find(|r| r.atype == RecordType::SOA) {
+                    Some(soa) => soa.name.clone(),
+                    None => return Err(format!("No SOA in {:?} to take the origin from", config.file)),
+                }
+            }
+        };
+        let mut config = config.clone();
+        config.origin = origin.to_string();
+        let mut zone = Self::from_records(origin, config, records)?;
+        zone.modified = modified;
+        zone.replay();
+        Ok(zone)
+    }
+
+    ///Apply the diffs in the journal file made since the master file was written. Ones from before,
+    ///left by a crash part way through compacting, are skipped
+    fn replay(&mut self) {
+        let file = match self.journal_file() {
+            Some(file) => file,
+            None => return,
+        };
+        let diffs = match file.read() {
+            Ok(diffs) => diffs,
+            Err(e) => {
+                error!("Not replaying journal. {}", e);
+                return;
+            }
+        };
+        let total = diffs.len();
+        let mut applied = Vec::<Diff>::new();
+        for diff in diffs {
+            if !serial_lt(self.serial(), diff.to_serial()) {
+                continue;
+            }
+            match self.apply(&diff) {
+                Ok(mut zone) => {
+                    zone.journal.push(diff.clone());
+                    *self = zone;
+                    applied.push(diff);
+                }
+                Err(e) => {
+                    //e.g. the master file was edited and its serial changed. The journal no longer follows it
+                    warn!("{}. Discarding the rest of journal {:?}", e, file.path());
+                    let rewritten = file.clear().and_then(|_| {
+                        applied.iter().map(|diff| file.append(diff)).collect::<Result<Vec<_>, String>>()
+                    });
+                    if let Err(e) = rewritten {
+                        error!("{}", e);
+                    }
+                    break;
+                }
+            }
+        }
+        if !applied.is_empty() {
+            info!("Replayed {} of {} changes to {:?} from {:?}", applied.len(), total, self.origin.to_string(), file.path());
+            self.uncompacted = applied.len();
+            self.uncompacted_since = Some(Instant::now());
+        }
+    }
+
+    ///Where changes made at runtime are kept until they're written to the master file. Secondaries
+    ///are kept up to date by their primary, so don't have one
+    pub fn journal_file(&self) -> Option<JournalFile> {
+        if self.config.file.is_empty() || self.config.is_secondary() {
+            return None;
+        }
+        Some(JournalFile::for_master_file(&self.config.file))
+    }
+
+    fn needs_compacting(&self) -> bool {
+        self.uncompacted >= COMPACT_AFTER_DIFFS ||
+        self.uncompacted_since.map_or(false, |since| since.elapsed() >= Duration::from_secs(COMPACT_AFTER_SECS))
+    }
+
+    ///Write the zone to its master file, then empty the journal file. A crash between the two leaves
+    ///diffs the master file already has, which replay skips
+    fn compact(&mut self) -> Result<(), String> {
+        let file = self.journal_file().ok_or(format!("Zone {:?} has no journal file", self.origin.to_string()))?;
+        let mut records = self.soa_record().into_iter().collect::<Vec<_>>();
+        records.extend(self.records());
+        master::save(&self.config.file, &master::write(&self.origin, &records))?;
+        //so it's not reloaded
+        self.modified = Self::file_modified(&self.config.file);
+        file.clear()?;
+        info!("Compacted {} changes to {:?} into {:?}", self.uncompacted, self.origin.to_string(), self.config.file);
+        self.uncompacted = 0;
+        self.uncompacted_since = None;
+        Ok(())
+    }
+
+    ///True if the master file has changed since it was loaded. Secondaries come from their primary
+    pub fn file_changed(&self) -> bool {
+        !self.config.file.is_empty() && !self.config.is_secondary() &&
+        Self::file_modified(&self.config.file) != self.modified
+    }
+
+    fn file_modified(path: &str) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    }
+
+    ///Adds the record unless an identical one exists. Returns true if added
+    pub fn add(&mut self, record: DnsAnswer) -> bool {
+        let rrs = self.records.entry(Self::key(&record.name)).or_insert_with(Vec::new);
+        if rrs.iter().any(|rr| rr.same_rr(&record)) {
+            return false;
+        }
+        rrs.push(record);
+        true
+    }
+
+    ///Removes the record, ignoring TTL. Returns true if it was there
+    pub fn remove(&mut self, record: &DnsAnswer) -> bool {
+        let key = Self::key(&record.name);
+        let (removed, now_empty) = match self.records.get_mut(&key) {
+            Some(rrs) => {
+                let len = rrs.len();
+                rrs.retain(|rr| !rr.same_rr(record));
+                (rrs.len() < len, rrs.is_empty())
+            }
+            None => (false, false),
+        };
+        if now_empty {
+            self.records.remove(&key);
+        }
+        removed
+    }
+
+    ///The next version of the zone, with diff applied. The diff must start at this version
+    pub fn apply(&self, diff: &Diff) -> Result<Zone, String> {
+        if diff.from_serial() != self.serial() {
+            return Err(format!("Diff from serial {} doesn't apply to {:?} serial {}",
+                               diff.from_serial(),
+                               self.origin.to_string(),
+                               self.serial()));
+        }
+        let mut zone = self.clone();
+        zone.remove(&diff.from);
+        for rr in &diff.deleted {
+            zone.remove(rr);
+        }
+        zone.add(diff.to.clone());
+        for rr in &diff.added {
+            zone.add(rr.clone());
+        }
+        Ok(zone)
+    }
+
+    pub fn soa_record(&self) -> Option<&DnsAnswer> {
+        self.records
+            .get(&Self::key(&self.origin))
+            .and_then(|rrs| rrs.iter().find(|rr| rr.atype == RecordType::SOA))
+    }
+
+    pub fn soa(&self) -> Option<Soa> {
+        self.soa_record().and_then(|rr| Soa::from_rdata(&rr.rdata))
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.soa().map_or(0, |soa| soa.serial)
+    }
+
+    ///The RRset at name of the type
+    pub fn find(&self, name: &DnsName, rtype: u16) -> Vec<DnsAnswer> {
+        match self.records.get(&Self::key(name)) {
+            Some(rrs) => rrs.iter().filter(|rr| rr.atype == rtype).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    ///Every RRset at name
+    pub fn find_all(&self, name: &DnsName) -> Vec<DnsAnswer> {
+        self.records.get(&Self::key(name)).cloned().unwrap_or_default()
+    }
+
+    ///Every record except the SOA, in owner name order
+    pub fn records(&self) -> Vec<&DnsAnswer> {
+        self.records
+            .values()
+            .flat_map(|rrs| rrs.iter())
+            .filter(|rr| rr.atype != RecordType::SOA)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.values().map(|rrs| rrs.len()).sum()
+    }
+
+    ///Answer a query for data in this zone. RFC1034 4.3.2, without wildcards
+    pub fn lookup(&self, qname: &DnsName, qtype: u16) -> LookupResult {
+        let mut result = LookupResult::new();
+        let mut name = qname.clone();
+        for _ in 0..MAX_CNAME_CHAIN {
+            //the DS at a cut is the parent's. RFC4035 3.1.4.1
+            let delegation = if qtype == RecordType::DS && self.is_cut(&name) {
+                name.parent().and_then(|parent| self.delegation(&parent))
+            } else {
+                self.delegation(&name)
+            };
+            if let Some(ns) = delegation {
+                //Referral. Only authoritative for any CNAMEs that led here
+                result.aa = !result.answers.is_empty();
+                result.additionals = self.glue(&ns);
+                result.denial = Some(Denial::Delegation(ns[0].name.clone()));
+                result.authorities = ns;
+                return result;
+            }
+            let rrs = match self.records_at(&name) {
+                Some(rrs) => rrs,
+                None => {
+                    if self.is_empty_non_terminal(&name) {
+                        result.denial = Some(Denial::Type(name, qtype));
+                    } else {
+                        if result.answers.is_empty() {
+                            result.rcode = Rcode::NXDOMAIN;
+                        }
+                        result.denial = Some(Denial::Name(name));
+                    }
+                    self.add_negative_soa(&mut result);
+                    return result;
+                }
+            };
+            let matching = rrs.iter()
+                              .filter(|rr| rr.atype == qtype || qtype == RecordType::ANY)
+                              .cloned()
+                              .collect::<Vec<_>>();
+            if !matching.is_empty() {
+                result.answers.extend(matching);
+                return result;
+            }
+            match rrs.iter().find(|rr| rr.atype == RecordType::CNAME) {
+                Some(cname) => {
+                    result.answers.push(cname.clone());
+                    name = DnsName::from_bytes(&cname.rdata);
+                    if !name.is_subdomain_of(&self.origin) {
+                        //the resolver follows it from here
+                        return result;
+                    }
+                }
+                None => {
+                    result.denial = Some(Denial::Type(name, qtype));
+                    self.add_negative_soa(&mut result);
+                    return result;
+                }
+            }
+        }
+        warn!("CNAME chain too long for {:?}", qname.to_string());
+        result
+    }
+
+    ///The records at name, with the DNSSEC records made when the zone was signed
+    fn records_at(&self, name: &DnsName) -> Option<Vec<DnsAnswer>> {
+        let key = Self::key(name);
+        let mut rrs = self.records.get(&key).cloned().unwrap_or_default();
+        if let Some(ref signed) = self.signed {
+            rrs.extend(signed.records_at(&key).iter().cloned());
+        }
+        if rrs.is_empty() { None } else { Some(rrs) }
+    }
+
+    ///
+    ///Add the DNSSEC records for a query with the DO bit set. The NSEC or NSEC3 records proving a
+    ///negative answer, or the DS of a referral, then the RRSIGs over the RRsets in the answer and
+    ///authority sections. Each RRSIG goes in the section of the RRset it covers. RFC4035 3.1
+    ///
+    pub fn add_dnssec(&self, result: &mut LookupResult) {
+        let signed = match self.signed {
+            Some(ref signed) => signed,
+            None => return,
+        };
+        if let Some(ref denial) = result.denial {
+            for rr in signed.proof(self, denial) {
+                if !result.authorities.contains(&rr) {
+                    result.authorities.push(rr);
+                }
+            }
+        }
+        for section in vec![&mut result.answers, &mut result.authorities] {
+            let mut rrsets = Vec::<(String, u16)>::new();
+            for rr in section.iter() {
+                let rrset = (Self::key(&rr.name), rr.atype);
+                if !rrsets.contains(&rrset) {
+                    rrsets.push(rrset);
+                }
+            }
+            for (key, rtype) in rrsets {
+                section.extend(signed.rrsigs(&key, rtype).iter().cloned());
+            }
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signed.is_some()
+    }
+
+    ///
+    ///Sign the zone with the keys in its config, reusing signatures from the last time for RRsets
+    ///that haven't changed. If signing fails the zone is served unsigned.
+    ///
+    fn sign(&mut self) {
+        if !self.config.is_signed() {
+            return;
+        }
+        if self.keys.is_empty() {
+            match dnssec::load_keys(&self.config) {
+                Ok(keys) => {
+                    for key in keys.iter().filter(|key| key.dnskey.is_sep()) {
+                        info!("Key {} signs {:?}. Its DS for the parent zone is {}",
+                              key.tag(),
+                              self.origin.to_string(),
+                              Ds::for_key(&self.origin, &key.dnskey).to_text(&self.origin));
+                    }
+                    self.keys = keys;
+                }
+                Err(e) => {
+                    error!("Serving {:?} unsigned. {}", self.origin.to_string(), e);
+                    return;
+                }
+            }
+        }
+        match dnssec::sign(self, &self.keys, self.signed.as_deref(), clock::now() as u32) {
+            Ok(signed) => self.signed = Some(Arc::new(signed)),
+            Err(e) => {
+                error!("Serving {:?} unsigned. {}", self.origin.to_string(), e);
+                self.signed = None;
+            }
+        }
+    }
+
+    ///NS records at a zone cut between the origin and name, if name is at or below one
+    fn delegation(&self, name: &DnsName) -> Option<Vec<DnsAnswer>> {
+        let depth = name.labels().len() - self.origin.labels().len();
+        //search from the top down, the highest cut wins
+        for i in (0..depth).rev() {
+            let cut = DnsName::from(name.labels()[i..].to_vec());
+            let ns = self.find(&cut, RecordType::NS);
+            if !ns.is_empty() {
+                return Some(ns);
+            }
+        }
+        None
+    }
+
+    fn glue(&self, ns: &[DnsAnswer]) -> Vec<DnsAnswer> {
+        let mut glue = Vec::new();
+        for rr in ns {
+            let target = DnsName::from_bytes(&rr.rdata);
+            if target.is_subdomain_of(&self.origin) {
+                glue.extend(self.find(&target, RecordType::A));
+                glue.extend(self.find(&target, RecordType::AAAA));
+            }
+        }
+        glue
+    }
+
+    ///Where the zone delegates name to a child zone
+    fn is_cut(&self, name: &DnsName) -> bool {
+        name.labels().len() > self.origin.labels().len() && !self.find(name, RecordType::NS).is_empty()
+    }
+
+    ///The name has records, or names below it
+    fn exists(&self, name: &DnsName) -> bool {
+        self.records.contains_key(&Self::key(name)) || self.is_empty_non_terminal(name)
+    }
+
+    ///A name with no records, but with names below it. E.g. b.example.org when only a.b.example.org exists
+    fn is_empty_non_terminal(&self, name: &DnsName) -> bool {
+        let suffix = format!(".{}", Self::key(name));
+        self.records.keys().any(|key| key.ends_with(&suffix))
+    }
+
+    ///Negative answers carry the SOA so they can be cached. RFC2308 3
+    fn add_negative_soa(&self, result: &mut LookupResult) {
+        if let (Some(rr), Some(soa)) = (self.soa_record(), self.soa()) {
+            let mut rr = rr.clone();
+            rr.ttl = ::std::cmp::min(rr.ttl, soa.minimum);
+            result.authorities.push(rr);
+        }
+    }
+
+    fn key(name: &DnsName) -> String {
+        name.to_lowercase().to_string()
+    }
+}
+
+///The authoritative zones we serve, by origin
+#[derive(Default)]
+pub struct ZoneStore {
+    zones: HashMap<String, Zone>,
+    changed: Vec<DnsName>, //zones with a new serial, for their secondaries to be told
+    pub keys: Keyring, //for checking the TSIGs on transfer, NOTIFY and UPDATE requests
+    reverse: ReverseIndex, //of the zones with reverse-ptr set. Rebuilt when a zone changes
+}
+
+impl ZoneStore {
+    ///Load the zones in the config and the master file. A zone that fails to load is logged and skipped
+    pub fn load(configs: &[ZoneConfig], master_file: &str) -> ZoneStore {
+        let mut store = ZoneStore::default();
+        if Path::new(master_file).exists() {
+            let config = ZoneConfig::new(String::new(), master_file.to_owned());
+            match Zone::load(&config) {
+                Ok(zone) => store.insert(zone),
+                Err(e) => error!("Failed to load master file. {}", e),
+            }
+        } else {
+            info!("No master file at {:?}", master_file);
+        }
+        //zones from the config replace one with the same origin from the master file
+        for config in configs.iter().filter(|config| !config.is_secondary()) {
+            match Zone::load(config) {
+                Ok(zone) => store.insert(zone),
+                Err(e) => error!("Failed to load zone {:?}. {}", config.origin, e),
+            }
+        }
+        store
+    }
+
+    ///Reload zones whose master file has changed
+    pub fn reload_changed(&mut self) {
+        let changed = self.zones
+                          .values_mut()
+                          .filter(|zone| zone.file_changed())
+                          .map(|zone| {
+                              //so a file that fails to load isn't retried until it changes again
+                              zone.modified = Zone::file_modified(&zone.config.file);
+                              zone.config.clone()
+                          })
+                          .collect::<Vec<_>>();
+        for config in changed {
+            info!("Master file {:?} changed. Reloading {:?}", config.file, config.origin);
+            match Zone::load(&config) {
+                Ok(zone) => self.replace(zone),
+                Err(e) => error!("Failed to reload zone {:?}. Still serving the old version. {}", config.origin, e),
+            }
+        }
+    }
+
+    ///
+    ///Swap in a version of a zone changed at runtime, e.g. by an UPDATE. The change is synced to the
+    ///zone's journal file first, so it isn't lost if we stop before it's compacted into the master file.
+    ///
+    pub fn commit(&mut self, mut zone: Zone) -> Result<(), String> {
+        if let (Some(old), Some(file)) = (self.get(&zone.origin), zone.journal_file()) {
+            let diff = Diff::between(old, &zone).ok_or(format!("Zone {:?} has no SOA", zone.origin.to_string()))?;
+            file.append(&diff)?;
+            zone.uncompacted = old.uncompacted + 1;
+            zone.uncompacted_since = old.uncompacted_since.or_else(|| Some(Instant::now()));
+        }
+        self.replace(zone);
+        Ok(())
+    }
+
+    ///Write runtime changes into the master files of zones with enough of them, or old enough ones
+    pub fn compact_journals(&mut self) {
+        for zone in self.zones.values_mut().filter(|zone| zone.needs_compacting()) {
+            if let Err(e) = zone.compact() {
+                error!("Failed to compact the journal of {:?}. {}", zone.origin.to_string(), e);
+            }
+        }
+    }
+
+    ///Swap in a new version of a zone. The difference is journaled for IXFR if the serial went up
+    pub fn replace(&mut self, mut zone: Zone) {
+        if let Some(old) = self.zones.remove(&zone.origin.to_string()) {
+            //so unchanged RRsets keep their signatures
+            zone.keys = old.keys.clone();
+            zone.signed = old.signed.clone();
+            let (old_serial, new_serial) = (old.serial(), zone.serial());
+            if serial_lt(old_serial, new_serial) {
+                let diff = Diff::between(&old, &zone);
+                zone.journal = old.journal;
+                if let Some(diff) = diff {
+                    zone.journal.push(diff);
+                }
+                if !self.changed.contains(&zone.origin) {
+                    self.changed.push(zone.origin.clone());
+                }
+            } else if old_serial == new_serial {
+                warn!("Zone {:?} changed without a serial change. Secondaries won't see the change",
+                      zone.origin.to_string());
+                zone.journal = old.journal;
+            } else {
+                warn!("Zone {:?} serial went backwards {} -> {}. Discarding the journal",
+                      zone.origin.to_string(),
+                      old_serial,
+                      new_serial);
+            }
+        }
+        self.insert(zone);
+    }
+
+    pub fn insert(&mut self, mut zone: Zone) {
+        zone.sign();
+        info!("Serving zone {:?} with {} records. Serial {}", zone.origin.to_string(), zone.len(), zone.serial());
+        if !zone.journal.is_empty() {
+            debug!("{} journaled versions of {:?}", zone.journal.len(), zone.origin.to_string());
+        }
+        self.zones.insert(zone.origin.to_string(), zone);
+        self.reverse = ReverseIndex::build(self.zones.values());
+        debug!("{} addresses with a PTR made from the forward zones", self.reverse.len());
+    }
+
+    ///Re-sign zones with signatures near expiry
+    pub fn refresh_signatures(&mut self) {
+        let now = clock::now() as u32;
+        for zone in self.zones.values_mut() {
+            if zone.signed.as_ref().map_or(false, |signed| signed.needs_refresh(now)) {
+                info!("Refreshing signatures of {:?}", zone.origin.to_string());
+                zone.sign();
+            }
+        }
+    }
+
+    ///Zones whose serial has gone up since last called
+    pub fn take_changed(&mut self) -> Vec<DnsName> {
+        ::std::mem::replace(&mut self.changed, Vec::new())
+    }
+
+    pub fn has_changed(&self) -> bool {
+        !self.changed.is_empty()
+    }
+
+    pub fn remove(&mut self, origin: &DnsName) -> Option<Zone> {
+        let zone = self.zones.remove(&origin.to_lowercase().to_string());
+        self.reverse = ReverseIndex::build(self.zones.values());
+        zone
+    }
+
+    ///The zone with exactly this origin
+    pub fn get(&self, origin: &DnsName) -> Option<&Zone> {
+        self.zones.get(&origin.to_lowercase().to_string())
+    }
+
+    ///
+    ///A PTR answer made from the forward zones, for the reverse name of one of their addresses.
+    ///Only when no zone of ours is for the name, so a real reverse zone takes precedence
+    ///
+    pub fn synthesize_ptr(&self, qname: &DnsName, qtype: u16) -> Option<LookupResult> {
+        if (qtype != RecordType::PTR && qtype != RecordType::ANY) || self.find(qname).is_some() {
+            return None;
+        }
+        self.reverse.ptr(qname).map(|ptr| {
+            let mut result = LookupResult::new();
+            result.answers.push(ptr);
+            result
+        })
+    }
+
+    ///The closest enclosing zone of name. I.e. the zone with the longest matching origin
+    pub fn find(&self, name: &DnsName) -> Option<&Zone> {
+        let mut candidate = Some(name.clone());
+        while let Some(name) = candidate {
+            if let Some(zone) = self.get(&name) {
+                return Some(zone);
+            }
+            candidate = name.parent();
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{Zone, ZoneStore};
+    use std::env;
+    use std::fs;
+    use std::sync::Arc;
+    use dns::dnssec::SigningKey;
+    use zone::dnssec;
+    use zone::journal::Diff;
+    use dns::message::DnsName;
+    use dns::record::*;
+    use settings::ZoneConfig;
+    use zone::master;
+
+    pub fn test_zone() -> Zone {
+        let text = r#"$ORIGIN example.org.
+$TTL 3600
+@       SOA ns1 hostmaster 1 3600 600 86400 300
+        NS  ns1
+ns1     A   10.0.0.1
+www     A   10.0.0.2
+        A   10.0.0.3
+alias   CNAME www
+ext     CNAME www.example.com.
+a.b     TXT "empty non-terminal above"
+sub     NS  ns.sub
+ns.sub  A   10.0.1.1
+"#;
+        let origin = name("example.org");
+        let records = master::parse(text, Some(&origin)).unwrap();
+        Zone::from_records(origin, ZoneConfig::default(), records).unwrap()
+    }
+
+    pub fn zone_with(serial: u32, extra: &str) -> Zone {
+        let text = format!("$ORIGIN example.org.\n$TTL 60\n@ SOA ns1 hostmaster {} 3600 600 86400 300\n  NS ns1\nns1 A 10.0.0.1\n{}",
+                           serial,
+                           extra);
+        let origin = DnsName::from_string("example.org".to_owned());
+        let records = master::parse(&text, Some(&origin)).unwrap();
+        Zone::from_records(origin, ZoneConfig::default(), records).unwrap()
+    }
+
+    ///A zone from master file text, signed at now with keys, without key files
+    pub fn signed_zone(origin: &str, text: &str, config: ZoneConfig, keys: Vec<Arc<SigningKey>>, now: u32) -> Zone {
+        let origin = name(origin);
+        let records = master::parse(text, Some(&origin)).unwrap();
+        let mut zone = Zone::from_records(origin, config, records).unwrap();
+        zone.signed = Some(Arc::new(dnssec::sign(&zone, &keys, None, now).unwrap()));
+        zone.keys = keys;
+        zone
+    }
+
+    fn name(s: &str) -> DnsName {
+        DnsName::from_string(s.to_owned())
+    }
+
+    #[test]
+    fn answer() {
+        let result = test_zone().lookup(&name("WWW.example.org"), RecordType::A);
+        assert_eq!(Rcode::NOERROR, result.rcode);
+        assert!(result.aa);
+        assert_eq!(2, result.answers.len());
+    }
+
+    #[test]
+    fn cname() {
+        let zone = test_zone();
+        let result = zone.lookup(&name("alias.example.org"), RecordType::A);
+        assert_eq!(3, result.answers.len());
+        assert_eq!(RecordType::CNAME, result.answers[0].atype);
+
+        let result = zone.lookup(&name("ext.example.org"), RecordType::A);
+        assert_eq!(1, result.answers.len());
+    }
+
+    #[test]
+    fn nxdomain_and_nodata() {
+        let zone = test_zone();
+        let result = zone.lookup(&name("nope.example.org"), RecordType::A);
+        assert_eq!(Rcode::NXDOMAIN, result.rcode);
+        assert_eq!(RecordType::SOA, result.authorities[0].atype);
+        assert_eq!(300, result.authorities[0].ttl);
+
+        let result = zone.lookup(&name("www.example.org"), RecordType::MX);
+        assert_eq!(Rcode::NOERROR, result.rcode);
+        assert!(result.answers.is_empty());
+        assert_eq!(1, result.authorities.len());
+
+        let result = zone.lookup(&name("b.example.org"), RecordType::A);
+        assert_eq!(Rcode::NOERROR, result.rcode);
+    }
+
+    #[test]
+    fn referral() {
+        let result = test_zone().lookup(&name("host.sub.example.org"), RecordType::A);
+        assert!(!result.aa);
+        assert!(result.answers.is_empty());
+        assert_eq!(RecordType::NS, result.authorities[0].atype);
+        assert_eq!(vec![10, 0, 1, 1], result.additionals[0].rdata);
+    }
+
+    #[test]
+    fn store_find() {
+        let mut store = ZoneStore::default();
+        store.insert(test_zone());
+        assert!(store.find(&name("deep.www.Example.org")).is_some());
+        assert!(store.find(&name("example.com")).is_none());
+        assert!(store.get(&name("www.example.org")).is_none());
+    }
+
+    #[test]
+    fn synthesized_ptr() {
+        let mut store = ZoneStore::default();
+        let mut zone = test_zone();
+        zone.config.reverse_ptr = true;
+        store.insert(zone);
+        let ptr = |store: &ZoneStore, qname: &str, qtype: u16| {
+            store.synthesize_ptr(&name(qname), qtype).map(|result| DnsName::from_bytes(&result.answers[0].rdata).to_string())
+        };
+        assert_eq!(Some("www.example.org".to_owned()), ptr(&store, "3.0.0.10.in-addr.arpa", RecordType::PTR));
+        assert_eq!(None, ptr(&store, "3.0.0.10.in-addr.arpa", RecordType::A));
+        assert_eq!(None, ptr(&store, "7.0.0.10.in-addr.arpa", RecordType::PTR));
+
+        //a reverse zone of ours answers instead, even if it doesn't have the name
+        let text = "$ORIGIN 0.0.10.in-addr.arpa.\n$TTL 60\n@ SOA ns1.example.org. hostmaster.example.org. 1 3600 600 86400 60\n\
+                    @ NS ns1.example.org.\n1 PTR router.example.org.\n";
+        let origin = name("0.0.10.in-addr.arpa");
+        let records = master::parse(text, Some(&origin)).unwrap();
+        store.insert(Zone::from_records(origin.clone(), ZoneConfig::default(), records).unwrap());
+        assert_eq!(None, ptr(&store, "3.0.0.10.in-addr.arpa", RecordType::PTR));
+        store.remove(&origin);
+        assert!(ptr(&store, "3.0.0.10.in-addr.arpa", RecordType::PTR).is_some());
+
+        //follows the zone as it changes
+        let mut zone = zone_with(2, "www A 10.0.0.7\n");
+        zone.config.reverse_ptr = true;
+        store.replace(zone);
+        assert_eq!(Some("www.example.org".to_owned()), ptr(&store, "7.0.0.10.in-addr.arpa", RecordType::ANY));
+        assert_eq!(None, ptr(&store, "3.0.0.10.in-addr.arpa", RecordType::PTR));
+    }
+
+    #[test]
+    fn replace_journals() {
+        let mut store = ZoneStore::default();
+        store.insert(zone_with(1, ""));
+        store.replace(zone_with(2, "www A 10.0.0.2\n"));
+        store.replace(zone_with(3, "www A 10.0.0.3\n"));
+        let zone = store.get(&name("example.org")).unwrap();
+        assert_eq!(3, zone.serial());
+        assert_eq!(2, zone.journal.len());
+        assert_eq!(vec![name("example.org")], store.take_changed());
+        assert!(!store.has_changed());
+
+        //backwards
+        store.replace(zone_with(2, ""));
+        assert!(store.get(&name("example.org")).unwrap().journal.is_empty());
+    }
+
+    #[test]
+    fn apply_diff() {
+        let old = zone_with(1, "www A 10.0.0.2\n");
+        let new = zone_with(2, "www A 10.0.0.3\nmail A 10.0.0.4\n");
+        let diff = Diff::between(&old, &new).unwrap();
+        let applied = old.apply(&diff).unwrap();
+        assert_eq!(2, applied.serial());
+        assert_eq!(new.len(), applied.len());
+        assert!(Diff::between(&applied, &new).unwrap().added.is_empty());
+        assert!(applied.apply(&diff).is_err());
+    }
+
+    #[test]
+    fn journal_file_replay_and_compact() {
+        let path = env::temp_dir().join("koala_compact_test.zone").to_string_lossy().into_owned();
+        let config = ZoneConfig::new("example.org".to_owned(), path.clone());
+        let journal = Zone::new(name("example.org"), config.clone()).journal_file().unwrap();
+        journal.clear().unwrap();
+        let text = "$ORIGIN example.org.\n$TTL 60\n@ SOA ns1 hostmaster 1 3600 600 86400 300\n  NS ns1\nns1 A 10.0.0.1\n";
+        fs::write(&path, text).unwrap();
+
+        let mut store = ZoneStore::default();
+        store.insert(Zone::load(&config).unwrap());
+        let mut next = zone_with(2, "www A 10.0.0.2\n");
+        next.config = config.clone();
+        store.commit(next).unwrap();
+
+        //a restart replays the change on top of the master file
+        let mut zone = Zone::load(&config).unwrap();
+        assert_eq!(2, zone.serial());
+        assert_eq!(1, zone.find(&name("www.example.org"), RecordType::A).len());
+        assert_eq!(1, zone.journal.len());
+        assert_eq!(1, zone.uncompacted);
+
+        zone.compact().unwrap();
+        assert!(journal.read().unwrap().is_empty());
+        assert!(!zone.file_changed());
+        let reloaded = Zone::load(&config).unwrap();
+        assert_eq!(2, reloaded.serial());
+        assert_eq!(zone.len(), reloaded.len());
+
+        //a crash after writing the master file but before emptying the journal
+        journal.append(&Diff::between(&zone_with(1, ""), &zone_with(2, "www A 10.0.0.2\n")).unwrap()).unwrap();
+        let reloaded = Zone::load(&config).unwrap();
+        assert_eq!(2, reloaded.serial());
+        assert_eq!(0, reloaded.uncompacted);
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn signed_lookup() {
+        let dir = env::temp_dir();
+        let (ksk, zsk) = (dir.join("koala_signed_test.ksk"), dir.join("koala_signed_test.zsk"));
+        let _ = fs::remove_file(&ksk);
+        let _ = fs::remove_file(&zsk);
+        let mut zone = test_zone();
+        zone.config.ksk = Some(ksk.to_string_lossy().into_owned());
+        zone.config.zsk = Some(zsk.to_string_lossy().into_owned());
+        let mut store = ZoneStore::default();
+        store.insert(zone.clone());
+        let signed = store.get(&name("example.org")).unwrap();
+        assert!(signed.is_signed());
+        assert_eq!(2, signed.lookup(&name("example.org"), RecordType::DNSKEY).answers.len());
+
+        let mut result = signed.lookup(&name("www.example.org"), RecordType::A);
+        signed.add_dnssec(&mut result);
+        assert_eq!(vec![RecordType::A, RecordType::A, RecordType::RRSIG],
+                   result.answers.iter().map(|rr| rr.atype).collect::<Vec<_>>());
+        let mut result = signed.lookup(&name("www.example.org"), RecordType::MX);
+        signed.add_dnssec(&mut result);
+        assert_eq!(vec![RecordType::SOA, RecordType::NSEC, RecordType::RRSIG, RecordType::RRSIG],
+                   result.authorities.iter().map(|rr| rr.atype).collect::<Vec<_>>());
+        //the child's NS and glue belong to it, so aren't signed. The NSEC shows it has no DS
+        let mut result = signed.lookup(&name("host.sub.example.org"), RecordType::A);
+        signed.add_dnssec(&mut result);
+        assert_eq!(vec![RecordType::NS, RecordType::NSEC, RecordType::RRSIG],
+                   result.authorities.iter().map(|rr| rr.atype).collect::<Vec<_>>());
+        assert!(result.additionals.iter().all(|rr| rr.atype != RecordType::RRSIG));
+
+        //the same keys are used after a restart
+        let dnskeys = signed.lookup(&name("example.org"), RecordType::DNSKEY).answers;
+        store.remove(&name("example.org"));
+        store.insert(zone);
+        assert_eq!(dnskeys, store.get(&name("example.org")).unwrap().lookup(&name("example.org"), RecordType::DNSKEY).answers);
+        fs::remove_file(&ksk).unwrap();
+        fs::remove_file(&zsk).unwrap();
+    }
+
+    #[test]
+    fn needs_soa() {
+        let records = master::parse("$TTL 60\nwww.example.org. A 1.2.3.4\n", None).unwrap();
+        assert!(Zone::from_records(name("example.org"), ZoneConfig::default(), records).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use mio::{EventSet, Token};
+use request::base::*;
+use request::udp::UdpRequestFactory;
+use server_mio::RequestCtx;
+use dns::message::*;
+use dns::record::*;
+use dns::tsig::{Signer, TsigKey, Verifier};
+
+///Resend a NOTIFY that hasn't been acknowledged within this. RFC1996 3.6
+const NOTIFY_TIMEOUT_MS: u64 = 2000;
+///Then give up until the serial changes again
+const MAX_ATTEMPTS: u32 = 5;
+
+///A NOTIFY to one secondary, waiting for its acknowledgement
+struct Notify {
+    origin: DnsName,
+    soa: DnsAnswer,
+    target: SocketAddr,
+    key: Option<TsigKey>, //the zone's transfer-key, to sign with
+    verifier: Option<Verifier>,
+    attempts: u32,
+    request: Box<dyn ForwardedRequest>,
+}
+
+///
+///Tells the secondaries of a zone, the servers in its notify list, when its serial changes
+///so they don't have to wait for the refresh interval. RFC1996
+///
+#[derive(Default)]
+pub struct Notifier {
+    pending: HashMap<Token, Notify>,
+    last_token: usize,
+}
+
+impl Notifier {
+    ///Clear of the tokens of requests and secondaries
+    pub const TOKEN_START: usize = 200000;
+    const TOKEN_COUNT: usize = 100000;
+
+    pub fn owns(&self, token: Token) -> bool {
+        self.pending.contains_key(&token)
+    }
+
+    ///Send NOTIFY for each zone whose serial has changed
+    pub fn send_changes(&mut self, ctx: &mut RequestCtx) {
+        let changes = match ctx.zones.write() {
+            Ok(mut zones) => {
+                zones.take_changed()
+                     .iter()
+                     .filter_map(|origin| zones.get(origin))
+                     .filter_map(|zone| {
+                         zone.soa_record().map(|soa| (zone.origin.clone(), soa.clone(), zone.config.notify.clone(), zone.config.transfer_key.clone()))
+                     })
+                     .collect::<Vec<_>>()
+            }
+            Err(e) => {
+                error!("Couldn't get write lock {:?}", e);
+                return;
+            }
+        };
+        for (origin, soa, targets, key) in changes {
+            for target in targets {
+                //a newer serial replaces any NOTIFY still going to the secondary
+                let stale = self.pending
+                                .iter()
+                                .filter(|&(_, notify)| notify.origin == origin && notify.target == target)
+                                .map(|(token, _)| *token)
+                                .collect::<Vec<_>>();
+                for token in stale {
+                    self.pending.remove(&token);
+                }
+                self.send(ctx, origin.clone(), soa.clone(), target, key.clone(), 1);
+            }
+        }
+    }
+
+    ///Signed with the zone's transfer-key if it has one. The secondary expects the key it transfers with
+    fn send(&mut self, ctx: &mut RequestCtx, origin: DnsName, soa: DnsAnswer, target: SocketAddr, key: Option<TsigKey>, attempts: u32) {
+        info!("NOTIFY {:?} to {:?}. Attempt {}", origin.to_string(), target, attempts);
+        let token = self.next_token();
+        let mut query = message(token.as_usize() as u16, &origin, &soa);
+        let mut verifier = None;
+        if let Some(ref key) = key {
+            let mut signer = Signer::new(key.clone());
+            query = signer.sign(&query);
+            verifier = Some(signer.verifier());
+        }
+        let params = RequestParams {
+            timeout: NOTIFY_TIMEOUT_MS,
+            upstream_addr: target,
+        };
+        let base = ForwardedRequestBase::new(token, query.to_bytes(), params);
+        let mut notify = Notify {
+            origin: origin,
+            soa: soa,
+            target: target,
+            key: key,
+            verifier: verifier,
+            attempts: attempts,
+            request: UdpRequestFactory.new_with(base),
+        };
+        let mut req_ctx = RequestCtx::new(ctx.event_loop,
+                                          EventSet::readable(),
+                                          token,
+                                          ctx.cache.clone(),
+                                          ctx.zones.clone(),
+                                          ctx.secondaries.clone(),
+                                          ctx.anchors.clone(),
+                                          ctx.views.clone(),
+                                          ctx.hosts.clone());
+        match notify.request.ready(&mut req_ctx) {
+            Some(response) => self.finish(&mut req_ctx, notify, response),
+            None => {
+                self.pending.insert(token, notify);
+            }
+        }
+    }
+
+    pub fn ready(&mut self, ctx: &mut RequestCtx) {
+        let response = match self.pending.get_mut(&ctx.token) {
+            Some(notify) => notify.request.ready(ctx),
+            None => None,
+        };
+        if let Some(response) = response {
+            if let Some(notify) = self.pending.remove(&ctx.token) {
+                self.finish(ctx, notify, response);
+            }
+        }
+    }
+
+    pub fn timeout(&mut self, ctx: &mut RequestCtx) {
+        if let Some(mut notify) = self.pending.remove(&ctx.token) {
+            let response = notify.request.get_mut().on_timeout(ctx.token);
+            self.finish(ctx, notify, response);
+        }
+    }
+
+    fn finish(&mut self, ctx: &mut RequestCtx, mut notify: Notify, response: Response) {
+        if response.source == Source::Upstream && is_ack(&response.msg) {
+            let verified = match notify.verifier {
+                Some(ref mut verifier) => verifier.verify(&response.bytes),
+                None => Ok(()),
+            };
+            match verified {
+                Ok(_) => {
+                    info!("NOTIFY {:?} acknowledged by {:?}", notify.origin.to_string(), notify.target);
+                    return;
+                }
+                Err(e) => warn!("NOTIFY {:?} acknowledgement from {:?} ignored. {}", notify.origin.to_string(), notify.target, e),
+            }
+        }
+        if notify.attempts >= MAX_ATTEMPTS {
+            warn!("NOTIFY {:?} to {:?} not acknowledged after {} attempts. Giving up",
+                  notify.origin.to_string(),
+                  notify.target,
+                  notify.attempts);
+            return;
+        }
+        self.send(ctx, notify.origin, notify.soa, notify.target, notify.key, notify.attempts + 1);
+    }
+
+    fn next_token(&mut self) -> Token {
+        self.last_token = (self.last_token + 1) % Self::TOKEN_COUNT;
+        Token(Self::TOKEN_START + self.last_token)
+    }
+}
+
+///A NOTIFY for the zone, with its SOA as a hint of the new serial. RFC1996 3.7
+pub fn message(id: u16, origin: &DnsName, soa: &DnsAnswer) -> DnsMessage {
+    let mut header = DnsHeader::new_query(id);
+    header.opcode = Opcode::NOTIFY;
+    header.aa = true;
+    let question = DnsQuestion::new(origin.clone(), RecordType::SOA, RecordClass::IN);
+    let mut msg = DnsMessage::new_query(header, vec![question]);
+    msg.answers.push(soa.clone());
+    msg
+}
+
+pub fn is_ack(msg: &DnsMessage) -> bool {
+    msg.header.qr && msg.header.opcode == Opcode::NOTIFY && msg.header.rcode == Rcode::NOERROR
+}
+
+///The reply to a NOTIFY. It echoes the question
+pub fn reply(query: &DnsMessage, rcode: u8) -> DnsMessage {
+    let mut header = DnsHeader::new_reply(&query.header, rcode);
+    header.aa = rcode == Rcode::NOERROR;
+    DnsMessage::new_reply(header, query.questions.clone(), Vec::new())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{message, is_ack, reply};
+    use dns::message::*;
+    use dns::record::*;
+    use zone::test::zone_with;
+
+    #[test]
+    fn notify_round_trip() {
+        let zone = zone_with(7, "");
+        let msg = message(42, &zone.origin, zone.soa_record().unwrap());
+        let parsed = DnsMessage::parse(&msg.to_bytes());
+        assert_eq!(Opcode::NOTIFY, parsed.header.opcode);
+        assert!(parsed.header.aa);
+        assert!(!parsed.header.qr);
+        assert_eq!(RecordType::SOA, parsed.first_question().unwrap().qtype);
+        assert_eq!(1, parsed.answers.len());
+        assert!(!is_ack(&parsed));
+
+        let ack = DnsMessage::parse(&reply(&parsed, Rcode::NOERROR).to_bytes());
+        assert!(is_ack(&ack));
+        assert_eq!(42, ack.header.id);
+        assert_eq!(1, ack.questions.len());
+        assert!(!is_ack(&reply(&parsed, Rcode::REFUSED)));
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use dns::message::{DnsAnswer, DnsName, IntoBytes};
+use dns::record::*;
+use zone::Zone;
+
+///
+///PTR records made from the A and AAAA records of zones with reverse-ptr set, so their hosts resolve
+///both ways without a hand-kept reverse zone. A name with more than one address gets a PTR for each.
+///An address more than one name has points back to the name with the fewest labels, then the
+///alphabetically first, so the answer doesn't depend on the order of the files. Wildcards are left out
+///
+#[derive(Default)]
+pub struct ReverseIndex {
+    names: HashMap<IpAddr, (DnsName, u32)>,
+}
+
+impl ReverseIndex {
+    pub fn build<'a, I: Iterator<Item = &'a Zone>>(zones: I) -> ReverseIndex {
+        let mut index = ReverseIndex::default();
+        for zone in zones.filter(|zone| zone.config.reverse_ptr) {
+            for rr in zone.records() {
+                if let Some(addr) = address(rr) {
+                    index.add(addr, rr);
+                }
+            }
+        }
+        index
+    }
+
+    fn add(&mut self, addr: IpAddr, rr: &DnsAnswer) {
+        if rr.name.labels().first().map_or(false, |label| label == "*") {
+            return;
+        }
+        match self.names.entry(addr) {
+            Entry::Occupied(mut entry) => {
+                if preferred(&rr.name, &entry.get().0) {
+                    entry.insert((rr.name.clone(), rr.ttl));
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert((rr.name.clone(), rr.ttl));
+            }
+        }
+    }
+
+    ///The PTR record for qname, if it's the reverse name of an address in the index
+    pub fn ptr(&self, qname: &DnsName) -> Option<DnsAnswer> {
+        let addr = address_of(qname)?;
+        self.names
+            .get(&addr)
+            .map(|&(ref name, ttl)| DnsAnswer::with_rdata(qname.clone(), RecordType::PTR, RecordClass::IN, ttl, name.to_bytes()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+}
+
+///True if a should be the PTR target over b
+fn preferred(a: &DnsName, b: &DnsName) -> bool {
+    (a.labels().len(), a.to_lowercase().to_string()) < (b.labels().len(), b.to_lowercase().to_string())
+}
+
+///The address of an A or AAAA record
+fn address(rr: &DnsAnswer) -> Option<IpAddr> {
+    match (rr.atype, rr.rdata.len()) {
+        (RecordType::A, 4) => Some(IpAddr::V4(Ipv4Addr::new(rr.rdata[0], rr.rdata[1], rr.rdata[2], rr.rdata[3]))),
+        (RecordType::AAAA, 16) => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&rr.rdata);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+///The address name is the reverse name of. E.g. 2.0.0.10.in-addr.arpa (RFC1035 3.5) or a name of 32 hex
+///nibbles under ip6.arpa (RFC3596 2.5). None for anything else, including the names above them
+pub fn address_of(name: &DnsName) -> Option<IpAddr> {
+    let name = name.to_lowercase();
+    let labels = name.labels();
+    if labels.len() == 6 && labels[4..] == ["in-addr", "arpa"] {
+        let mut octets = [0u8; 4];
+        for (i, label) in labels[..4].iter().rev().enumerate() {
+            octets[i] = label.parse().ok()?;
+            //one way to write each address
+            if octets[i].to_string() != *label {
+                return None;
+            }
+        }
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+    if labels.len() == 34 && labels[32..] == ["ip6", "arpa"] {
+        let mut octets = [0u8; 16];
+        for (i, label) in labels[..32].iter().rev().enumerate() {
+            if label.len() != 1 {
+                return None;
+            }
+            let nibble = u8::from_str_radix(label, 16).ok()?;
+            octets[i / 2] |= if i % 2 == 0 { nibble << 4 } else { nibble };
+        }
+        return Some(IpAddr::V6(Ipv6Addr::from(octets)));
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReverseIndex, address_of};
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use dns::message::DnsName;
+    use dns::record::RecordType;
+    use settings::ZoneConfig;
+    use zone::Zone;
+    use zone::master;
+
+    fn name(s: &str) -> DnsName {
+        DnsName::from_string(s.to_owned())
+    }
+
+    fn zone(origin: &str, reverse_ptr: bool, records: &str) -> Zone {
+        let text = format!("$TTL 300\n@ SOA ns1 hostmaster 1 3600 600 86400 60\n  NS ns1\nns1 A 10.0.0.1\n{}", records);
+        let mut config = ZoneConfig::default();
+        config.reverse_ptr = reverse_ptr;
+        let origin = name(origin);
+        Zone::from_records(origin.clone(), config, master::parse(&text, Some(&origin)).unwrap()).unwrap()
+    }
+
+    fn target(index: &ReverseIndex, qname: &str) -> Option<String> {
+        index.ptr(&name(qname)).map(|ptr| {
+            assert_eq!(RecordType::PTR, ptr.atype);
+            DnsName::from_bytes(&ptr.rdata).to_string()
+        })
+    }
+
+    #[test]
+    fn reverse_names() {
+        assert_eq!(Some(IpAddr::from_str("10.0.0.2").unwrap()), address_of(&name("2.0.0.10.IN-ADDR.arpa")));
+        assert_eq!(Some(IpAddr::from_str("2001:db8::567:89ab").unwrap()),
+                   address_of(&name("b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa")));
+        assert_eq!(None, address_of(&name("0.0.10.in-addr.arpa")));
+        assert_eq!(None, address_of(&name("02.0.0.10.in-addr.arpa")));
+        assert_eq!(None, address_of(&name("256.0.0.10.in-addr.arpa")));
+        assert_eq!(None, address_of(&name("2.0.0.10.example.org")));
+        assert_eq!(None, address_of(&name("ba.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.0.ip6.arpa")));
+    }
+
+    #[test]
+    fn ptr_from_forward_zones() {
+        let lan = zone("lan", true, "www A 10.0.0.2\n    A 10.0.0.3\nv6 AAAA 2001:db8::1\n* A 10.0.0.9\n");
+        let other = zone("example.org", false, "www A 10.0.0.4\n");
+        let index = ReverseIndex::build(vec![lan, other].iter());
+        assert_eq!(Some("www.lan".to_owned()), target(&index, "2.0.0.10.in-addr.arpa"));
+        assert_eq!(Some("www.lan".to_owned()), target(&index, "3.0.0.10.in-addr.arpa"));
+        assert_eq!(Some("ns1.lan".to_owned()), target(&index, "1.0.0.10.in-addr.arpa"));
+        assert_eq!(Some("v6.lan".to_owned()),
+                   target(&index, "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"));
+        //not from a wildcard, or a zone without reverse-ptr
+        assert_eq!(None, target(&index, "9.0.0.10.in-addr.arpa"));
+        assert_eq!(None, target(&index, "4.0.0.10.in-addr.arpa"));
+        assert_eq!(300, index.ptr(&name("2.0.0.10.in-addr.arpa")).unwrap().ttl);
+    }
+
+    #[test]
+    fn shared_address() {
+        //the fewest labels, then alphabetically, whatever order the records are in
+        let index = ReverseIndex::build(vec![zone("lan", true, "x.printer A 10.0.0.5\nzebra A 10.0.0.5\nPrinter A 10.0.0.5\n")].iter());
+        assert_eq!(Some("Printer.lan".to_owned()), target(&index, "5.0.0.10.in-addr.arpa"));
+        assert_eq!(2, index.len());
+    }
+}
@@ -0,0 +1,543 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use mio::{EventSet, Token};
+use request::base::*;
+use request::tcp::TcpRequestFactory;
+use server_mio::RequestCtx;
+use settings::ZoneConfig;
+use dns::message::*;
+use dns::record::*;
+use dns::tsig::{Signer, Verifier};
+use zone::{Zone, ZoneStore};
+use zone::journal::Diff;
+use zone::transfer;
+
+///Until we have an SOA with the retry interval, how long to wait before trying the primary again
+const INITIAL_RETRY_SECS: u64 = 10;
+///Transfers of big zones take longer than a query. Also used for SOA checks
+const TRANSFER_TIMEOUT_MS: u64 = 30000;
+
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Copy)]
+#[derive(PartialEq)]
+enum Check {
+    Soa,
+    Transfer,
+}
+
+///
+///A zone we are a secondary for. It's transferred from the primary at startup, then the primary's SOA
+///is checked every refresh interval, or retry interval after a failure, and the zone transferred again
+///if the serial has gone up. If the primary can't be reached for the expire interval, we stop serving
+///the zone until it can. RFC1034 4.3.5
+///
+pub struct Secondary {
+    pub config: ZoneConfig,
+    pub origin: DnsName,
+    token: Token,
+    request: Option<(Check, Box<dyn ForwardedRequest>)>,
+    next_check: Instant,
+    expires: Option<Instant>, //None when we have no copy to serve
+    axfr_next: bool, //the last IXFR couldn't be applied
+    notified: bool, //the primary sent a NOTIFY we haven't acted on
+    verifier: Option<Verifier>, //for the response to a query signed with the primary-key
+}
+
+///The secondary zones, each with its own token for requests to its primary
+#[derive(Default)]
+pub struct Secondaries {
+    zones: Vec<Secondary>,
+}
+
+impl Secondaries {
+    ///Clear of the tokens ServerBase gives requests
+    pub const TOKEN_START: usize = 100000;
+
+    pub fn new(configs: &[ZoneConfig]) -> Secondaries {
+        let zones = configs.iter()
+                           .filter(|config| config.is_secondary())
+                           .enumerate()
+                           .map(|(i, config)| Secondary::new(config.clone(), Token(Self::TOKEN_START + i)))
+                           .collect::<Vec<_>>();
+        for zone in &zones {
+            info!("Secondary for {:?}. Primary is {:?}", zone.origin.to_string(), zone.config.primary);
+        }
+        Secondaries { zones: zones }
+    }
+
+    pub fn owns(&self, token: Token) -> bool {
+        self.index(token).is_some()
+    }
+
+    ///
+    ///A NOTIFY that origin has changed. Only accepted from the zone's primary, signed with its
+    ///primary-key if it has one. The SOA check starts once the NOTIFY is answered, or if a check or
+    ///transfer is in progress, once that finishes. RFC1996 3.11
+    ///
+    pub fn notify(&mut self, origin: &DnsName, client: &IpAddr, signed_by: Option<&DnsName>) -> u8 {
+        let origin = origin.to_lowercase();
+        let secondary = match self.zones.iter_mut().find(|secondary| secondary.origin == origin) {
+            Some(secondary) => secondary,
+            None => {
+                warn!("NOTIFY for {:?} from {:?}. Not a secondary for it", origin.to_string(), client);
+                return Rcode::NOTAUTH;
+            }
+        };
+        if secondary.config.primary.map_or(true, |primary| primary.ip() != *client) {
+            warn!("NOTIFY for {:?} from {:?} refused. Not the primary", origin.to_string(), client);
+            return Rcode::REFUSED;
+        }
+        if let Some(ref key) = secondary.config.primary_key {
+            if signed_by.map_or(true, |name| !name.eq_ignore_case(&key.name)) {
+                warn!("NOTIFY for {:?} from {:?} refused. Not signed with primary-key", origin.to_string(), client);
+                return Rcode::REFUSED;
+            }
+        }
+        info!("NOTIFY for {:?} from {:?}", origin.to_string(), client);
+        secondary.notified = true;
+        Rcode::NOERROR
+    }
+
+    ///True if a NOTIFY is waiting for its SOA check to start
+    pub fn notified(&self) -> bool {
+        self.zones.iter().any(|secondary| secondary.notified && secondary.request.is_none())
+    }
+
+    fn index(&self, token: Token) -> Option<usize> {
+        let i = token.as_usize().wrapping_sub(Self::TOKEN_START);
+        if i < self.zones.len() { Some(i) } else { None }
+    }
+
+    ///Start checks that are due and stop serving zones that have expired
+    pub fn tick(&mut self, ctx: &mut RequestCtx) {
+        let now = Instant::now();
+        for secondary in &mut self.zones {
+            if secondary.expired(now) {
+                secondary.expire(ctx);
+            }
+            if secondary.due(now) {
+                secondary.check(ctx);
+            }
+        }
+    }
+
+    pub fn ready(&mut self, ctx: &mut RequestCtx) {
+        if let Some(i) = self.index(ctx.token) {
+            self.zones[i].ready(ctx);
+        }
+    }
+
+    pub fn timeout(&mut self, ctx: &mut RequestCtx) {
+        if let Some(i) = self.index(ctx.token) {
+            self.zones[i].timeout(ctx);
+        }
+    }
+}
+
+impl Secondary {
+    pub fn new(config: ZoneConfig, token: Token) -> Secondary {
+        Secondary {
+            origin: DnsName::from_string(config.origin.clone()).to_lowercase(),
+            config: config,
+            token: token,
+            request: None,
+            next_check: Instant::now(),
+            expires: None,
+            axfr_next: false,
+            notified: false,
+            verifier: None,
+        }
+    }
+
+    fn due(&self, now: Instant) -> bool {
+        self.request.is_none() && (self.notified || now >= self.next_check)
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        self.expires.map_or(false, |expires| now >= expires)
+    }
+
+    ///We heard from the primary and are up to date with it
+    fn contacted(&mut self, now: Instant, soa: &Soa) {
+        self.next_check = now + Duration::from_secs(soa.refresh as u64);
+        self.expires = Some(now + Duration::from_secs(soa.expire as u64));
+        self.axfr_next = false;
+    }
+
+    fn failed(&mut self, now: Instant, soa: Option<&Soa>) {
+        let retry = soa.map_or(INITIAL_RETRY_SECS, |soa| soa.retry as u64);
+        self.next_check = now + Duration::from_secs(retry);
+        info!("Will try the primary for {:?} again in {}s", self.origin.to_string(), retry);
+    }
+
+    fn expire(&mut self, ctx: &mut RequestCtx) {
+        warn!("No contact with the primary for {:?} within the expire interval. No longer serving it",
+              self.origin.to_string());
+        self.expires = None;
+        match ctx.zones.write() {
+            Ok(mut zones) => {
+                zones.remove(&self.origin);
+            }
+            Err(e) => error!("Couldn't get write lock {:?}", e),
+        }
+    }
+
+    ///Our copy's SOA record
+    fn current(&self, ctx: &RequestCtx) -> Option<DnsAnswer> {
+        match ctx.zones.read() {
+            Ok(zones) => zones.get(&self.origin).and_then(|zone| zone.soa_record()).cloned(),
+            Err(e) => {
+                error!("Couldn't get read lock {:?}", e);
+                None
+            }
+        }
+    }
+
+    ///With a copy, see if the primary has a newer one. Without, transfer it
+    fn check(&mut self, ctx: &mut RequestCtx) {
+        self.notified = false;
+        if self.current(ctx).is_some() {
+            let question = DnsQuestion::new(self.origin.clone(), RecordType::SOA, RecordClass::IN);
+            let query = DnsMessage::new_query(DnsHeader::new_query(Self::query_id()), vec![question]);
+            self.send(ctx, Check::Soa, query);
+        } else {
+            self.transfer(ctx);
+        }
+    }
+
+    ///IXFR if we have a copy, AXFR if not
+    fn transfer(&mut self, ctx: &mut RequestCtx) {
+        let current = if self.axfr_next { None } else { self.current(ctx) };
+        let query = transfer::query(Self::query_id(), &self.origin, current.as_ref());
+        self.send(ctx, Check::Transfer, query);
+    }
+
+    fn query_id() -> u16 {
+        ::time::precise_time_ns() as u16
+    }
+
+    ///Send the query to the primary with the TcpRequest used for forwarding. Signed if the zone has a primary-key
+    fn send(&mut self, ctx: &mut RequestCtx, check: Check, query: DnsMessage) {
+        let primary = match self.config.primary {
+            Some(primary) => primary,
+            None => return,
+        };
+        let query = match self.config.primary_key {
+            Some(ref key) => {
+                let mut signer = Signer::new(key.clone());
+                let signed = signer.sign(&query);
+                self.verifier = Some(signer.verifier());
+                signed
+            }
+            None => query,
+        };
+        debug!("{:?} for {:?} to {:?}", check, self.origin.to_string(), primary);
+        let params = RequestParams {
+            timeout: TRANSFER_TIMEOUT_MS,
+            upstream_addr: primary,
+        };
+        let base = ForwardedRequestBase::new(self.token, query.to_bytes(), params);
+        let mut request = TcpRequestFactory.new_with(base);
+        let mut req_ctx = RequestCtx::new(ctx.event_loop,
+                                          EventSet::readable(),
+                                          self.token,
+                                          ctx.cache.clone(),
+                                          ctx.zones.clone(),
+                                          ctx.secondaries.clone(),
+                                          ctx.anchors.clone(),
+                                          ctx.views.clone(),
+                                          ctx.hosts.clone());
+        match request.ready(&mut req_ctx) {
+            Some(response) => self.finish(&mut req_ctx, check, response),
+            None => self.request = Some((check, request)),
+        }
+    }
+
+    fn ready(&mut self, ctx: &mut RequestCtx) {
+        if let Some((check, mut request)) = self.request.take() {
+            match request.ready(ctx) {
+                Some(response) => self.finish(ctx, check, response),
+                None => self.request = Some((check, request)),
+            }
+        }
+    }
+
+    fn timeout(&mut self, ctx: &mut RequestCtx) {
+        if let Some((check, mut request)) = self.request.take() {
+            let response = request.get_mut().on_timeout(ctx.token);
+            self.finish(ctx, check, response);
+        }
+    }
+
+    fn finish(&mut self, ctx: &mut RequestCtx, check: Check, response: Response) {
+        let now = Instant::now();
+        let current = self.current(ctx).and_then(|rr| Soa::from_rdata(&rr.rdata));
+        if response.source != Source::Upstream || response.msg.header.rcode != Rcode::NOERROR {
+            warn!("{:?} for {:?} from {:?} failed. rcode {}",
+                  check,
+                  self.origin.to_string(),
+                  self.config.primary,
+                  response.msg.header.rcode);
+            self.failed(now, current.as_ref());
+            return;
+        }
+        if let Err(e) = self.verify(check, &response.bytes) {
+            warn!("{:?} for {:?} from {:?} failed. {}", check, self.origin.to_string(), self.config.primary, e);
+            self.failed(now, current.as_ref());
+            return;
+        }
+        if check == Check::Soa {
+            let primary = response.msg.answers.iter().find(|rr| rr.atype == RecordType::SOA).and_then(|rr| Soa::from_rdata(&rr.rdata));
+            match (current.as_ref(), primary) {
+                (Some(ours), Some(theirs)) => {
+                    if serial_lt(ours.serial, theirs.serial) {
+                        info!("Primary has serial {} of {:?}. We have {}", theirs.serial, self.origin.to_string(), ours.serial);
+                        self.transfer(ctx);
+                    } else {
+                        debug!("{:?} is up to date at serial {}", self.origin.to_string(), ours.serial);
+                        self.contacted(now, &ours);
+                    }
+                }
+                (_, None) => {
+                    warn!("No SOA in the primary's answer for {:?}", self.origin.to_string());
+                    self.failed(now, current.as_ref());
+                }
+                (None, Some(_)) => self.transfer(ctx),
+            }
+            return;
+        }
+        let result = match ctx.zones.write() {
+            Ok(mut zones) => self.update(&mut zones, &response.msg),
+            Err(e) => Err(format!("Couldn't get write lock {:?}", e)),
+        };
+        match result {
+            Ok(soa) => self.contacted(now, &soa),
+            Err(e) => {
+                warn!("Transfer of {:?} failed. {}", self.origin.to_string(), e);
+                self.axfr_next = true;
+                self.failed(now, current.as_ref());
+            }
+        }
+    }
+
+    ///Check the TSIGs on the response to a signed query. A transfer's are on its messages
+    fn verify(&mut self, check: Check, bytes: &[u8]) -> Result<(), String> {
+        let mut verifier = match self.verifier.take() {
+            Some(verifier) => verifier,
+            None => return Ok(()),
+        };
+        let messages = match check {
+            Check::Soa => vec![bytes],
+            Check::Transfer => transfer::split_stream(bytes),
+        };
+        for msg in messages {
+            verifier.verify(msg)?;
+        }
+        if verifier.is_complete() { Ok(()) } else { Err("Response ends with unsigned messages".to_owned()) }
+    }
+
+    ///Put a transfer response in the store. It's a whole zone, the diffs to our copy, or just the SOA if
+    ///we're up to date. Returns the zone's SOA
+    fn update(&self, zones: &mut ZoneStore, msg: &DnsMessage) -> Result<Soa, String> {
+        let answers = &msg.answers;
+        let soa = answers.first()
+                         .filter(|rr| rr.atype == RecordType::SOA)
+                         .and_then(|rr| Soa::from_rdata(&rr.rdata))
+                         .ok_or_else(|| "Response doesn't start with an SOA".to_owned())?;
+        if answers.len() == 1 {
+            debug!("{:?} is up to date at serial {}", self.origin.to_string(), soa.serial);
+            return Ok(soa);
+        }
+        let incremental = answers[1].atype == RecordType::SOA && !answers[1].same_rr(&answers[0]);
+        let zone = if incremental {
+            let mut zone = zones.get(&self.origin)
+                                .cloned()
+                                .ok_or_else(|| "No copy to apply an IXFR to".to_owned())?;
+            for diff in Diff::from_ixfr(answers)? {
+                zone = zone.apply(&diff)?;
+            }
+            zone
+        } else {
+            //the closing SOA is the same as the first
+            let records = answers[..answers.len() - 1].to_vec();
+            Zone::from_records(self.origin.clone(), self.config.clone(), records)?
+        };
+        if zone.serial() != soa.serial {
+            return Err(format!("Transfer ended at serial {}, expected {}", zone.serial(), soa.serial));
+        }
+        info!("Transferred {:?} serial {} from {:?}", self.origin.to_string(), soa.serial, self.config.primary);
+        zones.replace(zone);
+        Ok(soa)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Check, Secondary, Secondaries, INITIAL_RETRY_SECS};
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use mio::Token;
+    use cidr::Cidr;
+    use settings::{ZoneConfig, parse_server};
+    use dns::message::*;
+    use dns::record::*;
+    use dns::tsig::{self, Auth, Keyring, Signer, TsigKey};
+    use zone::ZoneStore;
+    use zone::test::zone_with;
+    use zone::transfer;
+
+    fn secondary() -> Secondary {
+        let mut config = ZoneConfig::new("example.org".to_owned(), String::new());
+        config.primary = Some(parse_server("127.0.0.1").unwrap());
+        Secondary::new(config, Token(1))
+    }
+
+    fn primary(versions: &[&str]) -> ZoneStore {
+        let mut store = ZoneStore::default();
+        for (i, extra) in versions.iter().enumerate() {
+            store.replace(zone_with(i as u32 + 1, extra));
+        }
+        for zone in store.zones.values_mut() {
+            zone.config.allow_transfer = vec![Cidr::from_str("127.0.0.1").unwrap()];
+        }
+        store
+    }
+
+    ///What the primary sends for a transfer query from the secondary's store
+    fn transfer_from(primary: &ZoneStore, ours: &ZoneStore) -> DnsMessage {
+        let origin = DnsName::from_string("example.org".to_owned());
+        let soa = ours.get(&origin).and_then(|zone| zone.soa_record());
+        let query = transfer::query(1, &origin, soa);
+        let client = IpAddr::from_str("127.0.0.1").unwrap();
+        transfer::join(transfer::respond(&query, primary, &client, None)).unwrap()
+    }
+
+    #[test]
+    fn timers() {
+        let mut secondary = secondary();
+        let now = Instant::now();
+        assert!(secondary.due(now));
+        assert!(!secondary.expired(now + Duration::from_secs(1000000)));
+
+        secondary.failed(now, None);
+        assert!(!secondary.due(now));
+        assert!(secondary.due(now + Duration::from_secs(INITIAL_RETRY_SECS)));
+
+        //refresh 3600 retry 600 expire 86400
+        let soa = zone_with(1, "").soa().unwrap();
+        secondary.contacted(now, &soa);
+        assert!(!secondary.due(now + Duration::from_secs(3599)));
+        assert!(secondary.due(now + Duration::from_secs(3600)));
+
+        secondary.failed(now + Duration::from_secs(3600), Some(&soa));
+        assert!(secondary.due(now + Duration::from_secs(4200)));
+        assert!(!secondary.expired(now + Duration::from_secs(86399)));
+        assert!(secondary.expired(now + Duration::from_secs(86400)));
+    }
+
+    #[test]
+    fn notify() {
+        let mut secondaries = Secondaries { zones: vec![secondary()] };
+        let origin = DnsName::from_string("Example.org".to_owned());
+        let primary = IpAddr::from_str("127.0.0.1").unwrap();
+        let now = Instant::now();
+        secondaries.zones[0].contacted(now, &zone_with(1, "").soa().unwrap());
+        assert!(!secondaries.zones[0].due(now));
+
+        assert_eq!(Rcode::REFUSED, secondaries.notify(&origin, &IpAddr::from_str("10.0.0.1").unwrap(), None));
+        assert_eq!(Rcode::NOTAUTH, secondaries.notify(&DnsName::from_string("example.com".to_owned()), &primary, None));
+        assert!(!secondaries.notified());
+        assert_eq!(Rcode::NOERROR, secondaries.notify(&origin, &primary, None));
+        assert!(secondaries.notified());
+        assert!(secondaries.zones[0].due(now));
+    }
+
+    #[test]
+    fn notify_key() {
+        let key = TsigKey::new("xfr", "hmac-sha256", b"secret".to_vec()).unwrap();
+        let mut secondary = secondary();
+        secondary.config.primary_key = Some(key.clone());
+        let mut secondaries = Secondaries { zones: vec![secondary] };
+        let origin = DnsName::from_string("example.org".to_owned());
+        let primary = IpAddr::from_str("127.0.0.1").unwrap();
+        assert_eq!(Rcode::REFUSED, secondaries.notify(&origin, &primary, None));
+        assert_eq!(Rcode::REFUSED, secondaries.notify(&origin, &primary, Some(&DnsName::from_string("other".to_owned()))));
+        assert_eq!(Rcode::NOERROR, secondaries.notify(&origin, &primary, Some(&key.name)));
+    }
+
+    #[test]
+    fn signed_transfer() {
+        let key = TsigKey::new("xfr", "hmac-sha256", b"secret".to_vec()).unwrap();
+        let origin = DnsName::from_string("example.org".to_owned());
+        let client = IpAddr::from_str("127.0.0.1").unwrap();
+        let primary_store = primary(&["www A 10.0.0.1\n"]);
+        let query = transfer::query(1, &origin, None);
+        let mut signer = Signer::new(key.clone());
+        let signed = signer.sign(&query);
+        let mut responder = match tsig::verify_request(&signed.to_bytes(), &Keyring::new(vec![key.clone()])) {
+            Auth::Signed(responder) => responder,
+            _ => panic!("Didn't verify"),
+        };
+        let messages = transfer::respond(&query, &primary_store, &client, Some(responder.key_name()));
+        let mut stream = Vec::new();
+        for msg in messages {
+            let bytes = responder.sign(&msg).to_bytes();
+            stream.extend(&[(bytes.len() >> 8) as u8, bytes.len() as u8]);
+            stream.extend(bytes);
+        }
+
+        let mut secondary = secondary();
+        assert!(secondary.verify(Check::Transfer, &stream).is_ok());
+        secondary.verifier = Some(signer.verifier());
+        assert!(secondary.verify(Check::Transfer, &stream).is_ok());
+        secondary.verifier = Some(Signer::new(key.clone()).verifier());
+        assert!(secondary.verify(Check::Transfer, &stream).is_err());
+    }
+
+    #[test]
+    fn axfr_then_ixfr() {
+        let secondary = secondary();
+        let mut ours = ZoneStore::default();
+        let origin = DnsName::from_string("example.org".to_owned());
+
+        let primary_store = primary(&["www A 10.0.0.1\n"]);
+        let msg = transfer_from(&primary_store, &ours);
+        assert_eq!(1, secondary.update(&mut ours, &msg).unwrap().serial);
+        assert_eq!(primary_store.get(&origin).unwrap().len(), ours.get(&origin).unwrap().len());
+
+        //up to date
+        let msg = transfer_from(&primary_store, &ours);
+        assert_eq!(1, msg.answers.len());
+        assert_eq!(1, secondary.update(&mut ours, &msg).unwrap().serial);
+
+        let primary_store = primary(&["www A 10.0.0.1\n", "www A 10.0.0.2\n", "www A 10.0.0.3\nmail A 10.0.0.4\n"]);
+        let msg = transfer_from(&primary_store, &ours);
+        assert_eq!(RecordType::SOA, msg.answers[1].atype);
+        assert_eq!(3, secondary.update(&mut ours, &msg).unwrap().serial);
+        let zone = ours.get(&origin).unwrap();
+        assert_eq!(primary_store.get(&origin).unwrap().len(), zone.len());
+        assert_eq!(vec![10, 0, 0, 3], zone.find(&DnsName::from_string("www.example.org".to_owned()), RecordType::A)[0].rdata);
+        //and we journal it for our own secondaries
+        assert_eq!(1, zone.journal.len());
+    }
+
+    #[test]
+    fn bad_transfers() {
+        let secondary = secondary();
+        let mut ours = primary(&["www A 10.0.0.1\n"]);
+        let primary_store = primary(&["www A 10.0.0.1\n", "www A 10.0.0.2\n"]);
+        let msg = transfer_from(&primary_store, &ours);
+        //an IXFR with no copy to apply it to
+        assert!(secondary.update(&mut ZoneStore::default(), &msg).is_err());
+        //missing the SOA between the deleted and added records
+        let mut bad = msg.clone();
+        bad.answers.remove(3);
+        assert!(secondary.update(&mut ours, &bad).is_err());
+        //refused
+        bad.answers.clear();
+        assert!(secondary.update(&mut ours, &bad).is_err());
+        assert_eq!(1, ours.get(&DnsName::from_string("example.org".to_owned())).unwrap().serial());
+    }
+}
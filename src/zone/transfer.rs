@@ -0,0 +1,413 @@
+use std::net::IpAddr;
+use dns::message::*;
+use dns::record::*;
+use dns::tsig;
+use zone::{Zone, ZoneStore};
+
+///TCP messages have a 2 byte length prefix, so this is as big as one can be
+pub const MAX_MESSAGE_LEN: usize = 65535;
+
+pub fn is_transfer(qtype: u16) -> bool {
+    qtype == RecordType::AXFR || qtype == RecordType::IXFR
+}
+
+///
+///Answer an AXFR (RFC5936) or IXFR (RFC1995) query from client. The reply is a stream of messages
+///that starts and ends with the zone's SOA. A refusal is a single message. signed_by is the name
+///of the key the query's TSIG verified with, if it had one. Signed zones are refused, as their
+///signatures are remade without a new serial, so a secondary's copy would expire.
+///
+pub fn respond(query: &DnsMessage, zones: &ZoneStore, client: &IpAddr, signed_by: Option<&DnsName>) -> Vec<DnsMessage> {
+    let question = match query.first_question() {
+        Some(question) => question,
+        None => return vec![error(query, Rcode::FORMERR)],
+    };
+    let kind = RecordType::name(question.qtype);
+    let zone = match zones.get(&question.qname) {
+        Some(zone) => zone,
+        None => {
+            info!("{} of {:?} from {:?}. Not authoritative", kind, question.qname.to_string(), client);
+            return vec![error(query, Rcode::NOTAUTH)];
+        }
+    };
+    if zone.is_signed() {
+        warn!("{} of {:?} from {:?} refused. The zone is signed", kind, question.qname.to_string(), client);
+        return vec![error(query, Rcode::REFUSED)];
+    }
+    if !tsig::permitted(&zone.config.allow_transfer, zone.config.transfer_key.as_ref(), client, signed_by) {
+        warn!("{} of {:?} from {:?} refused. Not in allow-transfer or not signed with transfer-key", kind, question.qname.to_string(), client);
+        return vec![error(query, Rcode::REFUSED)];
+    }
+    let records = match (question.qtype, client_serial(query)) {
+        (RecordType::IXFR, Some(serial)) => ixfr_records(zone, serial),
+        _ => zone_records(zone),
+    };
+    let messages = pack(query, records);
+    info!("{} of {:?} serial {} to {:?}. {} messages", kind, zone.origin.to_string(), zone.serial(), client, messages.len());
+    messages
+}
+
+///
+///Over UDP we only answer IXFR, and only with the current SOA. That tells a client that is
+///current it is up to date, and any other to retry over TCP. RFC1995 2 and 4
+///
+pub fn respond_udp(query: &DnsMessage, zones: &ZoneStore) -> DnsMessage {
+    let question = match query.first_question() {
+        Some(question) => question,
+        None => return error(query, Rcode::FORMERR),
+    };
+    if question.qtype != RecordType::IXFR {
+        return error(query, Rcode::REFUSED);
+    }
+    match zones.get(&question.qname).and_then(|zone| zone.soa_record().map(|soa| (zone, soa))) {
+        Some((zone, _)) if zone.is_signed() => error(query, Rcode::REFUSED),
+        Some((_, soa)) => {
+            let mut msg = reply(query, query.questions.clone());
+            msg.answers.push(soa.clone());
+            msg
+        }
+        None => error(query, Rcode::NOTAUTH),
+    }
+}
+
+///
+///A transfer query for a zone. IXFR has our SOA in the authority section, so the primary
+///knows what version we have. RFC1995 3
+///
+pub fn query(id: u16, origin: &DnsName, soa: Option<&DnsAnswer>) -> DnsMessage {
+    let qtype = if soa.is_some() { RecordType::IXFR } else { RecordType::AXFR };
+    let question = DnsQuestion::new(origin.clone(), qtype, RecordClass::IN);
+    let mut query = DnsMessage::new_query(DnsHeader::new_query(id), vec![question]);
+    query.authorities.extend(soa.cloned());
+    query
+}
+
+///
+///Whether the messages received so far are the whole response to a transfer query. An AXFR, or an
+///IXFR answered with the whole zone, ends with its first SOA repeated. An incremental IXFR has the
+///current SOA twice more, once at the end of the last diff and once to finish. An IXFR from a client
+///that is current is just the SOA. Errors, or anything not starting with an SOA, are complete.
+///
+pub fn is_complete(query: &DnsMessage, messages: &[DnsMessage]) -> bool {
+    let answers = messages.iter().flat_map(|msg| msg.answers.iter()).collect::<Vec<_>>();
+    let serial = match answers.first().and_then(|rr| soa_serial(rr)) {
+        Some(serial) => serial,
+        None => return !messages.is_empty(),
+    };
+    if answers.len() == 1 {
+        return client_serial(query).map_or(false, |client| !serial_lt(client, serial));
+    }
+    let current = answers[1..].iter().filter(|rr| soa_serial(rr) == Some(serial)).count();
+    let incremental = answers[1].atype == RecordType::SOA && soa_serial(answers[1]) != Some(serial);
+    if incremental { current >= 2 } else { current >= 1 }
+}
+
+///The messages of a transfer as one, with all the answers
+pub fn join(messages: Vec<DnsMessage>) -> Option<DnsMessage> {
+    let mut messages = messages.into_iter();
+    let mut joined = messages.next()?;
+    for msg in messages {
+        joined.answers.extend(msg.answers);
+    }
+    Some(joined)
+}
+
+///The messages of a transfer as received over TCP, each after its 2 byte length prefix
+pub fn split_stream(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut messages = Vec::new();
+    let mut rest = bytes;
+    while rest.len() >= 2 {
+        let len = ((rest[0] as usize) << 8) + rest[1] as usize;
+        if rest.len() < 2 + len {
+            break;
+        }
+        messages.push(&rest[2..2 + len]);
+        rest = &rest[2 + len..];
+    }
+    messages
+}
+
+fn soa_serial(rr: &DnsAnswer) -> Option<u32> {
+    if rr.atype != RecordType::SOA {
+        return None;
+    }
+    Soa::from_rdata(&rr.rdata).map(|soa| soa.serial)
+}
+
+///The serial of the SOA in the authority section of an IXFR query
+fn client_serial(query: &DnsMessage) -> Option<u32> {
+    query.authorities.iter().filter_map(soa_serial).next()
+}
+
+///The records of an IXFR. Just the SOA if the client is current. Otherwise the current SOA, the diffs
+///since the client's serial, then the SOA again. If the journal doesn't go back that far, the whole zone
+pub fn ixfr_records(zone: &Zone, client_serial: u32) -> Vec<DnsAnswer> {
+    let soa = match zone.soa_record() {
+        Some(soa) => soa.clone(),
+        None => return Vec::new(),
+    };
+    if !serial_lt(client_serial, zone.serial()) {
+        debug!("IXFR client serial {} is current", client_serial);
+        return vec![soa];
+    }
+    match zone.journal.since(client_serial) {
+        Some(diffs) => {
+            let mut records = vec![soa.clone()];
+            for diff in diffs {
+                records.extend(diff.records());
+            }
+            records.push(soa);
+            records
+        }
+        None => {
+            info!("No journal from serial {} for {:?}. Sending the whole zone", client_serial, zone.origin.to_string());
+            zone_records(zone)
+        }
+    }
+}
+
+///The records of an AXFR. SOA, everything else, SOA
+pub fn zone_records(zone: &Zone) -> Vec<DnsAnswer> {
+    let soa = match zone.soa_record() {
+        Some(soa) => soa.clone(),
+        None => return Vec::new(),
+    };
+    let mut records = vec![soa.clone()];
+    records.extend(zone.records().into_iter().cloned());
+    records.push(soa);
+    records
+}
+
+///Put as many records in each message as fit in MAX_MESSAGE_LEN. Only the first has the question
+pub fn pack(query: &DnsMessage, records: Vec<DnsAnswer>) -> Vec<DnsMessage> {
+    const HEADER_LEN: usize = 12;
+    let mut messages = Vec::<DnsMessage>::new();
+    let mut msg = reply(query, query.questions.clone());
+    let mut len = msg.to_bytes().len();
+    for record in records {
+        let record_len = record.wire_len();
+        //leaving room for a TSIG
+        if len + record_len > MAX_MESSAGE_LEN - tsig::MAX_LEN && !msg.answers.is_empty() {
+            messages.push(msg);
+            msg = reply(query, Vec::new());
+            len = HEADER_LEN;
+        }
+        len += record_len;
+        msg.answers.push(record);
+    }
+    messages.push(msg);
+    messages
+}
+
+fn reply(query: &DnsMessage, questions: Vec<DnsQuestion>) -> DnsMessage {
+    let mut header = DnsHeader::new_reply(&query.header, Rcode::NOERROR);
+    header.aa = true;
+    DnsMessage::new_reply(header, questions, Vec::new())
+}
+
+pub fn error(query: &DnsMessage, rcode: u8) -> DnsMessage {
+    let header = DnsHeader::new_reply(&query.header, rcode);
+    DnsMessage::new_reply(header, query.questions.clone(), Vec::new())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{respond, respond_udp, pack, is_complete, join, MAX_MESSAGE_LEN};
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use cidr::Cidr;
+    use dns::dnssec::{Algorithm, Dnskey, SigningKey};
+    use dns::message::*;
+    use dns::record::*;
+    use settings::ZoneConfig;
+    use zone::ZoneStore;
+    use zone::test::{signed_zone, test_zone, zone_with};
+
+    fn query() -> DnsMessage {
+        query_type(RecordType::AXFR)
+    }
+
+    fn query_type(qtype: u16) -> DnsMessage {
+        let mut header = DnsHeader::new_error(test_header(), 0);
+        header.qr = false;
+        let question = DnsQuestion::new(DnsName::from_string("example.org".to_owned()), qtype, 1);
+        DnsMessage::new_query(header, vec![question])
+    }
+
+    fn ixfr_query(serial: u32) -> DnsMessage {
+        let mut query = query_type(RecordType::IXFR);
+        let soa = Soa {
+            mname: DnsName::from_string(String::new()),
+            rname: DnsName::from_string(String::new()),
+            serial: serial,
+            refresh: 0,
+            retry: 0,
+            expire: 0,
+            minimum: 0,
+        };
+        query.authorities.push(DnsAnswer::with_rdata(DnsName::from_string("example.org".to_owned()),
+                                                     RecordType::SOA,
+                                                     1,
+                                                     0,
+                                                     soa.to_rdata()));
+        query
+    }
+
+    fn journaled_store() -> ZoneStore {
+        let mut store = ZoneStore::default();
+        store.insert(zone_with(1, "www A 10.0.0.1\n"));
+        store.replace(zone_with(2, "www A 10.0.0.2\n"));
+        store.replace(zone_with(3, "www A 10.0.0.3\n"));
+        for zone in store.zones.values_mut() {
+            zone.config.allow_transfer = vec![Cidr::from_str("127.0.0.1").unwrap()];
+        }
+        store
+    }
+
+    fn serials(msg: &DnsMessage) -> Vec<u32> {
+        msg.answers
+           .iter()
+           .filter(|rr| rr.atype == RecordType::SOA)
+           .map(|rr| Soa::from_rdata(&rr.rdata).unwrap().serial)
+           .collect()
+    }
+
+    fn test_header() -> DnsHeader {
+        DnsMessage::parse(&[0, 7, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).header
+    }
+
+    fn store(allow: &str) -> ZoneStore {
+        let mut zone = test_zone();
+        zone.config.allow_transfer = vec![Cidr::from_str(allow).unwrap()];
+        let mut store = ZoneStore::default();
+        store.insert(zone);
+        store
+    }
+
+    #[test]
+    fn soa_first_and_last() {
+        let client = IpAddr::from_str("127.0.0.1").unwrap();
+        let messages = respond(&query(), &store("127.0.0.0/8"), &client, None);
+        assert_eq!(1, messages.len());
+        let answers = &messages[0].answers;
+        assert_eq!(RecordType::SOA, answers[0].atype);
+        assert_eq!(RecordType::SOA, answers[answers.len() - 1].atype);
+        assert_eq!(test_zone().len() + 1, answers.len());
+        assert!(messages[0].header.aa);
+        assert_eq!(7, messages[0].header.id);
+    }
+
+    #[test]
+    fn refused() {
+        let client = IpAddr::from_str("10.0.0.1").unwrap();
+        let messages = respond(&query(), &store("127.0.0.1"), &client, None);
+        assert_eq!(1, messages.len());
+        assert_eq!(Rcode::REFUSED, messages[0].header.rcode);
+        assert!(messages[0].answers.is_empty());
+    }
+
+    #[test]
+    fn signed_refused() {
+        let client = IpAddr::from_str("127.0.0.1").unwrap();
+        let mut zone = signed_zone("example.org", "$TTL 60\n@ SOA ns1 hostmaster 1 3600 600 86400 60\n  NS ns1\nns1 A 10.0.0.1\n",
+                                   ZoneConfig::default(), vec![Arc::new(SigningKey::generate(Algorithm::ED25519, Dnskey::ZONE | Dnskey::SEP).unwrap().0)], 0);
+        zone.config.allow_transfer = vec![Cidr::from_str("127.0.0.1").unwrap()];
+        let mut store = ZoneStore::default();
+        store.insert(zone);
+        for query in &[query(), ixfr_query(0)] {
+            let messages = respond(query, &store, &client, None);
+            assert_eq!(1, messages.len());
+            assert_eq!(Rcode::REFUSED, messages[0].header.rcode);
+            assert!(messages[0].answers.is_empty());
+        }
+        assert_eq!(Rcode::REFUSED, respond_udp(&ixfr_query(1), &store).header.rcode);
+    }
+
+    #[test]
+    fn not_authoritative() {
+        let client = IpAddr::from_str("127.0.0.1").unwrap();
+        let messages = respond(&query(), &ZoneStore::default(), &client, None);
+        assert_eq!(Rcode::NOTAUTH, messages[0].header.rcode);
+    }
+
+    #[test]
+    fn ixfr_incremental() {
+        let client = IpAddr::from_str("127.0.0.1").unwrap();
+        let messages = respond(&ixfr_query(1), &journaled_store(), &client, None);
+        assert_eq!(1, messages.len());
+        //current, (1, deleted, 2, added), (2, deleted, 3, added), current
+        assert_eq!(vec![3, 1, 2, 2, 3, 3], serials(&messages[0]));
+        assert_eq!(10, messages[0].answers.len());
+    }
+
+    #[test]
+    fn ixfr_current() {
+        let client = IpAddr::from_str("127.0.0.1").unwrap();
+        let messages = respond(&ixfr_query(3), &journaled_store(), &client, None);
+        assert_eq!(1, messages[0].answers.len());
+        assert_eq!(vec![3], serials(&messages[0]));
+    }
+
+    #[test]
+    fn ixfr_falls_back_to_axfr() {
+        let client = IpAddr::from_str("127.0.0.1").unwrap();
+        let messages = respond(&ixfr_query(0), &journaled_store(), &client, None);
+        let answers = &messages[0].answers;
+        assert_eq!(vec![3, 3], serials(&messages[0]));
+        assert_eq!(zone_with(3, "www A 10.0.0.3\n").len() + 1, answers.len());
+    }
+
+    #[test]
+    fn ixfr_udp() {
+        let msg = respond_udp(&ixfr_query(1), &journaled_store());
+        assert_eq!(Rcode::NOERROR, msg.header.rcode);
+        assert_eq!(vec![3], serials(&msg));
+        assert_eq!(Rcode::REFUSED, respond_udp(&query(), &journaled_store()).header.rcode);
+    }
+
+    #[test]
+    fn complete() {
+        let client = IpAddr::from_str("127.0.0.1").unwrap();
+        let store = journaled_store();
+        for query in &[query(), ixfr_query(0), ixfr_query(1), ixfr_query(3)] {
+            let messages = respond(query, &store, &client, None);
+            assert!(is_complete(query, &messages));
+            //split into a message per record, only the whole lot is complete
+            let answers = join(messages).unwrap().answers;
+            let mut partial = Vec::<DnsMessage>::new();
+            for (i, rr) in answers.iter().enumerate() {
+                assert!(!is_complete(query, &partial), "complete at {} of {}", i, answers.len());
+                let mut msg = DnsMessage::new_reply(test_header(), Vec::new(), Vec::new());
+                msg.answers.push(rr.clone());
+                partial.push(msg);
+            }
+            assert!(is_complete(query, &partial));
+        }
+        //up to date is just the SOA. one that's newer needs the rest
+        let current = respond(&ixfr_query(3), &store, &client, None);
+        assert!(!is_complete(&ixfr_query(2), &current));
+
+        let refused = respond(&query(), &ZoneStore::default(), &client, None);
+        assert!(is_complete(&query(), &refused));
+    }
+
+    #[test]
+    fn packs_to_64k() {
+        let name = DnsName::from_string("big.example.org".to_owned());
+        let records = (0..2000)
+                          .map(|i| DnsAnswer::with_rdata(name.clone(), RecordType::TXT, 1, 60, vec![i as u8; 100]))
+                          .collect::<Vec<_>>();
+        let messages = pack(&query(), records);
+        assert_eq!(4, messages.len());
+        assert_eq!(1, messages[0].questions.len());
+        assert!(messages[1].questions.is_empty());
+        let mut total = 0;
+        for msg in &messages {
+            let len = msg.to_bytes_max(MAX_MESSAGE_LEN).len();
+            assert!(len <= MAX_MESSAGE_LEN);
+            total += msg.answers.len();
+        }
+        assert_eq!(2000, total);
+    }
+}
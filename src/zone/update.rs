@@ -0,0 +1,399 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use dns::message::*;
+use dns::record::*;
+use dns::tsig;
+use zone::{dnssec, Zone, ZoneStore};
+
+///
+///Apply a dynamic UPDATE (RFC2136) from client to the zone it names. The question is the zone,
+///the answers the prerequisites and the authorities the updates. Either all the updates are
+///applied, with the serial bumped if they didn't set it, or none are. signed_by is the name of
+///the key the query's TSIG verified with, if it had one.
+///
+pub fn respond(query: &DnsMessage, zones: &mut ZoneStore, client: &IpAddr, signed_by: Option<&DnsName>) -> DnsMessage {
+    let zone_question = match zone_section(query) {
+        Ok(question) => question,
+        Err(rcode) => return reply(query, rcode),
+    };
+    let origin = zone_question.qname.to_string();
+    let zone = match zones.get(&zone_question.qname) {
+        Some(zone) if !zone.config.is_secondary() && zone_question.qclass == RecordClass::IN => zone.clone(),
+        _ => {
+            info!("UPDATE of {:?} from {:?}. Not authoritative", origin, client);
+            return reply(query, Rcode::NOTAUTH);
+        }
+    };
+    if !tsig::permitted(&zone.config.allow_update, zone.config.update_key.as_ref(), client, signed_by) {
+        warn!("UPDATE of {:?} from {:?} refused. Not in allow-update or not signed with update-key", origin, client);
+        return reply(query, Rcode::REFUSED);
+    }
+    if let Err(rcode) = check_prerequisites(&zone, &query.answers) {
+        info!("UPDATE of {:?} from {:?}. Prerequisite failed with {}", origin, client, rcode);
+        return reply(query, rcode);
+    }
+    if let Err(rcode) = prescan(&zone, &query.authorities) {
+        warn!("UPDATE of {:?} from {:?} rejected with {}", origin, client, rcode);
+        return reply(query, rcode);
+    }
+    let updated = apply(&zone, &query.authorities);
+    if updated.serial() != zone.serial() {
+        info!("UPDATE of {:?} from {:?}. Serial {} -> {}", origin, client, zone.serial(), updated.serial());
+        if let Err(e) = zones.commit(updated) {
+            error!("UPDATE of {:?} from {:?} not applied. {}", origin, client, e);
+            return reply(query, Rcode::SERVFAIL);
+        }
+    } else {
+        info!("UPDATE of {:?} from {:?} changed nothing", origin, client);
+    }
+    reply(query, Rcode::NOERROR)
+}
+
+///The reply echoes the zone section. RFC2136 3.8
+fn reply(query: &DnsMessage, rcode: u8) -> DnsMessage {
+    DnsMessage::new_reply(DnsHeader::new_reply(&query.header, rcode), query.questions.clone(), Vec::new())
+}
+
+///One SOA question naming the zone. RFC2136 3.1.1
+fn zone_section(query: &DnsMessage) -> Result<&DnsQuestion, u8> {
+    match query.questions.first() {
+        Some(question) if query.questions.len() == 1 && question.qtype == RecordType::SOA => Ok(question),
+        _ => Err(Rcode::FORMERR),
+    }
+}
+
+fn is_meta(rtype: u16) -> bool {
+    rtype == RecordType::AXFR || rtype == RecordType::MAILA || rtype == RecordType::MAILB || rtype == RecordType::ANY ||
+    rtype == RecordType::IXFR
+}
+
+///RFC2136 3.2
+fn check_prerequisites(zone: &Zone, prerequisites: &[DnsAnswer]) -> Result<(), u8> {
+    //RRsets that must exist with exactly these records, by name and type
+    let mut values = Vec::<((String, u16), Vec<&DnsAnswer>)>::new();
+    for rr in prerequisites {
+        if rr.ttl != 0 {
+            return Err(Rcode::FORMERR);
+        }
+        if !rr.name.is_subdomain_of(&zone.origin) {
+            return Err(Rcode::NOTZONE);
+        }
+        match rr.aclass {
+            RecordClass::ANY | RecordClass::NONE if !rr.rdata.is_empty() => return Err(Rcode::FORMERR),
+            RecordClass::ANY if rr.atype == RecordType::ANY => {
+                if zone.find_all(&rr.name).is_empty() {
+                    return Err(Rcode::NXDOMAIN);
+                }
+            }
+            RecordClass::ANY => {
+                if zone.find(&rr.name, rr.atype).is_empty() {
+                    return Err(Rcode::NXRRSET);
+                }
+            }
+            RecordClass::NONE if rr.atype == RecordType::ANY => {
+                if !zone.find_all(&rr.name).is_empty() {
+                    return Err(Rcode::YXDOMAIN);
+                }
+            }
+            RecordClass::NONE => {
+                if !zone.find(&rr.name, rr.atype).is_empty() {
+                    return Err(Rcode::YXRRSET);
+                }
+            }
+            RecordClass::IN if !is_meta(rr.atype) => {
+                let key = (rr.name.to_lowercase().to_string(), rr.atype);
+                match values.iter().position(|entry| entry.0 == key) {
+                    Some(i) => values[i].1.push(rr),
+                    None => values.push((key, vec![rr])),
+                }
+            }
+            _ => return Err(Rcode::FORMERR),
+        }
+    }
+    for (_, expected) in values {
+        let first = expected[0];
+        let actual = zone.find(&first.name, first.atype);
+        let same = actual.len() == expected.iter().map(|rr| &rr.rdata).collect::<HashSet<_>>().len() &&
+                   actual.iter().all(|rr| expected.iter().any(|e| e.rdata == rr.rdata));
+        if !same {
+            return Err(Rcode::NXRRSET);
+        }
+    }
+    Ok(())
+}
+
+///Check every update before applying any. RFC2136 3.4.1
+fn prescan(zone: &Zone, updates: &[DnsAnswer]) -> Result<(), u8> {
+    for rr in updates {
+        if !rr.name.is_subdomain_of(&zone.origin) {
+            return Err(Rcode::NOTZONE);
+        }
+        if !may_update(zone, &rr.name) {
+            warn!("{:?} is not in update-names", rr.name.to_string());
+            return Err(Rcode::REFUSED);
+        }
+        //the signer makes these. RFC3007 4.3
+        if zone.is_signed() && dnssec::is_generated(rr.atype) {
+            warn!("Refusing an update to the {} records of signed zone {:?}", RecordType::name(rr.atype), zone.origin.to_string());
+            return Err(Rcode::REFUSED);
+        }
+        let valid = match rr.aclass {
+            RecordClass::IN => !is_meta(rr.atype),
+            RecordClass::ANY => {
+                rr.ttl == 0 && rr.rdata.is_empty() && (rr.atype == RecordType::ANY || !is_meta(rr.atype))
+            }
+            RecordClass::NONE => rr.ttl == 0 && !is_meta(rr.atype),
+            _ => false,
+        };
+        if !valid {
+            return Err(Rcode::FORMERR);
+        }
+    }
+    Ok(())
+}
+
+///Whether the zone's update-names lets name be changed. A pattern of *.suffix matches names below suffix
+fn may_update(zone: &Zone, name: &DnsName) -> bool {
+    let patterns = &zone.config.update_names;
+    patterns.is_empty() ||
+    patterns.iter().any(|pattern| {
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => {
+                let suffix = DnsName::from_string(suffix.to_owned());
+                name.is_subdomain_of(&suffix) && !name.eq_ignore_case(&suffix)
+            }
+            None => name.eq_ignore_case(&DnsName::from_string(pattern.clone())),
+        }
+    })
+}
+
+///The zone with the updates applied. RFC2136 3.4.2
+fn apply(zone: &Zone, updates: &[DnsAnswer]) -> Zone {
+    let mut updated = zone.clone();
+    let mut changed = false;
+    for rr in updates {
+        changed |= match rr.aclass {
+            RecordClass::IN => add(&mut updated, rr),
+            RecordClass::ANY => delete_rrset(&mut updated, rr),
+            _ => delete_rr(&mut updated, rr),
+        };
+    }
+    //the SOA is updated automatically, unless the update set a newer serial. RFC2136 3.6
+    if changed && !serial_lt(zone.serial(), updated.serial()) {
+        bump_serial(&mut updated);
+    }
+    updated
+}
+
+fn add(zone: &mut Zone, rr: &DnsAnswer) -> bool {
+    let at_apex = rr.name.eq_ignore_case(&zone.origin);
+    let existing = zone.find_all(&rr.name);
+    match rr.atype {
+        RecordType::SOA => {
+            let newer = match (Soa::from_rdata(&rr.rdata), zone.soa_record().cloned()) {
+                (Some(soa), Some(old)) if at_apex && serial_lt(zone.serial(), soa.serial) => Some(old),
+                _ => None,
+            };
+            match newer {
+                Some(old) => {
+                    zone.remove(&old);
+                    zone.add(rr.clone())
+                }
+                None => false,
+            }
+        }
+        //a CNAME can't share its name with other data. RFC2136 3.4.2.2
+        RecordType::CNAME => {
+            if existing.iter().any(|e| e.atype != RecordType::CNAME) {
+                return false;
+            }
+            for old in existing {
+                zone.remove(&old);
+            }
+            zone.add(rr.clone())
+        }
+        _ if existing.iter().any(|e| e.atype == RecordType::CNAME) => false,
+        _ => zone.add(rr.clone()),
+    }
+}
+
+///Class ANY deletes an RRset, or with type ANY, everything at the name. The apex SOA and NS stay
+fn delete_rrset(zone: &mut Zone, rr: &DnsAnswer) -> bool {
+    let at_apex = rr.name.eq_ignore_case(&zone.origin);
+    let mut changed = false;
+    for old in zone.find_all(&rr.name) {
+        let protected = at_apex && (old.atype == RecordType::SOA || old.atype == RecordType::NS);
+        if (rr.atype == RecordType::ANY || rr.atype == old.atype) && !protected {
+            changed |= zone.remove(&old);
+        }
+    }
+    changed
+}
+
+///Class NONE deletes one RR. Never the SOA, or the last NS at the apex
+fn delete_rr(zone: &mut Zone, rr: &DnsAnswer) -> bool {
+    if rr.atype == RecordType::SOA {
+        return false;
+    }
+    if rr.atype == RecordType::NS && rr.name.eq_ignore_case(&zone.origin) &&
+       zone.find(&zone.origin, RecordType::NS).len() <= 1 {
+        return false;
+    }
+    let mut record = rr.clone();
+    record.aclass = RecordClass::IN;
+    zone.remove(&record)
+}
+
+fn bump_serial(zone: &mut Zone) {
+    if let (Some(old), Some(mut soa)) = (zone.soa_record().cloned(), zone.soa()) {
+        soa.serial = soa.serial.wrapping_add(1);
+        let new = DnsAnswer::with_rdata(old.name.clone(), old.atype, old.aclass, old.ttl, soa.to_rdata());
+        zone.remove(&old);
+        zone.add(new);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::respond;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use cidr::Cidr;
+    use dns::message::*;
+    use dns::record::*;
+    use dns::tsig::TsigKey;
+    use zone::ZoneStore;
+    use zone::test::zone_with;
+
+    fn store(names: &[&str]) -> ZoneStore {
+        let mut zone = zone_with(1, "www A 10.0.0.2\nalias CNAME www\n");
+        zone.config.allow_update = vec![Cidr::from_str("10.0.0.0/8").unwrap()];
+        zone.config.update_names = names.iter().map(|s| s.to_string()).collect();
+        let mut store = ZoneStore::default();
+        store.insert(zone);
+        store
+    }
+
+    fn name(s: &str) -> DnsName {
+        DnsName::from_string(s.to_owned())
+    }
+
+    fn rr(owner: &str, atype: u16, aclass: u16, ttl: u32, rdata: Vec<u8>) -> DnsAnswer {
+        DnsAnswer::with_rdata(name(owner), atype, aclass, ttl, rdata)
+    }
+
+    fn update(prerequisites: Vec<DnsAnswer>, updates: Vec<DnsAnswer>) -> DnsMessage {
+        let mut header = DnsHeader::new_query(7);
+        header.opcode = Opcode::UPDATE;
+        let question = DnsQuestion::new(name("example.org"), RecordType::SOA, RecordClass::IN);
+        let mut msg = DnsMessage::new_query(header, vec![question]);
+        msg.answers = prerequisites;
+        msg.authorities = updates;
+        DnsMessage::parse(&msg.to_bytes())
+    }
+
+    fn send(store: &mut ZoneStore, msg: &DnsMessage, client: &str) -> u8 {
+        let reply = respond(msg, store, &IpAddr::from_str(client).unwrap(), None);
+        assert_eq!(Opcode::UPDATE, reply.header.opcode);
+        reply.header.rcode
+    }
+
+    #[test]
+    fn add_and_delete() {
+        let mut store = store(&[]);
+        let add = update(vec![], vec![rr("host.example.org", RecordType::A, RecordClass::IN, 60, vec![10, 0, 0, 9])]);
+        assert_eq!(Rcode::NOERROR, send(&mut store, &add, "10.1.2.3"));
+        {
+            let zone = store.get(&name("example.org")).unwrap();
+            assert_eq!(2, zone.serial());
+            assert_eq!(1, zone.find(&name("host.example.org"), RecordType::A).len());
+            assert_eq!(1, zone.journal.len());
+        }
+        assert_eq!(vec![name("example.org")], store.take_changed());
+
+        //a duplicate changes nothing, so the serial stays
+        assert_eq!(Rcode::NOERROR, send(&mut store, &add, "10.1.2.3"));
+        assert_eq!(2, store.get(&name("example.org")).unwrap().serial());
+
+        let delete = update(vec![], vec![rr("host.example.org", RecordType::A, RecordClass::NONE, 0, vec![10, 0, 0, 9]),
+                                         rr("www.example.org", RecordType::ANY, RecordClass::ANY, 0, vec![]),
+                                         rr("example.org", RecordType::ANY, RecordClass::ANY, 0, vec![])]);
+        assert_eq!(Rcode::NOERROR, send(&mut store, &delete, "10.1.2.3"));
+        let zone = store.get(&name("example.org")).unwrap();
+        assert_eq!(3, zone.serial());
+        assert!(zone.find_all(&name("host.example.org")).is_empty());
+        assert!(zone.find_all(&name("www.example.org")).is_empty());
+        assert_eq!(1, zone.find(&name("example.org"), RecordType::NS).len());
+    }
+
+    #[test]
+    fn prerequisites() {
+        let mut store = store(&[]);
+        let cases = vec![(rr("www.example.org", RecordType::ANY, RecordClass::ANY, 0, vec![]), Rcode::NOERROR),
+                         (rr("nope.example.org", RecordType::ANY, RecordClass::ANY, 0, vec![]), Rcode::NXDOMAIN),
+                         (rr("www.example.org", RecordType::MX, RecordClass::ANY, 0, vec![]), Rcode::NXRRSET),
+                         (rr("www.example.org", RecordType::ANY, RecordClass::NONE, 0, vec![]), Rcode::YXDOMAIN),
+                         (rr("www.example.org", RecordType::A, RecordClass::NONE, 0, vec![]), Rcode::YXRRSET),
+                         (rr("www.example.org", RecordType::A, RecordClass::IN, 0, vec![10, 0, 0, 2]), Rcode::NOERROR),
+                         (rr("www.example.org", RecordType::A, RecordClass::IN, 0, vec![10, 0, 0, 3]), Rcode::NXRRSET),
+                         (rr("www.example.com", RecordType::A, RecordClass::ANY, 0, vec![]), Rcode::NOTZONE),
+                         (rr("www.example.org", RecordType::A, RecordClass::ANY, 60, vec![]), Rcode::FORMERR)];
+        for (prerequisite, rcode) in cases {
+            let msg = update(vec![prerequisite.clone()], vec![]);
+            assert_eq!(rcode, send(&mut store, &msg, "10.1.2.3"), "{:?}", prerequisite);
+        }
+    }
+
+    #[test]
+    fn cname_conflicts() {
+        let mut store = store(&[]);
+        let msg = update(vec![],
+                         vec![rr("alias.example.org", RecordType::A, RecordClass::IN, 60, vec![10, 0, 0, 9]),
+                              rr("www.example.org", RecordType::CNAME, RecordClass::IN, 60, name("ns1.example.org").to_bytes())]);
+        assert_eq!(Rcode::NOERROR, send(&mut store, &msg, "10.1.2.3"));
+        let zone = store.get(&name("example.org")).unwrap();
+        assert_eq!(1, zone.serial());
+        assert!(zone.find(&name("alias.example.org"), RecordType::A).is_empty());
+        assert!(zone.find(&name("www.example.org"), RecordType::CNAME).is_empty());
+    }
+
+    #[test]
+    fn policy() {
+        let mut store = store(&["*.dhcp.example.org", "printer.example.org"]);
+        let add = |owner: &str| update(vec![], vec![rr(owner, RecordType::A, RecordClass::IN, 60, vec![10, 0, 0, 9])]);
+        assert_eq!(Rcode::REFUSED, send(&mut store, &add("host.dhcp.example.org"), "192.168.1.1"));
+        assert_eq!(Rcode::NOERROR, send(&mut store, &add("host.dhcp.example.org"), "10.1.2.3"));
+        assert_eq!(Rcode::NOERROR, send(&mut store, &add("Printer.example.org"), "10.1.2.3"));
+        assert_eq!(Rcode::REFUSED, send(&mut store, &add("dhcp.example.org"), "10.1.2.3"));
+        assert_eq!(Rcode::REFUSED, send(&mut store, &add("www.example.org"), "10.1.2.3"));
+        assert_eq!(Rcode::NOTZONE, send(&mut store, &add("host.example.com"), "10.1.2.3"));
+        assert_eq!(3, store.get(&name("example.org")).unwrap().serial());
+    }
+
+    #[test]
+    fn key_policy() {
+        let key = TsigKey::new("ddns", "hmac-sha256", b"secret".to_vec()).unwrap();
+        let mut zone = zone_with(1, "");
+        zone.config.allow_update = vec![Cidr::from_str("10.0.0.0/8").unwrap()];
+        zone.config.update_key = Some(key.clone());
+        let mut store = ZoneStore::default();
+        store.insert(zone);
+        let msg = update(vec![], vec![rr("host.example.org", RecordType::A, RecordClass::IN, 60, vec![10, 0, 0, 9])]);
+        let client = IpAddr::from_str("10.1.2.3").unwrap();
+        assert_eq!(Rcode::REFUSED, respond(&msg, &mut store, &client, None).header.rcode);
+        assert_eq!(Rcode::REFUSED, respond(&msg, &mut store, &client, Some(&name("other"))).header.rcode);
+        assert_eq!(Rcode::NOERROR, respond(&msg, &mut store, &client, Some(&key.name)).header.rcode);
+        let outside = IpAddr::from_str("192.168.1.1").unwrap();
+        assert_eq!(Rcode::REFUSED, respond(&msg, &mut store, &outside, Some(&key.name)).header.rcode);
+    }
+
+    #[test]
+    fn not_authoritative() {
+        let mut store = store(&[]);
+        let mut msg = update(vec![], vec![]);
+        msg.questions[0].qname = name("example.com");
+        assert_eq!(Rcode::NOTAUTH, send(&mut store, &msg, "10.1.2.3"));
+        msg.questions[0].qtype = RecordType::A;
+        assert_eq!(Rcode::FORMERR, send(&mut store, &msg, "10.1.2.3"));
+    }
+}